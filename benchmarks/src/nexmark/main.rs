@@ -25,10 +25,11 @@ use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
 use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
 use datafusion::physical_plan::ExecutionPlan;
-use flock::aws::{efs, lambda, s3};
+use flock::aws::{efs, lambda, s3, scheduler};
 use flock::prelude::*;
+use flock::registry::{QueryRecord, QueryRegistry};
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use nexmark::event::{side_input_schema, Auction, Bid, Person};
 use nexmark::NEXMarkSource;
 use rainbow::rainbow_string;
@@ -50,9 +51,9 @@ lazy_static! {
     pub static ref NEXMARK_PERSON: SchemaRef = Arc::new(Person::schema());
     pub static ref NEXMARK_AUCTION: SchemaRef = Arc::new(Auction::schema());
     pub static ref NEXMARK_SOURCE_LOG_GROUP: String = "/aws/lambda/flock_datasource".to_string();
-    pub static ref NEXMARK_Q4_S3_KEY: String = FLOCK_CONF["nexmark"]["q4_s3_key"].to_string();
-    pub static ref NEXMARK_Q6_S3_KEY: String = FLOCK_CONF["nexmark"]["q6_s3_key"].to_string();
-    pub static ref NEXMARK_Q13_S3_SIDE_INPUT_KEY: String = FLOCK_CONF["nexmark"]["q13_s3_side_input_key"].to_string();
+    pub static ref NEXMARK_Q4_S3_KEY: String = FLOCK_CONFIG.nexmark.q4_s3_key.clone();
+    pub static ref NEXMARK_Q6_S3_KEY: String = FLOCK_CONFIG.nexmark.q6_s3_key.clone();
+    pub static ref NEXMARK_Q13_S3_SIDE_INPUT_KEY: String = FLOCK_CONFIG.nexmark.q13_s3_side_input_key.clone();
 }
 
 #[derive(Default, Clone, Debug, StructOpt)]
@@ -101,6 +102,14 @@ pub struct NexmarkBenchmarkOpt {
     /// This is only used in distributed mode.
     #[structopt(short = "p", long = "target_partitions", default_value = "8")]
     pub target_partitions: usize,
+
+    /// The maximum number of in-flight function invocations of the query
+    #[structopt(long = "max_in_flight")]
+    pub max_in_flight: Option<usize>,
+
+    /// The maximum number of function invocations per second of the query
+    #[structopt(long = "invocation_rate")]
+    pub invocation_rate: Option<f64>,
 }
 
 #[allow(dead_code)]
@@ -254,6 +263,27 @@ pub async fn create_nexmark_functions(
         CloudFunction::Sink(_) => unreachable!(),
     }
 
+    // Record the query in the registry, so that its lifecycle can be managed by the
+    // CLI. The data source function is shared by all queries, so it isn't recorded.
+    let contexts = match &next_func_name {
+        CloudFunction::Group((name, concurrency)) => (0..*concurrency)
+            .map(|i| ExecutionContext {
+                name: format!("{}-{:02}", name, i),
+                ..nexmark_worker_ctx.clone()
+            })
+            .collect(),
+        _ => vec![nexmark_worker_ctx.clone()],
+    };
+    let record = QueryRecord::new(
+        &format!("q{}", opt.query_number),
+        &nexmark_query(opt.query_number).join("\n"),
+        DataSinkType::new(&opt.data_sink_type)?,
+        &contexts,
+    );
+    if let Err(e) = QueryRegistry::from_config()?.register(&record).await {
+        warn!("Failed to register the query {}: {}", record.query_code, e);
+    }
+
     Ok(next_func_name)
 }
 
@@ -334,6 +364,17 @@ pub async fn add_extra_metadata(
         },
     );
 
+    if let Some(max_in_flight) = opt.max_in_flight {
+        metadata.insert(
+            scheduler::MAX_IN_FLIGHT.to_string(),
+            max_in_flight.to_string(),
+        );
+    }
+
+    if let Some(rate) = opt.invocation_rate {
+        metadata.insert(scheduler::INVOCATION_RATE.to_string(), rate.to_string());
+    }
+
     if opt.query_number == 12 {
         metadata.insert(
             "add_process_time_query".to_string(),
@@ -442,17 +483,26 @@ mod tests {
 
             flock_ctx
                 .feed_data_sources(vec![
-                    vec![event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), 1024)],
-                    vec![event_bytes_to_batch(
-                        &event.persons,
-                        NEXMARK_PERSON.clone(),
-                        1024,
-                    )],
-                    vec![event_bytes_to_batch(
-                        &event.auctions,
-                        NEXMARK_AUCTION.clone(),
-                        1024,
-                    )],
+                    (
+                        "bid".to_owned(),
+                        vec![event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), 1024)],
+                    ),
+                    (
+                        "person".to_owned(),
+                        vec![event_bytes_to_batch(
+                            &event.persons,
+                            NEXMARK_PERSON.clone(),
+                            1024,
+                        )],
+                    ),
+                    (
+                        "auction".to_owned(),
+                        vec![event_bytes_to_batch(
+                            &event.auctions,
+                            NEXMARK_AUCTION.clone(),
+                            1024,
+                        )],
+                    ),
                 ])
                 .await?;
 
@@ -16,9 +16,10 @@ use datafusion::arrow::util::pretty::pretty_format_batches;
 use datafusion::physical_plan::ExecutionPlan;
 use flock::aws::{cloudwatch, lambda};
 use flock::prelude::*;
+use flock::registry::{QueryRecord, QueryRegistry};
 use humantime::parse_duration;
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use rusoto_lambda::InvocationResponse;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -136,6 +137,24 @@ async fn create_ysb_functions(
         _ => unreachable!(),
     }
 
+    // Record the query in the registry, so that its lifecycle can be managed by the
+    // CLI. The data source function is shared by all queries, so it isn't recorded.
+    let contexts = (0..*FLOCK_FUNCTION_CONCURRENCY)
+        .map(|i| ExecutionContext {
+            name: format!("{}-{:02}", worker_func_name, i),
+            ..ysb_worker_ctx.clone()
+        })
+        .collect::<Vec<_>>();
+    let record = QueryRecord::new(
+        "ysb",
+        &ysb_query(),
+        DataSinkType::new(&opt.data_sink_type)?,
+        &contexts,
+    );
+    if let Err(e) = QueryRegistry::from_config()?.register(&record).await {
+        warn!("Failed to register the query {}: {}", record.query_code, e);
+    }
+
     Ok(next_func_name)
 }
 
@@ -248,12 +267,18 @@ mod tests {
 
         flock_ctx
             .feed_data_sources(vec![
-                vec![event_bytes_to_batch(
-                    &event.ad_events,
-                    YSB_AD_EVENT.clone(),
-                    1024,
-                )],
-                vec![event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), 1024)],
+                (
+                    "ad_event".to_owned(),
+                    vec![event_bytes_to_batch(
+                        &event.ad_events,
+                        YSB_AD_EVENT.clone(),
+                        1024,
+                    )],
+                ),
+                (
+                    "campaign".to_owned(),
+                    vec![event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), 1024)],
+                ),
             ])
             .await?;
 
@@ -0,0 +1,60 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Flock CLI prints and validates the effective configuration.
+
+use anyhow::{anyhow, Result};
+use benchmarks::rainbow_println;
+use clap::{App, Arg, ArgMatches};
+use flock::configs::FlockConfig;
+
+pub fn command(matches: &ArgMatches) -> Result<()> {
+    let config = FlockConfig::load(matches.value_of("profile"), matches.value_of("file"))
+        .map_err(|e| anyhow!(e))?;
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+    } else {
+        println!("{}", config);
+    }
+    rainbow_println("[OK] the configuration is valid");
+
+    Ok(())
+}
+
+pub fn command_args() -> App<'static> {
+    App::new("config")
+        .about("Prints the effective configuration of Flock")
+        .arg(
+            Arg::new("profile")
+                .short('p')
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Sets the configuration profile (dev, prod, local)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("file")
+                .short('f')
+                .long("file")
+                .value_name("FILE")
+                .help("Sets the configuration file to override the defaults")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("json")
+                .short('j')
+                .long("json")
+                .help("Prints the configuration in JSON format"),
+        )
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Flock CLI inspects and replays the failed invocations in the dead-letter
+//! queue.
+
+use anyhow::{anyhow, Context as _, Result};
+use benchmarks::rainbow_println;
+use clap::{App, AppSettings, ArgMatches};
+use flock::deadletter::{self, DeadLetterQueue};
+use log::warn;
+
+pub fn command(matches: &ArgMatches) -> Result<()> {
+    let (command, _) = match matches.subcommand() {
+        Some((command, matches)) => (command, matches),
+        None => unreachable!(),
+    };
+
+    match command {
+        "list" => futures::executor::block_on(list_dead_letters()),
+        "replay" => futures::executor::block_on(replay_dead_letters()),
+        _ => {
+            warn!("{} command is not implemented", command);
+            Ok(())
+        }
+    }
+    .with_context(|| anyhow!("{} command failed", command))?;
+
+    Ok(())
+}
+
+pub fn command_args() -> App<'static> {
+    App::new("deadletter")
+        .about("The Dead-Letter Queue Tool for Flock")
+        .setting(AppSettings::SubcommandRequired)
+        .subcommand(App::new("list").about("Lists the failed invocations"))
+        .subcommand(App::new("replay").about("Re-invokes the failed invocations"))
+}
+
+async fn list_dead_letters() -> Result<()> {
+    let queue = DeadLetterQueue::from_config();
+    rainbow_println(format!("[INFO] dead-letter queue: {:?}", queue));
+
+    let letters = queue.peek().await.map_err(|e| anyhow!(e))?;
+    for letter in &letters {
+        println!(
+            "{} {} {:?} [{}]: {}",
+            letter.timestamp,
+            letter.function_name,
+            letter.uuid,
+            letter.invocation_type,
+            letter.error
+        );
+    }
+    rainbow_println(format!("[OK] {} dead letters", letters.len()));
+
+    Ok(())
+}
+
+async fn replay_dead_letters() -> Result<()> {
+    let queue = DeadLetterQueue::from_config();
+    let replayed = deadletter::replay(&queue).await.map_err(|e| anyhow!(e))?;
+    rainbow_println(format!("[OK] replayed {} dead letters", replayed));
+
+    Ok(())
+}
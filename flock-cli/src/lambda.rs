@@ -16,8 +16,8 @@
 use anyhow::{Ok, Result};
 use benchmarks::rainbow_println;
 use clap::{App, Arg, ArgMatches};
-use rusoto_core::Region;
-use rusoto_lambda::{DeleteFunctionRequest, Lambda, LambdaClient, ListFunctionsRequest};
+use flock::configs::FLOCK_LAMBDA_CLIENT;
+use rusoto_lambda::{DeleteFunctionRequest, Lambda, ListFunctionsRequest};
 
 pub fn command(matches: &ArgMatches) -> Result<()> {
     if matches.is_present("delete function") {
@@ -81,9 +81,7 @@ async fn delete_function(pattern: Option<&str>) -> Result<()> {
                     function_name: name,
                     ..Default::default()
                 };
-                LambdaClient::new(Region::default())
-                    .delete_function(request)
-                    .await
+                FLOCK_LAMBDA_CLIENT.delete_function(request).await
             })
         })
         .collect::<Vec<_>>();
@@ -118,7 +116,7 @@ async fn delete_all_functions() -> Result<()> {
 /// # Returns
 /// A vector of function names.
 async fn list_functions(pattern: Option<&str>) -> Result<Vec<String>> {
-    let client = LambdaClient::new(Region::default());
+    let client = &FLOCK_LAMBDA_CLIENT;
     let mut request = ListFunctionsRequest {
         ..Default::default()
     };
@@ -12,9 +12,12 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 mod args;
+mod config;
+mod deadletter;
 mod fsql;
 mod lambda;
 mod nexmark;
+mod query;
 #[cfg(feature = "cli")]
 mod repl;
 mod s3;
@@ -137,6 +137,18 @@ fn run_args() -> App<'static> {
                 .possible_values(&["1", "2", "4", "8", "16", "32"])
                 .default_value("8"),
         )
+        .arg(
+            Arg::new("max in flight")
+                .long("max-in-flight")
+                .help("Sets the maximum number of in-flight function invocations of the query")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("invocation rate")
+                .long("invocation-rate")
+                .help("Sets the maximum number of function invocations per second of the query")
+                .takes_value(true),
+        )
 }
 
 pub fn run(matches: &ArgMatches) -> Result<()> {
@@ -226,6 +238,26 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
             .with_context(|| anyhow!("Invalid Arrow Datafusion target partitions"))?;
     }
 
+    if matches.is_present("max in flight") {
+        opt.max_in_flight = Some(
+            matches
+                .value_of("max in flight")
+                .unwrap()
+                .parse::<usize>()
+                .with_context(|| anyhow!("Invalid max in flight"))?,
+        );
+    }
+
+    if matches.is_present("invocation rate") {
+        opt.invocation_rate = Some(
+            matches
+                .value_of("invocation rate")
+                .unwrap()
+                .parse::<f64>()
+                .with_context(|| anyhow!("Invalid invocation rate"))?,
+        );
+    }
+
     rainbow_println(include_str!("./flock"));
 
     futures::executor::block_on(nexmark_benchmark(&mut opt)).map_err(|e| e.into())
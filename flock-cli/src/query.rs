@@ -0,0 +1,147 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Flock CLI manages the lifecycle of the deployed queries in the query
+//! registry.
+
+use anyhow::{anyhow, Context as _, Result};
+use benchmarks::rainbow_println;
+use chrono::{TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use flock::registry::{QueryRecord, QueryRegistry};
+use log::warn;
+
+pub fn command(matches: &ArgMatches) -> Result<()> {
+    let (command, matches) = match matches.subcommand() {
+        Some((command, matches)) => (command, matches),
+        None => unreachable!(),
+    };
+    let registry = QueryRegistry::from_config().map_err(|e| anyhow!(e))?;
+    let query_code = matches.value_of("query code").unwrap_or_default();
+
+    match command {
+        "list" => futures::executor::block_on(list_queries(&registry)),
+        "describe" => futures::executor::block_on(describe_query(&registry, query_code)),
+        "pause" => futures::executor::block_on(pause_query(&registry, query_code)),
+        "resume" => futures::executor::block_on(resume_query(&registry, query_code)),
+        "teardown" => futures::executor::block_on(teardown_query(&registry, query_code)),
+        _ => {
+            warn!("{} command is not implemented", command);
+            Ok(())
+        }
+    }
+    .with_context(|| anyhow!("{} command failed", command))?;
+
+    Ok(())
+}
+
+pub fn command_args() -> App<'static> {
+    let query_code = || {
+        Arg::new("query code")
+            .value_name("QUERY CODE")
+            .help("The query code, e.g., q3")
+            .required(true)
+    };
+    App::new("query")
+        .about("The Query Lifecycle Tool for Flock")
+        .setting(AppSettings::SubcommandRequired)
+        .subcommand(App::new("list").about("Lists the deployed queries"))
+        .subcommand(
+            App::new("describe")
+                .about("Shows the SQL, stages and functions of a query")
+                .arg(query_code()),
+        )
+        .subcommand(
+            App::new("pause")
+                .about("Pauses a query by setting the concurrency of its functions to 0")
+                .arg(query_code()),
+        )
+        .subcommand(
+            App::new("resume")
+                .about("Resumes a paused query")
+                .arg(query_code()),
+        )
+        .subcommand(
+            App::new("teardown")
+                .about("Deletes a query's functions, event source mappings and state")
+                .arg(query_code()),
+        )
+}
+
+fn created_at(record: &QueryRecord) -> String {
+    Utc.timestamp_millis(record.created_at).to_rfc3339()
+}
+
+async fn list_queries(registry: &QueryRegistry) -> Result<()> {
+    let records = registry.list().await.map_err(|e| anyhow!(e))?;
+    for record in &records {
+        println!(
+            "{} [{}] created at {}, {} functions, sink: {:?}",
+            record.query_code,
+            record.status,
+            created_at(record),
+            record.functions.len(),
+            record.sink
+        );
+    }
+    rainbow_println(format!("[OK] {} queries", records.len()));
+
+    Ok(())
+}
+
+async fn describe_query(registry: &QueryRegistry, query_code: &str) -> Result<()> {
+    let record = registry
+        .describe(query_code)
+        .await
+        .map_err(|e| anyhow!(e))?;
+    println!("Query:      {}", record.query_code);
+    println!("Status:     {}", record.status);
+    println!("Created at: {}", created_at(&record));
+    println!("Sink:       {:?}", record.sink);
+    println!("SQL:\n{}\n", record.sql);
+    for stage in &record.stages {
+        println!("Stage {} -> {}", stage.function_name, stage.next);
+        println!("{}", stage.plan);
+    }
+    println!("Functions:\n{}", record.functions.join("\n"));
+
+    Ok(())
+}
+
+async fn pause_query(registry: &QueryRegistry, query_code: &str) -> Result<()> {
+    let record = registry.pause(query_code).await.map_err(|e| anyhow!(e))?;
+    rainbow_println(format!(
+        "[OK] paused {} functions of the query {}",
+        record.paused_concurrency.len(),
+        query_code
+    ));
+
+    Ok(())
+}
+
+async fn resume_query(registry: &QueryRegistry, query_code: &str) -> Result<()> {
+    registry.resume(query_code).await.map_err(|e| anyhow!(e))?;
+    rainbow_println(format!("[OK] resumed the query {}", query_code));
+
+    Ok(())
+}
+
+async fn teardown_query(registry: &QueryRegistry, query_code: &str) -> Result<()> {
+    registry
+        .teardown(query_code)
+        .await
+        .map_err(|e| anyhow!(e))?;
+    rainbow_println(format!("[OK] tore down the query {}", query_code));
+
+    Ok(())
+}
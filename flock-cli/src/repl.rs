@@ -12,9 +12,12 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use crate::args;
+use crate::config;
+use crate::deadletter;
 use crate::fsql;
 use crate::lambda;
 use crate::nexmark;
+use crate::query;
 use crate::s3;
 use crate::ysb;
 use anyhow::Context as _;
@@ -33,10 +36,13 @@ pub async fn main() -> Result<()> {
         .setting(AppSettings::PropagateVersion)
         .author("UMD Database Group")
         .args(&args::get_args())
+        .subcommand(config::command_args())
         .subcommand(nexmark::command_args())
         .subcommand(ysb::command_args())
         .subcommand(s3::command_args())
         .subcommand(lambda::command_args())
+        .subcommand(deadletter::command_args())
+        .subcommand(query::command_args())
         .subcommand(fsql::command_args());
 
     let global_matches = app_cli.get_matches();
@@ -48,10 +54,13 @@ pub async fn main() -> Result<()> {
     args::get_logging(&global_matches, matches)?.init();
 
     match command {
+        "config" => config::command(matches),
         "nexmark" => nexmark::command(matches),
         "ysb" => ysb::command(matches),
         "s3" => s3::command(matches),
         "lambda" => lambda::command(matches),
+        "deadletter" => deadletter::command(matches),
+        "query" => query::command(matches),
         "fsql" => fsql::command(matches),
         _ => {
             warn!("{} command is not implemented", command);
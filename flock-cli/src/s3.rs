@@ -17,20 +17,17 @@ use anyhow::{anyhow, bail, Context as _, Ok, Result};
 use benchmarks::rainbow_println;
 use clap::{App, AppSettings, Arg, ArgMatches};
 use flock::aws::s3;
-use ini::Ini;
+use flock::configs::{FLOCK_CONFIG, FLOCK_S3_CLIENT};
 use lazy_static::lazy_static;
 use log::warn;
-use rusoto_core::Region;
 use rusoto_s3::PutObjectRequest;
-use rusoto_s3::{S3Client, S3};
+use rusoto_s3::S3;
 use std::fs;
 use std::io::Write;
 use std::path::Path;
 
 lazy_static! {
-    /// Global settings.
-    pub static ref FLOCK_CONF: Ini = Ini::load_from_str(include_str!("../../flock/src/configs/flock.toml")).unwrap();
-    pub static ref FLOCK_S3_BUCKET: String = FLOCK_CONF["s3"]["bucket"].to_string();
+    pub static ref FLOCK_S3_BUCKET: String = FLOCK_CONFIG.s3.bucket.clone();
 }
 
 pub fn command(matches: &ArgMatches) -> Result<()> {
@@ -149,7 +146,7 @@ pub async fn put_function_object(matches: &ArgMatches) -> Result<()> {
         ..Default::default()
     };
 
-    S3Client::new(Region::default()).put_object(request).await?;
+    FLOCK_S3_CLIENT.put_object(request).await?;
     rainbow_println("[OK] Upload Succeed.");
 
     Ok(())
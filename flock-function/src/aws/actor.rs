@@ -11,33 +11,49 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::consistent_hash_context;
 use chrono::Utc;
-use datafusion::arrow::csv::reader::ReaderBuilder;
 use datafusion::arrow::record_batch::RecordBatch;
 use flock::aws::lambda;
 use flock::aws::s3;
+use flock::aws::scheduler;
+use flock::metrics::*;
 use flock::prelude::*;
-use flock::runtime::arena::WindowId;
+use flock::quarantine::{decode_payload, reject_payload};
+use flock::runtime::arena::{
+    EvictedWindow, InputFragments, SpillFile, TimeoutPolicy, WindowId, WindowInputs,
+    INCOMPLETE_WINDOW_KEY,
+};
+use flock::runtime::deadline::{Continuation, Deadline};
+use flock::runtime::group::{group_of, window_timestamp, FunctionGroup};
+use flock::runtime::join::INTERVAL_JOIN_INPUT;
+use flock::runtime::lookup::{cached_table, LookupSource, TableFormat, LOOKUP_JOIN_INPUT};
+use flock::runtime::pane::{Pane, PanePlan, PaneStore, PANE_INPUT};
+use flock::runtime::skew::{self, Salt, SkewPlanCache};
+use flock::trace::{Span, TraceContext, Tracer};
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde_json::json;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::Cursor;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 lazy_static! {
-    static ref CONCURRENCY: usize = FLOCK_CONF["lambda"]["concurrency"]
-        .parse::<usize>()
-        .unwrap();
+    static ref CONCURRENCY: usize = FLOCK_CONFIG.lambda.concurrency;
     static ref PROCESSED_WINDOWS: Mutex<HashSet<WindowId>> = Mutex::new(HashSet::new());
 }
 
+static REGISTERED_SCHEMAS: Once = Once::new();
+
+/// The default input id of the side input read from S3.
+const SIDE_INPUT_TABLE: &str = "side_input";
+
 /// The generic function executor.
 ///
 /// This function is invoked by the datafusion runtime. It is responsible for
@@ -48,24 +64,25 @@ lazy_static! {
 ///
 /// ## Arguments
 /// * `ctx` - The runtime context of the function.
-/// * `streams` - The input streams of the function.
+/// * `inputs` - The input streams of the function, keyed by the input ids.
 ///
 /// ## Returns
 /// The output stream of the function.
 pub async fn collect(
     ctx: &mut ExecutionContext,
-    streams: Vec<Vec<Vec<RecordBatch>>>,
+    inputs: WindowInputs,
 ) -> Result<Vec<Vec<RecordBatch>>> {
     info!("Executing the physical plan.");
-    ctx.feed_data_sources(streams).await?;
-    let output = if ctx.is_shuffling().await? {
-        let output = ctx.execute_partitioned().await?;
-        assert!(output.len() == 1);
-        output.into_iter().next().unwrap()
-    } else {
-        ctx.execute().await?
+    let spilled = inputs
+        .values()
+        .flat_map(|input| input.spilled.iter().flatten().cloned())
+        .collect::<Vec<_>>();
+    let result = match ctx.feed_spilled_sources(inputs).await {
+        Ok(()) => execute_plan(ctx).await,
+        Err(e) => Err(e),
     };
-    ctx.clean_data_sources().await?;
+    remove_spilled_files(spilled.iter());
+    let output = result?;
     info!("[OK] The execution is finished.");
 
     info!(
@@ -79,6 +96,145 @@ pub async fn collect(
     Ok(output)
 }
 
+/// Executes the physical plan over the data sources that are fed.
+async fn execute_plan(ctx: &mut ExecutionContext) -> Result<Vec<Vec<RecordBatch>>> {
+    let output = if ctx.is_shuffling().await? {
+        let output = ctx.execute_partitioned().await?;
+        assert!(output.len() == 1);
+        output.into_iter().next().unwrap()
+    } else {
+        ctx.execute().await?
+    };
+    ctx.clean_data_sources().await?;
+    Ok(output)
+}
+
+/// Runs the interval join and the lookup join of the function, if any, over the
+/// inputs of the window.
+async fn run_joins(
+    ctx: &ExecutionContext,
+    shuffle_id: Option<usize>,
+    window: &str,
+    inputs: WindowInputs,
+) -> Result<WindowInputs> {
+    let inputs = join_interval(ctx, shuffle_id, window, inputs).await?;
+    join_lookup(ctx, inputs).await
+}
+
+/// Joins the streams of a whole window, e.g., the union of the panes of a
+/// hopping window. Unlike [`run_joins`], the interval join doesn't touch the
+/// join states, since the window carries all the rows that can match.
+async fn run_window_joins(
+    ctx: &ExecutionContext,
+    mut inputs: WindowInputs,
+) -> Result<WindowInputs> {
+    if let Some(join) = ctx.plan.interval_join.as_ref() {
+        let mut streams = vec![];
+        for id in [&join.left.input, &join.right.input] {
+            if let Some(input) = inputs.remove(id) {
+                streams.push((id.clone(), read_fragments(input)?));
+            }
+        }
+        inputs.insert(
+            INTERVAL_JOIN_INPUT.to_owned(),
+            InputFragments {
+                records: vec![join.join_window(streams)?],
+                spilled: vec![None],
+            },
+        );
+    }
+    join_lookup(ctx, inputs).await
+}
+
+/// Reads the record batches of all the fragments of an input into memory, and
+/// removes the spilled files.
+fn read_fragments(input: InputFragments) -> Result<Vec<RecordBatch>> {
+    let mut records = input.records.into_iter().flatten().collect::<Vec<_>>();
+    for file in input.spilled.iter().flatten() {
+        records.extend(file.read()?);
+    }
+    remove_spilled_files(input.spilled.iter().flatten());
+    Ok(records)
+}
+
+/// Joins the new rows of the input streams with the interval join state of the
+/// partition, and replaces them with the new matches. The other inputs, such
+/// as the side input, are passed through.
+async fn join_interval(
+    ctx: &ExecutionContext,
+    shuffle_id: Option<usize>,
+    window: &str,
+    inputs: WindowInputs,
+) -> Result<WindowInputs> {
+    let join = match ctx.plan.interval_join.as_ref() {
+        Some(join) => join,
+        None => return Ok(inputs),
+    };
+
+    let mut streams = vec![];
+    let mut others = WindowInputs::new();
+    for (id, input) in inputs {
+        if id != join.left.input && id != join.right.input {
+            others.insert(id, input);
+            continue;
+        }
+        streams.push((id, read_fragments(input)?));
+    }
+
+    let output = join
+        .process(
+            ctx.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &ctx.name,
+            shuffle_id.unwrap_or(1),
+            window,
+            streams,
+        )
+        .await?;
+    others.insert(
+        INTERVAL_JOIN_INPUT.to_owned(),
+        InputFragments {
+            records: vec![output],
+            spilled: vec![None],
+        },
+    );
+    Ok(others)
+}
+
+/// Enriches the rows of the stream input with the reference table of the lookup
+/// join, and replaces them with the enriched rows.
+async fn join_lookup(ctx: &ExecutionContext, mut inputs: WindowInputs) -> Result<WindowInputs> {
+    let join = match ctx.plan.lookup_join.as_ref() {
+        Some(join) => join,
+        None => return Ok(inputs),
+    };
+    let input = match inputs.remove(&join.stream) {
+        Some(input) => input,
+        None => return Ok(inputs),
+    };
+
+    let output = join
+        .process(read_fragments(input)?, ctx.state_backend.as_ref())
+        .await?;
+    inputs.insert(
+        LOOKUP_JOIN_INPUT.to_owned(),
+        InputFragments {
+            records: vec![output],
+            spilled: vec![None],
+        },
+    );
+    Ok(inputs)
+}
+
+/// Removes the spilled files of a window that is processed or dropped.
+fn remove_spilled_files<'a>(files: impl Iterator<Item = &'a SpillFile>) {
+    for file in files {
+        if let Err(e) = file.remove() {
+            warn!("Failed to remove the spilled file {:?}: {}", file.path, e);
+        }
+    }
+}
+
 /// Read the payload from S3 via the S3 bucket and the key.
 async fn read_payload_from_s3(bucket: String, key: String) -> Result<Payload> {
     let body = s3::get_object(&bucket, &key).await?;
@@ -94,6 +250,7 @@ async fn read_payload_from_s3(bucket: String, key: String) -> Result<Payload> {
 /// * `ctx` - The runtime context of the function.
 /// * `arena` - The global memory arena for the function across invocations.
 /// * `payload` - The payload of the function invocation.
+/// * `deadline` - The deadline of the function invocation.
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
@@ -101,15 +258,136 @@ pub async fn handler(
     ctx: &mut ExecutionContext,
     arena: &mut Arena,
     event: Payload,
+    deadline: &Deadline,
+) -> Result<Value> {
+    let (qid, shuffle_id) = event.get_window_id();
+    let window_id = format!("{}/{}", qid, shuffle_id);
+
+    let metrics = Metrics::for_function(&ctx.name);
+    metrics.set_property(WINDOW_ID, &window_id);
+    metrics.put(BYTES_IN, event.data_size() as f64, Unit::Bytes);
+
+    // The span of the current invocation is a child of the sender's span.
+    let mut span = Span::start("handler", event.trace.as_ref());
+    span.set_attribute("flock.function", &ctx.name);
+    span.set_attribute("flock.window_id", &window_id);
+
+    let telemetry = Telemetry {
+        metrics,
+        tracer: Tracer::new(),
+        trace: span.context.clone(),
+    };
+    let result = process_payload(ctx, arena, event, deadline, &telemetry).await;
+    // Near the deadline, the evicted windows stay in the arena until the next
+    // invocation.
+    if !deadline.is_near() {
+        handle_evicted_windows(ctx, arena, &telemetry).await;
+    }
+    checkpoint_windows(ctx, arena, &telemetry.metrics).await;
+
+    let metrics = &telemetry.metrics;
+    metrics.put(ARENA_SIZE, arena.memory_size() as f64, Unit::Bytes);
+    metrics.put(RETRIES, take_retries() as f64, Unit::Count);
+    metrics.put(
+        QUARANTINE_FAILURES,
+        take_quarantine_failures() as f64,
+        Unit::Count,
+    );
+    record_scheduler_metrics(metrics);
+    metrics.emit();
+
+    telemetry.tracer.record(span.end_with(&result));
+    telemetry.tracer.flush(&ctx.name);
+
+    result
+}
+
+/// Records the statistics of the invocation schedulers since the last
+/// invocation of the container.
+pub fn record_scheduler_metrics(metrics: &Metrics) {
+    let stats = scheduler::take_stats();
+    metrics.put(INVOCATIONS, stats.invocations as f64, Unit::Count);
+    metrics.put(THROTTLES, stats.throttles as f64, Unit::Count);
+    metrics.put(
+        THROTTLE_DELAY,
+        stats.throttle_delay as f64,
+        Unit::Milliseconds,
+    );
+    metrics.put(SCHEDULER_WAIT, stats.wait_time as f64, Unit::Milliseconds);
+    metrics.put(PEAK_IN_FLIGHT, stats.peak_in_flight as f64, Unit::Count);
+}
+
+/// The observability handles of a function invocation.
+#[derive(Debug, Clone)]
+struct Telemetry {
+    /// The metrics of the invocation.
+    metrics: Metrics,
+    /// The spans of the invocation.
+    tracer:  Tracer,
+    /// The trace context of the current span.
+    trace:   TraceContext,
+}
+
+impl Telemetry {
+    /// Starts a child span of the current span.
+    fn start_span(&self, name: &str) -> Span {
+        Span::start(name, Some(&self.trace))
+    }
+
+    /// Returns the telemetry whose current span is the given span.
+    fn with_span(&self, span: &Span) -> Self {
+        Telemetry {
+            trace: span.context.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Processes the payload of the function invocation and records the metrics
+/// and spans.
+async fn process_payload(
+    ctx: &mut ExecutionContext,
+    arena: &mut Arena,
+    event: Payload,
+    deadline: &Deadline,
+    telemetry: &Telemetry,
 ) -> Result<Value> {
     info!("Receiving a data packet: {:?}", event.uuid);
+    let metrics = &telemetry.metrics;
+    let continuation = Continuation::from_metadata(&event.metadata)?;
+
+    // Near the deadline, the function stops accepting new work, and a stateless
+    // function hands the data packet over to a new invocation as it is.
+    if deadline.is_near() && !ctx.is_aggregate() {
+        if let Some(next) = Continuation::next(continuation, 0) {
+            hand_over(&ctx.name, event, next).await?;
+            metrics.add(CONTINUATIONS, 1.0, Unit::Count);
+            let info = format!("[Ok] Function {}: data is handed over.", ctx.name);
+            info!("{}", info);
+            return Ok(json!({ "response": info }));
+        }
+    }
 
     let query_number = event.query_number;
-    let metadata = event.metadata.clone();
+    let mut metadata = event.metadata.clone();
+    Continuation::remove_from_metadata(&mut metadata);
     let uuid = event.uuid.clone();
     let shuffle_id = event.shuffle_id;
+    let salt = event.salt;
+
+    // The payloads from the former stage only carry the schema fingerprints, so the
+    // schemas of the data sources must be registered before decoding.
+    if !REGISTERED_SCHEMAS.is_completed() {
+        ctx.register_schemas().await?;
+        REGISTERED_SCHEMAS.call_once(|| {});
+    }
 
-    let (input, status) = prepare_data_sources(ctx, arena, event).await?;
+    let span = telemetry.start_span("prepare_data_sources");
+    let start = Instant::now();
+    let result = prepare_data_sources(ctx, arena, event, continuation, deadline, metrics).await;
+    metrics.add_elapsed(DECODE_TIME, start);
+    telemetry.tracer.record(span.end_with(&result));
+    let (inputs, status) = result?;
 
     if status == HashAggregateStatus::Processed {
         let info = format!("[Ok] Function {}: data is already processed.", ctx.name);
@@ -121,8 +399,106 @@ pub async fn handler(
         return Ok(json!({ "response": info }));
     }
 
-    let output = collect(ctx, input).await?;
-    invoke_next_functions(ctx, query_number, uuid, metadata, shuffle_id, output).await
+    let spilled_rows = inputs
+        .values()
+        .flat_map(|input| input.spilled.iter().flatten())
+        .map(|f| f.rows)
+        .sum::<usize>();
+    metrics.put(
+        ROWS_IN,
+        (num_rows(inputs.values().flat_map(|input| input.records.iter())) + spilled_rows) as f64,
+        Unit::Count,
+    );
+
+    if FLOCK_CONFIG.skew.enabled {
+        sample_heavy_keys(ctx, shuffle_id, salt, &inputs).await;
+    }
+
+    // The aggregate function collects a whole pane of the hopping windows, and
+    // only emits the windows that the pane completes.
+    if let Some(pane) = Pane::from_metadata(&metadata)?.filter(|_| ctx.is_aggregate()) {
+        let header = Payload {
+            query_number,
+            uuid,
+            metadata,
+            shuffle_id,
+            salt,
+            ..Default::default()
+        };
+        let span = telemetry.start_span("evaluate_pane");
+        let result = evaluate_pane(ctx, pane, inputs, header, &telemetry.with_span(&span)).await;
+        telemetry.tracer.record(span.end_with(&result));
+        return result;
+    }
+
+    let span = telemetry.start_span("collect");
+    let start = Instant::now();
+    let result = match run_joins(ctx, shuffle_id, &uuid.qid, inputs).await {
+        Ok(inputs) => collect(ctx, inputs).await,
+        Err(e) => Err(e),
+    };
+    metrics.add_elapsed(EXECUTE_TIME, start);
+    telemetry.tracer.record(span.end_with(&result));
+    let output = result?;
+    metrics.put(ROWS_OUT, num_rows(output.iter()) as f64, Unit::Count);
+
+    let span = telemetry.start_span("invoke_next_functions");
+    let result = invoke_next_functions(
+        ctx,
+        query_number,
+        uuid,
+        metadata,
+        shuffle_id,
+        salt,
+        output,
+        &telemetry.with_span(&span),
+    )
+    .await;
+    telemetry.tracer.record(span.end_with(&result));
+    result
+}
+
+/// Samples the key frequencies of the group aggregation's input, and publishes
+/// the heavy keys of its partition to the upstream functions. A failure only
+/// delays the skew mitigation, so it doesn't fail the invocation.
+async fn sample_heavy_keys(
+    ctx: &mut ExecutionContext,
+    shuffle_id: Option<usize>,
+    salt: Option<Salt>,
+    inputs: &WindowInputs,
+) {
+    let plans = match ctx.plan().await {
+        Ok(plans) => plans,
+        Err(e) => {
+            warn!("Failed to sample the heavy keys: {}", e);
+            return;
+        }
+    };
+    if let Some(aggregate) = skew::salted_aggregate(&plans) {
+        let partition = salt.map_or(shuffle_id.unwrap_or(1), |s| s.partition);
+        let batches = inputs
+            .values()
+            .take(1)
+            .flat_map(|input| input.records.iter().flatten())
+            .cloned()
+            .collect::<Vec<_>>();
+        if let Err(e) = skew::publish_heavy_keys(
+            &group_of(&ctx.name),
+            partition,
+            salt,
+            &batches,
+            &skew::aggregate_key_columns(aggregate),
+        )
+        .await
+        {
+            warn!("Failed to publish the heavy keys: {}", e);
+        }
+    }
+}
+
+/// Returns the total number of rows in the partitions.
+fn num_rows<'a>(partitions: impl Iterator<Item = &'a Vec<RecordBatch>>) -> usize {
+    partitions.flatten().map(|b| b.num_rows()).sum()
 }
 
 /// Get the S3 key's prefix for the current query stage
@@ -140,27 +516,33 @@ fn s3_key_prefix(ctx: &ExecutionContext, event: &Payload) -> String {
 /// * `ctx` - The runtime context of the current function.
 /// * `arena` - The global memory arena for the function across invocations.
 /// * `event` - The payload of the current function invocation.
+/// * `metrics` - The metrics of the current function invocation.
 ///
 /// # Returns
-/// The input data for the executor in the current function.
+/// The input data for the executor in the current function keyed by the input
+/// ids, including the spilled files of the window's fragments that are no
+/// longer in memory.
 async fn prepare_data_sources(
     ctx: &mut ExecutionContext,
     arena: &mut Arena,
     event: Payload,
-) -> Result<(Vec<Vec<Vec<RecordBatch>>>, HashAggregateStatus)> {
+    continuation: Option<Continuation>,
+    deadline: &Deadline,
+    metrics: &Metrics,
+) -> Result<(WindowInputs, HashAggregateStatus)> {
     let uuid = event.uuid.clone();
     let metadata = event.metadata.clone();
     let s3_key_prefix = s3_key_prefix(ctx, &event);
     let window_id = event.get_window_id();
 
     if PROCESSED_WINDOWS.lock().unwrap().contains(&window_id) {
-        return Ok((vec![], HashAggregateStatus::Processed));
+        return Ok((WindowInputs::new(), HashAggregateStatus::Processed));
     }
 
     // If all data packets have been received, then the data sources are ready.
     #[allow(unused_assignments)]
     let mut status = HashAggregateStatus::NotReady;
-    let mut input = vec![];
+    let mut inputs = WindowInputs::new();
 
     // Read payload from S3 is a baseline for our system.
     if let Some((bucket, key)) = infer_s3_mode(&metadata) {
@@ -169,21 +551,27 @@ async fn prepare_data_sources(
         info!("[OK] Received payload from S3.");
 
         info!("Parsing payload to input partitions...");
-        let (r1, r2) = payload.to_record_batch();
+        inputs = fragment_inputs(decode_payload(&ctx.name, &payload).await?);
         info!("[OK] Parsed payload.");
 
-        input.push(vec![r1]);
-        input.push(vec![r2]);
         status = HashAggregateStatus::Ready;
     } else if ctx.is_aggregate() {
-        // aggregate incoming data to its specific destination
-        status = arena.collect(event);
+        // aggregate incoming data to its specific destination, or resume the window
+        // handed over by the former invocation.
+        status = match continuation {
+            Some(_) => resume_window(ctx, arena, &window_id).await?,
+            None => collect_fragment(ctx, arena, event).await?,
+        };
+        if status == HashAggregateStatus::Ready && deadline.is_near() {
+            if let Some(next) = Continuation::next(continuation, 0) {
+                hand_over_window(ctx, arena, &window_id, next, metrics).await?;
+                return Ok((WindowInputs::new(), HashAggregateStatus::NotReady));
+            }
+        }
         if status == HashAggregateStatus::Ready {
             info!("Received all data packets for the window: {:?}", window_id);
-            arena
-                .take_batches(&window_id)
-                .into_iter()
-                .for_each(|b| input.push(b));
+            record_window_latency(arena, &window_id, metrics);
+            inputs = arena.take_window(&window_id);
             PROCESSED_WINDOWS.lock().unwrap().insert(window_id);
         } else if status == HashAggregateStatus::NotReady {
             // Aggregation has not yet been completed. We can also check the query states in
@@ -193,62 +581,483 @@ async fn prepare_data_sources(
             // the former stage of the dataflow pipeline. Since aggregator's ancestors are
             // default Lambda functions with much higher concurrency, all of them can write
             // the partial aggregation states to the S3 buckets in parallel.
-            if let Some(bitmap) = arena.get_bitmap(&window_id) {
-                if ctx
-                    .state_backend
-                    .as_any()
-                    .downcast_ref::<S3StateBackend>()
-                    .is_some()
-                {
-                    let state_backend: &S3StateBackend = ctx
-                        .state_backend
-                        .as_any()
-                        .downcast_ref::<S3StateBackend>()
-                        .unwrap();
-                    let keys = state_backend
-                        .new_s3_keys(&uuid.qid, &s3_key_prefix, bitmap)
-                        .await?;
-
-                    if !keys.is_empty() {
-                        // TODO: optimize the performance of this part.
-                        // Because the S3 key include a negative sequence number, we don't need
-                        // to read its object from S3.
-                        state_backend
-                            .read(uuid.qid.clone(), keys)
-                            .await?
-                            .into_iter()
-                            .for_each(|payload| {
-                                arena.collect(payload);
-                            });
-                        if arena.is_complete(&window_id) {
-                            info!("Received all data packets for the window: {:?}", window_id);
-                            arena
-                                .take_batches(&window_id)
-                                .into_iter()
-                                .for_each(|b| input.push(b));
-                            status = HashAggregateStatus::Ready;
-                            PROCESSED_WINDOWS.lock().unwrap().insert(window_id);
-                        }
-                    }
-                }
+            if fetch_fragments(ctx, arena, &window_id, &uuid.qid, &s3_key_prefix).await? {
+                info!("Received all data packets for the window: {:?}", window_id);
+                record_window_latency(arena, &window_id, metrics);
+                inputs = arena.take_window(&window_id);
+                status = HashAggregateStatus::Ready;
+                PROCESSED_WINDOWS.lock().unwrap().insert(window_id);
+            } else {
+                spill_windows(arena, metrics);
             }
         }
     } else {
         // data packet is an individual event for the current function.
-        let (r1, r2) = event.to_record_batch();
-        input.push(vec![r1]);
-        input.push(vec![r2]);
+        inputs = fragment_inputs(decode_payload(&ctx.name, &event).await?);
         status = HashAggregateStatus::Ready;
     }
 
     if status == HashAggregateStatus::Ready {
         // If the data sources are ready, then we can read the side inputs from S3.
-        if let Ok(batch) = infer_side_input(&metadata).await {
-            input.push(vec![batch]);
+        add_side_input(ctx, &mut inputs, &metadata).await?;
+    }
+
+    Ok((inputs, status))
+}
+
+/// Returns the inputs of a single data fragment.
+fn fragment_inputs(batches: InputBatches) -> WindowInputs {
+    batches
+        .into_iter()
+        .map(|(id, batches)| (id, InputFragments::new(vec![batches])))
+        .collect()
+}
+
+/// Reads the side input from S3, if any, and adds it to the inputs under its
+/// table name. A side input that can't be read fails the invocation, rather
+/// than running the query without it.
+async fn add_side_input(
+    ctx: &ExecutionContext,
+    inputs: &mut WindowInputs,
+    metadata: &Option<HashMap<String, String>>,
+) -> Result<()> {
+    if let Some(batches) = infer_side_input(ctx, metadata).await? {
+        inputs.insert(side_input_id(metadata), InputFragments::new(vec![batches]));
+    }
+    Ok(())
+}
+
+/// Returns the input id of the side input, i.e., its table name.
+fn side_input_id(metadata: &Option<HashMap<String, String>>) -> InputId {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get("side_input_table"))
+        .map_or(SIDE_INPUT_TABLE, |table| table.as_str())
+        .to_owned()
+}
+
+/// Reduces a pane of the hopping windows to its state in the state backend,
+/// and emits the windows whose panes are all reduced. If the plan can be split
+/// at its final aggregation, the pane state is the output of the partial
+/// aggregation, and the windows merge the states of their panes. Otherwise,
+/// the pane state is the input of the pane, and the whole plan runs over the
+/// panes of each window. The matches of the interval and lookup joins can span
+/// the panes, so these plans are always evaluated over whole windows, and the
+/// joins run once per window.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the aggregate function.
+/// * `pane` - The pane of the inputs.
+/// * `inputs` - The inputs of the pane, including the side input.
+/// * `header` - The header of the pane's payloads.
+/// * `telemetry` - The metrics and the trace context of the invocation.
+async fn evaluate_pane(
+    ctx: &mut ExecutionContext,
+    pane: Pane,
+    inputs: WindowInputs,
+    header: Payload,
+    telemetry: &Telemetry,
+) -> Result<Value> {
+    let mut metadata = header.metadata;
+    Pane::remove_from_metadata(&mut metadata);
+
+    let stream_joins = ctx.plan.interval_join.is_some() || ctx.plan.lookup_join.is_some();
+    let split = match ctx.plan().await?.as_slice() {
+        [plan] if !stream_joins => PanePlan::try_new(plan)?,
+        _ => None,
+    };
+    let state = match split.as_ref() {
+        Some(split) => {
+            let mut partial = ctx.clone();
+            partial.plan = CloudExecutionPlan::new(vec![split.partial.clone()], None)
+                .with_inputs(ctx.plan.inputs.clone());
+            let output = collect(&mut partial, inputs).await?;
+            vec![(
+                PANE_INPUT.to_owned(),
+                output.into_iter().flatten().collect(),
+            )]
+        }
+        None => {
+            // The side input is read again for each window.
+            let side_input = side_input_id(&metadata);
+            inputs
+                .into_iter()
+                .filter(|(id, _)| *id != side_input)
+                .map(|(id, input)| Ok((id, read_fragments(input)?)))
+                .collect::<Result<Vec<_>>>()?
         }
+    };
+
+    // The panes without an owner are routed by their own query ids, so each of
+    // them is a stream of its own.
+    let stream = pane
+        .owner
+        .clone()
+        .unwrap_or_else(|| header.uuid.qid.clone());
+    let store = PaneStore::new(
+        ctx.state_backend.as_ref(),
+        &FLOCK_S3_BUCKET,
+        &ctx.name,
+        &stream,
+        header.shuffle_id.unwrap_or(1),
+    );
+    store.write(&pane, &state).await?;
+
+    let windows = store.complete_windows(&pane).await?;
+    for &window in windows.iter() {
+        let mut inputs = fragment_inputs(store.read_window(&pane, window).await?);
+        let mut window_ctx = ctx.clone();
+        match split.as_ref() {
+            Some(split) => {
+                window_ctx.plan = CloudExecutionPlan::new(vec![split.combine.clone()], None)
+                    .with_inputs(vec![PANE_INPUT.to_owned()]);
+            }
+            None => {
+                add_side_input(ctx, &mut inputs, &metadata).await?;
+                inputs = run_window_joins(ctx, inputs).await?;
+            }
+        }
+        let output = collect(&mut window_ctx, inputs).await?;
+        telemetry
+            .metrics
+            .put(ROWS_OUT, num_rows(output.iter()) as f64, Unit::Count);
+
+        // Each window is a new window for the next functions.
+        let uuid =
+            UuidBuilder::new_with_ts(&ctx.name, window_timestamp(&header.uuid.qid), 1).next_uuid();
+        invoke_next_functions(
+            &mut window_ctx,
+            header.query_number,
+            uuid,
+            metadata.clone(),
+            header.shuffle_id,
+            header.salt,
+            output,
+            telemetry,
+        )
+        .await?;
+        store.expire(&pane, window).await?;
     }
 
-    Ok((input, status))
+    let info = format!(
+        "[Ok] Function {}: pane {} completes {} windows.",
+        ctx.name,
+        pane.index,
+        windows.len()
+    );
+    info!("{}", info);
+    Ok(json!({ "response": info }))
+}
+
+/// Spills the fragments of the largest windows to disk beyond the spill
+/// threshold of the arena. A failure keeps the fragments in memory, so it
+/// doesn't fail the invocation.
+fn spill_windows(arena: &mut Arena, metrics: &Metrics) {
+    match arena.spill() {
+        Ok(0) => {}
+        Ok(size) => metrics.add(SPILLED_BYTES, size as f64, Unit::Bytes),
+        Err(e) => warn!("Failed to spill the windows: {}", e),
+    }
+}
+
+/// Reads the fragments of the window that are missing in the arena from the
+/// S3 state backend, where the former stage saves them.
+///
+/// # Returns
+/// True if the window is complete.
+async fn fetch_fragments(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    window_id: &WindowId,
+    qid: &str,
+    s3_key_prefix: &str,
+) -> Result<bool> {
+    let state_backend = match ctx.state_backend.as_any().downcast_ref::<S3StateBackend>() {
+        Some(state_backend) => state_backend,
+        None => return Ok(false),
+    };
+    let keys = match arena.get_bitmap(window_id) {
+        Some(bitmap) => {
+            state_backend
+                .new_s3_keys(qid, s3_key_prefix, bitmap)
+                .await?
+        }
+        None => return Ok(false),
+    };
+    if keys.is_empty() {
+        return Ok(false);
+    }
+
+    // TODO: optimize the performance of this part.
+    // Because the S3 key include a negative sequence number, we don't need
+    // to read its object from S3.
+    for payload in state_backend.read(qid.to_owned(), keys).await? {
+        collect_fragment(ctx, arena, payload).await?;
+    }
+    Ok(arena.is_complete(window_id))
+}
+
+/// Evicts the incomplete windows that time out or exceed the memory limit of
+/// the arena, and handles them with the timeout policy. The late fragments of
+/// an evicted window are ignored.
+async fn handle_evicted_windows(
+    ctx: &mut ExecutionContext,
+    arena: &mut Arena,
+    telemetry: &Telemetry,
+) {
+    let metrics = &telemetry.metrics;
+    let policy = match FLOCK_CONFIG.arena.timeout_policy.parse::<TimeoutPolicy>() {
+        Ok(policy) => policy,
+        Err(e) => {
+            warn!("{}", e);
+            TimeoutPolicy::Drop
+        }
+    };
+
+    for evicted in arena.evict() {
+        let EvictedWindow {
+            window_id,
+            session,
+            reason,
+        } = evicted;
+        warn!(
+            "Window {:?} is evicted ({:?}) with {}/{} fragments.",
+            window_id,
+            reason,
+            session.received(),
+            session.size
+        );
+        metrics.add(EVICTED_WINDOWS, 1.0, Unit::Count);
+        PROCESSED_WINDOWS.lock().unwrap().insert(window_id.clone());
+
+        let header = match (policy, session.header.clone()) {
+            (TimeoutPolicy::Drop, _) | (_, None) => {
+                metrics.add(DROPPED_WINDOWS, 1.0, Unit::Count);
+                remove_spilled_files(session.spilled_files());
+                continue;
+            }
+            (_, Some(header)) => header,
+        };
+
+        let result = match policy {
+            TimeoutPolicy::Emit => {
+                metrics.add(INCOMPLETE_WINDOWS, 1.0, Unit::Count);
+                emit_window(ctx, session.inputs, header, true, telemetry).await
+            }
+            _ => {
+                let prefix = s3_key_prefix(ctx, &header);
+                arena.reinsert(window_id.clone(), session);
+                match fetch_fragments(ctx, arena, &window_id, &header.uuid.qid, &prefix).await {
+                    Ok(true) => {
+                        record_window_latency(arena, &window_id, metrics);
+                        let inputs = arena.take_window(&window_id);
+                        emit_window(ctx, inputs, header, false, telemetry).await
+                    }
+                    result => {
+                        if let Some(session) = arena.remove_window(&window_id) {
+                            remove_spilled_files(session.spilled_files());
+                        }
+                        metrics.add(DROPPED_WINDOWS, 1.0, Unit::Count);
+                        result.map(|_| ())
+                    }
+                }
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to handle the evicted window {:?}: {}", window_id, e);
+        }
+    }
+}
+
+/// Checkpoints the incomplete windows of the arena to the state backend, so
+/// they survive the recycling of the function's container. A failure only
+/// delays the checkpoints, so it doesn't fail the invocation.
+async fn checkpoint_windows(ctx: &ExecutionContext, arena: &mut Arena, metrics: &Metrics) {
+    match arena
+        .checkpoint(ctx.state_backend.as_ref(), &FLOCK_S3_BUCKET, &ctx.name)
+        .await
+    {
+        Ok(0) => {}
+        Ok(n) => metrics.add(CHECKPOINTED_WINDOWS, n as f64, Unit::Count),
+        Err(e) => warn!("Failed to checkpoint the windows: {}", e),
+    }
+}
+
+/// Checkpoints the complete window instead of processing it near the deadline,
+/// and hands its processing over to a new invocation of the function.
+async fn hand_over_window(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    window_id: &WindowId,
+    continuation: Continuation,
+    metrics: &Metrics,
+) -> Result<()> {
+    arena
+        .checkpoint_window(
+            ctx.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &ctx.name,
+            window_id,
+        )
+        .await?;
+    let header = arena
+        .get(window_id)
+        .and_then(|w| w.header.clone())
+        .ok_or_else(|| {
+            FlockError::Internal(format!("The window {:?} has no header.", window_id))
+        })?;
+    hand_over(&ctx.name, header, continuation).await?;
+    metrics.add(CONTINUATIONS, 1.0, Unit::Count);
+    info!("Handed the window {:?} over near the deadline.", window_id);
+    Ok(())
+}
+
+/// Resumes the window handed over by the former invocation. The window is
+/// restored from its checkpoint if the function's container is recycled.
+async fn resume_window(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    window_id: &WindowId,
+) -> Result<HashAggregateStatus> {
+    let found = arena
+        .restore_window(
+            ctx.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &ctx.name,
+            window_id,
+        )
+        .await?;
+    if !found {
+        warn!("The window {:?} handed over is missing.", window_id);
+        return Ok(HashAggregateStatus::Processed);
+    }
+    Ok(if arena.is_complete(window_id) {
+        HashAggregateStatus::Ready
+    } else {
+        HashAggregateStatus::NotReady
+    })
+}
+
+/// Hands the rest of the work over to a new invocation of the function, which
+/// resumes from the continuation in the payload's metadata.
+///
+/// # Arguments
+/// * `function_name` - The name of the current function.
+/// * `payload` - The payload of the new invocation.
+/// * `continuation` - The progress of the work.
+pub async fn hand_over(
+    function_name: &str,
+    mut payload: Payload,
+    continuation: Continuation,
+) -> Result<()> {
+    info!(
+        "Handing over to a new invocation of {}: {:?}",
+        function_name, continuation
+    );
+    continuation.to_metadata(&mut payload.metadata)?;
+    lambda::invoke_function(
+        function_name,
+        &FLOCK_LAMBDA_ASYNC_CALL,
+        Some(serde_json::to_vec(&payload)?.into()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Executes the physical plan over the fragments of an evicted window, and
+/// sends the output to the next functions. The output of an incomplete window
+/// is flagged in the metadata.
+async fn emit_window(
+    ctx: &mut ExecutionContext,
+    mut inputs: WindowInputs,
+    header: Payload,
+    incomplete: bool,
+    telemetry: &Telemetry,
+) -> Result<()> {
+    let mut metadata = header.metadata;
+    if incomplete {
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(INCOMPLETE_WINDOW_KEY.to_owned(), "true".to_owned());
+    }
+    add_side_input(ctx, &mut inputs, &metadata).await?;
+
+    if let Some(pane) = Pane::from_metadata(&metadata)? {
+        let header = Payload { metadata, ..header };
+        evaluate_pane(ctx, pane, inputs, header, telemetry).await?;
+        return Ok(());
+    }
+
+    let inputs = run_joins(ctx, header.shuffle_id, &header.uuid.qid, inputs).await?;
+    let output = collect(ctx, inputs).await?;
+    invoke_next_functions(
+        ctx,
+        header.query_number,
+        header.uuid,
+        metadata,
+        header.shuffle_id,
+        header.salt,
+        output,
+        telemetry,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Returns true if the output belongs to an incomplete window.
+fn is_incomplete(metadata: &Option<HashMap<String, String>>) -> bool {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(INCOMPLETE_WINDOW_KEY))
+        .map_or(false, |v| v == "true")
+}
+
+/// Decodes the data fragment and adds it to its temporal window. A malformed
+/// fragment is quarantined, and the quarantine policy decides whether it
+/// counts as an empty fragment or fails the invocation. A fragment that
+/// doesn't fit its window is quarantined too, and it's dropped or fails the
+/// invocation under the same policy.
+async fn collect_fragment(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    payload: Payload,
+) -> Result<HashAggregateStatus> {
+    let window_id = payload.get_window_id();
+    if arena.is_collected(&window_id, &payload.uuid) {
+        return Ok(HashAggregateStatus::Processed);
+    }
+    let inputs = decode_payload(&ctx.name, &payload).await?;
+    match arena.add_fragment(&payload, inputs) {
+        Ok(status) => Ok(status),
+        Err(e) => {
+            reject_payload(&ctx.name, &payload, e).await?;
+            Ok(HashAggregateStatus::Processed)
+        }
+    }
+}
+
+/// Records the time between the first and the last data fragment of the window.
+fn record_window_latency(arena: &Arena, window_id: &WindowId, metrics: &Metrics) {
+    if let Some(latency) = arena.window_latency(window_id) {
+        metrics.put(
+            WINDOW_LATENCY,
+            latency.as_secs_f64() * 1000.0,
+            Unit::Milliseconds,
+        );
+    }
+}
+
+/// Logs the failed tasks that invoke the next functions or write the state
+/// backend. The failed invocations are already captured in the dead-letter
+/// queue, so a failure doesn't abort the remaining tasks.
+pub fn report_task_errors(results: Vec<std::result::Result<Result<()>, tokio::task::JoinError>>) {
+    for result in results {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Task failed: {}", e),
+            Err(e) => warn!("Task panicked or was cancelled: {}", e),
+        }
+    }
 }
 
 /// Invoke the next functions in the dataflow pipeline.
@@ -258,7 +1067,11 @@ async fn prepare_data_sources(
 /// * `query_num` - The query number of the current request (for testing).
 /// * `uuid` - The UUID of the current payload.
 /// * `metadata` - The metadata of the current request.
+/// * `shuffle_id` - The shuffle id of the current payload.
+/// * `salt` - The salted sub-partition of the current payload.
 /// * `output` - The output of the current function.
+/// * `telemetry` - The metrics and the trace context of the current function
+///   invocation. The trace context is propagated to the next functions.
 ///
 /// # Returns
 /// A JSON object that contains the return value of the current function.
@@ -268,9 +1081,13 @@ async fn invoke_next_functions(
     uuid: Uuid,
     metadata: Option<HashMap<String, String>>,
     shuffle_id: Option<usize>,
+    salt: Option<Salt>,
     output: Vec<Vec<RecordBatch>>,
+    telemetry: &Telemetry,
 ) -> Result<Value> {
-    let (ring, _) = consistent_hash_context!();
+    let metrics = &telemetry.metrics;
+    let trace = &telemetry.trace;
+    let (group, _) = consistent_hash_context!();
     let sync = infer_invocation_type(&metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
@@ -283,9 +1100,15 @@ async fn invoke_next_functions(
             info!("[Ok] Sinking data to {:?}", sink_type);
             let output = output.into_iter().flatten().collect::<Vec<_>>();
             if !output.is_empty() && DataSinkType::Blackhole != *sink_type {
-                DataSink::new(ctx.name.clone(), output, Encoding::default())
+                let start = Instant::now();
+                let mut sink = DataSink::new(ctx.name.clone(), output, Encoding::default());
+                sink.trace = Some(trace.clone());
+                sink.incomplete = is_incomplete(&metadata);
+                let result = sink
                     .write(sink_type.clone(), DataSinkFormat::SerdeBinary)
-                    .await
+                    .await;
+                metrics.add_elapsed(ENCODE_TIME, start);
+                result
             } else {
                 Ok(json!({ "response": "No data to sink." }))
             }
@@ -307,11 +1130,17 @@ async fn invoke_next_functions(
                         let meta = metadata.clone();
                         let invoke_type = invocation_type.clone();
                         let uuid = uuid_builder.next_uuid();
+                        let metrics = metrics.clone();
+                        let trace = trace.clone();
                         tokio::spawn(async move {
-                            let mut payload = to_payload(&data[i], &[], uuid, sync);
+                            let start = Instant::now();
+                            let mut payload = to_payload(&[(&output_id(0), &data[i])], uuid, sync);
                             payload.query_number = query_number;
                             payload.metadata = meta;
+                            payload.trace = Some(trace);
                             let bytes = serde_json::to_vec(&payload)?;
+                            metrics.add_elapsed(ENCODE_TIME, start);
+                            metrics.add(BYTES_OUT, bytes.len() as f64, Unit::Bytes);
 
                             info!(
                                 "[OK] {} function's payload bytes: {}",
@@ -328,22 +1157,22 @@ async fn invoke_next_functions(
                         })
                     })
                     .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
+                report_task_errors(futures::future::join_all(tasks).await);
             } else {
                 // If the current function is not an aggregator, which means its
                 // output CANNOT be repartitioned to multiple partitions,
                 // otherwise the future aggregator CANNOT ganuantee the
                 // correctness of the result. Therefore, we have to reuse the
                 // uuid of the current payload to the next function.
-                let mut payload = to_payload(
-                    &output.into_iter().flatten().collect::<Vec<_>>(),
-                    &[],
-                    uuid,
-                    sync,
-                );
+                let start = Instant::now();
+                let output = output.into_iter().flatten().collect::<Vec<_>>();
+                let mut payload = to_payload(&[(&output_id(0), &output)], uuid, sync);
                 payload.query_number = query_number;
                 payload.metadata = metadata;
+                payload.trace = Some(trace.clone());
                 let bytes = serde_json::to_vec(&payload)?;
+                metrics.add_elapsed(ENCODE_TIME, start);
+                metrics.add(BYTES_OUT, bytes.len() as f64, Unit::Bytes);
 
                 info!(
                     "[OK] {} function's payload bytes: {}",
@@ -358,16 +1187,33 @@ async fn invoke_next_functions(
         }
         CloudFunction::Group((group_name, _)) => {
             if !ctx.is_shuffling().await? {
-                let next_function = ring.get(&uuid.qid).expect("hash ring failure.").to_string();
-                let mut payload = to_payload(
-                    &output.into_iter().flatten().collect::<Vec<_>>(),
-                    &[],
-                    uuid,
-                    sync,
-                );
+                let (next_function, next_shuffle_id, uuid) =
+                    if skew::salted_aggregate(&ctx.plan().await?).is_some() {
+                        // The combine stage merges the partial results of the salted
+                        // sub-partitions, so each partition is a window of the next
+                        // function, and each sub-partition is a fragment of the window.
+                        let partition = salt.map_or(shuffle_id.unwrap_or(1), |s| s.partition);
+                        let mut uuid = uuid;
+                        uuid.seq_num = salt.map_or(1, |s| s.index);
+                        uuid.seq_len = salt.map_or(1, |s| s.count);
+                        let next_function = group.route_key(
+                            &(uuid.qid.clone(), partition),
+                            window_timestamp(&uuid.qid),
+                        )?;
+                        (next_function, Some(partition), uuid)
+                    } else {
+                        (group.route(&uuid.qid)?, None, uuid)
+                    };
+                let start = Instant::now();
+                let output = output.into_iter().flatten().collect::<Vec<_>>();
+                let mut payload = to_payload(&[(&output_id(0), &output)], uuid, sync);
                 payload.query_number = query_number;
                 payload.metadata = metadata;
+                payload.trace = Some(trace.clone());
+                payload.shuffle_id = next_shuffle_id;
                 let bytes = serde_json::to_vec(&payload)?;
+                metrics.add_elapsed(ENCODE_TIME, start);
+                metrics.add(BYTES_OUT, bytes.len() as f64, Unit::Bytes);
 
                 info!(
                     "[OK] {} function's payload bytes: {}",
@@ -391,7 +1237,8 @@ async fn invoke_next_functions(
                             .parse::<usize>()
                             .expect("parse the plan index error.");
                         let next_plan_index = plan_index + 1;
-                        let shuffle_id = 1; // since the current function is not shuffling
+                        // since the current function is not shuffling
+                        let shuffle_id = next_shuffle_id.unwrap_or(1);
                         let seq_num = if payload.is_empty_data() {
                             -(payload.get_seq_num() as i32)
                         } else {
@@ -417,21 +1264,22 @@ async fn invoke_next_functions(
                         .map(|_| ())
                 }));
 
-                futures::future::join_all(tasks).await;
+                report_task_errors(futures::future::join_all(tasks).await);
 
                 Ok(json!({
                     "response": format!("next function group: {}", group_name)
                 }))
             } else {
-                let output = Arc::new(output);
-                let mut rng = StdRng::seed_from_u64(0xDEAD); // Predictable RNG clutch
-                let tasks = (0..output.len())
-                    .map(|i| {
-                        let my_output = output.clone();
+                let partitions = split_heavy_partitions(ctx, &group, &uuid, output).await?;
+                let tasks = partitions
+                    .into_iter()
+                    .map(|(data, next_shuffle_id, salt, next_function)| {
                         let my_metadata = metadata.clone();
                         let state_backend = ctx.state_backend.clone();
                         let current_function = ctx.name.clone();
                         let invoke_type = invocation_type.clone();
+                        let metrics = metrics.clone();
+                        let trace = trace.clone();
 
                         let mut my_uuid = uuid.clone();
                         if let Some(new_seq_num) = shuffle_id {
@@ -442,33 +1290,19 @@ async fn invoke_next_functions(
                             my_uuid.seq_num = new_seq_num;
                         }
 
-                        // Partitions at the same index position in different functions can get the
-                        // same hash key. Therefore, they can be forwarded to the same lambda
-                        // function.
-                        //
-                        // Function 0: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // Function 1: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // Function 2: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // ..
-                        // Function n: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        //
-                        // F0[0], F1[0], F2[0] .. Fn[0] ---> lambda function x
-                        // F0[1], F1[1], F2[1] .. Fn[1] ---> lambda function y
-                        // F0[2], F1[2], F2[2] .. Fn[2] ---> lambda function z
-                        // ..
-                        // F0[n], F1[n], F2[n] .. Fn[n] ---> lambda function v
-                        let mut arr = [0u8; 64];
-                        rng.fill(&mut arr);
-                        let next_function = ring.get(&arr).expect("hash ring failure.").to_string();
-
                         tokio::spawn(async move {
-                            let mut payload = to_payload(&my_output[i], &[], my_uuid, sync);
+                            let start = Instant::now();
+                            let mut payload = to_payload(&[(&output_id(0), &data)], my_uuid, sync);
                             payload.query_number = query_number;
                             payload.metadata = my_metadata;
+                            payload.trace = Some(trace);
                             // set shuffle id to each data partition since they will be aggregated
                             // at different functions.
-                            payload.shuffle_id = Some(i + 1); // Starts from 1.
+                            payload.shuffle_id = Some(next_shuffle_id); // Starts from 1.
+                            payload.salt = salt;
                             let bytes = serde_json::to_vec(&payload)?;
+                            metrics.add_elapsed(ENCODE_TIME, start);
+                            metrics.add(BYTES_OUT, bytes.len() as f64, Unit::Bytes);
 
                             info!(
                                 "[OK] {} function's payload bytes: {}",
@@ -492,7 +1326,8 @@ async fn invoke_next_functions(
                                             .parse::<usize>()
                                             .expect("parse the plan index error.");
                                     let next_plan_index = plan_index + 1;
-                                    let shuffle_id = i + 1; // since the current function is shuffling
+                                    // since the current function is shuffling
+                                    let shuffle_id = next_shuffle_id;
                                     let seq_num = if payload.is_empty_data() {
                                         -(payload.get_seq_num() as i32)
                                     } else {
@@ -525,13 +1360,13 @@ async fn invoke_next_functions(
                                 .map(|_| ())
                             }));
 
-                            futures::future::join_all(tasks).await;
+                            report_task_errors(futures::future::join_all(tasks).await);
 
                             Ok(())
                         })
                     })
                     .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
+                report_task_errors(futures::future::join_all(tasks).await);
 
                 Ok(json!({
                     "response": format!("next function group: {}", group_name)
@@ -541,6 +1376,80 @@ async fn invoke_next_functions(
     }
 }
 
+/// A partition of the shuffled output: its record batches, its shuffle id, its
+/// salted sub-partition and the next function to aggregate it.
+type ShufflePartition = (Vec<RecordBatch>, usize, Option<Salt>, String);
+
+/// Assigns the partitions of the shuffled output to the functions of the next
+/// group. If skew mitigation is enabled, the partitions with heavy keys in the
+/// current window are split into salted sub-partitions. All sub-partitions are
+/// sent even if they are empty, so that their windows in the next functions
+/// can complete.
+async fn split_heavy_partitions(
+    ctx: &mut ExecutionContext,
+    group: &FunctionGroup,
+    uuid: &Uuid,
+    output: Vec<Vec<RecordBatch>>,
+) -> Result<Vec<ShufflePartition>> {
+    let settings = &FLOCK_CONFIG.skew;
+    let timestamp = window_timestamp(&uuid.qid);
+    let (plans, key_columns) = if settings.enabled {
+        (
+            skew::skew_plans(&group.name).await,
+            skew::shuffle_key_columns(&ctx.plan().await?),
+        )
+    } else {
+        (SkewPlanCache::default(), vec![])
+    };
+
+    let count = output.len();
+    let mut rng = StdRng::seed_from_u64(0xDEAD); // Predictable RNG clutch
+    let mut partitions = vec![];
+    for (i, data) in output.into_iter().enumerate() {
+        // Partitions at the same index position in different functions can get the
+        // same hash key. Therefore, they can be forwarded to the same lambda
+        // function.
+        //
+        // Function 0: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        // Function 1: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        // Function 2: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        // ..
+        // Function n: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        //
+        // F0[0], F1[0], F2[0] .. Fn[0] ---> lambda function x
+        // F0[1], F1[1], F2[1] .. Fn[1] ---> lambda function y
+        // F0[2], F1[2], F2[2] .. Fn[2] ---> lambda function z
+        // ..
+        // F0[n], F1[n], F2[n] .. Fn[n] ---> lambda function v
+        let mut arr = [0u8; 64];
+        rng.fill(&mut arr);
+
+        let heavy_keys = plans.heavy_keys_at(i + 1, timestamp);
+        if heavy_keys.is_empty() || key_columns.is_empty() {
+            partitions.push((data, i + 1, None, group.route_key(&arr, timestamp)?));
+            continue;
+        }
+
+        let subs = skew::salt_partition(&data, &key_columns, &heavy_keys, settings.salts)?;
+        for (j, sub) in subs.into_iter().enumerate() {
+            let salt = Salt {
+                partition: i + 1,
+                index:     j + 1,
+                count:     settings.salts,
+            };
+            // The first sub-partition stays in the function of the partition.
+            let next_function = if j == 0 {
+                group.route_key(&arr, timestamp)?
+            } else {
+                group.route_key(&(arr, j), timestamp)?
+            };
+            let shuffle_id = skew::salted_shuffle_id(count, &salt);
+            partitions.push((sub, shuffle_id, Some(salt), next_function));
+        }
+    }
+    Ok(partitions)
+}
+
 /// Infer the invocation mode of the function.
 pub fn infer_invocation_type(metadata: &Option<HashMap<String, String>>) -> Result<bool> {
     let mut sync = true;
@@ -568,60 +1477,48 @@ pub fn infer_s3_mode(metadata: &Option<HashMap<String, String>>) -> Option<(Stri
     None
 }
 
+/// Reads the side input from the container's cache, or from S3 if it isn't
+/// cached yet or its refresh interval (`side_input_refresh_secs`) has elapsed.
+/// Returns `None` if the payload has no side input.
 pub async fn infer_side_input(
+    ctx: &ExecutionContext,
     metadata: &Option<HashMap<String, String>>,
-) -> Result<Vec<RecordBatch>> {
+) -> Result<Option<Vec<RecordBatch>>> {
     if let Some(metadata) = metadata {
         if let Some(key) = metadata.get("side_input_s3_key") {
-            let key = key.parse::<String>().unwrap();
-            let bytes = s3::get_object(&FLOCK_S3_BUCKET, &key).await?;
-
             let format = metadata
                 .get("side_input_format")
-                .expect("side_input_format is missing")
-                .as_str();
-
+                .ok_or_else(|| FlockError::Execution("side_input_format is missing".to_string()))?
+                .parse::<TableFormat>()?;
             let schema = schema_from_bytes(&base64::decode(
                 metadata
                     .get("side_input_schema")
-                    .expect("side_input_schema is missing")
+                    .ok_or_else(|| {
+                        FlockError::Execution("side_input_schema is missing".to_string())
+                    })?
                     .as_str(),
             )?)?;
+            let refresh_interval = metadata
+                .get("side_input_refresh_secs")
+                .and_then(|secs| secs.parse::<u64>().ok())
+                .map(Duration::from_secs);
 
-            let mut batches = vec![];
-            match format {
-                "csv" => {
-                    let mut batch_reader = ReaderBuilder::new()
-                        .with_schema(schema)
-                        .has_header(true)
-                        .with_delimiter(b',')
-                        .with_batch_size(1024)
-                        .build(Cursor::new(bytes))?;
-                    loop {
-                        match batch_reader.next() {
-                            Some(Ok(batch)) => {
-                                batches.push(batch);
-                            }
-                            None => {
-                                break;
-                            }
-                            Some(Err(e)) => {
-                                return Err(FlockError::Execution(format!(
-                                    "Error reading batch from side input: {}",
-                                    e
-                                )));
-                            }
-                        }
-                    }
-                }
-                _ => unimplemented!(),
-            }
-            return Ok(batches);
+            let source = LookupSource::S3 {
+                bucket: FLOCK_S3_BUCKET.clone(),
+                key: key.clone(),
+                format,
+            };
+            let table = cached_table(
+                &source,
+                Some(schema),
+                refresh_interval,
+                ctx.state_backend.as_ref(),
+            )
+            .await?;
+            return Ok(Some(table.batches.clone()));
         }
     }
-    Err(FlockError::AWS(
-        "Side Input's S3 key is not specified".to_string(),
-    ))
+    Ok(None)
 }
 
 /// Infer group keys for session windows (used in NEXMark Q11 and Q12).
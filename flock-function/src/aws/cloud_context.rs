@@ -11,23 +11,31 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use chrono::Utc;
 use flock::prelude::*;
-use hashring::HashRing;
+use flock::runtime::group::FunctionGroup;
+use flock::runtime::route;
 use lazy_static::lazy_static;
+use log::{info, warn};
 use std::cell::Cell;
 use std::collections::HashMap;
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, RwLock};
 
 /// Initializes the lambda function once and only once.
 pub static INIT: Once = Once::new();
 
+/// The checkpointed windows are restored into the arena after the execution
+/// context is initialized.
+pub static RESTORE_WINDOWS: AtomicBool = AtomicBool::new(false);
+
 thread_local! {
     /// Is in the testing environment.
     pub static IS_TESTING: Cell<bool> = Cell::new(false);
 }
 
 lazy_static! {
-    pub static ref CONTEXT_NAME: String = FLOCK_CONF["lambda"]["environment"].to_string();
+    pub static ref CONTEXT_NAME: String = FLOCK_CONFIG.lambda.environment.clone();
 }
 
 /// A wrapper to allow the declaration of the execution context of the lambda
@@ -41,45 +49,52 @@ pub static mut EXECUTION_CONTEXT: CloudFunctionContext = CloudFunctionContext::U
 
 /// A wrapper to allow the declaration of consistent hashing.
 pub enum ConsistentHashContext {
-    Lambda((HashRing<String>, String /* group name */)),
+    Lambda(FunctionGroup),
     Uninitialized,
 }
-/// Consistent hashing context.
-pub static mut CONSISTENT_HASH_CONTEXT: ConsistentHashContext =
-    ConsistentHashContext::Uninitialized;
 
-/// Performs an initialization routine once and only once.
+lazy_static! {
+    /// Consistent hashing context. It's replaced when the membership of the
+    /// group is refreshed, so the readers take a snapshot of it rather than a
+    /// reference.
+    pub static ref CONSISTENT_HASH_CONTEXT: RwLock<ConsistentHashContext> =
+        RwLock::new(ConsistentHashContext::Uninitialized);
+}
+
+/// Replaces the consistent hashing context with the function group.
+fn set_consistent_hash_context(group: FunctionGroup) {
+    *CONSISTENT_HASH_CONTEXT.write().unwrap() = ConsistentHashContext::Lambda(group);
+}
+
+/// Returns a snapshot of the function group of the consistent hashing context,
+/// if it's initialized.
+pub fn function_group() -> Option<FunctionGroup> {
+    match &*CONSISTENT_HASH_CONTEXT.read().unwrap() {
+        ConsistentHashContext::Lambda(group) => Some(group.clone()),
+        ConsistentHashContext::Uninitialized => None,
+    }
+}
+
+/// Performs an initialization routine once and only once, and restores the
+/// checkpointed windows into the arena when the function starts cold.
 #[macro_export]
 macro_rules! init_exec_context {
     () => {{
-        unsafe {
+        let (ctx, arena) = unsafe {
             // Init query executor from the cloud evironment.
             let init_context = || match std::env::var(&**CONTEXT_NAME) {
                 Ok(s) => {
                     let ctx = context::unmarshal(&s).unwrap();
-                    let next_function = match &ctx.next {
-                        CloudFunction::Lambda(name) => (name.clone(), 1),
-                        CloudFunction::Group((name, group_size)) => {
-                            (name.clone(), group_size.clone())
-                        }
-                        CloudFunction::Sink(..) => (String::new(), 0),
-                    };
 
                     // The *consistent hash* technique distributes the data packets in a time window
                     // to the same function name in the function group. Because each function in the
                     // function group has a concurrency of *1*, all data packets from the same query
                     // can be routed to the same function execution environment.
-                    let mut ring: HashRing<String> = HashRing::new();
-                    if next_function.1 == 1 {
-                        ring.add(next_function.0.clone());
-                    } else if next_function.1 > 1 {
-                        (0..next_function.1).for_each(|i| {
-                            ring.add(format!("{}-{:02}", next_function.0, i));
-                        });
-                    }
-                    CONSISTENT_HASH_CONTEXT =
-                        ConsistentHashContext::Lambda((ring, next_function.0));
+                    let mut group = FunctionGroup::from_cloud_function(&ctx.next);
+                    group.upstream = ctx.name.clone();
+                    set_consistent_hash_context(group);
                     EXECUTION_CONTEXT = CloudFunctionContext::Lambda((Box::new(ctx), Arena::new()));
+                    RESTORE_WINDOWS.store(true, std::sync::atomic::Ordering::SeqCst);
                 }
                 Err(_) => {
                     panic!("No execution context in the cloud environment.");
@@ -94,21 +109,43 @@ macro_rules! init_exec_context {
                 CloudFunctionContext::Lambda((ctx, arena)) => (ctx, arena),
                 CloudFunctionContext::Uninitialized => panic!("Uninitialized execution context!"),
             }
-        }
+        };
+        restore_windows(ctx, arena).await;
+        (ctx, arena)
     }};
 }
 
-/// Returns the consistent hash context.
+/// Restores the checkpointed windows of the function into its arena after a
+/// cold start. The windows are collected from scratch if they can't be
+/// restored.
+pub async fn restore_windows(ctx: &ExecutionContext, arena: &mut Arena) {
+    if !RESTORE_WINDOWS.swap(false, Ordering::SeqCst)
+        || FLOCK_CONFIG.arena.checkpoint_interval == 0
+        || !ctx.is_aggregate()
+    {
+        return;
+    }
+    match arena
+        .restore(ctx.state_backend.as_ref(), &FLOCK_S3_BUCKET, &ctx.name)
+        .await
+    {
+        Ok(0) => {}
+        Ok(n) => info!("Restored {} windows from their checkpoints.", n),
+        Err(e) => warn!("Failed to restore the windows: {}", e),
+    }
+}
+
+/// Returns a snapshot of the function group of the next function and its
+/// name.
 #[macro_export]
 macro_rules! consistent_hash_context {
     () => {{
-        unsafe {
-            match &mut CONSISTENT_HASH_CONTEXT {
-                ConsistentHashContext::Uninitialized => {
-                    panic!("Uninitialized consistent hash context.")
-                }
-                ConsistentHashContext::Lambda((ring, group_name)) => (ring, group_name),
+        match $crate::function_group() {
+            Some(group) => {
+                let name = group.name.clone();
+                (group, name)
             }
+            None => panic!("Uninitialized consistent hash context."),
         }
     }};
 }
@@ -123,45 +160,56 @@ macro_rules! consistent_hash_context {
 pub fn update_consistent_hash_context(metadata: &Option<HashMap<String, String>>) -> Result<()> {
     if let Some(metadata) = metadata {
         if let Some(workers) = metadata.get("workers") {
-            let next_function = serde_json::from_str(workers)?;
-
-            let (group_name, group_size) = match &next_function {
-                CloudFunction::Lambda(name) => (name.clone(), 1),
-                CloudFunction::Group((name, size)) => (name.clone(), *size),
-                CloudFunction::Sink(..) => (String::new(), 0),
-            };
-
-            // The *consistent hash* technique distributes the data packets in a time window
-            // to the same function name in the function group. Because each function in the
-            // function group has a concurrency of *1*, all data packets from the same query
-            // can be routed to the same function execution environment.
-            let mut ring: HashRing<String> = HashRing::new();
-            match group_size {
-                0 => {
-                    unreachable!("group_size should not be 0.");
-                }
-                1 => {
-                    // only one function in the function group, the data packets are routed to the
-                    // next function.
-                    ring.add(group_name.clone());
-                }
-                _ => {
-                    // multiple functions in the function group, the data packets are routed to the
-                    // function with the same hash value.
-                    (0..group_size).for_each(|i| {
-                        ring.add(format!("{}-{:02}", group_name, i));
-                    });
-                }
+            let next_function: CloudFunction = serde_json::from_str(workers)?;
+            let mut group = FunctionGroup::from_cloud_function(&next_function);
+            if group.name.is_empty() {
+                unreachable!("group_size should not be 0.");
             }
 
-            unsafe {
-                // `ring`: the consistent hashing ring to forward the windowed events to the
-                // same function execution environment.
-                // `group_name`: function group name.
-                CONSISTENT_HASH_CONTEXT = ConsistentHashContext::Lambda((ring, group_name));
+            if let Some(current) = function_group() {
+                // The membership reloaded from S3 is newer than the one in the metadata.
+                if current.name == group.name && current.version > 0 {
+                    return Ok(());
+                }
+                group.upstream = current.upstream;
             }
+            // `group`: the function group to forward the windowed events to the same
+            // function execution environment with consistent hashing.
+            set_consistent_hash_context(group);
         }
     }
 
     Ok(())
 }
+
+/// Reloads the membership of the function group of the next function, and
+/// rebalances the group if autoscaling is enabled. The membership is kept if
+/// it can't be reloaded.
+///
+/// If the next function is replaced by an upgraded query, the windows from
+/// now on are sent to the function group of the upgraded query. It's called
+/// before each window, so the switch happens at a window boundary.
+pub async fn refresh_function_group(ctx: &ExecutionContext) {
+    // The group is refreshed on a snapshot, since the lock can't be held across
+    // the requests, and the snapshot replaces the context afterwards.
+    let mut group = match function_group() {
+        Some(group) => group,
+        None => return,
+    };
+    if !group.name.is_empty() {
+        match route::next_function(&group.name, Utc::now().timestamp()).await {
+            Ok(Some(next)) => {
+                info!("Switching from {} to {:?}", group.name, next);
+                let upstream = group.upstream.clone();
+                group = FunctionGroup::from_cloud_function(&next);
+                group.upstream = upstream;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load the route of {}: {}", group.name, e),
+        }
+    }
+    if let Err(e) = group.refresh(&ctx.state_backend).await {
+        warn!("Failed to refresh function group {}: {}", group.name, e);
+    }
+    set_consistent_hash_context(group);
+}
@@ -22,6 +22,7 @@ use lambda_runtime::{service_fn, LambdaEvent};
 use log::warn;
 use rayon::prelude::*;
 use runtime::prelude::*;
+use runtime::quarantine::quarantine_records;
 use rusoto_core::Region;
 use rusoto_lambda::{InvokeAsyncRequest, Lambda, LambdaClient};
 use serde_json::Value;
@@ -59,7 +60,7 @@ macro_rules! init_exec_context {
     () => {{
         unsafe {
             // Init query executor from the cloud evironment.
-            let init_context = || match std::env::var(&FLOCK_CONF["lambda"]["environment"]) {
+            let init_context = || match std::env::var(&FLOCK_CONFIG.lambda.environment) {
                 Ok(s) => {
                     EXECUTION_CONTEXT = CloudFunctionContext::Lambda((
                         Box::new(ExecutionContext::unmarshal(&s).unwrap()),
@@ -128,7 +129,8 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
     let batch = match &ctx.datasource {
         DataSource::KinesisEvent(_) => {
             let kinesis_event: KinesisEvent = serde_json::from_value(event).unwrap();
-            let batch = kinesis::to_batch(kinesis_event);
+            let (batch, malformed) = kinesis::to_batch(kinesis_event)?;
+            quarantine_records(&ctx.name, malformed).await?;
             if batch.is_empty() {
                 return Err(FlockError::Execution("No Kinesis input!".to_owned()));
             }
@@ -136,7 +138,8 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
         }
         DataSource::KafkaEvent(_) => {
             let kafka_event: KafkaEvent = serde_json::from_value(event).unwrap();
-            let batch = kafka::to_batch(kafka_event);
+            let (batch, malformed) = kafka::to_batch(kafka_event)?;
+            quarantine_records(&ctx.name, malformed).await?;
             if batch.is_empty() {
                 return Err(FlockError::Execution("No Kafka input!".to_owned()));
             }
@@ -148,18 +151,11 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
     match LambdaExecutor::choose_strategy(&ctx, &batch) {
         ExecutionStrategy::Centralized => {
             // feed data into the physical plan
-            let output_partitions = coalesce_batches(
-                vec![batch],
-                FLOCK_CONF["lambda"]["target_batch_size"]
-                    .parse::<usize>()
-                    .unwrap(),
-            )
-            .await?;
+            let output_partitions =
+                coalesce_batches(vec![batch], FLOCK_CONFIG.lambda.target_batch_size).await?;
 
             let num_batches = output_partitions[0].len();
-            let concurrency = FLOCK_CONF["lambda"]["concurrency"]
-                .parse::<usize>()
-                .unwrap();
+            let concurrency = FLOCK_CONFIG.lambda.concurrency;
 
             if num_batches > concurrency {
                 ctx.feed_one_source(
@@ -192,13 +188,8 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
             LambdaExecutor::event_sink(vec![batches]).await
         }
         ExecutionStrategy::Distributed => {
-            let mut batches = coalesce_batches(
-                vec![batch],
-                FLOCK_CONF["lambda"]["payload_batch_size"]
-                    .parse::<usize>()
-                    .unwrap(),
-            )
-            .await?;
+            let mut batches =
+                coalesce_batches(vec![batch], FLOCK_CONFIG.lambda.payload_batch_size).await?;
             assert_eq!(1, batches.len());
 
             invoke_next_functions(&ctx, &mut batches[0])?;
@@ -244,9 +235,7 @@ async fn payload_handler(
     if ctx.next != CloudFunction::Sink(..) {
         let mut batches = coalesce_batches(
             vec![output_partitions],
-            FLOCK_CONF["lambda"]["payload_batch_size"]
-                .parse::<usize>()
-                .unwrap(),
+            FLOCK_CONFIG.lambda.payload_batch_size,
         )
         .await?;
         assert_eq!(1, batches.len());
@@ -300,7 +289,7 @@ mod tests {
         let encoded = lambda_context.marshal(Encoding::default())?;
 
         // Configures the cloud environment
-        std::env::set_var(&FLOCK_CONF["lambda"]["environment"], encoded);
+        std::env::set_var(&FLOCK_CONFIG.lambda.environment, encoded);
 
         // First lambda call
         let event = json!({
@@ -378,8 +367,9 @@ mod tests {
         let event = init_lambda_exec(record_num);
 
         let kinesis_event: KinesisEvent = serde_json::from_value(event).unwrap();
-        let batch = kinesis::to_batch(kinesis_event);
+        let (batch, malformed) = kinesis::to_batch(kinesis_event)?;
 
+        assert!(malformed.is_empty());
         assert_eq!(10, batch.len());
 
         (0..10).for_each(|i| assert_eq!(1024, batch[i].num_rows()));
@@ -23,8 +23,11 @@ mod window;
 mod ysb;
 
 use cloud_context::*;
+use flock::aws::scheduler;
+use flock::metrics::Metrics;
 use flock::prelude::*;
-use hashring::HashRing;
+use flock::runtime::deadline::Deadline;
+use flock::runtime::group::FunctionGroup;
 use lambda_runtime::{service_fn, LambdaEvent};
 use log::info;
 use serde_json::Value;
@@ -39,21 +42,35 @@ static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 async fn handler(event: LambdaEvent<Payload>) -> Result<Value> {
     let payload = event.payload;
+    let deadline = Deadline::new(event.context.deadline);
     let (ctx, arena) = init_exec_context!();
     update_consistent_hash_context(&payload.metadata)?;
+    refresh_function_group(&ctx).await;
+    scheduler::configure(
+        &ctx.name,
+        scheduler::settings_from_metadata(&payload.metadata)?,
+    );
 
     info!(
         "AWS Lambda function architecture: {}",
         std::env::consts::ARCH
     );
 
-    match &payload.datasource {
-        DataSource::Payload(_) => actor::handler(ctx, arena, payload).await,
-        DataSource::NEXMarkEvent(_) => nexmark::handler(ctx, payload).await,
-        DataSource::YSBEvent(_) => ysb::handler(ctx, payload).await,
+    if let DataSource::Payload(_) = &payload.datasource {
+        return actor::handler(ctx, arena, payload, &deadline).await;
+    }
+
+    // The data source generators only report the invocation scheduling.
+    let metrics = Metrics::for_function(&ctx.name);
+    let result = match &payload.datasource {
+        DataSource::NEXMarkEvent(_) => nexmark::handler(ctx, payload, &deadline).await,
+        DataSource::YSBEvent(_) => ysb::handler(ctx, payload, &deadline).await,
         DataSource::S3(_) => s3::handler(ctx, payload).await,
         _ => unimplemented!(),
-    }
+    };
+    actor::record_scheduler_metrics(&metrics);
+    metrics.emit();
+    result
 }
 
 #[tokio::main]
@@ -15,6 +15,7 @@
 
 use crate::window::*;
 use flock::prelude::*;
+use flock::runtime::deadline::Deadline;
 use log::info;
 use serde_json::json;
 use serde_json::Value;
@@ -27,10 +28,15 @@ use std::sync::Arc;
 /// # Arguments
 /// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
+/// * `deadline` - The deadline of the function invocation.
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(
+    ctx: &mut ExecutionContext,
+    payload: Payload,
+    deadline: &Deadline,
+) -> Result<Value> {
     // Copy data source from the payload.
     let mut source = match payload.datasource.clone() {
         DataSource::NEXMarkEvent(source) => source,
@@ -57,19 +63,20 @@ pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Val
 
     match source.window {
         Window::Tumbling(Schedule::Seconds(window_size)) => {
-            tumbling_window_tasks(payload, events, sec, window_size).await?;
+            tumbling_window_tasks(ctx, payload, events, sec, window_size, deadline).await?;
         }
         Window::Hopping((window_size, hop_size)) => {
-            hopping_window_tasks(payload, events, sec, window_size, hop_size).await?;
+            hopping_window_tasks(ctx, payload, events, sec, window_size, hop_size, deadline)
+                .await?;
         }
         Window::ElementWise => {
-            elementwise_tasks(ctx, payload, events, sec).await?;
+            elementwise_tasks(ctx, payload, events, sec, deadline).await?;
         }
         Window::Session(Schedule::Seconds(timeout)) => {
-            session_window_tasks(payload, events, sec, timeout).await?;
+            session_window_tasks(ctx, payload, events, sec, timeout).await?;
         }
         Window::Global(Schedule::Seconds(window_size)) => {
-            global_window_tasks(payload, events, sec, window_size).await?;
+            global_window_tasks(ctx, payload, events, sec, window_size).await?;
         }
         _ => unimplemented!(),
     };
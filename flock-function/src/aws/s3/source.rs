@@ -13,7 +13,7 @@
 
 //! The entry point for the NEXMark benchmark on cloud functions.
 
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::consistent_hash_context;
 use chrono::Utc;
 use datafusion::physical_plan::Partitioning;
 use flock::prelude::*;
@@ -59,50 +59,48 @@ pub async fn handler(_ctx: &ExecutionContext, payload: Payload) -> Result<Value>
     info!("{:?}", source);
     info!("[OK] Generate nexmark events.");
 
-    let (ring, group_name) = consistent_hash_context!();
-    let uuid = UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), 1).next_uuid();
+    let (group, group_name) = consistent_hash_context!();
+    let uuid = UuidBuilder::new_with_ts(&group_name, Utc::now().timestamp(), 1).next_uuid();
     let sync = true;
 
-    let function_name = if ring.len() == 1 {
+    let function_name = if group.len() == 1 {
         group_name.clone()
     } else {
-        ring.get(&uuid.qid).expect("hash ring failure.").to_string()
+        group.route(&uuid.qid)?
     };
 
     let bytes = match source.window {
         Window::Hopping(..) | Window::Tumbling(..) => {
             assert!(sec == 10);
-            let mut r1 = vec![];
-            let mut r2 = vec![];
+            let mut relations: InputPartitions = vec![];
             for epoch in 0..sec {
-                let (mut a, mut b) = events.select_event_to_batches(
+                for (id, mut partitions) in events.select_event_to_batches(
                     epoch,
                     0, // generator id
                     payload.query_number,
                     sync,
-                )?;
-                if !a.is_empty() {
-                    r1.append(&mut a);
+                )? {
+                    match relations.iter_mut().find(|(r, _)| *r == id) {
+                        Some((_, r)) => r.append(&mut partitions),
+                        None => relations.push((id, partitions)),
+                    }
                 }
-                if !b.is_empty() {
-                    r2.append(&mut b);
-                }
-            }
-            if r1.len() > 1 {
-                r1 = repartition(r1, Partitioning::RoundRobinBatch(1)).await?;
             }
-            if r2.len() > 1 {
-                r2 = repartition(r2, Partitioning::RoundRobinBatch(1)).await?;
+            for (_, partitions) in relations.iter_mut() {
+                if partitions.len() > 1 {
+                    *partitions =
+                        repartition(std::mem::take(partitions), Partitioning::RoundRobinBatch(1))
+                            .await?;
+                }
+                assert!(partitions.len() <= 1);
             }
-            assert!(r1.len() <= 1);
-            assert!(r2.len() <= 1);
 
-            serde_json::to_vec(&to_payload(
-                if r1.len() == 1 { &r1[0] } else { &[] },
-                if r2.len() == 1 { &r2[0] } else { &[] },
-                uuid.clone(),
-                sync,
-            ))?
+            let inputs = relations
+                .iter()
+                .filter(|(_, partitions)| partitions.len() == 1)
+                .map(|(id, partitions)| (id.as_str(), partitions[0].as_slice()))
+                .collect::<Vec<_>>();
+            serde_json::to_vec(&to_payload(&inputs, uuid.clone(), sync))?
         }
         Window::ElementWise => {
             assert!(sec == 1);
@@ -12,7 +12,7 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::{consistent_hash_context, refresh_function_group};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use datafusion::arrow::array::{
     Int32Array, TimestampMillisecondArray, TimestampNanosecondArray, UInt64Array,
@@ -28,6 +28,10 @@ use datafusion::physical_plan::Partitioning::{HashDiff, RoundRobinBatch};
 use flock::aws::{lambda, s3};
 use flock::datasource::nexmark::config::BASE_TIME;
 use flock::prelude::*;
+use flock::runtime::deadline::{Continuation, Deadline};
+use flock::runtime::group::FunctionGroup;
+use flock::runtime::pane::{Pane, PANE_OWNER_KEY};
+use flock::runtime::subscription;
 use log::{info, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -37,15 +41,19 @@ use std::time::Instant;
 /// function services.
 ///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
 /// * `window_size` - the size of the window in seconds.
+/// * `deadline` - the deadline of the function invocation.
 pub async fn tumbling_window_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
     window_size: usize,
+    deadline: &Deadline,
 ) -> Result<()> {
     if seconds < window_size {
         warn!(
@@ -60,11 +68,14 @@ pub async fn tumbling_window_tasks(
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
 
-    let (ring, group_name) = consistent_hash_context!();
+    let mut window: Box<Vec<InputPartitions>> = Box::new(vec![]);
+    let mut subscribers = HashMap::new();
+    let continuation = Continuation::from_metadata(&payload.metadata)?;
 
-    let mut window: Box<Vec<(RelationPartitions, RelationPartitions)>> = Box::new(vec![]);
-
-    for time in 0..seconds / window_size {
+    for time in continuation.map_or(0, |c| c.epoch)..seconds / window_size {
+        if hand_over_near_deadline(ctx, &payload, continuation, time, deadline).await? {
+            break;
+        }
         let start = time * window_size;
         let end = start + window_size;
 
@@ -79,51 +90,21 @@ pub async fn tumbling_window_tasks(
             )?);
         }
 
-        // Calculate the total data packets to be sent.
-        let size = window
-            .iter()
-            .map(|(a, b)| if a.len() > b.len() { a.len() } else { b.len() })
-            .sum::<usize>();
-
-        let mut uuid_builder = UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
-
-        // Distribute the window data to a single function execution environment.
-        let function_name = ring
-            .get(&uuid_builder.qid)
-            .expect("hash ring failure.")
-            .to_string();
-
-        // Call the next stage of the dataflow graph.
-        info!(
-            "[OK] Send {} events from a window (epoch: {}-{}) to function: {}.",
-            size,
-            time,
-            time + window_size,
-            function_name
-        );
-
-        let mut eid = 0;
-        let empty = vec![];
-        for (a, b) in window.iter() {
-            let num = if a.len() > b.len() { a.len() } else { b.len() };
-            for i in 0..num {
-                let payload = serde_json::to_vec(&to_payload(
-                    if i < a.len() { &a[i] } else { &empty },
-                    if i < b.len() { &b[i] } else { &empty },
-                    uuid_builder.next_uuid(),
-                    sync,
-                ))?;
-                info!(
-                    "[OK] Event {} - {} function payload bytes: {}",
-                    eid,
-                    function_name,
-                    payload.len()
-                );
-                lambda::invoke_function(&function_name, &invocation_type, Some(payload.into()))
-                    .await?;
-                eid += 1;
-            }
-        }
+        // Reload the membership of the function group, so that the windows follow
+        // the scaling of the group and the upgrades of the query.
+        refresh_function_group(ctx).await;
+        let (group, _) = consistent_hash_context!();
+        let epoch = (time, time + window_size);
+        send_window(&window, &group, sync, &invocation_type, epoch, &None).await?;
+        fan_out_window(
+            ctx,
+            &window,
+            &mut subscribers,
+            sync,
+            &invocation_type,
+            epoch,
+        )
+        .await?;
     }
 
     Ok(())
@@ -132,18 +113,27 @@ pub async fn tumbling_window_tasks(
 /// Generate hopping windows workloads for the benchmark on cloud
 /// function services.
 ///
+/// If the window size is a multiple of the hop size and the next function is
+/// an aggregate function group, the windows are sent as panes of a hop, each
+/// of them once, and the aggregate function combines the panes of each window.
+/// Otherwise, the whole window is sent every hop.
+///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
 /// * `window_size` - the size of the window in seconds.
 /// * `hop_size` - the size of the hop in seconds.
+/// * `deadline` - the deadline of the function invocation.
 pub async fn hopping_window_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
     window_size: usize,
     hop_size: usize,
+    deadline: &Deadline,
 ) -> Result<()> {
     if seconds < window_size {
         warn!(
@@ -151,6 +141,18 @@ pub async fn hopping_window_tasks(
             seconds, window_size
         );
     }
+    if window_size % hop_size == 0 && matches!(ctx.next, CloudFunction::Group(..)) {
+        return pane_tasks(
+            ctx,
+            payload,
+            stream,
+            seconds,
+            window_size,
+            hop_size,
+            deadline,
+        )
+        .await;
+    }
     let sync = infer_invocation_type(&payload.metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
@@ -158,13 +160,18 @@ pub async fn hopping_window_tasks(
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
 
-    let (ring, group_name) = consistent_hash_context!();
-    let mut window: Box<Vec<(RelationPartitions, RelationPartitions)>> = Box::new(vec![]);
+    let mut window: Box<Vec<InputPartitions>> = Box::new(vec![]);
+    let mut subscribers = HashMap::new();
+    let continuation = Continuation::from_metadata(&payload.metadata)?;
 
-    for time in (0..seconds).step_by(hop_size) {
+    // The new invocation rebuilds the whole window from the epoch it resumes from.
+    for time in (continuation.map_or(0, |c| c.epoch)..seconds).step_by(hop_size) {
         if time + window_size > seconds {
             break;
         }
+        if hand_over_near_deadline(ctx, &payload, continuation, time, deadline).await? {
+            break;
+        }
 
         // Move the hopping window forward.
         let mut start_pos = 0;
@@ -183,56 +190,317 @@ pub async fn hopping_window_tasks(
             )?);
         }
 
-        // Calculate the total data packets to be sent.
-        let size = window
-            .iter()
-            .map(|(a, b)| if a.len() > b.len() { a.len() } else { b.len() })
-            .sum::<usize>();
+        // Reload the membership of the function group, so that the windows follow
+        // the scaling of the group and the upgrades of the query.
+        refresh_function_group(ctx).await;
+        let (group, _) = consistent_hash_context!();
+        let epoch = (time, time + window_size);
+        send_window(&window, &group, sync, &invocation_type, epoch, &None).await?;
+        fan_out_window(
+            ctx,
+            &window,
+            &mut subscribers,
+            sync,
+            &invocation_type,
+            epoch,
+        )
+        .await?;
+    }
 
-        let mut uuid_builder = UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
-
-        // Distribute the window data to a single function execution environment.
-        let function_name = ring
-            .get(&uuid_builder.qid)
-            .expect("hash ring failure.")
-            .to_string();
-
-        // Call the next stage of the dataflow graph.
-        info!(
-            "[OK] Send {} events from a window (epoch: {}-{}) to function: {}.",
-            size,
-            time,
-            time + window_size,
-            function_name
-        );
+    Ok(())
+}
 
-        let mut eid = 0;
-        let empty = vec![];
-        for (a, b) in window.iter() {
-            let num = if a.len() > b.len() { a.len() } else { b.len() };
-            for i in 0..num {
-                let payload = serde_json::to_vec(&to_payload(
-                    if i < a.len() { &a[i] } else { &empty },
-                    if i < b.len() { &b[i] } else { &empty },
-                    uuid_builder.next_uuid(),
-                    sync,
-                ))?;
-                info!(
-                    "[OK] Event {} - {} function's payload bytes: {}",
-                    eid,
-                    function_name,
-                    payload.len()
-                );
-                lambda::invoke_function(&function_name, &invocation_type, Some(payload.into()))
-                    .await?;
-                eid += 1;
+/// Generate the panes of hopping windows for the benchmark on cloud function
+/// services. Each pane holds the events of a hop, and is sent once with its
+/// position in the metadata, so the aggregate function reduces every event
+/// once instead of once per window. All panes are routed to the same member of
+/// the function group, which keeps the pane states of the windows.
+///
+/// The subscribers of the data source still receive the whole window every
+/// hop, since their plans may not combine the panes.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the function.
+/// * `payload` - The payload of the function.
+/// * `stream` - the source stream of events.
+/// * `seconds` - the total number of seconds to generate workloads.
+/// * `window_size` - the size of the window in seconds.
+/// * `hop_size` - the size of the hop in seconds, which divides the window
+///   size.
+/// * `deadline` - the deadline of the function invocation.
+async fn pane_tasks(
+    ctx: &ExecutionContext,
+    mut payload: Payload,
+    stream: Arc<dyn DataStream>,
+    seconds: usize,
+    window_size: usize,
+    hop_size: usize,
+    deadline: &Deadline,
+) -> Result<()> {
+    let sync = infer_invocation_type(&payload.metadata)?;
+    let invocation_type = if sync {
+        FLOCK_LAMBDA_SYNC_CALL.to_string()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.to_string()
+    };
+
+    // The owner of the panes is kept in the payload, so that the invocations
+    // that resume the stream route the panes to the same member.
+    let owner = match payload
+        .metadata
+        .as_ref()
+        .and_then(|m| m.get(PANE_OWNER_KEY))
+    {
+        Some(owner) => owner.to_owned(),
+        None => {
+            let (group, _) = consistent_hash_context!();
+            let owner = UuidBuilder::new_with_ts(&group.name, Utc::now().timestamp(), 1).qid;
+            payload
+                .metadata
+                .get_or_insert_with(HashMap::new)
+                .insert(PANE_OWNER_KEY.to_owned(), owner.clone());
+            owner
+        }
+    };
+
+    let mut window: Box<Vec<InputPartitions>> = Box::new(vec![]);
+    let mut subscribers = HashMap::new();
+    let continuation = Continuation::from_metadata(&payload.metadata)?;
+
+    // The epoch of the continuation is the index of the pane to resume from,
+    // and the new invocation rebuilds the rest of the window before the pane.
+    let panes = window_size / hop_size;
+    let count = seconds / hop_size;
+    for index in continuation.map_or(0, |c| c.epoch)..count {
+        if hand_over_near_deadline(ctx, &payload, continuation, index, deadline).await? {
+            break;
+        }
+        let start = index * hop_size;
+        let end = start + hop_size;
+
+        // Move the hopping window forward.
+        let mut from = start;
+        if window.is_empty() {
+            from = (start + hop_size).saturating_sub(window_size);
+        } else if window.len() == window_size {
+            window.drain(..hop_size);
+        }
+        for t in from..end {
+            window.push(stream.select_event_to_batches(
+                t,
+                0, // generator id
+                payload.query_number,
+                sync,
+            )?);
+        }
+
+        let mut metadata = None;
+        Pane::new(index, panes, count)
+            .with_owner(owner.clone())
+            .to_metadata(&mut metadata)?;
+
+        // Reload the membership of the function group, so that the panes follow
+        // the scaling of the group and the upgrades of the query.
+        refresh_function_group(ctx).await;
+        let (group, _) = consistent_hash_context!();
+        let pane = &window[window.len() - hop_size..];
+        send_window(
+            pane,
+            &group,
+            sync,
+            &invocation_type,
+            (start, end),
+            &metadata,
+        )
+        .await?;
+        if window.len() == window_size {
+            fan_out_window(
+                ctx,
+                &window,
+                &mut subscribers,
+                sync,
+                &invocation_type,
+                (end - window_size, end),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the window to the member of the function group that owns it.
+///
+/// # Arguments
+/// * `window` - The partitions of the relations for each second.
+/// * `group` - The function group of the next function.
+/// * `sync` - Whether the next function is invoked synchronously.
+/// * `invocation_type` - The invocation type of the next function.
+/// * `epoch` - The start and the end of the window.
+/// * `metadata` - The metadata of the payloads, e.g., the pane of the window.
+async fn send_window(
+    window: &[InputPartitions],
+    group: &FunctionGroup,
+    sync: bool,
+    invocation_type: &str,
+    epoch: (usize, usize),
+    metadata: &Option<HashMap<String, String>>,
+) -> Result<()> {
+    // Calculate the total data packets to be sent.
+    let size = window
+        .iter()
+        .map(|inputs| num_events(inputs))
+        .sum::<usize>();
+
+    let mut uuid_builder = UuidBuilder::new_with_ts(&group.name, Utc::now().timestamp(), size);
+
+    // Distribute the window data to a single function execution environment.
+    // The panes of hopping windows are routed by their owner instead.
+    let route_key = Pane::from_metadata(metadata)?
+        .and_then(|pane| pane.owner)
+        .unwrap_or_else(|| uuid_builder.qid.clone());
+    let function_name = group.route(&route_key)?;
+
+    // Call the next stage of the dataflow graph.
+    info!(
+        "[OK] Send {} events from a window (epoch: {}-{}) to function: {}.",
+        size, epoch.0, epoch.1, function_name
+    );
+
+    let mut eid = 0;
+    for inputs in window.iter() {
+        for i in 0..num_events(inputs) {
+            let mut payload = to_payload(&event_inputs(inputs, i), uuid_builder.next_uuid(), sync);
+            payload.metadata = metadata.clone();
+            let payload = serde_json::to_vec(&payload)?;
+            info!(
+                "[OK] Event {} - {} function's payload bytes: {}",
+                eid,
+                function_name,
+                payload.len()
+            );
+            lambda::invoke_function(&function_name, invocation_type, Some(payload.into())).await?;
+            eid += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the number of events of a second, i.e., the number of partitions of
+/// its largest relation.
+fn num_events(inputs: &InputPartitions) -> usize {
+    inputs
+        .iter()
+        .map(|(_, partitions)| partitions.len())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the `i`-th partition of each relation of a second, which are the
+/// inputs of the `i`-th event.
+fn event_inputs(inputs: &InputPartitions, i: usize) -> Vec<(&str, &[RecordBatch])> {
+    inputs
+        .iter()
+        .filter(|(_, partitions)| i < partitions.len())
+        .map(|(id, partitions)| (id.as_str(), partitions[i].as_slice()))
+        .collect()
+}
+
+/// Sends the window to the queries subscribed to the data source, pruned to
+/// the columns each of them reads. The subscribers are kept if they can't be
+/// reloaded.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the data source function.
+/// * `window` - The partitions of the relations for each second.
+/// * `subscribers` - The function groups of the subscribers by query code.
+/// * `sync` - Whether the subscribers are invoked synchronously.
+/// * `invocation_type` - The invocation type of the subscribers.
+/// * `epoch` - The start and the end of the window.
+async fn fan_out_window(
+    ctx: &ExecutionContext,
+    window: &[InputPartitions],
+    subscribers: &mut HashMap<String, FunctionGroup>,
+    sync: bool,
+    invocation_type: &str,
+    epoch: (usize, usize),
+) -> Result<()> {
+    let subscriptions = match subscription::subscribers(&ctx.name).await {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            warn!("Failed to load the subscribers of {}: {}", ctx.name, e);
+            return Ok(());
+        }
+    };
+    subscribers.retain(|code, _| subscriptions.iter().any(|s| &s.query_code == code));
+
+    for subscription in subscriptions {
+        let mut pruned = vec![];
+        for inputs in window.iter() {
+            let mut projected = vec![];
+            for (id, partitions) in inputs.iter() {
+                if let Some(partitions) = subscription.project(partitions)? {
+                    if !partitions.is_empty() {
+                        projected.push((id.clone(), partitions));
+                    }
+                }
+            }
+            if !projected.is_empty() {
+                pruned.push(projected);
             }
         }
+        if pruned.is_empty() {
+            continue;
+        }
+
+        let group = subscribers
+            .entry(subscription.query_code.clone())
+            .or_insert_with(|| {
+                let mut group = FunctionGroup::from_cloud_function(&subscription.next);
+                group.upstream = ctx.name.clone();
+                group
+            });
+        if let Err(e) = group.refresh(&ctx.state_backend).await {
+            warn!("Failed to refresh function group {}: {}", group.name, e);
+        }
+        send_window(&pruned, group, sync, invocation_type, epoch, &None).await?;
     }
 
     Ok(())
 }
 
+/// Hands the rest of the epochs over to a new invocation of the data source
+/// function if the deadline is near.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the function.
+/// * `payload` - The payload of the function.
+/// * `continuation` - The continuation of the current invocation, if any.
+/// * `epoch` - The next epoch to generate.
+/// * `deadline` - The deadline of the function invocation.
+///
+/// # Returns
+/// True if the rest of the epochs are handed over.
+async fn hand_over_near_deadline(
+    ctx: &ExecutionContext,
+    payload: &Payload,
+    continuation: Option<Continuation>,
+    epoch: usize,
+    deadline: &Deadline,
+) -> Result<bool> {
+    if !deadline.is_near() {
+        return Ok(false);
+    }
+    match Continuation::next(continuation, epoch) {
+        Some(next) => {
+            hand_over(&ctx.name, payload.clone(), next).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
 /// Get back the input data from the registered table after the query is
 /// executed to avoid copying the input data.
 fn get_input_from_registered_table(
@@ -381,6 +649,20 @@ fn find_timeout_session_windows(
     Ok(to_remove)
 }
 
+/// Returns the partitions of the stream table, or the first relation of the
+/// event if the table isn't one of its relations.
+fn stream_relation(mut relations: InputPartitions, table_name: &str) -> RelationPartitions {
+    let index = relations
+        .iter()
+        .position(|(id, _)| id == table_name)
+        .unwrap_or(0);
+    if relations.is_empty() {
+        vec![]
+    } else {
+        relations.swap_remove(index).1
+    }
+}
+
 /// Session windows group events that arrive at similar times, filtering out
 /// periods of time where there is no data. A session window begins when the
 /// first event occurs. If another event occurs within the specified timeout
@@ -388,6 +670,7 @@ fn find_timeout_session_windows(
 /// event. Otherwise if no events occur within the timeout, then the window is
 /// closed at the timeout.
 pub async fn session_window_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
@@ -398,7 +681,6 @@ pub async fn session_window_tasks(
     }
     let sync = infer_invocation_type(&payload.metadata)?;
     let (group_key, table_name) = infer_session_keys(&payload.metadata)?;
-    let (ring, group_name) = consistent_hash_context!();
 
     let (invocation_type, granule_size) = if sync {
         (FLOCK_LAMBDA_SYNC_CALL.to_string(), *FLOCK_SYNC_GRANULE_SIZE)
@@ -414,7 +696,7 @@ pub async fn session_window_tasks(
 
     let events = (0..seconds)
         .map(|t| {
-            let (r1, _) = stream
+            let relations = stream
                 .select_event_to_batches(
                     t,
                     0, // generator id
@@ -422,7 +704,7 @@ pub async fn session_window_tasks(
                     sync,
                 )
                 .unwrap();
-            r1.to_vec()
+            stream_relation(relations, &table_name)
         })
         .collect::<Vec<Vec<Vec<RecordBatch>>>>();
 
@@ -462,12 +744,18 @@ pub async fn session_window_tasks(
             sessions.push(windows.remove(bidder).unwrap());
         });
 
+        // Reload the membership of the function group, so that the windows follow
+        // the scaling of the group and the upgrades of the query.
+        refresh_function_group(ctx).await;
+        let (group, group_name) = consistent_hash_context!();
+
         let tasks = coalesce_windows(sessions, granule_size)?
             .into_iter()
             .filter(|session| !session.is_empty())
             .map(|session| {
                 let function_group = group_name.clone();
                 let invoke_type = invocation_type.clone();
+                let table_name = table_name.clone();
 
                 let query_code = group_name.split('-').next().unwrap();
                 let timestamp = Utc::now().timestamp();
@@ -475,7 +763,7 @@ pub async fn session_window_tasks(
                 let qid = format!("{}-{}-{}", query_code, timestamp, rand_id);
 
                 // Distribute the window data to a single function execution environment.
-                let function_name = ring.get(&qid).expect("hash ring failure.").to_string();
+                let function_name = group.route(&qid).expect("hash ring failure.");
                 info!("Session window -> function name: {}", function_name);
 
                 tokio::spawn(async move {
@@ -493,8 +781,7 @@ pub async fn session_window_tasks(
 
                     for (eid, partition) in window.iter().enumerate() {
                         let payload = serde_json::to_vec(&to_payload(
-                            partition,
-                            &[],
+                            &[(&table_name, partition)],
                             uuid_builder.next_uuid(),
                             sync,
                         ))?;
@@ -511,7 +798,7 @@ pub async fn session_window_tasks(
                 })
             })
             .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-        futures::future::join_all(tasks).await;
+        report_task_errors(futures::future::join_all(tasks).await);
     }
 
     Ok(())
@@ -673,11 +960,13 @@ fn coalesce_windows(
 /// aggregated elements.
 ///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function invocation.
 /// * `stream` - The data stream.
 /// * `seconds` - The number of seconds to group events into.
 /// * `window_size` - The size of the window.
 pub async fn global_window_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
@@ -692,7 +981,6 @@ pub async fn global_window_tasks(
     let sync = infer_invocation_type(&payload.metadata)?;
     let (group_key, table_name) = infer_session_keys(&payload.metadata)?;
     let add_process_time_sql = infer_add_process_time_query(&payload.metadata)?;
-    let (ring, group_name) = consistent_hash_context!();
 
     let (invocation_type, granule_size) = if sync {
         (FLOCK_LAMBDA_SYNC_CALL.to_string(), *FLOCK_SYNC_GRANULE_SIZE)
@@ -708,7 +996,7 @@ pub async fn global_window_tasks(
 
     let events = (0..seconds)
         .map(|t| {
-            let (r1, _) = stream
+            let relations = stream
                 .select_event_to_batches(
                     t,
                     0, // generator id
@@ -716,7 +1004,7 @@ pub async fn global_window_tasks(
                     sync,
                 )
                 .unwrap();
-            r1.to_vec()
+            stream_relation(relations, &table_name)
         })
         .collect::<Vec<Vec<Vec<RecordBatch>>>>();
 
@@ -765,12 +1053,18 @@ pub async fn global_window_tasks(
             tumblings.push(windows.remove(bidder).unwrap());
         });
 
+        // Reload the membership of the function group, so that the windows follow
+        // the scaling of the group and the upgrades of the query.
+        refresh_function_group(ctx).await;
+        let (group, group_name) = consistent_hash_context!();
+
         let tasks = coalesce_windows(tumblings, granule_size)?
             .into_iter()
             .filter(|window| !window.is_empty())
             .map(|window| {
                 let function_group = group_name.clone();
                 let invoke_type = invocation_type.clone();
+                let table_name = table_name.clone();
 
                 let query_code = group_name.split('-').next().unwrap();
                 let timestamp = Utc::now().timestamp();
@@ -778,7 +1072,7 @@ pub async fn global_window_tasks(
                 let qid = format!("{}-{}-{}", query_code, timestamp, rand_id);
 
                 // Distribute the window data to a single function execution environment.
-                let function_name = ring.get(&qid).expect("hash ring failure.").to_string();
+                let function_name = group.route(&qid).expect("hash ring failure.");
                 info!("Tumbling window -> function name: {}", function_name);
 
                 tokio::spawn(async move {
@@ -796,8 +1090,7 @@ pub async fn global_window_tasks(
 
                     for (eid, partition) in window.iter().enumerate() {
                         let payload = serde_json::to_vec(&to_payload(
-                            partition,
-                            &[],
+                            &[(&table_name, partition)],
                             uuid_builder.next_uuid(),
                             sync,
                         ))?;
@@ -814,7 +1107,7 @@ pub async fn global_window_tasks(
                 })
             })
             .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-        futures::future::join_all(tasks).await;
+        report_task_errors(futures::future::join_all(tasks).await);
 
         let elapsed = now.elapsed().as_millis() as u64;
         if elapsed < 1000 {
@@ -829,18 +1122,22 @@ pub async fn global_window_tasks(
 /// function services.
 ///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
+/// * `deadline` - the deadline of the function invocation.
 pub async fn elementwise_tasks(
     ctx: &mut ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream + Send + Sync>,
     seconds: usize,
+    deadline: &Deadline,
 ) -> Result<()> {
     let query_number = payload.query_number;
-    let metadata = payload.metadata;
-    let (ring, group_name) = consistent_hash_context!();
+    let mut metadata = payload.metadata.clone();
+    let continuation = Continuation::from_metadata(&metadata)?;
+    Continuation::remove_from_metadata(&mut metadata);
     let sync = infer_invocation_type(&metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
@@ -848,10 +1145,15 @@ pub async fn elementwise_tasks(
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
 
-    for epoch in 0..seconds {
+    for epoch in continuation.map_or(0, |c| c.epoch)..seconds {
+        if hand_over_near_deadline(ctx, &payload, continuation, epoch, deadline).await? {
+            break;
+        }
         info!("[OK] Send events (epoch: {}).", epoch);
         let events = stream.clone();
-        if ring.len() == 1 {
+        refresh_function_group(ctx).await;
+        let (group, group_name) = consistent_hash_context!();
+        if group.len() == 1 {
             // lambda default concurrency is 1000.
             assert!(!ctx.plan.execution_plans.is_empty());
             let exec_plans = &ctx.plan.execution_plans;
@@ -873,24 +1175,18 @@ pub async fn elementwise_tasks(
                     .await?;
             } else {
                 // distributed mode
-                let partitions = events.select_event_to_batches(
+                let input = events.select_event_to_batches(
                     epoch,
                     0, // generator id
                     payload.query_number,
                     sync,
                 )?;
-                let mut input = vec![];
-                for b in vec![partitions.0, partitions.1] {
-                    if !b.is_empty() {
-                        input.push(b);
-                    }
-                }
 
                 ctx.feed_data_sources(input).await?;
                 let output = Arc::new(ctx.execute_partitioned().await?);
                 let size = output[0].len();
                 let mut uuid_builder =
-                    UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+                    UuidBuilder::new_with_ts(&group_name, Utc::now().timestamp(), size);
 
                 // Creates the S3 bucket for the current query if state backend is S3.
                 if ctx
@@ -910,12 +1206,13 @@ pub async fn elementwise_tasks(
                         let invoke_type = invocation_type.clone();
                         let uuid = uuid_builder.next_uuid();
                         tokio::spawn(async move {
-                            let mut payload = to_payload(
-                                &data[0][i],
-                                if data.len() == 1 { &[] } else { &data[1][i] },
-                                uuid,
-                                sync,
-                            );
+                            let ids = (0..data.len()).map(output_id).collect::<Vec<_>>();
+                            let inputs = ids
+                                .iter()
+                                .zip(data.iter())
+                                .map(|(id, output)| (id.as_str(), output[i].as_slice()))
+                                .collect::<Vec<_>>();
+                            let mut payload = to_payload(&inputs, uuid, sync);
                             payload.query_number = query_number;
                             payload.metadata = meta;
 
@@ -935,28 +1232,24 @@ pub async fn elementwise_tasks(
                         })
                     })
                     .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
+                report_task_errors(futures::future::join_all(tasks).await);
                 ctx.clean_data_sources().await?;
             }
         } else {
             // Calculate the total data packets to be sent.
-            // transfrom tuple (a, b) to (Arc::new(a), Arc::new(b))
-            let (a, b) = events.select_event_to_batches(
+            let inputs = events.select_event_to_batches(
                 epoch,
                 0, // generator id
                 payload.query_number,
                 sync,
             )?;
-            let size = if a.len() > b.len() { a.len() } else { b.len() };
+            let size = num_events(&inputs);
 
             let mut uuid_builder =
-                UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+                UuidBuilder::new_with_ts(&group_name, Utc::now().timestamp(), size);
 
             // Distribute the epoch data to a single function execution environment.
-            let function_name = ring
-                .get(&uuid_builder.qid)
-                .expect("hash ring failure.")
-                .to_string();
+            let function_name = group.route(&uuid_builder.qid)?;
 
             // Call the next stage of the dataflow graph.
             info!(
@@ -964,14 +1257,9 @@ pub async fn elementwise_tasks(
                 size, epoch, function_name
             );
 
-            let empty = vec![];
             for i in 0..size {
-                let mut payload = to_payload(
-                    if i < a.len() { &a[i] } else { &empty },
-                    if i < b.len() { &b[i] } else { &empty },
-                    uuid_builder.next_uuid(),
-                    sync,
-                );
+                let mut payload =
+                    to_payload(&event_inputs(&inputs, i), uuid_builder.next_uuid(), sync);
                 payload.query_number = query_number;
                 payload.metadata = metadata.clone();
 
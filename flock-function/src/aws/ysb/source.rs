@@ -15,6 +15,7 @@
 
 use crate::window::*;
 use flock::prelude::*;
+use flock::runtime::deadline::Deadline;
 use log::info;
 use serde_json::json;
 use serde_json::Value;
@@ -27,10 +28,15 @@ use std::sync::Arc;
 /// # Arguments
 /// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
+/// * `deadline` - The deadline of the function invocation.
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(
+    ctx: &ExecutionContext,
+    payload: Payload,
+    deadline: &Deadline,
+) -> Result<Value> {
     // Copy data source from the payload.
     let mut source = match payload.datasource.clone() {
         DataSource::YSBEvent(source) => source,
@@ -55,7 +61,7 @@ pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value>
     info!("[OK] Generate YSB events.");
 
     if let Window::Tumbling(Schedule::Seconds(window_size)) = source.window {
-        tumbling_window_tasks(payload, events, sec, window_size).await?;
+        tumbling_window_tasks(ctx, payload, events, sec, window_size, deadline).await?;
     } else {
         unreachable!();
     }
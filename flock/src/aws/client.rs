@@ -0,0 +1,228 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module builds the clients of the AWS services from the settings, so
+//! that Flock can run against the official AWS endpoints as well as local
+//! stand-ins such as MinIO or LocalStack.
+
+use crate::configs::FlockConfig;
+use crate::error::{FlockError, Result};
+use async_trait::async_trait;
+use rusoto_core::credential::{
+    AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::{HttpClient, Region};
+use rusoto_dynamodb::DynamoDbClient;
+use rusoto_efs::EfsClient;
+use rusoto_iam::IamClient;
+use rusoto_kinesis::KinesisClient;
+use rusoto_lambda::LambdaClient;
+use rusoto_logs::CloudWatchLogsClient;
+use rusoto_s3::S3Client;
+use rusoto_sqs::SqsClient;
+use std::str::FromStr;
+
+/// The AWS services used by Flock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AwsService {
+    /// AWS S3.
+    S3,
+    /// AWS Lambda.
+    Lambda,
+    /// AWS SQS.
+    Sqs,
+    /// AWS EFS.
+    Efs,
+    /// AWS CloudWatch Logs.
+    Logs,
+    /// AWS Kinesis.
+    Kinesis,
+    /// AWS IAM.
+    Iam,
+    /// AWS DynamoDB.
+    DynamoDB,
+}
+
+/// The credential provider selected by `aws.credentials`.
+pub enum CredentialsProvider {
+    /// Environment variables, AWS profile, container or instance metadata.
+    Default(DefaultCredentialsProvider),
+    /// The static access key in the settings.
+    Static(StaticProvider),
+    /// The named profile in the AWS credentials file.
+    Profile(ProfileProvider),
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialsProvider {
+    async fn credentials(&self) -> std::result::Result<AwsCredentials, CredentialsError> {
+        match self {
+            CredentialsProvider::Default(p) => p.credentials().await,
+            CredentialsProvider::Static(p) => p.credentials().await,
+            CredentialsProvider::Profile(p) => p.credentials().await,
+        }
+    }
+}
+
+/// Returns the endpoint of the service. The per-service endpoint takes
+/// precedence over `aws.endpoint`.
+pub fn endpoint(config: &FlockConfig, service: AwsService) -> Option<String> {
+    let endpoints = &config.endpoints;
+    let endpoint = match service {
+        AwsService::S3 => &endpoints.s3,
+        AwsService::Lambda => &endpoints.lambda,
+        AwsService::Sqs => &endpoints.sqs,
+        AwsService::Efs => &endpoints.efs,
+        AwsService::Logs => &endpoints.logs,
+        AwsService::Kinesis => &endpoints.kinesis,
+        AwsService::Iam => &endpoints.iam,
+        AwsService::DynamoDB => &endpoints.dynamodb,
+    };
+    [endpoint, &config.aws.endpoint]
+        .into_iter()
+        .find(|e| !e.is_empty())
+        .cloned()
+}
+
+/// Returns the region of the service, which carries the custom endpoint if
+/// there is one.
+pub fn region(config: &FlockConfig, service: AwsService) -> Result<Region> {
+    let region = if config.aws.region.is_empty() {
+        Region::default()
+    } else {
+        Region::from_str(&config.aws.region).map_err(|e| {
+            FlockError::Config(format!("`aws.region` = {:?}: {}", config.aws.region, e))
+        })?
+    };
+
+    Ok(match endpoint(config, service) {
+        Some(endpoint) => Region::Custom {
+            name: region.name().to_string(),
+            endpoint,
+        },
+        None => region,
+    })
+}
+
+/// Returns the credential provider selected by `aws.credentials`.
+pub fn credentials_provider(config: &FlockConfig) -> Result<CredentialsProvider> {
+    let provider = match config.aws.credentials.as_str() {
+        "static" => CredentialsProvider::Static(StaticProvider::new_minimal(
+            config.aws.access_key_id.clone(),
+            config.aws.secret_access_key.clone(),
+        )),
+        "profile" => {
+            let mut provider =
+                ProfileProvider::new().map_err(|e| FlockError::Config(e.to_string()))?;
+            provider.set_profile(config.aws.credentials_profile.clone());
+            CredentialsProvider::Profile(provider)
+        }
+        _ => CredentialsProvider::Default(
+            DefaultCredentialsProvider::new().map_err(|e| FlockError::Config(e.to_string()))?,
+        ),
+    };
+    Ok(provider)
+}
+
+macro_rules! new_client {
+    ($name:ident, $client:ty, $service:expr, $doc:expr) => {
+        #[doc = $doc]
+        pub fn $name(config: &FlockConfig) -> Result<$client> {
+            Ok(<$client>::new_with(
+                HttpClient::new().map_err(|e| FlockError::AWS(e.to_string()))?,
+                credentials_provider(config)?,
+                region(config, $service)?,
+            ))
+        }
+    };
+}
+
+new_client!(s3_client, S3Client, AwsService::S3, "Creates an S3 client.");
+new_client!(
+    lambda_client,
+    LambdaClient,
+    AwsService::Lambda,
+    "Creates a Lambda client."
+);
+new_client!(
+    sqs_client,
+    SqsClient,
+    AwsService::Sqs,
+    "Creates an SQS client."
+);
+new_client!(
+    efs_client,
+    EfsClient,
+    AwsService::Efs,
+    "Creates an EFS client."
+);
+new_client!(
+    logs_client,
+    CloudWatchLogsClient,
+    AwsService::Logs,
+    "Creates a CloudWatch Logs client."
+);
+new_client!(
+    kinesis_client,
+    KinesisClient,
+    AwsService::Kinesis,
+    "Creates a Kinesis client."
+);
+new_client!(
+    iam_client,
+    IamClient,
+    AwsService::Iam,
+    "Creates an IAM client."
+);
+new_client!(
+    dynamodb_client,
+    DynamoDbClient,
+    AwsService::DynamoDB,
+    "Creates a DynamoDB client."
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_endpoints() -> Result<()> {
+        let mut config = FlockConfig::default();
+        config.aws.region = "us-east-1".to_string();
+        assert_eq!(Region::UsEast1, region(&config, AwsService::S3)?);
+
+        // LocalStack for all services, and MinIO for S3.
+        config.aws.endpoint = "http://localhost:4566".to_string();
+        config.endpoints.s3 = "http://localhost:9000".to_string();
+        assert_eq!(
+            Region::Custom {
+                name:     "us-east-1".to_string(),
+                endpoint: "http://localhost:9000".to_string(),
+            },
+            region(&config, AwsService::S3)?
+        );
+        assert_eq!(
+            Region::Custom {
+                name:     "us-east-1".to_string(),
+                endpoint: "http://localhost:4566".to_string(),
+            },
+            region(&config, AwsService::Lambda)?
+        );
+
+        config.aws.region = "moon-east-1".to_string();
+        assert!(region(&config, AwsService::Sqs).is_err());
+
+        Ok(())
+    }
+}
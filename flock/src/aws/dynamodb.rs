@@ -12,3 +12,36 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! This crate contains all wrapped functions of the AWS DynamoDB service.
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use rusoto_dynamodb::{AttributeValue, DynamoDb, ScanInput};
+use std::collections::HashMap;
+
+/// Reads all the items of the table.
+///
+/// # Arguments
+/// * `table_name` - The name of the table.
+///
+/// # Returns
+/// The items of the table, keyed by the attribute names.
+pub async fn scan(table_name: &str) -> Result<Vec<HashMap<String, AttributeValue>>> {
+    let mut items = vec![];
+    let mut exclusive_start_key = None;
+    loop {
+        let output = FLOCK_DYNAMODB_CLIENT
+            .scan(ScanInput {
+                table_name: table_name.to_owned(),
+                exclusive_start_key,
+                consistent_read: Some(true),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        items.extend(output.items.unwrap_or_default());
+        match output.last_evaluated_key {
+            Some(key) if !key.is_empty() => exclusive_start_key = Some(key),
+            _ => return Ok(items),
+        }
+    }
+}
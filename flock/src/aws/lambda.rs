@@ -13,14 +13,23 @@
 
 //! This crate contains all wrapped functions of the AWS Lambda services.
 
+use crate::aws::scheduler;
 use crate::configs::*;
+use crate::deadletter;
+use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
-use crate::runtime::context::ExecutionContext;
+use crate::metrics;
+use crate::runtime::context::{self, ExecutionContext};
+use crate::runtime::payload::Payload;
+use crate::runtime::schema::UNKNOWN_SCHEMA_FINGERPRINT;
 use bytes::Bytes;
 use log::info;
 use rusoto_lambda::{
-    CreateFunctionRequest, GetFunctionRequest, InvocationRequest, InvocationResponse, Lambda,
-    PutFunctionConcurrencyRequest, UpdateFunctionCodeRequest,
+    CreateFunctionRequest, DeleteEventSourceMappingRequest, DeleteFunctionConcurrencyRequest,
+    DeleteFunctionRequest, Environment, FunctionCode, GetFunctionConcurrencyRequest,
+    GetFunctionRequest, InvocationRequest, InvocationResponse, Lambda,
+    ListEventSourceMappingsRequest, ListFunctionsRequest, PutFunctionConcurrencyRequest,
+    UpdateFunctionCodeRequest, VpcConfig,
 };
 use std::time::Duration;
 
@@ -36,14 +45,117 @@ pub async fn set_concurrency(function_name: &str, concurrency: i64) -> Result<()
         function_name:                  function_name.to_owned(),
         reserved_concurrent_executions: concurrency,
     };
-    let concurrency = FLOCK_LAMBDA_CLIENT
+    let response = FLOCK_LAMBDA_CLIENT
         .put_function_concurrency(request)
         .await
-        .map_err(|e| FlockError::Internal(e.to_string()))?;
-    assert_ne!(concurrency.reserved_concurrent_executions, Some(0));
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    if response.reserved_concurrent_executions != Some(concurrency) {
+        return Err(FlockError::AWS(format!(
+            "Failed to set the concurrency of {} to {}, got {:?}",
+            function_name, concurrency, response.reserved_concurrent_executions
+        )));
+    }
     Ok(())
 }
 
+/// Returns the lambda function's reserved concurrency, or None if the function
+/// uses the unreserved concurrency of the account.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+pub async fn get_concurrency(function_name: &str) -> Result<Option<i64>> {
+    Ok(FLOCK_LAMBDA_CLIENT
+        .get_function_concurrency(GetFunctionConcurrencyRequest {
+            function_name: function_name.to_owned(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .reserved_concurrent_executions)
+}
+
+/// Removes the lambda function's reserved concurrency, so the function uses
+/// the unreserved concurrency of the account.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+pub async fn delete_concurrency(function_name: &str) -> Result<()> {
+    FLOCK_LAMBDA_CLIENT
+        .delete_function_concurrency(DeleteFunctionConcurrencyRequest {
+            function_name: function_name.to_owned(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
+
+/// Lists the lambda functions whose names start with the given prefix.
+///
+/// # Arguments
+/// * `prefix` - The prefix of the function names.
+pub async fn list_functions(prefix: &str) -> Result<Vec<String>> {
+    let mut request = ListFunctionsRequest::default();
+    let mut function_names = vec![];
+    loop {
+        let response = FLOCK_LAMBDA_CLIENT
+            .list_functions(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        function_names.extend(
+            response
+                .functions
+                .into_iter()
+                .flatten()
+                .filter_map(|f| f.function_name)
+                .filter(|name| name.starts_with(prefix)),
+        );
+        if response.next_marker.is_none() {
+            break;
+        }
+        request.marker = response.next_marker;
+    }
+    Ok(function_names)
+}
+
+/// Deletes the event source mappings of the lambda function, e.g., the Kinesis
+/// streams or Kafka topics that trigger it.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+///
+/// # Returns
+/// The number of deleted event source mappings.
+pub async fn delete_event_source_mappings(function_name: &str) -> Result<usize> {
+    let mut request = ListEventSourceMappingsRequest {
+        function_name: Some(function_name.to_owned()),
+        ..Default::default()
+    };
+    let mut uuids = vec![];
+    loop {
+        let response = FLOCK_LAMBDA_CLIENT
+            .list_event_source_mappings(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        uuids.extend(
+            response
+                .event_source_mappings
+                .into_iter()
+                .flatten()
+                .filter_map(|m| m.uuid),
+        );
+        if response.next_marker.is_none() {
+            break;
+        }
+        request.marker = response.next_marker;
+    }
+
+    for uuid in uuids.iter() {
+        FLOCK_LAMBDA_CLIENT
+            .delete_event_source_mapping(DeleteEventSourceMappingRequest { uuid: uuid.clone() })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+    }
+    Ok(uuids.len())
+}
+
 /// Invokes the lambda function with the given payload.
 ///
 /// # Arguments
@@ -53,6 +165,18 @@ pub async fn set_concurrency(function_name: &str, concurrency: i64) -> Result<()
 ///   - `Event`: Asynchronous invocation.
 ///   - `RequestResponse`: Synchronous invocation.
 ///
+/// The invocation goes through the invocation scheduler of the query (see
+/// [`scheduler`]), which bounds the in-flight invocations, paces them and
+/// backs off when they're throttled. If the invocation fails permanently, the
+/// payload is captured in the dead-letter queue (see [`crate::deadletter`])
+/// before the error is returned. The captured payload carries the schemas of
+/// its inputs, so it can be replayed to a function that doesn't know them.
+///
+/// A payload only carries the schema bytes the first time the container sends
+/// the schema. If a synchronous receiver reports an unknown fingerprint, the
+/// payload is resent with the schema bytes attached. Asynchronous receivers
+/// can't report it, so they rely on the schemas registered by their plans.
+///
 /// # Returns
 /// The result of the invocation.
 pub async fn invoke_function(
@@ -60,37 +184,50 @@ pub async fn invoke_function(
     invocation_type: &str,
     payload: Option<Bytes>,
 ) -> Result<InvocationResponse> {
-    let request = InvocationRequest {
+    let result = try_invoke_function(function_name, invocation_type, payload.clone()).await;
+    if let Err(e) = &result {
+        let payload = attach_schemas(payload).unwrap_or_default();
+        deadletter::capture(function_name, invocation_type, &payload, e).await;
+    }
+    result
+}
+
+/// Invokes the lambda function like [`invoke_function`], but leaves the failed
+/// invocation to the caller instead of capturing it in the dead-letter queue,
+/// e.g., to replay a dead letter.
+pub async fn try_invoke_function(
+    function_name: &str,
+    invocation_type: &str,
+    payload: Option<Bytes>,
+) -> Result<InvocationResponse> {
+    let mut request = InvocationRequest {
         function_name: function_name.to_owned(),
         invocation_type: Some(invocation_type.to_owned()),
         payload,
         ..Default::default()
     };
+    let scheduler = scheduler::for_function(function_name);
 
     if invocation_type == *FLOCK_LAMBDA_ASYNC_CALL {
-        let response = FLOCK_LAMBDA_CLIENT
-            .invoke(request)
-            .await
-            .map_err(|e| FlockError::AWS(e.to_string()))?;
-        Ok(response)
+        scheduler.invoke(request).await
     } else {
         // Error retries and exponential backoff in AWS Lambda
         let mut retries = 0;
         loop {
-            match FLOCK_LAMBDA_CLIENT
-                .invoke(request.clone())
-                .await
-                .map_err(|e| FlockError::AWS(e.to_string()))
-            {
+            match scheduler.invoke(request.clone()).await {
                 Ok(response) => {
                     if response.function_error.is_none() {
                         return Ok(response);
                     } else {
+                        let details = response.payload.unwrap_or_default();
                         info!(
                             "Function execution error: {}, details: {:?}",
                             response.function_error.unwrap(),
-                            serde_json::from_slice::<serde_json::Value>(&response.payload.unwrap())
+                            serde_json::from_slice::<serde_json::Value>(&details)
                         );
+                        if String::from_utf8_lossy(&details).contains(UNKNOWN_SCHEMA_FINGERPRINT) {
+                            request.payload = attach_schemas(request.payload.take());
+                        }
                     }
                 }
                 Err(e) => {
@@ -99,6 +236,7 @@ pub async fn invoke_function(
             }
 
             info!("Retrying {} function invocation...", function_name);
+            metrics::record_retry();
             tokio::time::sleep(Duration::from_millis(2_u64.pow(retries) * 100)).await;
             retries += 1;
 
@@ -112,6 +250,22 @@ pub async fn invoke_function(
     }
 }
 
+/// Attaches the IPC-encoded schemas to the payload if it only carries their
+/// fingerprints.
+fn attach_schemas(payload: Option<Bytes>) -> Option<Bytes> {
+    let bytes = payload?;
+    match serde_json::from_slice::<Payload>(&bytes) {
+        Ok(mut payload) => {
+            payload.attach_schemas();
+            serde_json::to_vec(&payload)
+                .map(Bytes::from)
+                .ok()
+                .or(Some(bytes))
+        }
+        Err(_) => Some(bytes),
+    }
+}
+
 /// Creates a single lambda function.
 ///
 /// # Arguments
@@ -181,3 +335,88 @@ pub async fn create_function(
             .ok_or_else(|| FlockError::AWS("No function name!".to_string()))
     }
 }
+
+/// Creates a lambda function with the same configuration as an existing one.
+/// The execution context in the environment of the new function is renamed,
+/// and its concurrency is set to 1 as the other members of a function group.
+///
+/// # Arguments
+/// * `source` - The name of the existing lambda function.
+/// * `function_name` - The name of the new lambda function.
+///
+/// # Returns
+/// The name of the created lambda function.
+pub async fn clone_function(source: &str, function_name: &str) -> Result<String> {
+    let conf = FLOCK_LAMBDA_CLIENT
+        .get_function(GetFunctionRequest {
+            function_name: source.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .configuration
+        .ok_or_else(|| FlockError::AWS(format!("No configuration of function {}!", source)))?;
+
+    let mut variables = conf
+        .environment
+        .and_then(|env| env.variables)
+        .unwrap_or_default();
+    if let Some(encoded_ctx) = variables.get(&FLOCK_CONFIG.lambda.environment) {
+        let mut ctx = context::unmarshal(encoded_ctx)?;
+        ctx.name = function_name.to_owned();
+        variables.insert(
+            FLOCK_CONFIG.lambda.environment.clone(),
+            context::marshal(&ctx, Encoding::default())?,
+        );
+    }
+
+    let s3_key = match conf.architectures.as_ref().and_then(|a| a.first()) {
+        Some(arch) if arch == "arm64" => FLOCK_S3_ARM_64_KEY.clone(),
+        _ => FLOCK_S3_X86_64_KEY.clone(),
+    };
+
+    let resp = FLOCK_LAMBDA_CLIENT
+        .create_function(CreateFunctionRequest {
+            architectures: conf.architectures,
+            function_name: function_name.to_owned(),
+            code: FunctionCode {
+                s3_bucket: Some(FLOCK_S3_BUCKET.clone()),
+                s3_key: Some(s3_key),
+                ..Default::default()
+            },
+            handler: conf.handler,
+            runtime: conf.runtime,
+            role: conf.role.unwrap_or_default(),
+            vpc_config: conf.vpc_config.map(|vpc| VpcConfig {
+                security_group_ids: vpc.security_group_ids,
+                subnet_ids:         vpc.subnet_ids,
+            }),
+            environment: Some(Environment {
+                variables: Some(variables),
+            }),
+            timeout: conf.timeout,
+            memory_size: conf.memory_size,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+
+    set_concurrency(function_name, 1).await?;
+
+    resp.function_name
+        .ok_or_else(|| FlockError::AWS("No function name!".to_string()))
+}
+
+/// Deletes a lambda function.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+pub async fn delete_function(function_name: &str) -> Result<()> {
+    FLOCK_LAMBDA_CLIENT
+        .delete_function(DeleteFunctionRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
@@ -15,9 +15,11 @@
 //! Lambda, DynamoDB, S3, etc. Flock uses the AWS services to build the
 //! distributed query engine.
 
+pub mod client;
 pub mod cloudwatch;
 pub mod dynamodb;
 pub mod efs;
 pub mod lambda;
 pub mod s3;
+pub mod scheduler;
 pub mod sqs;
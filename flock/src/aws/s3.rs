@@ -18,8 +18,9 @@ use crate::error::{FlockError, Result};
 use rayon::prelude::*;
 use rusoto_core::ByteStream;
 use rusoto_s3::{
-    CreateBucketRequest, Delete, DeleteBucketRequest, DeleteObjectsRequest, GetObjectRequest,
-    HeadBucketRequest, ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3,
+    CreateBucketRequest, Delete, DeleteBucketRequest, DeleteObjectRequest, DeleteObjectsRequest,
+    GetObjectRequest, HeadBucketRequest, ListObjectsV2Request, ObjectIdentifier, PutObjectRequest,
+    S3,
 };
 use std::io::Read;
 
@@ -296,6 +297,23 @@ pub async fn get_matched_keys(bucket: &str, prefix: &str) -> Result<Vec<String>>
     Ok(keys)
 }
 
+/// Deletes an object in a bucket.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket.
+/// * `key` - The key of the object to delete.
+pub async fn delete_object(bucket: &str, key: &str) -> Result<()> {
+    FLOCK_S3_CLIENT
+        .delete_object(DeleteObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+        .map(|_| ())
+}
+
 /// Deletes all objects in a bucket.
 pub async fn delete_all_objects(bucket: &str) -> Result<()> {
     if bucket_exists(bucket).await? {
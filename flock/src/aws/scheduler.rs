@@ -0,0 +1,628 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The invocation scheduler bounds the fan-out of the function invocations.
+//!
+//! Every query has its own [`InvocationScheduler`] in the container, shared by
+//! all tasks that invoke the functions of the query. The scheduler limits the
+//! number of in-flight invocations, paces the invocations with a token bucket,
+//! and backs off when AWS Lambda throttles the invocations with
+//! `TooManyRequestsException`. A throttle pauses the token bucket, so all tasks
+//! of the query slow down rather than only the throttled one.
+//!
+//! The defaults come from the `[scheduler]` section, and can be overridden per
+//! query with the payload metadata, see [`settings_from_metadata`].
+
+use crate::configs::{SchedulerSettings, FLOCK_CONFIG, FLOCK_LAMBDA_CLIENT};
+use crate::error::{FlockError, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use rusoto_core::RusotoError;
+use rusoto_lambda::{InvocationRequest, InvocationResponse, InvokeError, Lambda};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// The payload metadata key of the maximum number of in-flight invocations.
+pub const MAX_IN_FLIGHT: &str = "max_in_flight";
+/// The payload metadata key of the maximum number of invocations per second.
+pub const INVOCATION_RATE: &str = "invocation_rate";
+/// The payload metadata key of the invocation burst size.
+pub const INVOCATION_BURST: &str = "invocation_burst";
+
+lazy_static! {
+    /// The invocation schedulers of the queries, keyed by query code.
+    static ref SCHEDULERS: Mutex<HashMap<String, Arc<InvocationScheduler>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// An error that tells whether the request was throttled by the service.
+pub trait Throttling {
+    /// Returns true if the request was throttled.
+    fn is_throttled(&self) -> bool;
+}
+
+impl Throttling for RusotoError<InvokeError> {
+    fn is_throttled(&self) -> bool {
+        match self {
+            RusotoError::Service(InvokeError::TooManyRequests(_)) => true,
+            RusotoError::Unknown(response) => response.status.as_u16() == 429,
+            _ => false,
+        }
+    }
+}
+
+/// A token bucket that paces the invocations.
+///
+/// The bucket is refilled at `rate` tokens per second up to `burst` tokens.
+/// A caller always takes a token, possibly driving the bucket negative, and
+/// waits until the token would have been available. This way the concurrent
+/// callers are served in order without polling.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate:         f64,
+    capacity:     f64,
+    tokens:       f64,
+    last:         Instant,
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Creates a full token bucket. A rate of 0 means unlimited.
+    pub fn new(rate: f64, burst: usize, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity: burst as f64,
+            tokens: burst as f64,
+            last: now,
+            paused_until: None,
+        }
+    }
+
+    /// Takes a token at `now`, and returns how long the caller must wait
+    /// before sending the invocation.
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        let mut wait = Duration::ZERO;
+        if let Some(until) = self.paused_until {
+            if until > now {
+                wait = until - now;
+            } else {
+                self.paused_until = None;
+            }
+        }
+        if self.rate <= 0.0 {
+            return wait;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens < 0.0 {
+            wait = wait.max(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+        wait
+    }
+
+    /// Pauses all callers until the given instant.
+    pub fn pause(&mut self, until: Instant) {
+        self.paused_until = Some(self.paused_until.map_or(until, |u| u.max(until)));
+    }
+
+    /// Changes the rate and the burst at `now`. The tokens accumulated with
+    /// the old rate are kept up to the new burst, and the debt of the waiting
+    /// callers is kept, so they aren't released early.
+    pub fn resize(&mut self, rate: f64, burst: usize, now: Instant) {
+        if self.rate > 0.0 {
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        } else {
+            self.tokens = burst as f64;
+        }
+        self.last = now;
+        self.rate = rate;
+        self.capacity = burst as f64;
+        self.tokens = self.tokens.min(self.capacity);
+    }
+}
+
+/// The statistics of the invocation schedulers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    /// The number of invocation attempts.
+    pub invocations:    usize,
+    /// The number of throttled invocation attempts.
+    pub throttles:      usize,
+    /// The total backoff delay after throttles, in milliseconds.
+    pub throttle_delay: u64,
+    /// The total time the invocations waited for an in-flight slot or a
+    /// token, in milliseconds.
+    pub wait_time:      u64,
+    /// The peak number of in-flight invocations.
+    pub peak_in_flight: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    invocations:    AtomicUsize,
+    throttles:      AtomicUsize,
+    throttle_delay: AtomicU64,
+    wait_time:      AtomicU64,
+    in_flight:      AtomicUsize,
+    peak_in_flight: AtomicUsize,
+}
+
+impl Counters {
+    fn take(&self) -> SchedulerStats {
+        SchedulerStats {
+            invocations:    self.invocations.swap(0, Ordering::Relaxed),
+            throttles:      self.throttles.swap(0, Ordering::Relaxed),
+            throttle_delay: self.throttle_delay.swap(0, Ordering::Relaxed),
+            wait_time:      self.wait_time.swap(0, Ordering::Relaxed),
+            peak_in_flight: self
+                .peak_in_flight
+                .swap(self.in_flight.load(Ordering::Relaxed), Ordering::Relaxed),
+        }
+    }
+}
+
+/// Releases the in-flight slot when the invocation completes. If the
+/// scheduler has shrunk since the slot was taken, the permit is forgotten
+/// instead, so the semaphore converges to the new limit without exceeding it.
+struct Slot<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    excess: &'a AtomicUsize,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let shrunk = self
+            .excess
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if let (true, Some(permit)) = (shrunk, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+/// Decrements the in-flight counter when the invocation completes.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The invocation scheduler of a query.
+#[derive(Debug)]
+pub struct InvocationScheduler {
+    settings: RwLock<SchedulerSettings>,
+    permits:  Semaphore,
+    /// The permits to forget when they're released, after a shrink.
+    excess:   AtomicUsize,
+    bucket:   Mutex<TokenBucket>,
+    counters: Counters,
+}
+
+impl InvocationScheduler {
+    /// Creates a new invocation scheduler.
+    pub fn new(settings: SchedulerSettings) -> Self {
+        Self {
+            permits:  Semaphore::new(settings.max_in_flight),
+            bucket:   Mutex::new(TokenBucket::new(
+                settings.rate,
+                settings.burst,
+                Instant::now(),
+            )),
+            excess:   AtomicUsize::new(0),
+            counters: Counters::default(),
+            settings: RwLock::new(settings),
+        }
+    }
+
+    /// Returns the settings of the scheduler.
+    pub fn settings(&self) -> SchedulerSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Applies the new settings in place. The semaphore and the token bucket
+    /// are resized rather than replaced, so the permits held by the in-flight
+    /// invocations still count towards the new limit.
+    pub fn resize(&self, settings: SchedulerSettings) {
+        let mut current = self.settings.write().unwrap();
+        if *current == settings {
+            return;
+        }
+
+        let (old, new) = (current.max_in_flight, settings.max_in_flight);
+        if new > old {
+            // Cancels the pending shrink first, then adds the rest.
+            let mut grow = new - old;
+            let cancelled = self
+                .excess
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    Some(n - n.min(grow))
+                })
+                .unwrap();
+            grow -= cancelled.min(grow);
+            self.permits.add_permits(grow);
+        } else {
+            // Forgets the idle permits now, and the held ones when released.
+            let mut shrink = old - new;
+            while shrink > 0 {
+                match self.permits.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                shrink -= 1;
+            }
+            self.excess.fetch_add(shrink, Ordering::Relaxed);
+        }
+
+        self.bucket
+            .lock()
+            .unwrap()
+            .resize(settings.rate, settings.burst, Instant::now());
+        *current = settings;
+    }
+
+    /// Returns the statistics since the last call, and resets the counters.
+    pub fn take_stats(&self) -> SchedulerStats {
+        self.counters.take()
+    }
+
+    /// Returns the backoff delay after the given number of throttles: the
+    /// exponential delay capped by `throttle_max_delay`, with equal jitter.
+    pub fn throttle_delay(&self, throttles: usize) -> Duration {
+        let settings = self.settings.read().unwrap();
+        let delay = settings
+            .throttle_base_delay
+            .saturating_mul(1_u64 << throttles.min(32))
+            .min(settings.throttle_max_delay);
+        let jitter = if delay > 1 {
+            rand::random::<u64>() % (delay / 2)
+        } else {
+            0
+        };
+        Duration::from_millis(delay - delay / 2 + jitter)
+    }
+
+    /// Invokes a lambda function.
+    pub async fn invoke(&self, request: InvocationRequest) -> Result<InvocationResponse> {
+        self.schedule(|| FLOCK_LAMBDA_CLIENT.invoke(request.clone()))
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))
+    }
+
+    /// Runs the call within the in-flight limit and the rate limit, and
+    /// retries it with backoff while it's throttled.
+    pub async fn schedule<F, Fut, T, E>(&self, mut call: F) -> std::result::Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+        E: Throttling + std::fmt::Display,
+    {
+        let start = Instant::now();
+        let _slot = Slot {
+            permit: Some(
+                self.permits
+                    .acquire()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            excess: &self.excess,
+        };
+        let in_flight = self.counters.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        let _in_flight = InFlight(&self.counters.in_flight);
+        self.counters
+            .peak_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+
+        let mut throttles = 0;
+        loop {
+            let wait = self.bucket.lock().unwrap().acquire(Instant::now());
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            if throttles == 0 {
+                self.counters
+                    .wait_time
+                    .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+            }
+
+            self.counters.invocations.fetch_add(1, Ordering::Relaxed);
+            match call().await {
+                Err(e) if e.is_throttled() => {
+                    self.counters.throttles.fetch_add(1, Ordering::Relaxed);
+                    if throttles >= self.settings.read().unwrap().throttle_retries {
+                        return Err(e);
+                    }
+                    let delay = self.throttle_delay(throttles);
+                    warn!("Invocation throttled: {}, backing off {:?}", e, delay);
+                    self.counters
+                        .throttle_delay
+                        .fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+                    self.bucket.lock().unwrap().pause(Instant::now() + delay);
+                    throttles += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns the scheduler settings of a query: the defaults in the `[scheduler]`
+/// section overridden by the payload metadata.
+///
+/// # Arguments
+/// * `metadata` - The payload metadata.
+pub fn settings_from_metadata(
+    metadata: &Option<HashMap<String, String>>,
+) -> Result<SchedulerSettings> {
+    let mut settings = FLOCK_CONFIG.scheduler.clone();
+    if let Some(metadata) = metadata {
+        let parse = |key: &str| -> Result<Option<f64>> {
+            metadata
+                .get(key)
+                .map(|v| {
+                    v.parse::<f64>().map_err(|_| {
+                        FlockError::Config(format!("`{}` has an invalid value `{}`", key, v))
+                    })
+                })
+                .transpose()
+        };
+        if let Some(v) = parse(MAX_IN_FLIGHT)? {
+            settings.max_in_flight = v as usize;
+        }
+        if let Some(v) = parse(INVOCATION_RATE)? {
+            settings.rate = v;
+        }
+        if let Some(v) = parse(INVOCATION_BURST)? {
+            settings.burst = v as usize;
+        }
+    }
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Returns the query code of the function.
+fn query_code(function_name: &str) -> &str {
+    function_name.split('-').next().unwrap_or(function_name)
+}
+
+/// Sets the scheduler settings of the query that the function belongs to.
+/// An existing scheduler is resized in place, so the in-flight limit is
+/// shared across invocations of the container even when the settings change.
+pub fn configure(function_name: &str, settings: SchedulerSettings) {
+    let mut schedulers = SCHEDULERS.lock().unwrap();
+    let code = query_code(function_name);
+    match schedulers.get(code) {
+        Some(scheduler) => scheduler.resize(settings),
+        None => {
+            schedulers.insert(
+                code.to_owned(),
+                Arc::new(InvocationScheduler::new(settings)),
+            );
+        }
+    }
+}
+
+/// Returns the scheduler of the query that the function belongs to.
+pub fn for_function(function_name: &str) -> Arc<InvocationScheduler> {
+    SCHEDULERS
+        .lock()
+        .unwrap()
+        .entry(query_code(function_name).to_owned())
+        .or_insert_with(|| Arc::new(InvocationScheduler::new(FLOCK_CONFIG.scheduler.clone())))
+        .clone()
+}
+
+/// Returns the statistics of all schedulers in the container since the last
+/// call, and resets the counters.
+pub fn take_stats() -> SchedulerStats {
+    SCHEDULERS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|s| s.take_stats())
+        .fold(SchedulerStats::default(), |acc, s| SchedulerStats {
+            invocations:    acc.invocations + s.invocations,
+            throttles:      acc.throttles + s.throttles,
+            throttle_delay: acc.throttle_delay + s.throttle_delay,
+            wait_time:      acc.wait_time + s.wait_time,
+            peak_in_flight: acc.peak_in_flight.max(s.peak_in_flight),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestError(bool);
+
+    impl Throttling for TestError {
+        fn is_throttled(&self) -> bool {
+            self.0
+        }
+    }
+
+    impl std::fmt::Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "throttled: {}", self.0)
+        }
+    }
+
+    fn settings() -> SchedulerSettings {
+        SchedulerSettings {
+            max_in_flight:       2,
+            rate:                0.0,
+            burst:               1,
+            throttle_retries:    3,
+            throttle_base_delay: 1,
+            throttle_max_delay:  4,
+        }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, 2, now);
+        assert_eq!(Duration::ZERO, bucket.acquire(now));
+        assert_eq!(Duration::ZERO, bucket.acquire(now));
+        // The bucket is empty: wait for 1 and 2 tokens at 4 tokens/s.
+        assert_eq!(Duration::from_millis(250), bucket.acquire(now));
+        assert_eq!(Duration::from_millis(500), bucket.acquire(now));
+        // A throttle pauses all callers.
+        bucket.pause(now + Duration::from_secs(1));
+        assert_eq!(
+            Duration::from_secs(1),
+            bucket.acquire(now + Duration::from_millis(500)) + Duration::from_millis(500)
+        );
+
+        let mut unlimited = TokenBucket::new(0.0, 1, now);
+        (0..100).for_each(|_| assert_eq!(Duration::ZERO, unlimited.acquire(now)));
+    }
+
+    #[tokio::test]
+    async fn throttle_backoff() {
+        let scheduler = InvocationScheduler::new(settings());
+        (0..8).for_each(|i| {
+            let delay = scheduler.throttle_delay(i);
+            assert!(delay <= Duration::from_millis(4));
+        });
+
+        // Throttled twice, then succeeds.
+        let mut attempts = 0;
+        let result = scheduler
+            .schedule(|| {
+                attempts += 1;
+                let result = if attempts <= 2 {
+                    Err(TestError(true))
+                } else {
+                    Ok(attempts)
+                };
+                async move { result }
+            })
+            .await;
+        assert_eq!(3, result.unwrap());
+
+        // Gives up after `throttle_retries` retries.
+        let result: std::result::Result<(), _> =
+            scheduler.schedule(|| async { Err(TestError(true)) }).await;
+        assert!(result.is_err());
+
+        // Other errors are not retried.
+        let result: std::result::Result<(), _> =
+            scheduler.schedule(|| async { Err(TestError(false)) }).await;
+        assert!(result.is_err());
+
+        let stats = scheduler.take_stats();
+        assert_eq!(3 + 4 + 1, stats.invocations);
+        assert_eq!(2 + 4, stats.throttles);
+        assert_eq!(1, stats.peak_in_flight);
+    }
+
+    #[tokio::test]
+    async fn bounded_in_flight() {
+        let scheduler = Arc::new(InvocationScheduler::new(settings()));
+        let tasks = (0..8)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                tokio::spawn(async move {
+                    scheduler
+                        .schedule(|| async {
+                            tokio::time::sleep(Duration::from_millis(5)).await;
+                            std::result::Result::<(), TestError>::Ok(())
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for result in futures::future::join_all(tasks).await {
+            assert!(result.unwrap().is_ok());
+        }
+
+        let stats = scheduler.take_stats();
+        assert_eq!(8, stats.invocations);
+        assert!(stats.peak_in_flight <= 2);
+    }
+
+    #[tokio::test]
+    async fn resize_in_flight() {
+        let scheduler = Arc::new(InvocationScheduler::new(settings()));
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        let hold = |scheduler: Arc<InvocationScheduler>| {
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                scheduler
+                    .schedule(|| {
+                        let mut rx = rx.clone();
+                        async move {
+                            while !*rx.borrow() {
+                                rx.changed().await.unwrap();
+                            }
+                            std::result::Result::<(), TestError>::Ok(())
+                        }
+                    })
+                    .await
+            })
+        };
+
+        // Both slots are held while the limit shrinks to 1.
+        let tasks = vec![hold(scheduler.clone()), hold(scheduler.clone())];
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        scheduler.resize(SchedulerSettings {
+            max_in_flight: 1,
+            ..settings()
+        });
+        assert_eq!(0, scheduler.permits.available_permits());
+        tx.send(true).unwrap();
+        for result in futures::future::join_all(tasks).await {
+            assert!(result.unwrap().is_ok());
+        }
+        // One of the released permits is forgotten.
+        assert_eq!(1, scheduler.permits.available_permits());
+
+        scheduler.resize(SchedulerSettings {
+            max_in_flight: 3,
+            ..settings()
+        });
+        assert_eq!(3, scheduler.permits.available_permits());
+    }
+
+    #[test]
+    fn per_query_settings() -> Result<()> {
+        let mut metadata = HashMap::new();
+        metadata.insert(MAX_IN_FLIGHT.to_owned(), "4".to_owned());
+        metadata.insert(INVOCATION_RATE.to_owned(), "50".to_owned());
+        let settings = settings_from_metadata(&Some(metadata.clone()))?;
+        assert_eq!(4, settings.max_in_flight);
+        assert_eq!(50.0, settings.rate);
+        assert_eq!(FLOCK_CONFIG.scheduler.burst, settings.burst);
+
+        metadata.insert(MAX_IN_FLIGHT.to_owned(), "0".to_owned());
+        assert!(settings_from_metadata(&Some(metadata)).is_err());
+
+        configure("q1-00-00", settings.clone());
+        assert_eq!(settings, for_function("q1-01-02").settings());
+        assert_eq!(FLOCK_CONFIG.scheduler, for_function("q2-00-00").settings());
+
+        Ok(())
+    }
+}
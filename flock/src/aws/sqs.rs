@@ -12,3 +12,112 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! This crate contains all wrapped functions of the AWS SQS service.
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use rusoto_sqs::{
+    ChangeMessageVisibilityRequest, CreateQueueRequest, DeleteMessageRequest,
+    ReceiveMessageRequest, SendMessageRequest, Sqs,
+};
+
+/// The maximum size of a message body in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// Creates a standard queue if it doesn't exist.
+///
+/// # Arguments
+/// * `queue_name` - The name of the queue.
+///
+/// # Returns
+/// The URL of the queue.
+pub async fn create_queue(queue_name: &str) -> Result<String> {
+    FLOCK_SQS_CLIENT
+        .create_queue(CreateQueueRequest {
+            queue_name: queue_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .queue_url
+        .ok_or_else(|| FlockError::AWS(format!("Queue URL of {} not found", queue_name)))
+}
+
+/// Sends a message to the queue.
+///
+/// # Arguments
+/// * `queue_url` - The URL of the queue.
+/// * `body` - The message body. The maximum size is 256 KB.
+pub async fn send_message(queue_url: &str, body: String) -> Result<()> {
+    FLOCK_SQS_CLIENT
+        .send_message(SendMessageRequest {
+            queue_url: queue_url.to_owned(),
+            message_body: body,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+        .map(|_| ())
+}
+
+/// Receives up to `max_messages` messages from the queue, up to 10. The
+/// received messages are hidden from the other receivers for the visibility
+/// timeout of the queue, unless they're deleted or released before.
+///
+/// A single receive only samples a subset of the SQS servers, so it can come
+/// back empty even if the queue isn't. It waits up to a second for the
+/// messages to reduce the empty receives; the callers that need all messages
+/// receive again until nothing comes back.
+///
+/// # Arguments
+/// * `queue_url` - The URL of the queue.
+/// * `max_messages` - The maximum number of messages to receive.
+///
+/// # Returns
+/// The receipt handles and the bodies of the messages.
+pub async fn receive_messages(queue_url: &str, max_messages: i64) -> Result<Vec<(String, String)>> {
+    Ok(FLOCK_SQS_CLIENT
+        .receive_message(ReceiveMessageRequest {
+            queue_url: queue_url.to_owned(),
+            max_number_of_messages: Some(max_messages.min(10)),
+            wait_time_seconds: Some(1),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .messages
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|m| Some((m.receipt_handle?, m.body?)))
+        .collect())
+}
+
+/// Deletes a received message from the queue.
+///
+/// # Arguments
+/// * `queue_url` - The URL of the queue.
+/// * `receipt_handle` - The receipt handle of the message.
+pub async fn delete_message(queue_url: &str, receipt_handle: String) -> Result<()> {
+    FLOCK_SQS_CLIENT
+        .delete_message(DeleteMessageRequest {
+            queue_url: queue_url.to_owned(),
+            receipt_handle,
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
+
+/// Makes a received message visible to the other receivers again.
+///
+/// # Arguments
+/// * `queue_url` - The URL of the queue.
+/// * `receipt_handle` - The receipt handle of the message.
+pub async fn release_message(queue_url: &str, receipt_handle: String) -> Result<()> {
+    FLOCK_SQS_CLIENT
+        .change_message_visibility(ChangeMessageVisibilityRequest {
+            queue_url: queue_url.to_owned(),
+            receipt_handle,
+            visibility_timeout: 0,
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
@@ -13,12 +13,11 @@
 
 //! Helper functions to create a Lambda function.
 
-use crate::configs::FLOCK_CONF;
+use crate::configs::{FLOCK_CONFIG, FLOCK_IAM_CLIENT};
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
 use crate::runtime::context::{self, ExecutionContext};
-use rusoto_core::Region;
-use rusoto_iam::{GetRoleRequest, Iam, IamClient};
+use rusoto_iam::{GetRoleRequest, Iam};
 use rusoto_lambda::{Environment, FunctionCode};
 use std::collections::hash_map::HashMap;
 
@@ -170,14 +169,10 @@ pub struct AwsLambdaConfig {
 impl AwsLambdaConfig {
     /// Creates a new AWS Lambda function.
     pub async fn try_new() -> Result<AwsLambdaConfig> {
-        let runtime = Some(FLOCK_CONF["aws"]["runtime"].to_string());
+        let runtime = Some(FLOCK_CONFIG.aws.runtime.clone());
         let handler = Some("handler".to_owned());
-        let memory_size = Some(
-            FLOCK_CONF["lambda"]["regular_memory_size"]
-                .parse::<i64>()
-                .unwrap(),
-        );
-        let timeout = Some(FLOCK_CONF["lambda"]["timeout"].parse::<i64>().unwrap());
+        let memory_size = Some(FLOCK_CONFIG.lambda.regular_memory_size);
+        let timeout = Some(FLOCK_CONFIG.lambda.timeout);
         let role = AwsLambdaConfig::default_role().await?;
         let vpc_config = None;
         let environment = None;
@@ -185,9 +180,9 @@ impl AwsLambdaConfig {
         // Flock uploaded the pre-compiled deployment package to Amazon S3 in advance.
         let code = FunctionCode {
             // S3 bucket for the pre-compiled deployment package.
-            s3_bucket:         Some(FLOCK_CONF["s3"]["bucket"].to_string()),
+            s3_bucket:         Some(FLOCK_CONFIG.s3.bucket.clone()),
             // S3 key for the pre-compiled deployment package.
-            s3_key:            Some(FLOCK_CONF["s3"]["x86_64_key"].to_string()),
+            s3_key:            Some(FLOCK_CONFIG.s3.x86_64_key.clone()),
             s3_object_version: None,
             zip_file:          None,
             image_uri:         None,
@@ -215,7 +210,7 @@ impl AwsLambdaConfig {
     pub fn set_code(&mut self, key: &str) -> &mut Self {
         self.code = FunctionCode {
             // S3 bucket for the pre-compiled deployment package.
-            s3_bucket:         Some(FLOCK_CONF["s3"]["bucket"].to_string()),
+            s3_bucket:         Some(FLOCK_CONFIG.s3.bucket.clone()),
             // S3 key for the pre-compiled deployment package.
             s3_key:            Some(key.to_string()),
             s3_object_version: None,
@@ -276,7 +271,7 @@ impl AwsLambdaConfig {
         // Set the environment variables.
         let mut map = HashMap::new();
         map.insert(
-            (&FLOCK_CONF["lambda"]["environment"]).to_owned(),
+            FLOCK_CONFIG.lambda.environment.clone(),
             context::marshal(ctx, Encoding::default()).unwrap(),
         );
         map.insert("RUST_LOG".to_owned(), "info".to_owned());
@@ -300,10 +295,9 @@ impl AwsLambdaConfig {
 
     /// Creates a new AWS Lambda function with a default role.
     async fn default_role() -> Result<String> {
-        let iam = IamClient::new(Region::default());
-        let resp = iam
+        let resp = FLOCK_IAM_CLIENT
             .get_role(GetRoleRequest {
-                role_name: FLOCK_CONF["aws"]["role"].to_string(),
+                role_name: FLOCK_CONFIG.aws.role.clone(),
             })
             .await
             .map_err(|e| FlockError::AWS(e.to_string()))?;
@@ -12,19 +12,1063 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! Configuration settings that affect all crates in current system.
+//!
+//! The default settings are embedded in the binary (`flock.toml`). At runtime,
+//! they are layered in the following order, where the later layers win:
+//!
+//! 1. the embedded defaults;
+//! 2. the profile sections of the embedded defaults, selected by the
+//!    `FLOCK_PROFILE` environment variable, i.e., `[<profile>.<section>]`;
+//! 3. the config file pointed by the `FLOCK_CONFIG` environment variable, which
+//!    may have its own profile sections;
+//! 4. the environment variables `FLOCK_<SECTION>_<KEY>`, e.g.,
+//!    `FLOCK_AWS_SUBNET_ID` or `FLOCK_LAMBDA_CONCURRENCY`.
+//!
+//! The effective settings are validated and exposed as the typed
+//! [`FlockConfig`].
 
+use crate::error::{FlockError, Result};
 use ini::Ini;
 use lazy_static::lazy_static;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The environment variable to select the configuration profile.
+pub const FLOCK_PROFILE_ENV: &str = "FLOCK_PROFILE";
+/// The environment variable to specify the configuration file.
+pub const FLOCK_CONFIG_ENV: &str = "FLOCK_CONFIG";
+/// The prefix of the environment variables that override the settings.
+pub const FLOCK_ENV_PREFIX: &str = "FLOCK_";
+
+/// The embedded default settings.
+const FLOCK_DEFAULT_CONF: &str = include_str!("./flock.toml");
 
 lazy_static! {
-    /// Global settings.
-    pub static ref FLOCK_CONF: Ini = Ini::load_from_str(include_str!("./flock.toml")).unwrap();
+    /// Global settings (the effective settings after all overrides).
+    pub static ref FLOCK_CONF: Ini = FlockConfig::load_ini_from_env().unwrap_or_else(|e| invalid_env_settings(e));
+    /// Global typed settings.
+    pub static ref FLOCK_CONFIG: FlockConfig = FlockConfig::from_ini(&FLOCK_CONF).unwrap_or_else(|e| invalid_env_settings(e));
+}
+
+/// Panics with the configuration error and the source of the invalid setting
+/// selected by the `FLOCK_PROFILE` and `FLOCK_CONFIG` environment variables.
+fn invalid_env_settings(e: FlockError) -> ! {
+    let profile = std::env::var(FLOCK_PROFILE_ENV).ok();
+    let path = std::env::var(FLOCK_CONFIG_ENV).ok();
+    panic!(
+        "{}",
+        FlockConfig::describe_error(&e, profile.as_deref(), path.as_deref(), std::env::vars())
+    )
+}
+
+/// General settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlockSettings {
+    /// Whether Flock runs in the production mode.
+    pub production:  bool,
+    /// The data source function name.
+    pub data_source: String,
+}
+
+/// S3 settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct S3Settings {
+    /// The bucket to store the generic functions.
+    pub bucket:     String,
+    /// The key prefix of the x86_64 function code.
+    pub x86_64_key: String,
+    /// The key prefix of the arm64 function code.
+    pub arm_64_key: String,
+}
+
+/// AWS settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AwsSettings {
+    /// The runtime of the cloud functions.
+    pub runtime:             String,
+    /// The name of the function's execution role.
+    pub role:                String,
+    /// The availability zone.
+    pub availability_zone:   String,
+    /// The subnet id.
+    pub subnet_id:           String,
+    /// The security group id.
+    pub security_group_id:   String,
+    /// The region of the AWS services. If empty, the default region is used.
+    pub region:              String,
+    /// The endpoint of all AWS services. If empty, the official endpoints
+    /// are used.
+    pub endpoint:            String,
+    /// The credential provider: `default`, `static` or `profile`.
+    pub credentials:         String,
+    /// The access key id of the `static` credential provider.
+    pub access_key_id:       String,
+    /// The secret access key of the `static` credential provider.
+    pub secret_access_key:   String,
+    /// The profile name of the `profile` credential provider.
+    pub credentials_profile: String,
+}
+
+/// Per-service endpoint settings. An empty endpoint falls back to
+/// `aws.endpoint`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EndpointSettings {
+    /// The endpoint of AWS S3.
+    pub s3:       String,
+    /// The endpoint of AWS Lambda.
+    pub lambda:   String,
+    /// The endpoint of AWS SQS.
+    pub sqs:      String,
+    /// The endpoint of AWS EFS.
+    pub efs:      String,
+    /// The endpoint of AWS CloudWatch Logs.
+    pub logs:     String,
+    /// The endpoint of AWS Kinesis.
+    pub kinesis:  String,
+    /// The endpoint of AWS IAM.
+    pub iam:      String,
+    /// The endpoint of AWS DynamoDB.
+    pub dynamodb: String,
+}
+
+/// Lambda settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LambdaSettings {
+    /// The environment context name (key) in the function's environment.
+    pub environment:                   String,
+    /// The default target batch size.
+    pub target_batch_size:             usize,
+    /// The default raw record batch size in the payload.
+    pub payload_batch_size:            usize,
+    /// The size of the function group.
+    pub concurrency:                   usize,
+    /// The payload size threshold of the aggregate stage.
+    pub aggregate_threshold:           usize,
+    /// The payload size threshold of the join stage.
+    pub join_threshold:                usize,
+    /// The payload size threshold of the other stages.
+    pub regular_threshold:             usize,
+    /// The number of rows per async invocation.
+    pub async_granule:                 usize,
+    /// The number of rows per sync invocation.
+    pub sync_granule:                  usize,
+    /// The maximum number of invocation retries.
+    pub max_invoke_retries:            usize,
+    /// The function timeout in seconds.
+    pub timeout:                       i64,
+    /// The memory size (MB) of the regular functions.
+    pub regular_memory_size:           i64,
+    /// The memory size (MB) of the offline aggregate functions.
+    pub offline_aggreate_memory_size:  i64,
+    /// The memory size (MB) of the realtime aggregate functions.
+    pub realtime_aggreate_memory_size: i64,
+}
+
+/// EFS settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EfsSettings {
+    /// The token to ensure idempotent creation.
+    pub creation_token: String,
+    /// The root directory of the access point.
+    pub root_directory: String,
+    /// The local mount directory.
+    pub mount_path:     String,
+    /// The POSIX group id of the access point.
+    pub group_id:       i64,
+    /// The POSIX user id of the access point.
+    pub user_id:        i64,
+    /// The permissions of the root directory.
+    pub permissions:    String,
+}
+
+/// NEXMark benchmark settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NexmarkSettings {
+    /// The S3 key of the side input of Q13.
+    pub q13_s3_side_input_key: String,
+    /// The S3 key of the physical plan of Q4.
+    pub q4_s3_key:             String,
+    /// The S3 key of the physical plan of Q6.
+    pub q6_s3_key:             String,
+    /// The S3 key prefix of the benchmark.
+    pub s3_key:                String,
+}
+
+/// YSB benchmark settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct YsbSettings {
+    /// The S3 key prefix of the benchmark.
+    pub s3_key: String,
+}
+
+/// DataFusion settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataFusionSettings {
+    /// The number of target partitions.
+    pub target_partitions: usize,
+}
+
+/// Runtime metrics settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSettings {
+    /// The output format: `emf`, `json` or `off`.
+    pub format:    String,
+    /// The CloudWatch namespace of the metrics.
+    pub namespace: String,
+}
+
+/// Tracing settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceSettings {
+    /// Whether to export the spans of each function invocation.
+    pub enabled: bool,
+}
+
+/// Dead-letter settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetterSettings {
+    /// The destination of the dead letters: `s3`, `sqs`, `file` or `off`.
+    pub destination: String,
+    /// The SQS queue name.
+    pub queue:       String,
+    /// The key prefix in the Flock S3 bucket.
+    pub prefix:      String,
+    /// The local JSON lines file.
+    pub path:        String,
+}
+
+/// Invocation scheduler settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerSettings {
+    /// The maximum number of in-flight invocations.
+    pub max_in_flight:       usize,
+    /// The maximum number of invocations per second, 0 means unlimited.
+    pub rate:                f64,
+    /// The number of invocations that can be sent in a burst.
+    pub burst:               usize,
+    /// The maximum number of retries of a throttled invocation.
+    pub throttle_retries:    usize,
+    /// The initial backoff delay of a throttled invocation, in milliseconds.
+    pub throttle_base_delay: u64,
+    /// The maximum backoff delay of a throttled invocation, in milliseconds.
+    pub throttle_max_delay:  u64,
+}
+
+impl SchedulerSettings {
+    /// Validates the scheduler settings.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            Err(FlockError::Config(format!(
+                "`scheduler.{}` {}",
+                key, reason
+            )))
+        };
+
+        if self.max_in_flight == 0 {
+            return invalid("max_in_flight", "must be greater than 0");
+        }
+        if !self.rate.is_finite() || self.rate < 0.0 {
+            return invalid("rate", "must be a non-negative number");
+        }
+        if self.burst == 0 {
+            return invalid("burst", "must be greater than 0");
+        }
+        if self.throttle_base_delay > self.throttle_max_delay {
+            return invalid(
+                "throttle_base_delay",
+                "must not be greater than `throttle_max_delay`",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Quarantine settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantineSettings {
+    /// The policy for the rest of the window: `skip` or `fail`.
+    pub policy:      String,
+    /// The destination of the malformed data: `s3`, `file` or `off`.
+    pub destination: String,
+    /// The key prefix in the Flock S3 bucket.
+    pub prefix:      String,
+    /// The local JSON lines file.
+    pub path:        String,
+}
+
+/// Elastic function group settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupSettings {
+    /// Scales the function groups with the observed load.
+    pub autoscale:        bool,
+    /// The minimum number of member functions in a group.
+    pub min_size:         usize,
+    /// The maximum number of member functions in a group.
+    pub max_size:         usize,
+    /// The target load of each member function, in payloads per second.
+    pub target_load:      f64,
+    /// The interval between two membership refreshes, in seconds.
+    pub refresh_interval: i64,
+    /// The delay before a membership change takes effect, in seconds.
+    pub grace_period:     i64,
+    /// The time a retired member keeps draining its windows, in seconds.
+    pub drain_timeout:    i64,
+    /// The minimum time between two scaling decisions, in seconds.
+    pub cooldown:         i64,
+}
+
+impl GroupSettings {
+    /// Validates the function group settings.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            Err(FlockError::Config(format!("`group.{}` {}", key, reason)))
+        };
+
+        if self.min_size == 0 {
+            return invalid("min_size", "must be greater than 0");
+        }
+        if self.max_size < self.min_size || self.max_size > 100 {
+            return invalid("max_size", "must be between `min_size` and 100");
+        }
+        if !self.target_load.is_finite() || self.target_load <= 0.0 {
+            return invalid("target_load", "must be a positive number");
+        }
+        if self.refresh_interval <= 0 {
+            return invalid("refresh_interval", "must be greater than 0");
+        }
+        if self.grace_period <= self.refresh_interval {
+            // Every upstream function must see the new membership before it takes
+            // effect, otherwise the windows are routed differently.
+            return invalid("grace_period", "must be greater than `refresh_interval`");
+        }
+        if self.drain_timeout <= 0 {
+            return invalid("drain_timeout", "must be greater than 0");
+        }
+        if self.cooldown < 0 {
+            return invalid("cooldown", "must be a non-negative number");
+        }
+        Ok(())
+    }
+}
+
+/// Skew mitigation settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkewSettings {
+    /// Inserts the combine stages and splits the heavy keys in hash shuffles.
+    pub enabled:          bool,
+    /// One in `sample_interval` rows is sampled to estimate key frequencies.
+    pub sample_interval:  usize,
+    /// The fraction of a partition's rows above which a key is heavy.
+    pub heavy_fraction:   f64,
+    /// The minimum estimated number of rows of a heavy key.
+    pub min_rows:         usize,
+    /// The number of salted sub-partitions of a partition with heavy keys.
+    pub salts:            usize,
+    /// The interval between two refreshes of the heavy keys, in seconds.
+    pub refresh_interval: i64,
+    /// The delay before a change of the heavy keys takes effect, in seconds.
+    pub grace_period:     i64,
+    /// The minimum time between two changes of the heavy keys, in seconds.
+    pub cooldown:         i64,
+}
+
+impl SkewSettings {
+    /// Validates the skew mitigation settings.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            Err(FlockError::Config(format!("`skew.{}` {}", key, reason)))
+        };
+
+        if self.sample_interval == 0 {
+            return invalid("sample_interval", "must be greater than 0");
+        }
+        if !(self.heavy_fraction > 0.0 && self.heavy_fraction <= 1.0) {
+            return invalid("heavy_fraction", "must be in (0, 1]");
+        }
+        if self.salts < 2 {
+            return invalid("salts", "must be greater than 1");
+        }
+        if self.refresh_interval <= 0 {
+            return invalid("refresh_interval", "must be greater than 0");
+        }
+        if self.grace_period <= self.refresh_interval {
+            return invalid("grace_period", "must be greater than `refresh_interval`");
+        }
+        if self.cooldown < 0 {
+            return invalid("cooldown", "must be a non-negative number");
+        }
+        Ok(())
+    }
+}
+
+/// Arena settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArenaSettings {
+    /// The time-to-live of an incomplete window, in seconds. 0 means no
+    /// time-to-live.
+    pub window_ttl:          u64,
+    /// The maximum memory size of the windows in a function, in MB. 0 means no
+    /// limit.
+    pub max_memory:          usize,
+    /// The policy for an evicted window: `emit`, `fetch` or `drop`.
+    pub timeout_policy:      String,
+    /// The memory size of the windows beyond which their fragments are spilled
+    /// to disk, in MB. 0 disables spilling.
+    pub spill_threshold:     usize,
+    /// The directory of the spilled fragments, e.g., `/tmp` or the EFS mount.
+    pub spill_dir:           String,
+    /// The interval between the checkpoints of an incomplete window, in
+    /// seconds. 0 disables checkpointing.
+    pub checkpoint_interval: u64,
+}
+
+impl ArenaSettings {
+    /// Validates the arena settings.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            Err(FlockError::Config(format!("`arena.{}` {}", key, reason)))
+        };
+
+        if !["emit", "fetch", "drop"].contains(&self.timeout_policy.as_str()) {
+            return invalid("timeout_policy", "must be `emit`, `fetch` or `drop`");
+        }
+        if self.max_memory > 0 && self.spill_threshold >= self.max_memory {
+            return invalid("spill_threshold", "must be less than `arena.max_memory`");
+        }
+        if self.spill_dir.is_empty() {
+            return invalid("spill_dir", "must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Deadline settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadlineSettings {
+    /// The time reserved before the function timeout to hand over the rest of
+    /// the work, in seconds.
+    pub margin:            u64,
+    /// The maximum number of times a computation is handed over to a new
+    /// invocation.
+    pub max_continuations: usize,
+}
+
+/// Query registry settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegistrySettings {
+    /// The state backend of the query registry: `hashmap`, `s3` or `efs`.
+    pub state_backend: String,
+}
+
+/// Data sink settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SinkSettings {
+    /// The maximum number of retries of a failed write to a data sink.
+    pub max_retries: usize,
+    /// The initial delay before retrying a failed write, in milliseconds.
+    pub retry_delay: u64,
+}
+
+/// The typed Flock configuration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlockConfig {
+    /// General settings.
+    pub flock:      FlockSettings,
+    /// S3 settings.
+    pub s3:         S3Settings,
+    /// AWS settings.
+    pub aws:        AwsSettings,
+    /// Per-service endpoint settings.
+    pub endpoints:  EndpointSettings,
+    /// Lambda settings.
+    pub lambda:     LambdaSettings,
+    /// EFS settings.
+    pub efs:        EfsSettings,
+    /// NEXMark benchmark settings.
+    pub nexmark:    NexmarkSettings,
+    /// YSB benchmark settings.
+    pub ysb:        YsbSettings,
+    /// DataFusion settings.
+    pub datafusion: DataFusionSettings,
+    /// Runtime metrics settings.
+    pub metrics:    MetricsSettings,
+    /// Tracing settings.
+    pub trace:      TraceSettings,
+    /// Dead-letter settings.
+    pub deadletter: DeadLetterSettings,
+    /// Invocation scheduler settings.
+    pub scheduler:  SchedulerSettings,
+    /// Quarantine settings.
+    pub quarantine: QuarantineSettings,
+    /// Elastic function group settings.
+    pub group:      GroupSettings,
+    /// Skew mitigation settings.
+    pub skew:       SkewSettings,
+    /// Arena settings.
+    pub arena:      ArenaSettings,
+    /// Deadline settings.
+    pub deadline:   DeadlineSettings,
+    /// Query registry settings.
+    pub registry:   RegistrySettings,
+    /// Data sink settings.
+    pub sink:       SinkSettings,
+}
+
+impl Default for FlockConfig {
+    fn default() -> Self {
+        FlockConfig::from_ini(&Ini::load_from_str(FLOCK_DEFAULT_CONF).unwrap()).unwrap()
+    }
+}
+
+impl fmt::Display for FlockConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut conf = self.to_ini();
+        if !self.aws.secret_access_key.is_empty() {
+            conf.set_to(
+                Some("aws"),
+                "secret_access_key".to_string(),
+                "********".to_string(),
+            );
+        }
+        let mut buf = Vec::new();
+        conf.write_to(&mut buf).map_err(|_| fmt::Error)?;
+        write!(f, "{}", String::from_utf8_lossy(&buf))
+    }
+}
+
+/// Reads a typed value from the settings.
+fn get<T: FromStr>(conf: &Ini, section: &str, key: &str) -> Result<T> {
+    let value = conf
+        .section(Some(section))
+        .and_then(|s| s.get(key))
+        .ok_or_else(|| FlockError::Config(format!("`{}.{}` is missing", section, key)))?;
+    value.parse::<T>().map_err(|_| {
+        FlockError::Config(format!(
+            "`{}.{}` = {:?} is not a valid {}",
+            section,
+            key,
+            value,
+            std::any::type_name::<T>()
+        ))
+    })
+}
+
+impl FlockConfig {
+    /// Creates the typed settings from the INI settings and validates them.
+    pub fn from_ini(conf: &Ini) -> Result<Self> {
+        let config = FlockConfig {
+            flock:      FlockSettings {
+                production:  get(conf, "flock", "production")?,
+                data_source: get(conf, "flock", "data_source")?,
+            },
+            s3:         S3Settings {
+                bucket:     get(conf, "s3", "bucket")?,
+                x86_64_key: get(conf, "s3", "x86_64_key")?,
+                arm_64_key: get(conf, "s3", "arm_64_key")?,
+            },
+            aws:        AwsSettings {
+                runtime:             get(conf, "aws", "runtime")?,
+                role:                get(conf, "aws", "role")?,
+                availability_zone:   get(conf, "aws", "availability_zone")?,
+                subnet_id:           get(conf, "aws", "subnet_id")?,
+                security_group_id:   get(conf, "aws", "security_group_id")?,
+                region:              get(conf, "aws", "region")?,
+                endpoint:            get(conf, "aws", "endpoint")?,
+                credentials:         get(conf, "aws", "credentials")?,
+                access_key_id:       get(conf, "aws", "access_key_id")?,
+                secret_access_key:   get(conf, "aws", "secret_access_key")?,
+                credentials_profile: get(conf, "aws", "credentials_profile")?,
+            },
+            endpoints:  EndpointSettings {
+                s3:       get(conf, "endpoints", "s3")?,
+                lambda:   get(conf, "endpoints", "lambda")?,
+                sqs:      get(conf, "endpoints", "sqs")?,
+                efs:      get(conf, "endpoints", "efs")?,
+                logs:     get(conf, "endpoints", "logs")?,
+                kinesis:  get(conf, "endpoints", "kinesis")?,
+                iam:      get(conf, "endpoints", "iam")?,
+                dynamodb: get(conf, "endpoints", "dynamodb")?,
+            },
+            lambda:     LambdaSettings {
+                environment:                   get(conf, "lambda", "environment")?,
+                target_batch_size:             get(conf, "lambda", "target_batch_size")?,
+                payload_batch_size:            get(conf, "lambda", "payload_batch_size")?,
+                concurrency:                   get(conf, "lambda", "concurrency")?,
+                aggregate_threshold:           get(conf, "lambda", "aggregate_threshold")?,
+                join_threshold:                get(conf, "lambda", "join_threshold")?,
+                regular_threshold:             get(conf, "lambda", "regular_threshold")?,
+                async_granule:                 get(conf, "lambda", "async_granule")?,
+                sync_granule:                  get(conf, "lambda", "sync_granule")?,
+                max_invoke_retries:            get(conf, "lambda", "max_invoke_retries")?,
+                timeout:                       get(conf, "lambda", "timeout")?,
+                regular_memory_size:           get(conf, "lambda", "regular_memory_size")?,
+                offline_aggreate_memory_size:  get(conf, "lambda", "offline_aggreate_memory_size")?,
+                realtime_aggreate_memory_size: get(
+                    conf,
+                    "lambda",
+                    "realtime_aggreate_memory_size",
+                )?,
+            },
+            efs:        EfsSettings {
+                creation_token: get(conf, "efs", "creation_token")?,
+                root_directory: get(conf, "efs", "root_directory")?,
+                mount_path:     get(conf, "efs", "mount_path")?,
+                group_id:       get(conf, "efs", "group_id")?,
+                user_id:        get(conf, "efs", "user_id")?,
+                permissions:    get(conf, "efs", "permissions")?,
+            },
+            nexmark:    NexmarkSettings {
+                q13_s3_side_input_key: get(conf, "nexmark", "q13_s3_side_input_key")?,
+                q4_s3_key:             get(conf, "nexmark", "q4_s3_key")?,
+                q6_s3_key:             get(conf, "nexmark", "q6_s3_key")?,
+                s3_key:                get(conf, "nexmark", "s3_key")?,
+            },
+            ysb:        YsbSettings {
+                s3_key: get(conf, "ysb", "s3_key")?,
+            },
+            datafusion: DataFusionSettings {
+                target_partitions: get(conf, "datafusion", "target_partitions")?,
+            },
+            metrics:    MetricsSettings {
+                format:    get(conf, "metrics", "format")?,
+                namespace: get(conf, "metrics", "namespace")?,
+            },
+            trace:      TraceSettings {
+                enabled: get(conf, "trace", "enabled")?,
+            },
+            deadletter: DeadLetterSettings {
+                destination: get(conf, "deadletter", "destination")?,
+                queue:       get(conf, "deadletter", "queue")?,
+                prefix:      get(conf, "deadletter", "prefix")?,
+                path:        get(conf, "deadletter", "path")?,
+            },
+            scheduler:  SchedulerSettings {
+                max_in_flight:       get(conf, "scheduler", "max_in_flight")?,
+                rate:                get(conf, "scheduler", "rate")?,
+                burst:               get(conf, "scheduler", "burst")?,
+                throttle_retries:    get(conf, "scheduler", "throttle_retries")?,
+                throttle_base_delay: get(conf, "scheduler", "throttle_base_delay")?,
+                throttle_max_delay:  get(conf, "scheduler", "throttle_max_delay")?,
+            },
+            quarantine: QuarantineSettings {
+                policy:      get(conf, "quarantine", "policy")?,
+                destination: get(conf, "quarantine", "destination")?,
+                prefix:      get(conf, "quarantine", "prefix")?,
+                path:        get(conf, "quarantine", "path")?,
+            },
+            group:      GroupSettings {
+                autoscale:        get(conf, "group", "autoscale")?,
+                min_size:         get(conf, "group", "min_size")?,
+                max_size:         get(conf, "group", "max_size")?,
+                target_load:      get(conf, "group", "target_load")?,
+                refresh_interval: get(conf, "group", "refresh_interval")?,
+                grace_period:     get(conf, "group", "grace_period")?,
+                drain_timeout:    get(conf, "group", "drain_timeout")?,
+                cooldown:         get(conf, "group", "cooldown")?,
+            },
+            skew:       SkewSettings {
+                enabled:          get(conf, "skew", "enabled")?,
+                sample_interval:  get(conf, "skew", "sample_interval")?,
+                heavy_fraction:   get(conf, "skew", "heavy_fraction")?,
+                min_rows:         get(conf, "skew", "min_rows")?,
+                salts:            get(conf, "skew", "salts")?,
+                refresh_interval: get(conf, "skew", "refresh_interval")?,
+                grace_period:     get(conf, "skew", "grace_period")?,
+                cooldown:         get(conf, "skew", "cooldown")?,
+            },
+            arena:      ArenaSettings {
+                window_ttl:          get(conf, "arena", "window_ttl")?,
+                max_memory:          get(conf, "arena", "max_memory")?,
+                timeout_policy:      get(conf, "arena", "timeout_policy")?,
+                spill_threshold:     get(conf, "arena", "spill_threshold")?,
+                spill_dir:           get(conf, "arena", "spill_dir")?,
+                checkpoint_interval: get(conf, "arena", "checkpoint_interval")?,
+            },
+            deadline:   DeadlineSettings {
+                margin:            get(conf, "deadline", "margin")?,
+                max_continuations: get(conf, "deadline", "max_continuations")?,
+            },
+            registry:   RegistrySettings {
+                state_backend: get(conf, "registry", "state_backend")?,
+            },
+            sink:       SinkSettings {
+                max_retries: get(conf, "sink", "max_retries")?,
+                retry_delay: get(conf, "sink", "retry_delay")?,
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Loads the settings with the given profile and config file, and applies
+    /// the environment variable overrides.
+    ///
+    /// # Arguments
+    /// * `profile` - The profile name, e.g., `dev`, `prod` or `local`.
+    /// * `path` - The path of the config file.
+    pub fn load(profile: Option<&str>, path: Option<&str>) -> Result<Self> {
+        FlockConfig::from_ini(&FlockConfig::load_ini(profile, path, std::env::vars())?)
+    }
+
+    /// Loads the settings from the `FLOCK_PROFILE` and `FLOCK_CONFIG`
+    /// environment variables.
+    pub fn from_env() -> Result<Self> {
+        FlockConfig::from_ini(&FlockConfig::load_ini_from_env()?)
+    }
+
+    /// Returns the effective INI settings selected by the `FLOCK_PROFILE` and
+    /// `FLOCK_CONFIG` environment variables.
+    pub fn load_ini_from_env() -> Result<Ini> {
+        let profile = std::env::var(FLOCK_PROFILE_ENV).ok();
+        let path = std::env::var(FLOCK_CONFIG_ENV).ok();
+        FlockConfig::load_ini(profile.as_deref(), path.as_deref(), std::env::vars())
+    }
+
+    /// Returns the effective INI settings.
+    ///
+    /// # Arguments
+    /// * `profile` - The profile name.
+    /// * `path` - The path of the config file.
+    /// * `vars` - The environment variables.
+    pub fn load_ini<I>(profile: Option<&str>, path: Option<&str>, vars: I) -> Result<Ini>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let defaults = Ini::load_from_str(FLOCK_DEFAULT_CONF)
+            .map_err(|e| FlockError::Config(format!("invalid default settings: {}", e)))?;
+        let file = match path {
+            Some(path) => Some(Ini::load_from_file(path).map_err(|e| {
+                FlockError::Config(format!("failed to load the config file {}: {}", path, e))
+            })?),
+            None => None,
+        };
+
+        // Base settings: the sections without the profile prefix.
+        let mut conf = Ini::new();
+        for (section, props) in defaults.iter() {
+            if let Some(section) = section.filter(|s| !s.contains('.')) {
+                for (key, value) in props.iter() {
+                    conf.set_to(Some(section), key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        if let Some(profile) = profile {
+            let profiles = FlockConfig::profiles(&defaults, file.as_ref());
+            if !profiles.iter().any(|p| p == profile) {
+                return Err(FlockError::Config(format!(
+                    "unknown profile `{}`, available profiles: {:?}",
+                    profile, profiles
+                )));
+            }
+            merge(&mut conf, &defaults, Some(profile))?;
+        }
+
+        if let Some(file) = file.as_ref() {
+            merge(&mut conf, file, None)?;
+            if let Some(profile) = profile {
+                merge(&mut conf, file, Some(profile))?;
+            }
+        }
+
+        for (var, value) in vars {
+            if let Some((section, key)) = env_var_to_key(&conf, &var) {
+                conf.set_to(Some(section.as_str()), key, value);
+            }
+        }
+
+        Ok(conf)
+    }
+
+    /// Validates the settings.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, reason: &str| -> Result<()> {
+            Err(FlockError::Config(format!("`{}` {}", key, reason)))
+        };
+
+        if self.s3.bucket.is_empty() {
+            return invalid("s3.bucket", "must not be empty");
+        }
+        if self.flock.data_source.is_empty() {
+            return invalid("flock.data_source", "must not be empty");
+        }
+        if self.lambda.environment.is_empty() {
+            return invalid("lambda.environment", "must not be empty");
+        }
+        // The local endpoints, e.g., LocalStack, don't require the VPC resources.
+        if self.aws.endpoint.is_empty() {
+            if !self.aws.subnet_id.starts_with("subnet-") {
+                return invalid("aws.subnet_id", "must start with `subnet-`");
+            }
+            if !self.aws.security_group_id.starts_with("sg-") {
+                return invalid("aws.security_group_id", "must start with `sg-`");
+            }
+        }
+        if !self.aws.region.is_empty() && Region::from_str(&self.aws.region).is_err() {
+            return invalid("aws.region", "must be an AWS region, e.g., `us-east-1`");
+        }
+        match self.aws.credentials.as_str() {
+            "default" | "profile" => {}
+            "static" => {
+                if self.aws.access_key_id.is_empty() || self.aws.secret_access_key.is_empty() {
+                    return invalid(
+                        "aws.credentials",
+                        "is `static`, but `aws.access_key_id` or `aws.secret_access_key` is empty",
+                    );
+                }
+            }
+            _ => {
+                return invalid(
+                    "aws.credentials",
+                    "must be `default`, `static` or `profile`",
+                )
+            }
+        }
+        for (key, endpoint) in [
+            ("aws.endpoint", &self.aws.endpoint),
+            ("endpoints.s3", &self.endpoints.s3),
+            ("endpoints.lambda", &self.endpoints.lambda),
+            ("endpoints.sqs", &self.endpoints.sqs),
+            ("endpoints.efs", &self.endpoints.efs),
+            ("endpoints.logs", &self.endpoints.logs),
+            ("endpoints.kinesis", &self.endpoints.kinesis),
+            ("endpoints.iam", &self.endpoints.iam),
+            ("endpoints.dynamodb", &self.endpoints.dynamodb),
+        ] {
+            if !endpoint.is_empty()
+                && !endpoint.starts_with("http://")
+                && !endpoint.starts_with("https://")
+            {
+                return invalid(key, "must start with `http://` or `https://`");
+            }
+        }
+        if !["emf", "json", "off"].contains(&self.metrics.format.as_str()) {
+            return invalid("metrics.format", "must be `emf`, `json` or `off`");
+        }
+        if !["s3", "sqs", "file", "off"].contains(&self.deadletter.destination.as_str()) {
+            return invalid(
+                "deadletter.destination",
+                "must be `s3`, `sqs`, `file` or `off`",
+            );
+        }
+        self.scheduler.validate()?;
+        self.group.validate()?;
+        self.skew.validate()?;
+        self.arena.validate()?;
+        if !["skip", "fail"].contains(&self.quarantine.policy.as_str()) {
+            return invalid("quarantine.policy", "must be `skip` or `fail`");
+        }
+        if !["s3", "file", "off"].contains(&self.quarantine.destination.as_str()) {
+            return invalid("quarantine.destination", "must be `s3`, `file` or `off`");
+        }
+        if !(1..=900).contains(&self.lambda.timeout) {
+            return invalid("lambda.timeout", "must be between 1 and 900 seconds");
+        }
+        if self.deadline.margin as i64 >= self.lambda.timeout {
+            return invalid("deadline.margin", "must be less than `lambda.timeout`");
+        }
+        if !["hashmap", "s3", "efs"].contains(&self.registry.state_backend.as_str()) {
+            return invalid("registry.state_backend", "must be `hashmap`, `s3` or `efs`");
+        }
+        if self.sink.retry_delay == 0 {
+            return invalid("sink.retry_delay", "must be positive");
+        }
+        for (key, size) in [
+            (
+                "lambda.regular_memory_size",
+                self.lambda.regular_memory_size,
+            ),
+            (
+                "lambda.offline_aggreate_memory_size",
+                self.lambda.offline_aggreate_memory_size,
+            ),
+            (
+                "lambda.realtime_aggreate_memory_size",
+                self.lambda.realtime_aggreate_memory_size,
+            ),
+        ] {
+            if !(128..=10240).contains(&size) {
+                return invalid(key, "must be between 128 and 10240 MB");
+            }
+        }
+        for (key, value) in [
+            ("lambda.concurrency", self.lambda.concurrency),
+            ("lambda.target_batch_size", self.lambda.target_batch_size),
+            ("lambda.payload_batch_size", self.lambda.payload_batch_size),
+            ("lambda.async_granule", self.lambda.async_granule),
+            ("lambda.sync_granule", self.lambda.sync_granule),
+            (
+                "datafusion.target_partitions",
+                self.datafusion.target_partitions,
+            ),
+        ] {
+            if value == 0 {
+                return invalid(key, "must be greater than 0");
+            }
+        }
+        if u32::from_str_radix(&self.efs.permissions, 8).is_err() {
+            return invalid("efs.permissions", "must be an octal number, e.g., 0777");
+        }
+        Ok(())
+    }
+
+    /// Returns where the effective value of the setting comes from: an
+    /// environment variable, the config file or one of its profiles, a profile
+    /// of the embedded defaults, or the embedded defaults.
+    ///
+    /// # Arguments
+    /// * `key` - The setting, i.e., `<section>.<key>`.
+    /// * `profile` - The profile name.
+    /// * `path` - The path of the config file.
+    /// * `vars` - The environment variables.
+    pub fn source_of<I>(key: &str, profile: Option<&str>, path: Option<&str>, vars: I) -> String
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let defaults = Ini::load_from_str(FLOCK_DEFAULT_CONF).unwrap_or_else(|_| Ini::new());
+        let (section, name) = match key.split_once('.') {
+            Some(setting) => setting,
+            None => return "the embedded defaults".to_string(),
+        };
+        let has = |conf: &Ini, section: &str| conf.get_from(Some(section), name).is_some();
+
+        // The environment variables are applied last, see `load_ini`.
+        let setting = Some((section.to_string(), name.to_string()));
+        if let Some(var) = vars
+            .into_iter()
+            .map(|(var, _)| var)
+            .filter(|var| env_var_to_key(&defaults, var) == setting)
+            .last()
+        {
+            return format!("the environment variable {}", var);
+        }
+        if let Some((path, file)) =
+            path.and_then(|path| Ini::load_from_file(path).ok().map(|file| (path, file)))
+        {
+            if let Some(profile) = profile {
+                if has(&file, &format!("{}.{}", profile, section)) {
+                    return format!("the profile `{}` of the config file {}", profile, path);
+                }
+            }
+            if has(&file, section) {
+                return format!("the config file {}", path);
+            }
+        }
+        if let Some(profile) = profile {
+            if has(&defaults, &format!("{}.{}", profile, section)) {
+                return format!("the profile `{}` of the embedded defaults", profile);
+            }
+        }
+        "the embedded defaults".to_string()
+    }
+
+    /// Describes the configuration error together with the source of the
+    /// setting it's about. See [`FlockConfig::source_of`].
+    pub fn describe_error<I>(
+        e: &FlockError,
+        profile: Option<&str>,
+        path: Option<&str>,
+        vars: I,
+    ) -> String
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        // The configuration errors start with the quoted setting, e.g.,
+        // "`aws.subnet_id` must start with `subnet-`".
+        let key = match e {
+            FlockError::Config(msg) => msg
+                .strip_prefix('`')
+                .and_then(|msg| msg.split_once('`'))
+                .map(|(key, _)| key),
+            _ => None,
+        };
+        match key {
+            Some(key) => format!(
+                "{}, set by {}",
+                e,
+                FlockConfig::source_of(key, profile, path, vars)
+            ),
+            None => e.to_string(),
+        }
+    }
+
+    /// Converts the typed settings to the INI settings.
+    pub fn to_ini(&self) -> Ini {
+        let mut conf = Ini::new();
+        if let serde_json::Value::Object(sections) = serde_json::to_value(self).unwrap() {
+            for (section, props) in sections {
+                if let serde_json::Value::Object(props) = props {
+                    for (key, value) in props {
+                        let value = match value {
+                            serde_json::Value::String(s) => s,
+                            v => v.to_string(),
+                        };
+                        conf.set_to(Some(section.as_str()), key, value);
+                    }
+                }
+            }
+        }
+        conf
+    }
+
+    /// Returns the profile names in the settings.
+    fn profiles(defaults: &Ini, file: Option<&Ini>) -> Vec<String> {
+        let mut profiles = vec![];
+        for conf in std::iter::once(defaults).chain(file) {
+            for section in conf.sections().flatten() {
+                if let Some((profile, _)) = section.split_once('.') {
+                    if !profiles.iter().any(|p| p == profile) {
+                        profiles.push(profile.to_string());
+                    }
+                }
+            }
+        }
+        profiles
+    }
+}
+
+/// Merges the sections of `other` into `conf`. If `profile` is given, only
+/// the `[<profile>.<section>]` sections are merged.
+fn merge(conf: &mut Ini, other: &Ini, profile: Option<&str>) -> Result<()> {
+    for (section, props) in other.iter() {
+        let section = match (section, profile) {
+            (Some(s), Some(p)) => match s.strip_prefix(p).and_then(|s| s.strip_prefix('.')) {
+                Some(s) => s,
+                None => continue,
+            },
+            (Some(s), None) if !s.contains('.') => s,
+            _ => continue,
+        };
+        for (key, value) in props.iter() {
+            if conf.get_from(Some(section), key).is_none() {
+                return Err(FlockError::Config(format!(
+                    "unknown setting `{}.{}`",
+                    section, key
+                )));
+            }
+            conf.set_to(Some(section), key.to_string(), value.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Maps the environment variable `FLOCK_<SECTION>_<KEY>` to the setting.
+fn env_var_to_key(conf: &Ini, var: &str) -> Option<(String, String)> {
+    let name = var.strip_prefix(FLOCK_ENV_PREFIX)?.to_lowercase();
+    conf.sections().flatten().find_map(|section| {
+        let key = name.strip_prefix(section)?.strip_prefix('_')?;
+        conf.get_from(Some(section), key)
+            .map(|_| (section.to_string(), key.to_string()))
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::error::Result;
+    use std::io::Write;
 
     #[tokio::test]
     async fn setting_shows() -> Result<()> {
@@ -56,4 +1100,129 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn typed_settings() -> Result<()> {
+        let vars = vec![
+            ("FLOCK_LAMBDA_CONCURRENCY".to_string(), "32".to_string()),
+            ("FLOCK_AWS_SUBNET_ID".to_string(), "subnet-0123".to_string()),
+            ("FLOCK_UNKNOWN_KEY".to_string(), "ignored".to_string()),
+        ];
+        let config = FlockConfig::from_ini(&FlockConfig::load_ini(None, None, vars)?)?;
+        assert_eq!(32, config.lambda.concurrency);
+        assert_eq!("subnet-0123", config.aws.subnet_id);
+        assert_eq!(120, config.lambda.timeout);
+
+        // The effective settings can be printed and loaded again.
+        let conf = Ini::load_from_str(&config.to_string()).unwrap();
+        assert_eq!(config, FlockConfig::from_ini(&conf)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn profile_settings() -> Result<()> {
+        let config = FlockConfig::from_ini(&FlockConfig::load_ini(Some("local"), None, vec![])?)?;
+        assert_eq!(1, config.lambda.concurrency);
+
+        let config = FlockConfig::from_ini(&FlockConfig::load_ini(Some("prod"), None, vec![])?)?;
+        assert!(config.flock.production);
+
+        assert!(FlockConfig::load_ini(Some("staging"), None, vec![]).is_err());
+
+        // The config file overrides the embedded profiles.
+        let path = std::env::temp_dir().join("flock_profile_settings.toml");
+        let mut file = std::fs::File::create(&path)?;
+        writeln!(
+            file,
+            "[s3]\nbucket = \"my-bucket\"\n\n[local.lambda]\nconcurrency = 4"
+        )?;
+        let ini = FlockConfig::load_ini(Some("local"), path.to_str(), vec![])?;
+        let config = FlockConfig::from_ini(&ini)?;
+        assert_eq!("my-bucket", config.s3.bucket);
+        assert_eq!(4, config.lambda.concurrency);
+
+        // Unknown settings in the config file are rejected.
+        let mut file = std::fs::File::create(&path)?;
+        writeln!(file, "[lambda]\nconcurency = 4")?;
+        assert!(FlockConfig::load_ini(None, path.to_str(), vec![]).is_err());
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_settings() -> Result<()> {
+        let settings = |vars: &[(&str, &str)]| {
+            let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            FlockConfig::from_ini(&FlockConfig::load_ini(None, None, vars)?)
+        };
+
+        match settings(&[("FLOCK_LAMBDA_TIMEOUT", "1000")]) {
+            Err(FlockError::Config(msg)) => assert!(msg.contains("lambda.timeout")),
+            _ => panic!("expected a configuration error"),
+        }
+        assert!(settings(&[("FLOCK_LAMBDA_CONCURRENCY", "many")]).is_err());
+        match settings(&[("FLOCK_AWS_REGION", "mars-east-1")]) {
+            Err(FlockError::Config(msg)) => assert!(msg.starts_with("`aws.region`")),
+            _ => panic!("expected a configuration error"),
+        }
+        assert!(settings(&[("FLOCK_AWS_SUBNET_ID", "sg-1234")]).is_err());
+        assert_eq!(
+            8,
+            settings(&[("FLOCK_LAMBDA_CONCURRENCY", "8")])?
+                .lambda
+                .concurrency
+        );
+
+        // The VPC resources aren't checked with a local endpoint.
+        assert!(settings(&[
+            ("FLOCK_AWS_ENDPOINT", "http://localhost:4566"),
+            ("FLOCK_AWS_SUBNET_ID", ""),
+            ("FLOCK_AWS_SECURITY_GROUP_ID", ""),
+        ])
+        .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn setting_sources() -> Result<()> {
+        let path = std::env::temp_dir().join("flock_setting_sources.toml");
+        let mut file = std::fs::File::create(&path)?;
+        writeln!(
+            file,
+            "[aws]\nsubnet_id = \"vpc-0123\"\n\n[local.lambda]\nconcurrency = 4"
+        )?;
+        let path = path.to_str();
+        let vars = || vec![("FLOCK_LAMBDA_TIMEOUT".to_string(), "1000".to_string())];
+
+        let source = |key| FlockConfig::source_of(key, Some("local"), path, vars());
+        assert_eq!(
+            "the environment variable FLOCK_LAMBDA_TIMEOUT",
+            source("lambda.timeout")
+        );
+        assert!(source("aws.subnet_id").starts_with("the config file "));
+        assert!(source("lambda.concurrency").starts_with("the profile `local` of the config file "));
+        assert_eq!(
+            "the profile `local` of the embedded defaults",
+            source("aws.endpoint")
+        );
+        assert_eq!("the embedded defaults", source("s3.bucket"));
+
+        // The error names the source of the invalid setting.
+        let ini = FlockConfig::load_ini(Some("local"), path, vars())?;
+        let e = FlockConfig::from_ini(&ini).unwrap_err();
+        assert_eq!(
+            "Configuration error: `lambda.timeout` must be between 1 and 900 seconds, set by the \
+             environment variable FLOCK_LAMBDA_TIMEOUT",
+            FlockConfig::describe_error(&e, Some("local"), path, vars())
+        );
+        let e = FlockConfig::from_ini(&FlockConfig::load_ini(None, path, vec![])?).unwrap_err();
+        assert!(FlockConfig::describe_error(&e, None, path, vec![])
+            .contains("`aws.subnet_id` must start with `subnet-`, set by the config file "));
+        std::fs::remove_file(path.unwrap())?;
+
+        Ok(())
+    }
 }
@@ -17,13 +17,16 @@ pub mod aws_lambda;
 pub use aws_lambda::AwsLambdaConfig;
 
 mod flock;
-pub use self::flock::FLOCK_CONF;
+pub use self::flock::*;
+use crate::aws::client;
 use datafusion::arrow::datatypes::Schema;
 use datafusion::physical_plan::empty::EmptyExec;
 use datafusion::physical_plan::ExecutionPlan;
 use lazy_static::lazy_static;
-use rusoto_core::Region;
+use rusoto_dynamodb::DynamoDbClient;
 use rusoto_efs::EfsClient;
+use rusoto_iam::IamClient;
+use rusoto_kinesis::KinesisClient;
 use rusoto_lambda::LambdaClient;
 use rusoto_logs::CloudWatchLogsClient;
 use rusoto_s3::S3Client;
@@ -36,60 +39,67 @@ lazy_static! {
     /// AWS Lambda function sync invocation.
     pub static ref FLOCK_LAMBDA_SYNC_CALL: String = "RequestResponse".to_string();
     /// AWS Lambda function maximum error retry.
-    pub static ref FLOCK_LAMBDA_MAX_RETRIES: usize = FLOCK_CONF["lambda"]["max_invoke_retries"].parse::<usize>().unwrap();
+    pub static ref FLOCK_LAMBDA_MAX_RETRIES: usize = FLOCK_CONFIG.lambda.max_invoke_retries;
     /// AWS Lambda function timeout.
-    pub static ref FLOCK_LAMBDA_TIMEOUT: i64 = FLOCK_CONF["lambda"]["timeout"].parse::<i64>().unwrap();
+    pub static ref FLOCK_LAMBDA_TIMEOUT: i64 = FLOCK_CONFIG.lambda.timeout;
     /// AWS Lambda function concurrency.
-    pub static ref FLOCK_FUNCTION_CONCURRENCY: usize = FLOCK_CONF["lambda"]["concurrency"].parse::<usize>().unwrap();
+    pub static ref FLOCK_FUNCTION_CONCURRENCY: usize = FLOCK_CONFIG.lambda.concurrency;
 
     /// Flock sync invocation granularity.
-    pub static ref FLOCK_SYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["sync_granule"].parse::<usize>().unwrap();
+    pub static ref FLOCK_SYNC_GRANULE_SIZE: usize = FLOCK_CONFIG.lambda.sync_granule;
     /// Flock async invocation granularity.
-    pub static ref FLOCK_ASYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["async_granule"].parse::<usize>().unwrap();
+    pub static ref FLOCK_ASYNC_GRANULE_SIZE: usize = FLOCK_CONFIG.lambda.async_granule;
 
     /// Flock x86_64 binary S3 key prefix.
-    pub static ref FLOCK_S3_X86_64_KEY: String = FLOCK_CONF["s3"]["x86_64_key"].to_string();
+    pub static ref FLOCK_S3_X86_64_KEY: String = FLOCK_CONFIG.s3.x86_64_key.clone();
     /// Flock Arm_64 binary S3 key prefix.
-    pub static ref FLOCK_S3_ARM_64_KEY: String = FLOCK_CONF["s3"]["arm_64_key"].to_string();
+    pub static ref FLOCK_S3_ARM_64_KEY: String = FLOCK_CONFIG.s3.arm_64_key.clone();
     /// Flock S3 bucket name.
-    pub static ref FLOCK_S3_BUCKET: String = FLOCK_CONF["s3"]["bucket"].to_string();
+    pub static ref FLOCK_S3_BUCKET: String = FLOCK_CONFIG.s3.bucket.clone();
     /// Flock availablity zone.
-    pub static ref FLOCK_AVAILABILITY_ZONE: String = FLOCK_CONF["aws"]["availability_zone"].to_string();
+    pub static ref FLOCK_AVAILABILITY_ZONE: String = FLOCK_CONFIG.aws.availability_zone.clone();
     /// Flock subnet id.
-    pub static ref FLOCK_SUBNET_ID: String = FLOCK_CONF["aws"]["subnet_id"].to_string();
+    pub static ref FLOCK_SUBNET_ID: String = FLOCK_CONFIG.aws.subnet_id.clone();
     /// Flock security group id.
-    pub static ref FLOCK_SECURITY_GROUP_ID: String = FLOCK_CONF["aws"]["security_group_id"].to_string();
+    pub static ref FLOCK_SECURITY_GROUP_ID: String = FLOCK_CONFIG.aws.security_group_id.clone();
 
     /// Flock EFS creation token.
-    pub static ref FLOCK_EFS_CREATION_TOKEN: String = FLOCK_CONF["efs"]["creation_token"].to_string();
+    pub static ref FLOCK_EFS_CREATION_TOKEN: String = FLOCK_CONFIG.efs.creation_token.clone();
     /// Flock EFS Posix user ID.
-    pub static ref FLOCK_EFS_POSIX_UID: i64 = FLOCK_CONF["efs"]["user_id"].parse::<i64>().unwrap();
+    pub static ref FLOCK_EFS_POSIX_UID: i64 = FLOCK_CONFIG.efs.user_id;
     /// Flock EFS Posix group ID.
-    pub static ref FLOCK_EFS_POSIX_GID: i64 = FLOCK_CONF["efs"]["group_id"].parse::<i64>().unwrap();
+    pub static ref FLOCK_EFS_POSIX_GID: i64 = FLOCK_CONFIG.efs.group_id;
     /// Flock EFS access point permissions.
-    pub static ref FLOCK_EFS_PERMISSIONS: String = FLOCK_CONF["efs"]["permissions"].to_string();
+    pub static ref FLOCK_EFS_PERMISSIONS: String = FLOCK_CONFIG.efs.permissions.clone();
     /// Flock EFS root directory.
-    pub static ref FLOCK_EFS_ROOT_DIR: String = FLOCK_CONF["efs"]["root_directory"].to_string();
+    pub static ref FLOCK_EFS_ROOT_DIR: String = FLOCK_CONFIG.efs.root_directory.clone();
     /// Flocl EFS local mount point.
-    pub static ref FLOCK_EFS_MOUNT_PATH: String = FLOCK_CONF["efs"]["mount_path"].to_string();
+    pub static ref FLOCK_EFS_MOUNT_PATH: String = FLOCK_CONFIG.efs.mount_path.clone();
 
-    /// Flock associated services.
+    /// Flock associated services. The endpoints, regions and credentials are
+    /// configured in the `[aws]` and `[endpoints]` sections.
     /// Flock S3 Client.
-    pub static ref FLOCK_S3_CLIENT: S3Client = S3Client::new(Region::default());
+    pub static ref FLOCK_S3_CLIENT: S3Client = client::s3_client(&FLOCK_CONFIG).unwrap();
     /// Flock LAMBDA Client.
-    pub static ref FLOCK_LAMBDA_CLIENT: LambdaClient = LambdaClient::new(Region::default());
+    pub static ref FLOCK_LAMBDA_CLIENT: LambdaClient = client::lambda_client(&FLOCK_CONFIG).unwrap();
     /// Flock EFS Client.
-    pub static ref FLOCK_EFS_CLIENT: EfsClient = EfsClient::new(Region::default());
+    pub static ref FLOCK_EFS_CLIENT: EfsClient = client::efs_client(&FLOCK_CONFIG).unwrap();
     /// Flock SQS Client.
-    pub static ref FLOCK_SQS_CLIENT: SqsClient = SqsClient::new(Region::default());
+    pub static ref FLOCK_SQS_CLIENT: SqsClient = client::sqs_client(&FLOCK_CONFIG).unwrap();
     /// Flock CloudWatch Logs Client.
-    pub static ref FLOCK_WATCHLOGS_CLIENT: CloudWatchLogsClient = CloudWatchLogsClient::new(Region::default());
+    pub static ref FLOCK_WATCHLOGS_CLIENT: CloudWatchLogsClient = client::logs_client(&FLOCK_CONFIG).unwrap();
+    /// Flock Kinesis Client.
+    pub static ref FLOCK_KINESIS_CLIENT: KinesisClient = client::kinesis_client(&FLOCK_CONFIG).unwrap();
+    /// Flock IAM Client.
+    pub static ref FLOCK_IAM_CLIENT: IamClient = client::iam_client(&FLOCK_CONFIG).unwrap();
+    /// Flock DynamoDB Client.
+    pub static ref FLOCK_DYNAMODB_CLIENT: DynamoDbClient = client::dynamodb_client(&FLOCK_CONFIG).unwrap();
 
     /// Flock Empty query plan
     pub static ref FLOCK_EMPTY_PLAN: Arc<dyn ExecutionPlan> = Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())));
     /// Flock data source function name
-    pub static ref FLOCK_DATA_SOURCE_FUNC_NAME: String = FLOCK_CONF["flock"]["data_source"].to_string();
+    pub static ref FLOCK_DATA_SOURCE_FUNC_NAME: String = FLOCK_CONFIG.flock.data_source.clone();
 
     /// Flock target partitions.
-    pub static ref FLOCK_TARGET_PARTITIONS: usize = FLOCK_CONF["datafusion"]["target_partitions"].parse::<usize>().unwrap();
+    pub static ref FLOCK_TARGET_PARTITIONS: usize = FLOCK_CONFIG.datafusion.target_partitions;
 }
@@ -14,18 +14,20 @@
 //! This module provides different data sinks for the Flock runtime to write
 //! data to.
 
+mod multi;
+pub use multi::SinkSpec;
+
 use crate::aws::s3;
 use crate::configs::*;
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
 use crate::runtime::payload::DataFrame;
+use crate::trace::TraceContext;
 use crate::transmute::*;
 use datafusion::arrow::csv;
-use datafusion::arrow::datatypes::Schema;
+use datafusion::arrow::json;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
-use datafusion::arrow_flight::utils::flight_data_to_arrow_batch;
-use datafusion::arrow_flight::FlightData;
 use datafusion::execution::context::ExecutionContext;
 use datafusion::parquet::arrow::ArrowWriter;
 use datafusion::prelude::CsvReadOptions;
@@ -38,12 +40,11 @@ use serde_json::json;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
 use tokio::task::{self, JoinHandle};
 use uuid::Uuid;
 
 /// Flock data format for data sink.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum DataSinkFormat {
     /// CSV format.
     CSV,
@@ -75,6 +76,8 @@ pub enum DataSinkType {
     SQS,
     /// Write to AWS EFS.
     EFS,
+    /// Write to several data sinks at once.
+    Multiple(Vec<SinkSpec>),
 }
 
 impl Default for DataSinkType {
@@ -85,7 +88,19 @@ impl Default for DataSinkType {
 
 impl DataSinkType {
     /// Convert the user input to the corresponding data sink type.
+    ///
+    /// A comma-separated list, e.g., `s3,sqs`, writes to several data sinks
+    /// at once with the default format.
     pub fn new(data_sink: &str) -> Result<DataSinkType> {
+        if data_sink.contains(',') {
+            return Ok(DataSinkType::Multiple(
+                data_sink
+                    .split(',')
+                    .map(|s| DataSinkType::new(s.trim()))
+                    .map(|t| t.map(|t| SinkSpec::new(t, DataSinkFormat::default())))
+                    .collect::<Result<Vec<_>>>()?,
+            ));
+        }
         match data_sink {
             "blackhole" => Ok(DataSinkType::Blackhole),
             "s3" => Ok(DataSinkType::S3),
@@ -115,6 +130,17 @@ pub struct DataSink {
     /// The last actor in the dag that wrote to the data sink.
     /// Client can use this to fetch the logs for AWS WatchLogs.
     pub function_name:  String,
+    /// The trace context of the last actor, which is used to correlate the
+    /// data sink with the spans of the query.
+    pub trace:          Option<TraceContext>,
+    /// True if the record batches are the output of an incomplete window that
+    /// timed out in the arena.
+    #[serde(default)]
+    pub incomplete:     bool,
+    /// True if the record batches are written to a side output, i.e., they're
+    /// late or rejected by the other data sinks.
+    #[serde(default)]
+    pub side_output:    bool,
 }
 
 impl DataSink {
@@ -144,11 +170,12 @@ impl DataSink {
                 self.write_to_sqs().await?;
             }
             DataSinkType::S3 => {
-                self.write_to_s3().await?;
+                self.write_to_s3(sink_format).await?;
             }
             DataSinkType::EFS => {
                 self.write_to_efs(sink_format).await?;
             }
+            DataSinkType::Multiple(sinks) => return self.write_all(&sinks).await,
             _ => unimplemented!(),
         }
         Ok(json!({"name": self.function_name.clone(), "sink_type": sink_type, "status": "success"}))
@@ -175,27 +202,12 @@ impl DataSink {
     /// This is an internal function that is used to decode `self.encoded_data`
     /// to `self.record_batches` for future use.
     fn decode_record_batches(&mut self) -> Result<()> {
-        let record_batch = |df: Vec<DataFrame>, schema: Arc<Schema>| -> Vec<RecordBatch> {
-            df.into_par_iter()
-                .map(|d| {
-                    flight_data_to_arrow_batch(
-                        &FlightData {
-                            data_body:         d.body,
-                            data_header:       d.header,
-                            app_metadata:      vec![],
-                            flight_descriptor: None,
-                        },
-                        schema.clone(),
-                        &[],
-                    )
-                    .unwrap()
-                })
-                .collect()
-        };
-
         if self.record_batches.is_empty() {
-            let dataframe = unmarshal(self.encoded_data.clone(), self.encoding.clone());
-            self.record_batches = record_batch(dataframe, schema_from_bytes(&self.schema)?);
+            self.record_batches = dataframes_to_batches(
+                &self.encoded_data,
+                &self.encoding,
+                schema_from_bytes(&self.schema)?,
+            )?;
         }
 
         Ok(())
@@ -269,15 +281,70 @@ impl DataSink {
         Ok(())
     }
 
-    async fn write_to_s3(&mut self) -> Result<()> {
-        self.encode_record_batches();
-
-        let s3_key = self.function_name.split('-').next().unwrap();
-        s3::put_object(&FLOCK_S3_BUCKET, s3_key, serde_json::to_vec(&self)?).await?;
+    async fn write_to_s3(&mut self, sink_format: DataSinkFormat) -> Result<()> {
+        let query_code = self.function_name.split('-').next().unwrap();
+        let side = if self.side_output { "/side" } else { "" };
+
+        let (s3_key, body) = match sink_format {
+            DataSinkFormat::SerdeBinary => {
+                self.encode_record_batches();
+                (
+                    format!("{}{}", query_code, side),
+                    serde_json::to_vec(&self)?,
+                )
+            }
+            // The files are appended under the query code, e.g., for archiving.
+            format => {
+                let (body, extension) = self.encode_as(format)?;
+                (
+                    format!("{}{}/{}.{}", query_code, side, Uuid::new_v4(), extension),
+                    body,
+                )
+            }
+        };
+        s3::put_object(&FLOCK_S3_BUCKET, &s3_key, body).await?;
 
         Ok(())
     }
 
+    /// Encodes the record batches into a file of the given format.
+    ///
+    /// # Returns
+    /// The file content and its extension.
+    fn encode_as(&self, sink_format: DataSinkFormat) -> Result<(Vec<u8>, &'static str)> {
+        let mut bytes = vec![];
+        match sink_format {
+            DataSinkFormat::CSV => {
+                let mut writer = csv::Writer::new(&mut bytes);
+                for batch in self.record_batches.iter() {
+                    writer.write(batch)?;
+                }
+                drop(writer);
+                Ok((bytes, "csv"))
+            }
+            DataSinkFormat::JSON => {
+                let mut writer = json::LineDelimitedWriter::new(&mut bytes);
+                writer.write_batches(&self.record_batches)?;
+                writer.finish()?;
+                drop(writer);
+                Ok((bytes, "json"))
+            }
+            DataSinkFormat::Parquet => {
+                let path = std::env::temp_dir().join(format!("{}.parquet", Uuid::new_v4()));
+                let file = std::fs::File::create(&path)?;
+                let mut writer = ArrowWriter::try_new(file, self.record_batches[0].schema(), None)?;
+                for batch in self.record_batches.iter() {
+                    writer.write(batch)?;
+                }
+                writer.close()?;
+                let bytes = std::fs::read(&path)?;
+                std::fs::remove_file(&path)?;
+                Ok((bytes, "parquet"))
+            }
+            DataSinkFormat::SerdeBinary => unreachable!("encoded by `encode_record_batches`"),
+        }
+    }
+
     async fn write_to_efs(&mut self, sink_format: DataSinkFormat) -> Result<()> {
         let fs_path = Path::new(&*FLOCK_EFS_MOUNT_PATH).join(self.function_name.clone());
         let mut tasks = vec![];
@@ -0,0 +1,279 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! A query can write its output to several data sinks at once, e.g., Parquet
+//! files on S3 for archiving and SQS messages for alerts. Each [`SinkSpec`]
+//! has its own format and an optional SQL filter over the output columns.
+//!
+//! The sinks flagged as side outputs don't receive the regular output, but the
+//! late records, i.e., the output of the incomplete windows that timed out in
+//! the arena, and the records rejected by the other sinks. A failed write is
+//! retried per sink (see the `[sink]` section), so the sinks that succeeded
+//! aren't written twice, and a sink's records are rejected once its retries
+//! are exhausted. The write only fails if records are rejected and the query
+//! has no side output.
+
+use super::{DataSink, DataSinkFormat, DataSinkType};
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::plan::physical_plan;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::MemTable;
+use datafusion::execution::context::ExecutionContext;
+use datafusion::physical_plan::collect;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The table name of the output in the filters of the sinks.
+const OUTPUT_TABLE: &str = "output";
+
+/// A data sink of the query's output.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SinkSpec {
+    /// The data sink type.
+    pub sink_type:   DataSinkType,
+    /// The format of the records in the data sink.
+    pub format:      DataSinkFormat,
+    /// The SQL predicate over the output columns. Only the matching records
+    /// are written to the data sink.
+    pub filter:      Option<String>,
+    /// True if the data sink receives the late and the rejected records
+    /// instead of the regular output.
+    pub side_output: bool,
+}
+
+impl SinkSpec {
+    /// Creates a data sink of the regular output.
+    pub fn new(sink_type: DataSinkType, format: DataSinkFormat) -> Self {
+        SinkSpec {
+            sink_type,
+            format,
+            filter: None,
+            side_output: false,
+        }
+    }
+
+    /// Only writes the records that match the SQL predicate, e.g.,
+    /// `price > 1000`.
+    pub fn with_filter(mut self, filter: &str) -> Self {
+        self.filter = Some(filter.to_owned());
+        self
+    }
+
+    /// Makes the data sink a side output.
+    pub fn as_side_output(mut self) -> Self {
+        self.side_output = true;
+        self
+    }
+
+    /// Returns the records that match the filter of the data sink.
+    pub async fn filter(&self, batches: &[RecordBatch]) -> Result<Vec<RecordBatch>> {
+        let predicate = match &self.filter {
+            Some(predicate) if !batches.is_empty() => predicate,
+            _ => return Ok(batches.to_vec()),
+        };
+        let mut ctx = ExecutionContext::new();
+        let table = MemTable::try_new(batches[0].schema(), vec![batches.to_vec()])?;
+        ctx.register_table(OUTPUT_TABLE, Arc::new(table))?;
+        let sql = format!("SELECT * FROM {} WHERE {}", OUTPUT_TABLE, predicate);
+        let plan = physical_plan(&ctx, sql).await?;
+        Ok(collect(plan)
+            .await?
+            .into_iter()
+            .filter(|b| b.num_rows() > 0)
+            .collect())
+    }
+}
+
+impl DataSink {
+    /// Writes the record batches to several data sinks. The late and the
+    /// rejected records are written to the side outputs.
+    ///
+    /// # Returns
+    /// The status of each data sink.
+    pub async fn write_all(&self, sinks: &[SinkSpec]) -> Result<Value> {
+        let (side, main): (Vec<_>, Vec<_>) = sinks.iter().partition(|s| s.side_output);
+
+        let mut statuses = vec![];
+        let mut rejected = vec![];
+        if self.incomplete && !side.is_empty() {
+            rejected = self.record_batches.clone();
+        } else {
+            let writes = main
+                .iter()
+                .map(|spec| self.write_with_retries(spec, &self.record_batches, false));
+            for (spec, (batches, result)) in
+                main.iter().zip(futures::future::join_all(writes).await)
+            {
+                let status = match result {
+                    Ok(()) => "success",
+                    Err(e) => {
+                        warn!("Failed to write to {:?}: {}", spec.sink_type, e);
+                        rejected.extend(batches);
+                        "rejected"
+                    }
+                };
+                statuses.push(json!({"sink_type": spec.sink_type, "status": status}));
+            }
+        }
+
+        if !rejected.is_empty() {
+            if side.is_empty() {
+                return Err(FlockError::DataSink(format!(
+                    "{} records are rejected, and the query has no side output.",
+                    rejected.iter().map(|b| b.num_rows()).sum::<usize>()
+                )));
+            }
+            for spec in side {
+                let (_, result) = self.write_with_retries(spec, &rejected, true).await;
+                result?;
+                statuses.push(json!({"sink_type": spec.sink_type, "status": "side output"}));
+            }
+        }
+
+        Ok(json!({"name": self.function_name.clone(), "sinks": statuses}))
+    }
+
+    /// Writes the records that match the filter of the data sink, and retries
+    /// with exponential backoff if the write fails.
+    ///
+    /// # Returns
+    /// The records to write and the result of the last attempt.
+    async fn write_with_retries(
+        &self,
+        spec: &SinkSpec,
+        batches: &[RecordBatch],
+        side_output: bool,
+    ) -> (Vec<RecordBatch>, Result<()>) {
+        let batches = match spec.filter(batches).await {
+            Ok(batches) => batches,
+            Err(e) => return (batches.to_vec(), Err(e)),
+        };
+        if batches.is_empty() {
+            return (batches, Ok(()));
+        }
+
+        let mut sink = DataSink {
+            record_batches: batches,
+            encoding: self.encoding.clone(),
+            function_name: self.function_name.clone(),
+            trace: self.trace.clone(),
+            incomplete: self.incomplete,
+            side_output,
+            ..Default::default()
+        };
+        let settings = &FLOCK_CONFIG.sink;
+        let mut retries = 0;
+        loop {
+            match sink
+                .write(spec.sink_type.clone(), spec.format.clone())
+                .await
+            {
+                Ok(_) => return (sink.record_batches, Ok(())),
+                Err(e) if retries >= settings.max_retries => return (sink.record_batches, Err(e)),
+                Err(e) => {
+                    warn!("Retrying the write to {:?}: {}", spec.sink_type, e);
+                    tokio::time::sleep(Duration::from_millis(
+                        settings.retry_delay * 2_u64.pow(retries as u32),
+                    ))
+                    .await;
+                    retries += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    fn bids() -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int64, false),
+            Field::new("price", DataType::Int64, false),
+            Field::new("channel", DataType::Utf8, false),
+        ]));
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(Int64Array::from(vec![10, 2000, 3000])),
+                Arc::new(StringArray::from(vec!["a", "b", "c"])),
+            ],
+        )?)
+    }
+
+    #[test]
+    fn parse_multiple_sinks() -> Result<()> {
+        assert_eq!(
+            DataSinkType::Multiple(vec![
+                SinkSpec::new(DataSinkType::S3, DataSinkFormat::SerdeBinary),
+                SinkSpec::new(DataSinkType::SQS, DataSinkFormat::SerdeBinary),
+            ]),
+            DataSinkType::new("s3, sqs")?
+        );
+        assert!(DataSinkType::new("s3,kafka").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filter_per_sink() -> Result<()> {
+        let spec =
+            SinkSpec::new(DataSinkType::SQS, DataSinkFormat::JSON).with_filter("price > 1000");
+        let batches = spec.filter(&[bids()?]).await?;
+        assert_eq!(2, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        let spec = SinkSpec::new(DataSinkType::S3, DataSinkFormat::Parquet);
+        let batches = spec.filter(&[bids()?]).await?;
+        assert_eq!(3, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_to_side_output() -> Result<()> {
+        // The EFS directory of the function doesn't exist, so the writes fail.
+        let failing = SinkSpec::new(DataSinkType::EFS, DataSinkFormat::CSV);
+        let archive = SinkSpec::new(DataSinkType::Blackhole, DataSinkFormat::Parquet);
+        let side = SinkSpec::new(DataSinkType::Blackhole, DataSinkFormat::JSON).as_side_output();
+
+        let mut sink = DataSink::new(
+            "missing/SX72HzqFz1Qij4bP-00".to_owned(),
+            vec![bids()?],
+            Encoding::default(),
+        );
+        let result = sink
+            .write_all(&[failing.clone(), archive.clone(), side])
+            .await?;
+        assert_eq!("rejected", result["sinks"][0]["status"]);
+        assert_eq!("success", result["sinks"][1]["status"]);
+        assert_eq!("side output", result["sinks"][2]["status"]);
+
+        // Without a side output, the rejected records fail the write.
+        assert!(sink.write_all(&[failing, archive.clone()]).await.is_err());
+
+        // The late records only go to the side outputs.
+        sink.incomplete = true;
+        let result = sink.write_all(&[archive]).await?;
+        assert_eq!("success", result["sinks"][0]["status"]);
+
+        Ok(())
+    }
+}
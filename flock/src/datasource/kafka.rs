@@ -19,16 +19,12 @@
 
 use aws_lambda_events::event::kafka::KafkaEvent;
 
-use datafusion::arrow::json::{self, reader::infer_json_schema};
 use datafusion::arrow::record_batch::RecordBatch;
 
 use crate::prelude::*;
-use datafusion::arrow::datatypes::Schema;
-use rayon::prelude::*;
+use crate::quarantine::MalformedRecord;
 use rusoto_lambda::CreateEventSourceMappingRequest;
 use serde::{Deserialize, Serialize};
-use std::io::BufReader;
-use std::sync::Arc;
 
 /// A struct to manage all KafKa info in cloud environment.
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -88,53 +84,34 @@ pub async fn create_event_source_mapping_request(
 }
 
 /// Converts KafKa event to record batch in Arrow.
-pub fn to_batch(event: KafkaEvent) -> Vec<RecordBatch> {
-    let mut input = vec![];
-    let mut schema = Schema::empty();
-
-    // get all data from KafKa event
-    for (i, records) in event.records.values().enumerate() {
-        if i == 0 {
-            assert!(!records.is_empty());
-            // infer schema based on the first record
-            let record = base64::decode(records[0].value.as_ref().unwrap()).unwrap();
-            schema = infer_json_schema(&mut BufReader::new(&record[..]), Some(1)).unwrap();
-        }
-
-        input.append(
-            &mut records
-                .into_par_iter()
-                .flat_map(|r| {
-                    base64::decode(r.value.as_ref().unwrap())
-                        .unwrap()
-                        .into_iter()
-                        .chain(vec![10].into_iter())
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        );
-    }
-
-    // transform data to record batch in Arrow
-    let batch_size = 1024;
-    let mut reader = json::Reader::new(
-        BufReader::with_capacity(input.len(), &input[..]),
-        Arc::new(schema),
-        batch_size,
-        None,
-    );
-
-    let mut batches = vec![];
-    while let Some(batch) = reader.next().unwrap() {
-        batches.push(batch);
-    }
-    batches
+///
+/// The records that can't be decoded are returned separately with the reason,
+/// so that they can be quarantined without failing the whole batch.
+pub fn to_batch(event: KafkaEvent) -> Result<(Vec<RecordBatch>, Vec<MalformedRecord>)> {
+    json_records_to_batch(
+        event
+            .records
+            .into_values()
+            .flatten()
+            .map(|r| match r.value {
+                Some(value) => base64::decode(&value).map_err(|e| MalformedRecord {
+                    data:   value.into_bytes(),
+                    reason: format!("Invalid base64 value: {}", e),
+                }),
+                None => Err(MalformedRecord {
+                    data:   vec![],
+                    reason: "The record has no value".to_owned(),
+                }),
+            })
+            .collect(),
+    )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use datafusion::arrow::util::pretty;
+    use rayon::prelude::*;
 
     #[test]
     #[ignore]
@@ -158,7 +135,7 @@ mod test {
             std::str::from_utf8(&batches).unwrap()
         );
 
-        pretty::print_batches(&to_batch(parsed))?;
+        pretty::print_batches(&to_batch(parsed)?.0)?;
 
         Ok(())
     }
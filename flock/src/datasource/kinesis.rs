@@ -16,17 +16,13 @@
 
 use aws_lambda_events::event::kinesis::KinesisEvent;
 
-use datafusion::arrow::json::{self, reader::infer_json_schema};
 use datafusion::arrow::record_batch::RecordBatch;
 
 use crate::prelude::*;
-use rayon::prelude::*;
-use rusoto_core::Region;
-use rusoto_kinesis::{DescribeStreamInput, Kinesis, KinesisClient};
+use crate::quarantine::MalformedRecord;
+use rusoto_kinesis::{DescribeStreamInput, Kinesis};
 use rusoto_lambda::CreateEventSourceMappingRequest;
 use serde::{Deserialize, Serialize};
-use std::io::BufReader;
-use std::sync::Arc;
 
 /// A struct to manage all Kinesis info in cloud environment.
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -50,8 +46,7 @@ pub async fn create_event_source_mapping_request(
     function_name: &str,
     window_in_seconds: i64,
 ) -> Result<CreateEventSourceMappingRequest> {
-    let client = KinesisClient::new(Region::default());
-    let output = client
+    let output = FLOCK_KINESIS_CLIENT
         .describe_stream(DescribeStreamInput {
             stream_name: stream_name.to_string(),
             ..DescribeStreamInput::default()
@@ -88,42 +83,25 @@ pub async fn create_event_source_mapping_request(
 }
 
 /// Converts Kinesis event to record batch in Arrow.
-pub fn to_batch(event: KinesisEvent) -> Vec<RecordBatch> {
-    // infer schema based on the first record
-    let record: &[u8] = &event.records[0].kinesis.data.0.clone();
-    let mut reader = BufReader::new(record);
-    let schema = Arc::new(infer_json_schema(&mut reader, Some(1)).unwrap());
-
-    // The default batch size when using the
-    // [`ReaderBuilder`](json::Reader::ReaderBuilder) is 1024 records
-    let batch_size = 1024;
-    let input: &[u8] = &event
-        .records
-        .into_par_iter()
-        .flat_map(|r| {
-            r.kinesis
-                .data
-                .0
-                .into_iter()
-                .chain(vec![10].into_iter())
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
-
-    // transform data to record batch in Arrow
-    reader = BufReader::with_capacity(input.len(), input);
-    let mut reader = json::Reader::from_buf_reader(reader, schema, batch_size, None);
-
-    let mut batches = vec![];
-    while let Some(batch) = reader.next().unwrap() {
-        batches.push(batch);
-    }
-    batches
+///
+/// The records that aren't valid JSON objects are returned separately with the
+/// reason, so that they can be quarantined without failing the whole batch.
+pub fn to_batch(event: KinesisEvent) -> Result<(Vec<RecordBatch>, Vec<MalformedRecord>)> {
+    json_records_to_batch(
+        event
+            .records
+            .into_iter()
+            .map(|r| Ok(r.kinesis.data.0))
+            .collect(),
+    )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use datafusion::arrow::json::{self, reader::infer_json_schema};
+    use std::io::BufReader;
+    use std::sync::Arc;
 
     #[test]
     #[ignore]
@@ -18,12 +18,14 @@ use self::kinesis::KinesisSource;
 use self::nexmark::NEXMarkSource;
 use self::ysb::YSBSource;
 use crate::error::Result;
-use crate::runtime::payload::{Payload, Uuid};
+use crate::runtime::payload::{InputId, Payload, Uuid};
 use datafusion::arrow::record_batch::RecordBatch;
 use serde::{Deserialize, Serialize};
 
 /// A relation's data in Arrow record batches.
 pub type RelationPartitions = Vec<Vec<RecordBatch>>;
+/// The partitions of each input relation, keyed by the input id.
+pub type InputPartitions = Vec<(InputId, RelationPartitions)>;
 /// To determine the function type to be called: sync or async.
 pub type FastAggregate = bool;
 
@@ -46,14 +48,15 @@ pub trait DataStream {
     ///
     /// This function will partition the event to multiple record batches,
     /// therefore the number of record batches will be equal to the number
-    /// of partitions, which is equal to the number of payloads.
+    /// of partitions, which is equal to the number of payloads. The relations
+    /// are keyed by their table names, and the empty ones are omitted.
     fn select_event_to_batches(
         &self,
         time: usize,
         generator: usize,
         query_number: Option<usize>,
         sync: bool,
-    ) -> Result<(RelationPartitions, RelationPartitions)>;
+    ) -> Result<InputPartitions>;
 }
 
 /// A Data Source for either stream processing or batch processing.
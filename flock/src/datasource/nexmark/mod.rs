@@ -78,6 +78,7 @@ pub use self::event::{side_input_schema, Auction, Bid, Person};
 pub use self::nexmark::{NEXMarkEvent, NEXMarkSource, NEXMarkStream};
 use crate::configs::FLOCK_TARGET_PARTITIONS;
 use crate::error::Result;
+use crate::sketch::register_sketches;
 use datafusion::arrow::datatypes::Schema;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
@@ -132,6 +133,9 @@ pub async fn register_nexmark_tables_with_config(
     )?;
     ctx.register_table("side_input", Arc::new(side_input_table))?;
 
+    // The approximate aggregates, e.g., for the distinct counts and percentiles.
+    register_sketches(&mut ctx);
+
     Ok(ctx)
 }
 
@@ -13,13 +13,13 @@
 
 //! Nexmark benchmark suite
 
-use crate::configs::FLOCK_CONF;
+use crate::configs::{FLOCK_ASYNC_GRANULE_SIZE, FLOCK_SYNC_GRANULE_SIZE};
 use crate::datasource::config::Config;
 use crate::datasource::epoch::Epoch;
 use crate::datasource::nexmark::event::{Auction, Bid, Person};
 use crate::datasource::nexmark::generator::NEXMarkGenerator;
 use crate::datasource::DataStream;
-use crate::datasource::RelationPartitions;
+use crate::datasource::InputPartitions;
 use crate::error::FlockError;
 use crate::error::Result;
 use crate::runtime::payload::{Payload, Uuid};
@@ -39,12 +39,6 @@ lazy_static! {
     static ref NEXMARK_BID: SchemaRef = Arc::new(Bid::schema());
     static ref NEXMARK_PERSON: SchemaRef = Arc::new(Person::schema());
     static ref NEXMARK_AUCTION: SchemaRef = Arc::new(Auction::schema());
-    static ref FLOCK_SYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["sync_granule"]
-        .parse::<usize>()
-        .unwrap();
-    static ref FLOCK_ASYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["async_granule"]
-        .parse::<usize>()
-        .unwrap();
 }
 
 type SourceId = usize;
@@ -159,7 +153,7 @@ impl DataStream for NEXMarkStream {
         generator: usize,
         query_number: Option<usize>,
         sync: bool,
-    ) -> Result<(RelationPartitions, RelationPartitions)> {
+    ) -> Result<InputPartitions> {
         let (event, (persons_num, auctions_num, bids_num)) = self
             .select(time, generator)
             .expect("Failed to select event.");
@@ -178,38 +172,60 @@ impl DataStream for NEXMarkStream {
         } else {
             *FLOCK_ASYNC_GRANULE_SIZE
         };
-        let (r1, r2) = match query_number.expect("Query number is not set.") {
-            0 | 1 | 2 | 5 | 7 | 10..=13 => (
+        let relations = match query_number.expect("Query number is not set.") {
+            0 | 1 | 2 | 5 | 7 | 10..=13 => vec![(
+                "bid",
                 event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), granule_size * 2),
-                vec![],
-            ),
-            3 | 8 => (
-                event_bytes_to_batch(&event.persons, NEXMARK_PERSON.clone(), granule_size / 5),
-                event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), granule_size / 5),
-            ),
-            4 | 6 | 9 => (
-                event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), granule_size / 8),
-                event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), granule_size * 2),
-            ),
+            )],
+            3 | 8 => vec![
+                (
+                    "person",
+                    event_bytes_to_batch(&event.persons, NEXMARK_PERSON.clone(), granule_size / 5),
+                ),
+                (
+                    "auction",
+                    event_bytes_to_batch(
+                        &event.auctions,
+                        NEXMARK_AUCTION.clone(),
+                        granule_size / 5,
+                    ),
+                ),
+            ],
+            4 | 6 | 9 => vec![
+                (
+                    "auction",
+                    event_bytes_to_batch(
+                        &event.auctions,
+                        NEXMARK_AUCTION.clone(),
+                        granule_size / 8,
+                    ),
+                ),
+                (
+                    "bid",
+                    event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), granule_size * 2),
+                ),
+            ],
             _ => unimplemented!(),
         };
 
-        let step = if r2.is_empty() { 2 } else { 1 };
-
-        let batch_chunks = |batches: Vec<RecordBatch>| {
-            batches
-                .into_iter()
-                .chunks(step)
-                .into_iter()
-                .map(|c| c.collect())
-                .collect()
-        };
-
-        if r2.is_empty() {
-            Ok((batch_chunks(r1), vec![]))
-        } else {
-            Ok((batch_chunks(r1), batch_chunks(r2)))
-        }
+        // A single relation is chunked by two batches per partition.
+        let relations = relations
+            .into_iter()
+            .filter(|(_, batches)| !batches.is_empty())
+            .collect::<Vec<(&str, Vec<RecordBatch>)>>();
+        let step = if relations.len() == 1 { 2 } else { 1 };
+        Ok(relations
+            .into_iter()
+            .map(|(table, batches)| {
+                let partitions = batches
+                    .into_iter()
+                    .chunks(step)
+                    .into_iter()
+                    .map(|c| c.collect())
+                    .collect();
+                (table.to_owned(), partitions)
+            })
+            .collect())
     }
 
     /// Select events from the stream and transform them into a payload
@@ -247,20 +263,38 @@ impl DataStream for NEXMarkStream {
         let batch_size = *FLOCK_SYNC_GRANULE_SIZE;
         let mut payload = match query_number.expect("Query number is not set.") {
             0 | 1 | 2 | 5 | 7 | 10..=13 => to_payload(
-                &event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), batch_size),
-                &[],
+                &[(
+                    "bid",
+                    &event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), batch_size),
+                )],
                 uuid,
                 sync,
             ),
             3 | 8 => to_payload(
-                &event_bytes_to_batch(&event.persons, NEXMARK_PERSON.clone(), batch_size),
-                &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
+                &[
+                    (
+                        "person",
+                        &event_bytes_to_batch(&event.persons, NEXMARK_PERSON.clone(), batch_size),
+                    ),
+                    (
+                        "auction",
+                        &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
+                    ),
+                ],
                 uuid,
                 sync,
             ),
             4 | 6 | 9 => to_payload(
-                &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
-                &event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), batch_size),
+                &[
+                    (
+                        "auction",
+                        &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
+                    ),
+                    (
+                        "bid",
+                        &event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), batch_size),
+                    ),
+                ],
                 uuid,
                 sync,
             ),
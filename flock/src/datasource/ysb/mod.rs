@@ -71,6 +71,7 @@ pub use self::ysb::{YSBEvent, YSBSource, YSBStream};
 
 use self::event::{AdEvent, Campaign};
 use crate::error::Result;
+use crate::sketch::register_sketches;
 use datafusion::arrow::datatypes::Schema;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
@@ -106,5 +107,8 @@ pub async fn register_ysb_tables() -> Result<ExecutionContext> {
     )?;
     ctx.register_table("campaign", Arc::new(campaign_table))?;
 
+    // The approximate aggregates, e.g., for the distinct counts and percentiles.
+    register_sketches(&mut ctx);
+
     Ok(ctx)
 }
@@ -13,13 +13,13 @@
 
 //! Yahoo Streaming Benchmark Suite.
 
-use crate::configs::FLOCK_CONF;
+use crate::configs::{FLOCK_ASYNC_GRANULE_SIZE, FLOCK_SYNC_GRANULE_SIZE};
 use crate::datasource::config::Config;
 use crate::datasource::epoch::Epoch;
 use crate::datasource::ysb::event::{AdEvent, Campaign};
 use crate::datasource::ysb::generator::YSBGenerator;
 use crate::datasource::DataStream;
-use crate::datasource::RelationPartitions;
+use crate::datasource::InputPartitions;
 use crate::error::FlockError;
 use crate::error::Result;
 use crate::runtime::payload::{Payload, Uuid};
@@ -38,12 +38,6 @@ use std::thread;
 lazy_static! {
     static ref YSB_AD_EVENT: SchemaRef = Arc::new(AdEvent::schema());
     static ref YSB_CAMPAIGN: SchemaRef = Arc::new(Campaign::schema());
-    static ref FLOCK_SYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["sync_granule"]
-        .parse::<usize>()
-        .unwrap();
-    static ref FLOCK_ASYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["async_granule"]
-        .parse::<usize>()
-        .unwrap();
 }
 
 type SourceId = usize;
@@ -123,7 +117,7 @@ impl DataStream for YSBStream {
         generator: usize,
         _query_number: Option<usize>,
         sync: bool,
-    ) -> Result<(RelationPartitions, RelationPartitions)> {
+    ) -> Result<InputPartitions> {
         let (campaigns, num_campaigns) = self.campaigns.clone();
         let (events, num_ad_events) = self
             .select(time, generator)
@@ -144,25 +138,34 @@ impl DataStream for YSBStream {
             *FLOCK_ASYNC_GRANULE_SIZE
         };
 
-        let r1 = event_bytes_to_batch(&events.ad_events, YSB_AD_EVENT.clone(), granule_size / 4);
-        let r2 = event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), granule_size / 10);
-
-        let step = if r2.is_empty() { 2 } else { 1 };
-
-        let batch_chunks = |batches: Vec<RecordBatch>| {
-            batches
-                .into_iter()
-                .chunks(step)
-                .into_iter()
-                .map(|c| c.collect())
-                .collect()
-        };
-
-        if r2.is_empty() {
-            Ok((batch_chunks(r1), vec![]))
-        } else {
-            Ok((batch_chunks(r1), batch_chunks(r2)))
-        }
+        let relations = vec![
+            (
+                "ad_event",
+                event_bytes_to_batch(&events.ad_events, YSB_AD_EVENT.clone(), granule_size / 4),
+            ),
+            (
+                "campaign",
+                event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), granule_size / 10),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, batches)| !batches.is_empty())
+        .collect::<Vec<(&str, Vec<RecordBatch>)>>();
+
+        // A single relation is chunked by two batches per partition.
+        let step = if relations.len() == 1 { 2 } else { 1 };
+        Ok(relations
+            .into_iter()
+            .map(|(table, batches)| {
+                let partitions = batches
+                    .into_iter()
+                    .chunks(step)
+                    .into_iter()
+                    .map(|c| c.collect())
+                    .collect();
+                (table.to_owned(), partitions)
+            })
+            .collect())
     }
 
     /// Select events from the stream and transform them into a payload
@@ -200,8 +203,16 @@ impl DataStream for YSBStream {
 
         let batch_size = *FLOCK_SYNC_GRANULE_SIZE;
         Ok(to_payload(
-            &event_bytes_to_batch(&events.ad_events, YSB_AD_EVENT.clone(), batch_size),
-            &event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), batch_size),
+            &[
+                (
+                    "ad_event",
+                    &event_bytes_to_batch(&events.ad_events, YSB_AD_EVENT.clone(), batch_size),
+                ),
+                (
+                    "campaign",
+                    &event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), batch_size),
+                ),
+            ],
             uuid,
             sync,
         ))
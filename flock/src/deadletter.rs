@@ -0,0 +1,422 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The dead-letter queue captures the function invocations that failed
+//! permanently, so that a lost data fragment doesn't silently stall an
+//! aggregation window forever. Each [`DeadLetter`] keeps the original payload,
+//! its [`Uuid`] and the error, and can be re-injected by [`replay`].
+//!
+//! The destination is configured in the `[deadletter]` section: an SQS queue,
+//! a key prefix in the Flock S3 bucket, or a local JSON lines file.
+//!
+//! The capture has two limits:
+//!
+//! - An asynchronous invocation is only captured if AWS Lambda doesn't accept
+//!   it. Once it's accepted (HTTP 202), a failure of the function is retried by
+//!   AWS Lambda and then discarded, since the functions have no on-failure
+//!   destination (`EventInvokeConfig`).
+//! - An SQS message is at most 256 KB, and the payload is base64-encoded, so
+//!   the payloads above roughly 190 KB can't be captured in an SQS queue. Use
+//!   the `s3` destination for large payloads.
+
+use crate::aws::{lambda, s3, sqs};
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::payload::Uuid;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A failed function invocation.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct DeadLetter {
+    /// The name of the function that failed to be invoked.
+    pub function_name:   String,
+    /// The invocation type: `Event` or `RequestResponse`.
+    pub invocation_type: String,
+    /// The UUID of the payload, if the payload is a Flock payload.
+    pub uuid:            Option<Uuid>,
+    /// The error of the last attempt.
+    pub error:           String,
+    /// The time when the invocation failed, in milliseconds.
+    pub timestamp:       i64,
+    /// The base64-encoded payload of the invocation.
+    pub payload:         String,
+}
+
+impl DeadLetter {
+    /// Creates a dead letter for the failed invocation.
+    ///
+    /// # Arguments
+    /// * `function_name` - The name of the function.
+    /// * `invocation_type` - The invocation type.
+    /// * `payload` - The payload of the invocation.
+    /// * `error` - The error of the last attempt.
+    pub fn new(
+        function_name: &str,
+        invocation_type: &str,
+        payload: &[u8],
+        error: &FlockError,
+    ) -> Self {
+        let uuid = serde_json::from_slice::<Value>(payload)
+            .ok()
+            .and_then(|v| serde_json::from_value(v.get("uuid")?.clone()).ok());
+        Self {
+            function_name: function_name.to_owned(),
+            invocation_type: invocation_type.to_owned(),
+            uuid,
+            error: error.to_string(),
+            timestamp: Utc::now().timestamp_millis(),
+            payload: base64::encode(payload),
+        }
+    }
+
+    /// Returns the decoded payload of the invocation.
+    pub fn payload(&self) -> Result<Vec<u8>> {
+        Ok(base64::decode(&self.payload)?)
+    }
+
+    /// Returns the unique key of the dead letter.
+    pub fn key(&self) -> String {
+        match &self.uuid {
+            Some(uuid) => format!(
+                "{}/{}-{}-{}",
+                self.function_name, uuid.qid, uuid.seq_num, self.timestamp
+            ),
+            None => format!("{}/{}", self.function_name, self.timestamp),
+        }
+    }
+}
+
+/// The destination of the dead letters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeadLetterQueue {
+    /// An SQS queue.
+    Sqs(String),
+    /// A key prefix in an S3 bucket.
+    S3 {
+        /// The bucket name.
+        bucket: String,
+        /// The key prefix.
+        prefix: String,
+    },
+    /// A local JSON lines file.
+    File(PathBuf),
+    /// The dead letters are dropped.
+    Off,
+}
+
+impl DeadLetterQueue {
+    /// Returns the destination in the settings.
+    pub fn from_config() -> Self {
+        let settings = &FLOCK_CONFIG.deadletter;
+        match settings.destination.as_str() {
+            "sqs" => DeadLetterQueue::Sqs(settings.queue.clone()),
+            "s3" => DeadLetterQueue::S3 {
+                bucket: FLOCK_S3_BUCKET.clone(),
+                prefix: settings.prefix.clone(),
+            },
+            "file" => DeadLetterQueue::File(PathBuf::from(&settings.path)),
+            _ => DeadLetterQueue::Off,
+        }
+    }
+
+    /// Writes a dead letter to the destination.
+    pub async fn send(&self, letter: &DeadLetter) -> Result<()> {
+        match self {
+            DeadLetterQueue::Sqs(queue) => {
+                let body = serde_json::to_string(letter)?;
+                if body.len() > sqs::MAX_MESSAGE_SIZE {
+                    return Err(FlockError::Execution(format!(
+                        "The dead letter of {} is {} bytes, beyond the {} bytes of an SQS message",
+                        letter.function_name,
+                        body.len(),
+                        sqs::MAX_MESSAGE_SIZE
+                    )));
+                }
+                let queue_url = sqs::create_queue(queue).await?;
+                sqs::send_message(&queue_url, body).await
+            }
+            DeadLetterQueue::S3 { bucket, prefix } => {
+                let key = format!("{}/{}", prefix, letter.key());
+                s3::put_object(bucket, &key, serde_json::to_vec(letter)?).await
+            }
+            DeadLetterQueue::File(path) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                writeln!(file, "{}", serde_json::to_string(letter)?)?;
+                Ok(())
+            }
+            DeadLetterQueue::Off => Ok(()),
+        }
+    }
+
+    /// Returns the dead letters in the destination without removing them.
+    pub async fn peek(&self) -> Result<Vec<DeadLetter>> {
+        let mut letters = vec![];
+        match self {
+            DeadLetterQueue::Sqs(queue) => {
+                // The received messages are hidden until they're released, so the
+                // receives go through the queue once.
+                let queue_url = sqs::create_queue(queue).await?;
+                let mut handles = vec![];
+                let result: Result<()> = async {
+                    loop {
+                        let messages = sqs::receive_messages(&queue_url, 10).await?;
+                        if messages.is_empty() {
+                            return Ok(());
+                        }
+                        for (receipt_handle, body) in messages {
+                            handles.push(receipt_handle);
+                            letters.push(serde_json::from_str(&body)?);
+                        }
+                    }
+                }
+                .await;
+                for receipt_handle in handles {
+                    sqs::release_message(&queue_url, receipt_handle).await?;
+                }
+                result?;
+            }
+            DeadLetterQueue::S3 { bucket, prefix } => {
+                for key in s3::get_matched_keys(bucket, prefix).await? {
+                    letters.push(serde_json::from_slice(
+                        &s3::get_object(bucket, &key).await?,
+                    )?);
+                }
+            }
+            DeadLetterQueue::File(path) => {
+                for line in read_lines(path)? {
+                    letters.push(serde_json::from_str(&line)?);
+                }
+            }
+            DeadLetterQueue::Off => {}
+        }
+        Ok(letters)
+    }
+
+    /// Takes the dead letters out of the destination one at a time: each letter
+    /// is handed to `handle`, and only removed once it's handled. The letters
+    /// that fail to be handled stay in the destination, and so do the letters
+    /// that arrive in the meantime, e.g., the invocations that fail again.
+    ///
+    /// # Returns
+    /// The number of handled dead letters.
+    pub async fn consume<F, Fut>(&self, mut handle: F) -> Result<usize>
+    where
+        F: FnMut(DeadLetter) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut handled = 0;
+        match self {
+            DeadLetterQueue::Sqs(queue) => {
+                let queue_url = sqs::create_queue(queue).await?;
+                let mut kept = vec![];
+                let mut seen = HashSet::new();
+                loop {
+                    let (receipt_handle, body) =
+                        match sqs::receive_messages(&queue_url, 1).await?.pop() {
+                            Some(message) => message,
+                            None => break,
+                        };
+                    let letter: DeadLetter = serde_json::from_str(&body)?;
+                    // A kept letter is visible again after the visibility timeout.
+                    if !seen.insert(letter.key()) {
+                        kept.push(receipt_handle);
+                        break;
+                    }
+                    match handle(letter.clone()).await {
+                        Ok(()) => {
+                            sqs::delete_message(&queue_url, receipt_handle).await?;
+                            handled += 1;
+                        }
+                        Err(e) => {
+                            warn!("Kept the dead letter {}: {}", letter.key(), e);
+                            kept.push(receipt_handle);
+                        }
+                    }
+                }
+                for receipt_handle in kept {
+                    sqs::release_message(&queue_url, receipt_handle).await?;
+                }
+            }
+            DeadLetterQueue::S3 { bucket, prefix } => {
+                for key in s3::get_matched_keys(bucket, prefix).await? {
+                    let letter: DeadLetter =
+                        serde_json::from_slice(&s3::get_object(bucket, &key).await?)?;
+                    match handle(letter).await {
+                        Ok(()) => {
+                            s3::delete_object(bucket, &key).await?;
+                            handled += 1;
+                        }
+                        Err(e) => warn!("Kept the dead letter {}: {}", key, e),
+                    }
+                }
+            }
+            DeadLetterQueue::File(path) => {
+                for line in read_lines(path)? {
+                    let letter: DeadLetter = serde_json::from_str(&line)?;
+                    match handle(letter.clone()).await {
+                        Ok(()) => {
+                            remove_line(path, &line)?;
+                            handled += 1;
+                        }
+                        Err(e) => warn!("Kept the dead letter {}: {}", letter.key(), e),
+                    }
+                }
+            }
+            DeadLetterQueue::Off => {}
+        }
+        Ok(handled)
+    }
+}
+
+/// Returns the non-empty lines of the file, or nothing if it doesn't exist.
+fn read_lines(path: &PathBuf) -> Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+/// Removes the first occurrence of the line from the file. The file is read
+/// again, since the failed invocations can append to it in the meantime.
+fn remove_line(path: &PathBuf, line: &str) -> Result<()> {
+    let mut lines = read_lines(path)?;
+    if let Some(index) = lines.iter().position(|l| l == line) {
+        lines.remove(index);
+    }
+    if lines.is_empty() {
+        std::fs::remove_file(path)?;
+    } else {
+        std::fs::write(path, lines.join("\n") + "\n")?;
+    }
+    Ok(())
+}
+
+/// Captures a failed invocation in the configured dead-letter queue. Failures
+/// to write the dead letter are logged rather than returned, because the
+/// caller is already handling the original error.
+pub async fn capture(
+    function_name: &str,
+    invocation_type: &str,
+    payload: &[u8],
+    error: &FlockError,
+) {
+    let letter = DeadLetter::new(function_name, invocation_type, payload, error);
+    let queue = DeadLetterQueue::from_config();
+    match queue.send(&letter).await {
+        Ok(()) => warn!(
+            "Captured a dead letter for function {} (uuid: {:?}): {}",
+            function_name, letter.uuid, letter.error
+        ),
+        Err(e) => warn!(
+            "Failed to capture a dead letter for function {} (uuid: {:?}) in {:?}: {}",
+            function_name, letter.uuid, queue, e
+        ),
+    }
+}
+
+/// Re-injects the dead letters in the queue one at a time. A letter is only
+/// removed from the queue once its invocation succeeds, so the letters that
+/// fail again stay in the queue for the next replay.
+///
+/// # Returns
+/// The number of dead letters that are successfully re-injected.
+pub async fn replay(queue: &DeadLetterQueue) -> Result<usize> {
+    let replayed = queue
+        .consume(|letter| async move {
+            lambda::try_invoke_function(
+                &letter.function_name,
+                &letter.invocation_type,
+                Some(letter.payload()?.into()),
+            )
+            .await
+            .map(|_| ())
+        })
+        .await?;
+    info!("Replayed {} dead letters.", replayed);
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::Payload;
+
+    #[tokio::test]
+    async fn file_dead_letters() -> Result<()> {
+        let payload = Payload {
+            uuid: Uuid {
+                qid:     "q-00-1024".to_owned(),
+                seq_num: 3,
+                seq_len: 8,
+            },
+            ..Default::default()
+        };
+        let bytes = serde_json::to_vec(&payload)?;
+        let error = FlockError::AWS("TooManyRequestsException".to_owned());
+
+        let path = std::env::temp_dir().join("flock_file_dead_letters.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let queue = DeadLetterQueue::File(path);
+
+        let letter = DeadLetter::new("q-01-00", "Event", &bytes, &error);
+        assert_eq!(Some(payload.uuid.clone()), letter.uuid);
+        assert_eq!(bytes, letter.payload()?);
+        assert!(letter.error.contains("TooManyRequestsException"));
+
+        queue.send(&letter).await?;
+        queue
+            .send(&DeadLetter::new("q-01-01", "Event", b"raw", &error))
+            .await?;
+
+        // The letters are listed without removing them.
+        let letters = queue.peek().await?;
+        assert_eq!(2, letters.len());
+        assert_eq!(letter, letters[0]);
+        assert_eq!(None, letters[1].uuid);
+        assert_eq!(letters, queue.peek().await?);
+
+        // The letter that fails to be handled stays in the queue.
+        let handled = queue
+            .consume(|letter| async move {
+                match letter.uuid {
+                    Some(_) => Ok(()),
+                    None => Err(FlockError::AWS("ResourceNotFoundException".to_owned())),
+                }
+            })
+            .await?;
+        assert_eq!(1, handled);
+        assert_eq!(vec![letters[1].clone()], queue.peek().await?);
+
+        assert_eq!(1, queue.consume(|_| async { Ok(()) }).await?);
+        assert!(queue.peek().await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -23,7 +23,7 @@
 //! work on other cloud functions that then together execute the query in a
 //! distributed dataflow model.
 
-use crate::configs::FLOCK_CONF;
+use crate::configs::FLOCK_CONFIG;
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
 use crate::runtime::context::CloudFunction;
@@ -96,30 +96,18 @@ impl LambdaExecutor {
             })
             .sum();
         if contain_join(&ctx.plan) {
-            if size
-                < FLOCK_CONF["lambda"]["join_threshold"]
-                    .parse::<usize>()
-                    .unwrap()
-            {
+            if size < FLOCK_CONFIG.lambda.join_threshold {
                 ExecutionStrategy::Centralized
             } else {
                 ExecutionStrategy::Distributed
             }
         } else if contain_aggregate(&ctx.plan) {
-            if size
-                < FLOCK_CONF["lambda"]["aggregate_threshold"]
-                    .parse::<usize>()
-                    .unwrap()
-            {
+            if size < FLOCK_CONFIG.lambda.aggregate_threshold {
                 ExecutionStrategy::Centralized
             } else {
                 ExecutionStrategy::Distributed
             }
-        } else if size
-            < FLOCK_CONF["lambda"]["regular_threshold"]
-                .parse::<usize>()
-                .unwrap()
-        {
+        } else if size < FLOCK_CONFIG.lambda.regular_threshold {
             ExecutionStrategy::Centralized
         } else {
             ExecutionStrategy::Distributed
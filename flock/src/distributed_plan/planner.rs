@@ -14,8 +14,12 @@
 //! Distributed plnner is a unified API for users to split their query plan into
 //! multiple functions, and execute them in distributed fashion on cloud.
 
+use crate::configs::FLOCK_CONFIG;
 use crate::distributed_plan::stage::{self, QueryDag};
 use crate::error::Result;
+use crate::runtime::join::IntervalJoin;
+use crate::runtime::lookup::LookupJoin;
+use crate::runtime::payload::InputId;
 use datafusion::physical_plan::ExecutionPlan;
 use log::debug;
 use std::sync::Arc;
@@ -23,12 +27,58 @@ use std::sync::Arc;
 /// Distributed Planer deals with the physical plan and convert it into
 /// distributed plan.
 #[derive(Debug)]
-pub struct DistributedPlanner {}
+pub struct DistributedPlanner {
+    /// Whether to insert a combine stage after each group aggregation, so that
+    /// the heavy keys of the aggregation can be split into salted
+    /// sub-partitions.
+    skew_mitigation: bool,
+    /// The source tables scanned by the leaf nodes of the query plan, from
+    /// left to right. The leaf nodes aren't tagged if they are unknown.
+    source_tables:   Vec<InputId>,
+    /// The interval join that replaces the hash join of the query plan.
+    interval_join:   Option<IntervalJoin>,
+    /// The lookup join that replaces the hash join of the query plan.
+    lookup_join:     Option<LookupJoin>,
+}
 
 impl DistributedPlanner {
     /// Create a new distributed planner.
     pub fn new() -> Self {
-        DistributedPlanner {}
+        DistributedPlanner {
+            skew_mitigation: FLOCK_CONFIG.skew.enabled,
+            source_tables:   vec![],
+            interval_join:   None,
+            lookup_join:     None,
+        }
+    }
+
+    /// Enable or disable the combine stages for skew mitigation.
+    pub fn with_skew_mitigation(mut self, enabled: bool) -> Self {
+        self.skew_mitigation = enabled;
+        self
+    }
+
+    /// Tag the scans of the first stage with the source tables, so that the
+    /// data sources are fed to them by table rather than by schema.
+    pub fn with_source_tables(mut self, tables: Vec<InputId>) -> Self {
+        self.source_tables = tables;
+        self
+    }
+
+    /// Replace the hash join of the query plan with an interval join, which
+    /// keeps the rows of both sides across invocations. The inputs of the
+    /// sides are set by the planner.
+    pub fn with_interval_join(mut self, interval_join: IntervalJoin) -> Self {
+        self.interval_join = Some(interval_join);
+        self
+    }
+
+    /// Replace the hash join of the query plan with a lookup join, which
+    /// enriches the stream with a cached reference table instead of reading
+    /// the table as a second input. The stream input is set by the planner.
+    pub fn with_lookup_join(mut self, lookup_join: LookupJoin) -> Self {
+        self.lookup_join = Some(lookup_join);
+        self
     }
 }
 
@@ -54,7 +104,13 @@ impl DistributedPlanner {
         &self,
         execution_plan: Arc<dyn ExecutionPlan>,
     ) -> Result<QueryDag> {
-        stage::build_query_dag(execution_plan)
+        stage::build_query_dag_with_sources(
+            execution_plan,
+            self.skew_mitigation,
+            &self.source_tables,
+            self.interval_join.as_ref(),
+            self.lookup_join.as_ref(),
+        )
     }
 }
 
@@ -63,6 +119,9 @@ mod tests {
     use super::*;
     use crate::datasource::nexmark::*;
     use crate::datasource::ysb::*;
+    use crate::runtime::context::CloudFunctionType;
+    use crate::runtime::payload::output_id;
+    use crate::runtime::skew;
     use datafusion::physical_plan::displayable;
 
     #[tokio::test]
@@ -344,4 +403,91 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn ysb_distributed_plan_with_combine_stage() -> Result<()> {
+        let mut ctx = register_ysb_tables().await?;
+        let df = ctx
+            .sql(include_str!("../../../benchmarks/src/ysb/ysb.sql"))
+            .await?;
+
+        let plan = df.to_logical_plan();
+        let plan = ctx.optimize(&plan)?;
+        let plan = ctx.create_physical_plan(&plan).await?;
+
+        let planner = DistributedPlanner::new().with_skew_mitigation(true);
+        let dag = planner.plan_query_stages(plan).await?;
+        let stages = &dag.get_all_stages();
+        for (i, stage) in stages.iter().enumerate() {
+            println!("=== Stage {} ===\n{}\n", i, stage.get_plan_str());
+        }
+
+        #[rustfmt::skip]
+        // Expected result:
+        // === Stage 0 ===
+        // ProjectionExec: expr=[campaign_id@0 as campaign_id, COUNT(UInt8(1))@1 as COUNT(UInt8(1))]
+        //   HashAggregateExec: mode=Final, gby=[campaign_id@0 as campaign_id], aggr=[SUM(COUNT(UInt8(1)))]
+        //     HashAggregateExec: mode=Partial, gby=[campaign_id@0 as campaign_id], aggr=[SUM(COUNT(UInt8(1)))]
+        //       MemoryExec: partitions=0, partition_sizes=[]
+        //
+        // === Stage 1 ===
+        // HashAggregateExec: mode=FinalPartitioned, gby=[campaign_id@0 as campaign_id], aggr=[COUNT(UInt8(1))]
+        //   MemoryExec: partitions=0, partition_sizes=[]
+        // ...
+        assert_eq!(4, stages.len());
+        assert_eq!(
+            1,
+            stages
+                .iter()
+                .filter(|s| skew::salted_aggregate(&s.stage).is_some())
+                .count()
+        );
+        assert_eq!(
+            2,
+            stages
+                .iter()
+                .filter(|s| s.get_function_type() == CloudFunctionType::Group)
+                .count()
+        );
+
+        // The query without the combine stage.
+        let planner = DistributedPlanner::new().with_skew_mitigation(false);
+        let plan = ctx.optimize(&df.to_logical_plan())?;
+        let plan = ctx.create_physical_plan(&plan).await?;
+        let dag = planner.plan_query_stages(plan).await?;
+        assert_eq!(3, dag.get_all_stages().len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nexmark_q3_input_ids() -> Result<()> {
+        let mut ctx = register_nexmark_tables().await?;
+        let df = ctx
+            .sql(include_str!("../../../benchmarks/src/nexmark/query/q3.sql"))
+            .await?;
+
+        let plan = df.to_logical_plan();
+        let plan = ctx.optimize(&plan)?;
+        let plan = ctx.create_physical_plan(&plan).await?;
+
+        // The scans of the first stage read the source tables, and the join reads
+        // the outputs of the first stage.
+        let planner = DistributedPlanner::new()
+            .with_source_tables(vec!["auction".to_owned(), "person".to_owned()]);
+        let dag = planner.plan_query_stages(plan.clone()).await?;
+        let stages = &dag.get_all_stages();
+        assert_eq!(2, stages.len());
+        assert_eq!(vec!["auction", "person"], stages[0].inputs);
+        assert_eq!(vec![output_id(0), output_id(1)], stages[1].inputs);
+
+        // The scans aren't tagged if the source tables don't match them.
+        let planner = DistributedPlanner::new().with_source_tables(vec!["auction".to_owned()]);
+        let dag = planner.plan_query_stages(plan).await?;
+        let stages = &dag.get_all_stages();
+        assert!(stages[0].inputs.is_empty());
+        assert_eq!(vec![output_id(0), output_id(1)], stages[1].inputs);
+
+        Ok(())
+    }
 }
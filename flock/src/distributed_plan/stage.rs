@@ -16,11 +16,18 @@
 
 extern crate daggy;
 use crate::error::{FlockError, Result};
-use crate::runtime::context::{CloudFunctionType, ExecutionContext};
+use crate::runtime::context::{leaf_nodes, CloudFunctionType, ExecutionContext};
+use crate::runtime::join::{IntervalJoin, INTERVAL_JOIN_INPUT};
+use crate::runtime::lookup::{LookupJoin, LOOKUP_JOIN_INPUT};
+use crate::runtime::payload::{output_id, InputId};
+use crate::runtime::skew;
 use daggy::{Dag, NodeIndex, Walker};
 use datafusion::physical_plan::displayable;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::hash_join::HashJoinExec;
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::ExecutionPlan;
+use log::debug;
 use serde_json::Value;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
@@ -35,6 +42,15 @@ pub struct QueryStage {
     pub stage:         Vec<Arc<dyn ExecutionPlan>>,
     /// Function type in cloud environment.
     pub function_type: CloudFunctionType,
+    /// The ids of the inputs fed to the leaf nodes of the subplans, from left
+    /// to right: the source tables for the scans of the first stage, and the
+    /// outputs of the former stage for the other stages. It's empty if the
+    /// source tables of the scans are unknown.
+    pub inputs:        Vec<InputId>,
+    /// The interval join that replaces the hash join of the stage, if any.
+    pub interval_join: Option<IntervalJoin>,
+    /// The lookup join that replaces the hash join of the stage, if any.
+    pub lookup_join:   Option<LookupJoin>,
     /// The cloud execution context for this query stage.
     pub context:       Option<ExecutionContext>,
 }
@@ -62,6 +78,9 @@ impl QueryStage {
         QueryStage {
             stage,
             function_type,
+            inputs: vec![],
+            interval_join: None,
+            lookup_join: None,
             context: None,
         }
     }
@@ -72,6 +91,9 @@ impl From<Vec<Arc<dyn ExecutionPlan>>> for QueryStage {
         QueryStage {
             stage,
             function_type: CloudFunctionType::Lambda,
+            inputs: vec![],
+            interval_join: None,
+            lookup_join: None,
             context: None,
         }
     }
@@ -230,27 +252,25 @@ impl QueryDag {
         parent: NodeIndex,
         nodes: Vec<Value>,
         function_type: CloudFunctionType,
+        inputs: Vec<InputId>,
     ) -> Result<NodeIndex> {
         let stage = nodes
             .into_iter()
             .map(|node| serde_json::from_value(node).unwrap())
             .collect();
+        let node = QueryStage {
+            stage,
+            function_type,
+            inputs,
+            interval_join: None,
+            lookup_join: None,
+            context: None,
+        };
         if parent == NodeIndex::end() {
-            Ok(self.add_node(QueryStage {
-                stage,
-                function_type,
-                context: None,
-            }))
+            Ok(self.add_node(node))
         } else {
             // TODO: call add_parent instead of add_child
-            Ok(self.add_child(
-                parent,
-                QueryStage {
-                    stage,
-                    function_type,
-                    context: None,
-                },
-            ))
+            Ok(self.add_child(parent, node))
         }
     }
 }
@@ -263,10 +283,86 @@ impl QueryDag {
 /// # Returns
 /// * `QueryDag` - the DAG representation of the query plan.
 pub fn build_query_dag(plan: Arc<dyn ExecutionPlan>) -> Result<QueryDag> {
-    build_query_dag_from_serde_json(plan)
+    build_query_dag_from_serde_json(plan, false, &[], None, None)
+}
+
+/// Build a DAG from a query plan, optionally with a combine stage after each
+/// group aggregation.
+///
+/// The combine stage merges the partial results of a group aggregation whose
+/// heavy keys are split into salted sub-partitions. It's only inserted if all
+/// aggregate functions can be merged.
+///
+/// # Arguments
+/// * `plan` - The query plan.
+/// * `combine` - Whether to insert the combine stages.
+///
+/// # Returns
+/// * `QueryDag` - the DAG representation of the query plan.
+pub fn build_query_dag_with_combine(
+    plan: Arc<dyn ExecutionPlan>,
+    combine: bool,
+) -> Result<QueryDag> {
+    build_query_dag_from_serde_json(plan, combine, &[], None, None)
 }
 
-fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<QueryDag> {
+/// Build a DAG from a query plan, and tag the leaf nodes of each stage with the
+/// ids of their inputs.
+///
+/// The scans of the source tables all end up in the first stage, since the plan
+/// is only split above them. The other stages read the outputs of the former
+/// stage, which are tagged with [`output_id`].
+///
+/// # Arguments
+/// * `plan` - The query plan.
+/// * `combine` - Whether to insert the combine stages.
+/// * `sources` - The source tables of the plan's leaf nodes, from left to
+///   right.
+/// * `interval_join` - The interval join that replaces the hash join of the
+///   plan, if any.
+/// * `lookup_join` - The lookup join that replaces the hash join of the plan,
+///   if any.
+///
+/// # Returns
+/// * `QueryDag` - the DAG representation of the query plan.
+pub fn build_query_dag_with_sources(
+    plan: Arc<dyn ExecutionPlan>,
+    combine: bool,
+    sources: &[InputId],
+    interval_join: Option<&IntervalJoin>,
+    lookup_join: Option<&LookupJoin>,
+) -> Result<QueryDag> {
+    build_query_dag_from_serde_json(plan, combine, sources, interval_join, lookup_join)
+}
+
+/// Returns the combine stage of the final group aggregation, if all aggregate
+/// functions can be merged.
+fn combine_stage(plan: &Arc<dyn ExecutionPlan>) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    match plan.as_any().downcast_ref::<HashAggregateExec>() {
+        Some(aggregate) if !aggregate.group_expr().is_empty() => {
+            let input = Arc::new(MemoryExec::try_new(&[], aggregate.schema(), None)?);
+            skew::combine_plan(aggregate, input)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn build_query_dag_from_serde_json(
+    plan: Arc<dyn ExecutionPlan>,
+    combine: bool,
+    sources: &[InputId],
+    interval_join: Option<&IntervalJoin>,
+    lookup_join: Option<&LookupJoin>,
+) -> Result<QueryDag> {
+    let sources = if sources.len() == leaf_nodes(&[plan.clone()]).len() {
+        sources.to_vec()
+    } else {
+        if !sources.is_empty() {
+            debug!("The source tables don't match the leaf nodes of the plan.");
+        }
+        vec![]
+    };
+
     let mut dag = QueryDag::new();
     let mut root = serde_json::to_value(&plan).unwrap();
     let mut json = &mut root;
@@ -276,7 +372,24 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
         match json["execution_plan"].as_str() {
             Some("hash_aggregate_exec") => match json["mode"].as_str() {
                 Some("Final") | Some("FinalPartitioned") => {
-                    // Split the plan into two subplans
+                    let combine_plan = if combine { combine_stage(&curr)? } else { None };
+                    if let Some(plan) = combine_plan {
+                        // Replace the aggregation with the combine stage, and split the
+                        // aggregation into its own subplan.
+                        let object = json.take();
+                        *json = serde_json::to_value(plan)?;
+                        leaf = dag.insert(
+                            leaf,
+                            vec![root],
+                            CloudFunctionType::Group,
+                            vec![output_id(0)],
+                        )?;
+                        root = object;
+                        json = &mut root;
+                    }
+                    // Split the plan into two subplans. The partial aggregation stays
+                    // in the former stage, so each lambda instance only sends its
+                    // partial states to the group, and the group merges them.
                     let object = (*json["input"].take().as_object().ok_or_else(|| {
                         FlockError::QueryStage(
                             "Failed to parse input for HashAggregateExec".to_string(),
@@ -291,7 +404,12 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
                     )?);
                     json["input"] = serde_json::to_value(input)?;
                     // Add the new subplan to DAG
-                    leaf = dag.insert(leaf, vec![root], CloudFunctionType::Group)?;
+                    leaf = dag.insert(
+                        leaf,
+                        vec![root],
+                        CloudFunctionType::Group,
+                        vec![output_id(0)],
+                    )?;
                     // Point to the next subplan
                     root = Value::Object(object);
                     json = &mut root;
@@ -326,12 +444,68 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
                 json["left"] = serde_json::to_value(left)?;
                 json["right"] = serde_json::to_value(right)?;
 
-                leaf = dag.insert(leaf, vec![root], CloudFunctionType::Lambda)?;
-                dag.insert(
-                    leaf,
-                    vec![Value::Object(left_obj), Value::Object(right_obj)],
-                    CloudFunctionType::Lambda,
-                )?;
+                let hash_join = curr.as_any().downcast_ref::<HashJoinExec>().ok_or_else(|| {
+                    FlockError::QueryStage("Failed to parse HashJoinExec".to_string())
+                });
+                let mut subplans = vec![Value::Object(left_obj), Value::Object(right_obj)];
+                let mut sources = sources;
+                leaf = match (interval_join, lookup_join) {
+                    (Some(_), Some(_)) => {
+                        return Err(FlockError::QueryStage(
+                            "A query can't have both an interval join and a lookup join."
+                                .to_string(),
+                        ));
+                    }
+                    (Some(join), None) => {
+                        // The hash join is replaced with the matches of the interval join.
+                        // The former stage shuffles both sides by the join key, so each
+                        // member of the group owns the join states of its keys.
+                        let join = join.bind(hash_join?)?;
+                        let matches: Arc<dyn ExecutionPlan> =
+                            Arc::new(MemoryExec::try_new(&[], curr.schema(), None)?);
+                        *json = serde_json::to_value(matches)?;
+                        let node = dag.insert(
+                            leaf,
+                            vec![root],
+                            CloudFunctionType::Group,
+                            vec![INTERVAL_JOIN_INPUT.to_owned()],
+                        )?;
+                        dag.get_node_mut(node).unwrap().interval_join = Some(join);
+                        node
+                    }
+                    (None, Some(join)) => {
+                        // The hash join is replaced with the rows enriched by the lookup
+                        // join, and the former stage only reads the stream.
+                        let join = join.bind(hash_join?)?;
+                        let matches: Arc<dyn ExecutionPlan> =
+                            Arc::new(MemoryExec::try_new(&[], curr.schema(), None)?);
+                        *json = serde_json::to_value(matches)?;
+                        let node = dag.insert(
+                            leaf,
+                            vec![root],
+                            CloudFunctionType::Lambda,
+                            vec![LOOKUP_JOIN_INPUT.to_owned()],
+                        )?;
+                        let left_leaves = leaf_nodes(&[curr.children()[0].clone()]).len();
+                        let stream = if join.table_first {
+                            sources = sources.split_off(left_leaves.min(sources.len()));
+                            subplans.remove(1)
+                        } else {
+                            sources.truncate(left_leaves);
+                            subplans.remove(0)
+                        };
+                        subplans = vec![stream];
+                        dag.get_node_mut(node).unwrap().lookup_join = Some(join);
+                        node
+                    }
+                    (None, None) => dag.insert(
+                        leaf,
+                        vec![root],
+                        CloudFunctionType::Lambda,
+                        vec![output_id(0), output_id(1)],
+                    )?,
+                };
+                dag.insert(leaf, subplans, CloudFunctionType::Lambda, sources)?;
                 return Ok(dag);
             }
             Some("sort_exec") => {
@@ -347,7 +521,12 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
                 )?);
                 json["input"] = serde_json::to_value(input)?;
                 // Add the new subplan to DAG
-                leaf = dag.insert(leaf, vec![root], CloudFunctionType::Group)?;
+                leaf = dag.insert(
+                    leaf,
+                    vec![root],
+                    CloudFunctionType::Group,
+                    vec![output_id(0)],
+                )?;
                 // Point to the next subplan
                 root = Value::Object(object);
                 json = &mut root;
@@ -360,7 +539,7 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
         curr = curr.children()[0].clone();
     }
 
-    dag.insert(leaf, vec![root], CloudFunctionType::Lambda)?;
+    dag.insert(leaf, vec![root], CloudFunctionType::Lambda, sources)?;
     assert!(dag.node_count() >= 1);
 
     Ok(dag)
@@ -304,7 +304,7 @@ mod tests {
         let input = include_str!("../../tests/data/example-kinesis-event-1.json");
         let input: KinesisEvent = serde_json::from_str(input).unwrap();
 
-        let partitions = vec![kinesis::to_batch(input)];
+        let partitions = vec![kinesis::to_batch(input)?.0];
 
         let mut ctx = ExecutionContext::new();
 
@@ -93,7 +93,10 @@ impl Encoding {
                 .map_err(|e| FlockError::Execution(e.to_string()))?,
             Encoding::None => s.into(),
             _ => {
-                unimplemented!();
+                return Err(FlockError::Execution(format!(
+                    "Unsupported encoding: {:?}",
+                    self
+                )));
             }
         })
     }
@@ -73,6 +73,8 @@ pub enum FlockError {
     DataSink(String),
     /// Error returned when accessing the AWS services fails.
     AWS(String),
+    /// Error returned when the configuration is missing or invalid.
+    Config(String),
 }
 
 impl From<io::Error> for FlockError {
@@ -159,6 +161,7 @@ impl Display for FlockError {
             }
             FlockError::DataSink(ref desc) => write!(f, "Data sink error: {}", desc),
             FlockError::AWS(ref desc) => write!(f, "AWS error: {}", desc),
+            FlockError::Config(ref desc) => write!(f, "Configuration error: {}", desc),
         }
     }
 }
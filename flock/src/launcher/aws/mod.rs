@@ -14,22 +14,29 @@
 //! This crate responsibles for executing queries on AWS Lambda Functions.
 
 extern crate daggy;
+pub mod upgrade;
+
+use crate::aws::lambda;
 use crate::configs::*;
 use crate::datasink::DataSinkType;
 use crate::distributed_plan::DistributedPlanner;
 use crate::distributed_plan::QueryDag;
-use crate::error::Result;
+use crate::error::{FlockError, Result};
 use crate::launcher::{ExecutionMode, Launcher};
 use crate::query::Query;
 use crate::runtime::context::*;
+use crate::runtime::group::FunctionGroup;
 use crate::runtime::plan::CloudExecutionPlan;
+use crate::runtime::subscription::Subscription;
 use crate::state::*;
 use async_trait::async_trait;
 use daggy::NodeIndex;
+use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::ExecutionPlan;
 use log::debug;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
@@ -59,7 +66,13 @@ impl Launcher for AwsLambdaLauncher {
         let plan = query.plan()?;
         let sink_type = query.datasink();
 
-        let planner = DistributedPlanner::new();
+        let mut planner = DistributedPlanner::new().with_source_tables(query.source_tables()?);
+        if let Some(interval_join) = query.interval_join() {
+            planner = planner.with_interval_join(interval_join);
+        }
+        if let Some(lookup_join) = query.lookup_join() {
+            planner = planner.with_lookup_join(lookup_join);
+        }
         let dag = planner.plan_query_stages(plan.clone()).await?;
 
         let mut query_code = query.query_code();
@@ -164,8 +177,17 @@ impl AwsLambdaLauncher {
                     CloudFunction::Lambda(format!("{}-{:02}", query_code, count - 1 - (i - 1)))
                 };
 
+                let mut plan = CloudExecutionPlan::new(node.stage.clone(), None)
+                    .with_inputs(node.inputs.clone());
+                if let Some(interval_join) = node.interval_join.clone() {
+                    plan = plan.with_interval_join(interval_join);
+                }
+                if let Some(lookup_join) = node.lookup_join.clone() {
+                    plan = plan.with_lookup_join(lookup_join);
+                }
+
                 let ctx = ExecutionContext {
-                    plan: CloudExecutionPlan::new(node.stage.clone(), None),
+                    plan,
                     name: format!("{}-{:02}", query_code, count - 1 - i),
                     next,
                     state_backend: self.state_backend.clone(),
@@ -199,6 +221,127 @@ impl AwsLambdaLauncher {
         Ok(())
     }
 
+    /// Returns the execution contexts of the query stages, starting from the
+    /// first stage.
+    pub fn contexts(&self) -> Vec<ExecutionContext> {
+        self.dag
+            .get_all_stages()
+            .into_iter()
+            .filter_map(|stage| stage.context.clone())
+            .collect()
+    }
+
+    /// Returns the first function of the query, which receives the windows
+    /// from the data source.
+    ///
+    /// # Arguments
+    /// * `group_size` - The size of the function groups.
+    pub fn entry_function(&self, group_size: usize) -> Result<CloudFunction> {
+        let stages = self.dag.get_all_stages();
+        let stage = stages
+            .first()
+            .ok_or_else(|| FlockError::Internal("The query has no stage.".to_owned()))?;
+        let name = stage
+            .context
+            .as_ref()
+            .map(|ctx| ctx.name.clone())
+            .ok_or_else(|| FlockError::Internal("The cloud contexts aren't created.".to_owned()))?;
+        Ok(match stage.get_function_type() {
+            CloudFunctionType::Group => CloudFunction::Group((name, group_size)),
+            _ => CloudFunction::Lambda(name),
+        })
+    }
+
+    /// Subscribes the query to a shared data source, which sends the query
+    /// only the columns it reads. The leaf nodes of the first stage are pruned
+    /// accordingly, so it must be called after the cloud contexts are created
+    /// and before the cloud functions are.
+    ///
+    /// # Arguments
+    /// * `source` - The name of the data source function.
+    /// * `relations` - The schemas of the source's relations.
+    /// * `group_size` - The size of the function groups.
+    pub async fn subscribe(
+        &mut self,
+        source: &str,
+        relations: &[SchemaRef],
+        group_size: usize,
+    ) -> Result<Subscription> {
+        let next = self.entry_function(group_size)?;
+        let query_code = self.query_code.clone().expect("query code not set");
+        let count = self.dag.node_count();
+        let ctx = self
+            .dag
+            .get_node_mut(NodeIndex::new(count - 1))
+            .and_then(|node| node.context.as_mut())
+            .ok_or_else(|| FlockError::Internal("The cloud contexts aren't created.".to_owned()))?;
+
+        let plan = ctx.plan.execution_plans[0].clone();
+        let subscription = Subscription::new(source, &query_code, next, relations, &plan);
+        ctx.plan = CloudExecutionPlan::new(vec![subscription.prune_plan(&plan)?], None)
+            .with_inputs(ctx.plan.inputs.clone());
+        subscription.subscribe().await?;
+        Ok(subscription)
+    }
+
+    /// Creates the cloud functions of the query stages. Each stage of a
+    /// function group is created as the members of the group, with a
+    /// concurrency of 1.
+    ///
+    /// # Arguments
+    /// * `group_size` - The size of the function groups.
+    /// * `architecture` - The instruction set architecture of the functions.
+    ///
+    /// # Returns
+    /// The execution contexts of the created functions.
+    pub async fn create_functions(
+        &self,
+        group_size: usize,
+        architecture: &str,
+    ) -> Result<Vec<ExecutionContext>> {
+        let contexts = self.contexts();
+        let mut groups = contexts
+            .iter()
+            .filter_map(|ctx| match &ctx.next {
+                CloudFunction::Group((name, size)) => Some((name.clone(), *size)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        if let CloudFunction::Group((name, size)) = self.entry_function(group_size)? {
+            groups.insert(name, size);
+        }
+
+        let mut created = vec![];
+        for ctx in contexts {
+            match groups.remove(&ctx.name) {
+                Some(size) => {
+                    for name in FunctionGroup::new(&ctx.name, size).members_at(0) {
+                        let mut member = ctx.clone();
+                        member.name = name;
+                        lambda::create_function(
+                            &member,
+                            FLOCK_CONFIG.lambda.realtime_aggreate_memory_size,
+                            architecture,
+                        )
+                        .await?;
+                        lambda::set_concurrency(&member.name, 1).await?;
+                        created.push(member);
+                    }
+                }
+                None => {
+                    lambda::create_function(
+                        &ctx,
+                        FLOCK_CONFIG.lambda.regular_memory_size,
+                        architecture,
+                    )
+                    .await?;
+                    created.push(ctx);
+                }
+            }
+        }
+        Ok(created)
+    }
+
     /// Create the cloud functions for the query.
     fn create_cloud_functions(&self) -> Result<()> {
         unimplemented!();
@@ -211,14 +354,20 @@ mod tests {
 
     use crate::assert_batches_eq;
     use crate::assert_batches_sorted_eq;
+    use crate::datasource::nexmark::event::side_input_schema;
     use crate::datasource::nexmark::event::{Auction, Bid, Person};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::datasource::ysb::event::{AdEvent, Campaign};
     use crate::datasource::ysb::YSBSource;
     use crate::datasource::DataSource;
+    use crate::encoding::Encoding;
     use crate::launcher::LocalLauncher;
     use crate::query::Table;
     use crate::query::{QueryType, StreamType};
+    use crate::runtime::arena::write_batches;
+    use crate::runtime::join::{IntervalJoin, JoinSide, INTERVAL_JOIN_INPUT};
+    use crate::runtime::lookup::{LookupJoin, LookupSource, LOOKUP_JOIN_INPUT};
+    use crate::runtime::payload::output_id;
     use crate::stream::{Schedule, Window};
     use crate::transmute::event_bytes_to_batch;
     use datafusion::arrow::array::*;
@@ -301,7 +450,10 @@ mod tests {
         )?;
 
         let stages = launcher.dag.get_all_stages();
-        let mut input = vec![vec![vec![batch1]], vec![vec![batch2]]];
+        let mut input = vec![
+            (query.tables()[0].0.clone(), vec![vec![batch1]]),
+            (query.tables()[1].0.clone(), vec![vec![batch2]]),
+        ];
         for (i, stage) in stages.into_iter().enumerate() {
             println!("=== Query Stage {:02} ===", i);
             let mut ctx = stage.context.clone().unwrap();
@@ -310,7 +462,8 @@ mod tests {
                 .execute()
                 .await?
                 .into_iter()
-                .map(|batches| vec![batches])
+                .enumerate()
+                .map(|(i, batches)| (output_id(i), vec![batches]))
                 .collect();
         }
 
@@ -420,7 +573,10 @@ mod tests {
             println!("=== Stage {} ===\n{}", i, stage.get_plan_str());
         }
 
-        let input = vec![vec![auctions_batches], vec![person_batches]];
+        let input = vec![
+            ("auction".to_owned(), vec![auctions_batches]),
+            ("person".to_owned(), vec![person_batches]),
+        ];
 
         // === Query Stage 0 ===
         let mut ctx = stages[0].context.clone().unwrap();
@@ -439,8 +595,11 @@ mod tests {
         assert!(!ctx.is_last_stage().await?);
         let mut output1 = vec![];
         for i in 0..num_partitions {
-            ctx.feed_data_sources(vec![vec![output[0][i].clone()], vec![output[1][i].clone()]])
-                .await?;
+            ctx.feed_data_sources(vec![
+                (output_id(0), vec![output[0][i].clone()]),
+                (output_id(1), vec![output[1][i].clone()]),
+            ])
+            .await?;
             let sliced_output = ctx.execute().await?;
             ctx.clean_data_sources().await?;
             assert!(sliced_output.len() == 1);
@@ -452,7 +611,8 @@ mod tests {
         assert!(!ctx.is_shuffling().await?);
         assert!(ctx.is_last_stage().await?);
         let output1 = output1.into_iter().flatten().collect::<Vec<_>>();
-        ctx.feed_data_sources(vec![vec![output1]]).await?;
+        ctx.feed_data_sources(vec![(output_id(0), vec![output1])])
+            .await?;
         let result = ctx.execute().await?;
         ctx.clean_data_sources().await?;
 
@@ -574,7 +734,10 @@ mod tests {
             );
         }
 
-        let input = vec![vec![auctions_batches], vec![bids_batches]];
+        let input = vec![
+            ("auction".to_owned(), vec![auctions_batches]),
+            ("bid".to_owned(), vec![bids_batches]),
+        ];
 
         // === Query Stage 0 ===
         let mut ctx = stages[0].context.clone().unwrap();
@@ -590,8 +753,8 @@ mod tests {
         let mut output1 = vec![];
         for i in 0..num_partitions {
             ctx.feed_data_sources(vec![
-                vec![output0[0][i].clone()],
-                vec![output0[1][i].clone()],
+                (output_id(0), vec![output0[0][i].clone()]),
+                (output_id(1), vec![output0[1][i].clone()]),
             ])
             .await?;
             // We **MUST USE** execute_partitioned() instead of execute() here.
@@ -625,7 +788,8 @@ mod tests {
             for o1 in output1.iter().take(num_partitions) {
                 output1_partition.push(o1[i].clone());
             }
-            ctx.feed_data_sources(vec![output1_partition]).await?;
+            ctx.feed_data_sources(vec![(output_id(0), output1_partition)])
+                .await?;
             // We **MUST USE** execute_partitioned() instead of execute() here.
             let sliced_output = ctx.execute_partitioned().await?;
             ctx.clean_data_sources().await?;
@@ -655,7 +819,8 @@ mod tests {
             // plan is `FinalPartitioned` and all shuffled inputs belong to the same
             // partition.
             let output2_partition = output2_partition.into_iter().flatten().collect::<Vec<_>>();
-            ctx.feed_data_sources(vec![vec![output2_partition]]).await?;
+            ctx.feed_data_sources(vec![(output_id(0), vec![output2_partition])])
+                .await?;
             let sliced_output = ctx.execute().await?;
 
             ctx.clean_data_sources().await?;
@@ -677,6 +842,412 @@ mod tests {
         Ok(())
     }
 
+    /// Runs a single-table aggregate query as cloud functions. The source stage
+    /// is split across `invocations` lambda instances, and each shuffled
+    /// partition is merged by one group member.
+    ///
+    /// Returns the query result and the number of rows sent to the group.
+    async fn distributed_aggregate(
+        query: &Query,
+        input: Vec<RecordBatch>,
+        invocations: usize,
+    ) -> Result<(Vec<RecordBatch>, usize)> {
+        let mut launcher = AwsLambdaLauncher::new(query).await?;
+        launcher.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
+
+        let stages = launcher.dag.get_all_stages();
+        assert_eq!(2, stages.len());
+        assert_eq!(CloudFunctionType::Lambda, stages[0].get_function_type());
+        assert_eq!(CloudFunctionType::Group, stages[1].get_function_type());
+        assert!(stages[0].get_plan_str().contains("mode=Partial"));
+        assert!(stages[1].get_plan_str().contains("mode=Final"));
+
+        // The contexts are shipped to the cloud functions as JSON.
+        let ship = |ctx: &ExecutionContext| unmarshal(marshal(ctx, Encoding::default())?);
+
+        // === Query Stage 0 ===
+        let table = query.tables()[0].0.clone();
+        let mut ctx = ship(stages[0].context.as_ref().unwrap())?;
+        let mut partitions: Vec<Vec<RecordBatch>> = vec![];
+        for i in 0..invocations {
+            let batches = input
+                .iter()
+                .skip(i)
+                .step_by(invocations)
+                .cloned()
+                .collect::<Vec<_>>();
+            ctx.feed_data_sources(vec![(table.clone(), vec![batches])])
+                .await?;
+            let output = if ctx.is_shuffling().await? {
+                ctx.execute_partitioned().await?.remove(0)
+            } else {
+                vec![ctx.execute().await?.remove(0)]
+            };
+            ctx.clean_data_sources().await?;
+            partitions.resize(output.len(), vec![]);
+            partitions
+                .iter_mut()
+                .zip(output)
+                .for_each(|(p, batches)| p.extend(batches));
+        }
+        let shipped = partitions
+            .iter()
+            .flatten()
+            .map(|b| b.num_rows())
+            .sum::<usize>();
+
+        // === Query Stage 1 ===
+        let mut ctx = ship(stages[1].context.as_ref().unwrap())?;
+        let mut result = vec![];
+        for partition in partitions {
+            ctx.feed_data_sources(vec![(output_id(0), vec![partition])])
+                .await?;
+            result.extend(ctx.execute().await?.into_iter().flatten());
+            ctx.clean_data_sources().await?;
+        }
+
+        Ok((result, shipped))
+    }
+
+    #[tokio::test]
+    async fn aws_launcher_nexmark_partial_aggregate() -> Result<()> {
+        let bid_schema = Arc::new(Bid::schema());
+
+        // Generate events.
+        let seconds = 1;
+        let threads = 1;
+        let event_per_second = 1000;
+        let nexmark_source =
+            NEXMarkSource::new(seconds, threads, event_per_second, Window::ElementWise);
+        let stream = nexmark_source.generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let bids_batches = event_bytes_to_batch(&events.bids, bid_schema.clone(), 128);
+
+        let invocations = 4;
+        for sql in [
+            // The inner aggregation of NEXMark Query 5
+            "SELECT auction, COUNT(*) AS num FROM bid GROUP BY auction;",
+            // The inner aggregation of NEXMark Query 7
+            "SELECT MAX(price) AS maxprice FROM bid;",
+            "SELECT bidder, COUNT(*), MIN(price), MAX(price), AVG(price) FROM bid GROUP BY bidder;",
+        ] {
+            let query = Query::new(
+                sql,
+                vec![Table("bid".to_string(), bid_schema.clone())],
+                DataSource::Memory,
+                DataSinkType::Blackhole,
+                None,
+                QueryType::Streaming(StreamType::NEXMarkBench),
+                Arc::new(HashMapStateBackend::new()),
+            );
+
+            let (result, shipped) =
+                distributed_aggregate(&query, bids_batches.clone(), invocations).await?;
+            let formatted = pretty_format_batches(&result).unwrap().to_string();
+            let expected: Vec<&str> = formatted.trim().lines().collect();
+
+            // Centralized execution mode
+            let mut launcher = LocalLauncher::new(&query).await?;
+            launcher.feed_data_sources(vec![("bid".to_owned(), vec![bids_batches.clone()])]);
+            let batches = launcher.collect().await?;
+            assert_batches_sorted_eq!(expected, &batches);
+
+            // Each input batch contributes at most one partial state per group,
+            // instead of all its rows.
+            let groups = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+            assert!(shipped <= groups * bids_batches.len());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aws_launcher_nexmark_sketch_aggregate() -> Result<()> {
+        let bid_schema = Arc::new(Bid::schema());
+
+        let nexmark_source = NEXMarkSource::new(1, 1, 1000, Window::ElementWise);
+        let stream = nexmark_source.generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let bids_batches = event_bytes_to_batch(&events.bids, bid_schema.clone(), 128);
+
+        let query = |sql: &str| {
+            Query::new(
+                sql,
+                vec![Table("bid".to_string(), bid_schema.clone())],
+                DataSource::Memory,
+                DataSinkType::Blackhole,
+                None,
+                QueryType::Streaming(StreamType::NEXMarkBench),
+                Arc::new(HashMapStateBackend::new()),
+            )
+        };
+        let centralized = |query: Query| {
+            let batches = bids_batches.clone();
+            async move {
+                let mut launcher = LocalLauncher::new(&query).await?;
+                launcher.feed_data_sources(vec![("bid".to_owned(), vec![batches])]);
+                launcher.collect().await
+            }
+        };
+
+        // The merged HyperLogLog registers don't depend on how the input is
+        // split, so the distinct counts are the same as the centralized ones.
+        let sql =
+            "SELECT auction, approx_count_distinct(bidder) AS bidders FROM bid GROUP BY auction;";
+        let (result, _) = distributed_aggregate(&query(sql), bids_batches.clone(), 4).await?;
+        let formatted = pretty_format_batches(&result).unwrap().to_string();
+        let expected: Vec<&str> = formatted.trim().lines().collect();
+        let batches = centralized(query(sql)).await?;
+        assert_batches_sorted_eq!(expected, &batches);
+
+        let sql = "SELECT approx_percentile(price, 0.5) AS median, \
+                   approx_top_k(bidder, 3) AS top FROM bid;";
+        let (result, _) = distributed_aggregate(&query(sql), bids_batches.clone(), 4).await?;
+        let batches = centralized(query(sql)).await?;
+        assert_eq!(1, result.iter().map(|b| b.num_rows()).sum::<usize>());
+        let median = |batches: &[RecordBatch]| {
+            batches
+                .iter()
+                .find(|b| b.num_rows() > 0)
+                .and_then(|b| b.column(0).as_any().downcast_ref::<Float64Array>())
+                .map(|a| a.value(0))
+                .unwrap()
+        };
+        let (distributed, exact) = (median(&result), median(&batches));
+        assert!((distributed - exact).abs() <= exact.abs() * 0.05);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aws_launcher_nexmark_interval_join() -> Result<()> {
+        let auction_schema = Arc::new(Auction::schema());
+        let person_schema = Arc::new(Person::schema());
+
+        // The bounds are wide enough for all the events of the epoch, so the
+        // interval join has the same matches as the hash join.
+        let hour = 3_600_000;
+        let query = Query::new(
+            "SELECT a_id, seller, a_date_time, p_id, name, p_date_time \
+             FROM auction INNER JOIN person ON seller = p_id;",
+            vec![
+                Table("auction".to_string(), auction_schema.clone()),
+                Table("person".to_string(), person_schema.clone()),
+            ],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::Streaming(StreamType::NEXMarkBench),
+            Arc::new(HashMapStateBackend::new()),
+        )
+        .with_interval_join(
+            IntervalJoin::new(
+                JoinSide::new("auction", "seller", "a_date_time"),
+                JoinSide::new("person", "p_id", "p_date_time"),
+                hour,
+                hour,
+            )
+            .with_allowed_lateness(hour),
+        );
+
+        let mut launcher = AwsLambdaLauncher::new(&query).await?;
+        launcher.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
+        let stages = launcher.dag.get_all_stages();
+        assert_eq!(2, stages.len());
+        assert_eq!(CloudFunctionType::Group, stages[1].get_function_type());
+        assert!(!stages[1].get_plan_str().contains("HashJoinExec"));
+
+        let nexmark_source = NEXMarkSource::new(1, 1, 1000, Window::ElementWise);
+        let stream = nexmark_source.generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let auctions_batches = event_bytes_to_batch(&events.auctions, auction_schema, 16);
+        let person_batches = event_bytes_to_batch(&events.persons, person_schema, 16);
+
+        // The contexts are shipped to the cloud functions as JSON.
+        let ship = |ctx: &ExecutionContext| unmarshal(marshal(ctx, Encoding::default())?);
+        let mut ctx0 = ship(stages[0].context.as_ref().unwrap())?;
+        let mut ctx1 = ship(stages[1].context.as_ref().unwrap())?;
+        let join = ctx1.plan.interval_join.clone().unwrap();
+
+        // The events arrive in two invocations, so the matches of the later rows
+        // with the rows of the first invocation come from the join states.
+        let mut result = vec![];
+        for (window, (auctions, persons)) in [
+            (
+                &auctions_batches[..auctions_batches.len() / 2],
+                &person_batches[..person_batches.len() / 2],
+            ),
+            (
+                &auctions_batches[auctions_batches.len() / 2..],
+                &person_batches[person_batches.len() / 2..],
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            // === Query Stage 0 ===
+            ctx0.feed_data_sources(vec![
+                ("auction".to_owned(), vec![auctions.to_vec()]),
+                ("person".to_owned(), vec![persons.to_vec()]),
+            ])
+            .await?;
+            let output = ctx0.execute_partitioned().await?;
+            ctx0.clean_data_sources().await?;
+
+            // === Query Stage 1 ===
+            for partition in 0..output[0].len() {
+                let matches = join
+                    .process(
+                        ctx1.state_backend.as_ref(),
+                        &FLOCK_S3_BUCKET,
+                        &format!("{}-{:02}", ctx1.name, partition),
+                        partition,
+                        &format!("window-{}", window),
+                        vec![
+                            (output_id(0), output[0][partition].clone()),
+                            (output_id(1), output[1][partition].clone()),
+                        ],
+                    )
+                    .await?;
+                ctx1.feed_data_sources(vec![(INTERVAL_JOIN_INPUT.to_owned(), vec![matches])])
+                    .await?;
+                result.extend(ctx1.execute().await?.into_iter().flatten());
+                ctx1.clean_data_sources().await?;
+            }
+        }
+        assert!(!result.is_empty());
+        let formatted = pretty_format_batches(&result).unwrap().to_string();
+        let expected: Vec<&str> = formatted.trim().lines().collect();
+
+        // Centralized execution mode
+        let mut launcher = LocalLauncher::new(&query).await?;
+        launcher.feed_data_sources(vec![
+            ("auction".to_owned(), vec![auctions_batches]),
+            ("person".to_owned(), vec![person_batches]),
+        ]);
+        let batches = launcher.collect().await?;
+        assert_batches_sorted_eq!(expected, &batches);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aws_launcher_nexmark_lookup_join() -> Result<()> {
+        let bid_schema = Arc::new(Bid::schema());
+        let side_input_schema = Arc::new(side_input_schema());
+
+        let nexmark_source = NEXMarkSource::new(1, 1, 1000, Window::ElementWise);
+        let stream = nexmark_source.generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let bids_batches = event_bytes_to_batch(&events.bids, bid_schema.clone(), 128);
+
+        // The reference table has every other auction of the bids.
+        let mut auctions = bids_batches
+            .iter()
+            .flat_map(|b| {
+                let column = b.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+                column.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        auctions.sort_unstable();
+        auctions.dedup();
+        let keys = auctions.into_iter().step_by(2).collect::<Vec<_>>();
+        let values = keys.iter().map(|k| k % 7).collect::<Vec<_>>();
+        let side_input = RecordBatch::try_new(
+            side_input_schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(keys)),
+                Arc::new(Int32Array::from(values)),
+            ],
+        )?;
+
+        let state_backend: Arc<dyn StateBackend> = Arc::new(HashMapStateBackend::new());
+        let key = "lookup/nexmark_q13";
+        let mut bytes = vec![];
+        write_batches(&mut bytes, &side_input_schema, &[side_input.clone()])?;
+        state_backend
+            .write(FLOCK_S3_BUCKET.clone(), key.to_owned(), bytes)
+            .await?;
+
+        let query = Query::new(
+            include_str!("../../../../benchmarks/src/nexmark/query/q13.sql"),
+            vec![
+                Table("bid".to_string(), bid_schema.clone()),
+                Table("side_input".to_string(), side_input_schema.clone()),
+            ],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::Streaming(StreamType::NEXMarkBench),
+            state_backend.clone(),
+        )
+        .with_lookup_join(
+            LookupJoin::new(
+                LookupSource::State {
+                    bucket: FLOCK_S3_BUCKET.clone(),
+                    key:    key.to_owned(),
+                },
+                "bid",
+                "auction",
+                "key",
+            )
+            .with_schema(side_input_schema.clone()),
+        );
+
+        let mut launcher = AwsLambdaLauncher::new(&query).await?;
+        launcher.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
+        let stages = launcher.dag.get_all_stages();
+        assert_eq!(2, stages.len());
+        // The first stage only reads the stream.
+        assert_eq!(1, stages[0].stage.len());
+        assert!(!stages[1].get_plan_str().contains("HashJoinExec"));
+
+        // The contexts are shipped to the cloud functions as JSON.
+        let ship = |ctx: &ExecutionContext| unmarshal(marshal(ctx, Encoding::default())?);
+        let mut ctx0 = ship(stages[0].context.as_ref().unwrap())?;
+        let mut ctx1 = ship(stages[1].context.as_ref().unwrap())?;
+        let join = ctx1.plan.lookup_join.clone().unwrap();
+
+        // === Query Stage 0 ===
+        ctx0.feed_data_sources(vec![("bid".to_owned(), vec![bids_batches.clone()])])
+            .await?;
+        let stream = if ctx0.is_shuffling().await? {
+            ctx0.execute_partitioned()
+                .await?
+                .remove(0)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+        } else {
+            ctx0.execute().await?.remove(0)
+        };
+
+        // === Query Stage 1 ===
+        let enriched = join.process(stream, ctx1.state_backend.as_ref()).await?;
+        ctx1.feed_data_sources(vec![(LOOKUP_JOIN_INPUT.to_owned(), vec![enriched])])
+            .await?;
+        let result = ctx1
+            .execute()
+            .await?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert!(!result.is_empty());
+        let formatted = pretty_format_batches(&result).unwrap().to_string();
+        let expected: Vec<&str> = formatted.trim().lines().collect();
+
+        // Centralized execution mode
+        let mut launcher = LocalLauncher::new(&query).await?;
+        launcher.feed_data_sources(vec![
+            ("bid".to_owned(), vec![bids_batches]),
+            ("side_input".to_owned(), vec![vec![side_input]]),
+        ]);
+        let batches = launcher.collect().await?;
+        assert_batches_sorted_eq!(expected, &batches);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn aws_launcher_nexmark_ysb_hash_join() -> Result<()> {
         let ad_event_schema = Arc::new(AdEvent::schema());
@@ -766,7 +1337,10 @@ mod tests {
         }
         assert!(launcher.dag.node_count() == 3);
 
-        let input = vec![vec![ad_events_batches], vec![campaigns_batches]];
+        let input = vec![
+            ("ad_event".to_owned(), vec![ad_events_batches]),
+            ("campaign".to_owned(), vec![campaigns_batches]),
+        ];
 
         // === Query Stage 0 ===
         let mut ctx = stages[0].context.clone().unwrap();
@@ -782,8 +1356,8 @@ mod tests {
         let mut output1 = vec![];
         for i in 0..num_partitions {
             ctx.feed_data_sources(vec![
-                vec![output0[0][i].clone()],
-                vec![output0[1][i].clone()],
+                (output_id(0), vec![output0[0][i].clone()]),
+                (output_id(1), vec![output0[1][i].clone()]),
             ])
             .await?;
             // We **MUST USE** execute_partitioned() instead of execute() here.
@@ -821,7 +1395,8 @@ mod tests {
             // plan is `FinalPartitioned` and all shuffled inputs belong to the same
             // partition.
             let output1_partition = output1_partition.into_iter().flatten().collect::<Vec<_>>();
-            ctx.feed_data_sources(vec![vec![output1_partition]]).await?;
+            ctx.feed_data_sources(vec![(output_id(0), vec![output1_partition])])
+                .await?;
             let sliced_output = ctx.execute().await?;
 
             ctx.clean_data_sources().await?;
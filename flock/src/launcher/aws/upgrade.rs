@@ -0,0 +1,555 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Blue/green upgrades of a running query.
+//!
+//! Redeploying a changed query yields a new query code, so the new functions
+//! start with empty arenas. Instead of tearing the running query (blue) down
+//! first, the upgraded query (green) is deployed alongside it:
+//!
+//! 1. [`BlueGreenUpgrade::deploy`] creates and registers the green functions.
+//! 2. [`BlueGreenUpgrade::switch`] saves the [`Route`] from blue's first
+//!    function to green's, which takes effect at the first window boundary
+//!    after the grace period of the function groups (`group.grace_period`).
+//! 3. Blue processes the windows that started before the switch until it's
+//!    drained (`group.drain_timeout`).
+//! 4. [`BlueGreenUpgrade::migrate_state`] moves the keyed state of blue's
+//!    function groups, i.e., the interval join states, the pane states and the
+//!    heavy keys, to the green stages, and the checkpoints of the windows blue
+//!    couldn't complete to the green members that own them, if their data
+//!    fragments match the inputs of the green stage. Then it invokes the
+//!    members to resume the windows.
+//! 5. [`BlueGreenUpgrade::retire`] tears blue down.
+
+use crate::aws::lambda;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::launcher::AwsLambdaLauncher;
+use crate::registry::{QueryRecord, QueryRegistry};
+use crate::runtime::arena::{checkpoint_key, WindowCheckpoint, CHECKPOINT_KEY_PREFIX};
+use crate::runtime::context::CloudFunction;
+use crate::runtime::deadline::Continuation;
+use crate::runtime::group::{group_of, FunctionGroup};
+use crate::runtime::join::JOIN_STATE_KEY_PREFIX;
+use crate::runtime::pane::PANE_STATE_KEY_PREFIX;
+use crate::runtime::route::{window_boundary, Route};
+use crate::runtime::schema::check_compatibility;
+use crate::runtime::skew::SKEW_KEY_PREFIX;
+use crate::state::StateBackend;
+use chrono::Utc;
+use datafusion::arrow::datatypes::SchemaRef;
+use log::{info, warn};
+use std::collections::HashMap;
+
+/// The function group and the input schemas of each stage of the upgraded
+/// query, keyed by the stage's function name.
+type Stages = HashMap<String, (FunctionGroup, Vec<SchemaRef>)>;
+
+/// A blue/green upgrade of a running query.
+#[derive(Debug, Clone)]
+pub struct BlueGreenUpgrade {
+    /// The running query.
+    pub blue:  QueryRecord,
+    /// The upgraded query.
+    pub green: QueryRecord,
+    /// The route from blue's first function to green's.
+    pub route: Route,
+}
+
+impl BlueGreenUpgrade {
+    /// Deploys the upgraded query alongside the running one.
+    ///
+    /// # Arguments
+    /// * `launcher` - The launcher of the upgraded query.
+    /// * `blue` - The query code of the running query.
+    /// * `sql` - The SQL statement of the upgraded query.
+    /// * `window_size` - The window size of the query in seconds, which aligns
+    ///   the switch to a window boundary.
+    /// * `architecture` - The instruction set architecture of the functions.
+    /// * `registry` - The query registry.
+    pub async fn deploy(
+        launcher: &mut AwsLambdaLauncher,
+        blue: &str,
+        sql: &str,
+        window_size: i64,
+        architecture: &str,
+        registry: &QueryRegistry,
+    ) -> Result<Self> {
+        let blue = registry.describe(blue).await?;
+        let green_code = launcher.query_code.clone().expect("query code not set");
+        if green_code == blue.query_code {
+            return Err(FlockError::Config(format!(
+                "The upgraded query has the same query code as the running one: {}",
+                green_code
+            )));
+        }
+
+        let group_size = *FLOCK_FUNCTION_CONCURRENCY;
+        launcher.create_cloud_contexts(group_size)?;
+        let contexts = launcher.create_functions(group_size, architecture).await?;
+        let green = QueryRecord::new(&green_code, sql, launcher.sink_type.clone(), &contexts);
+        registry.register(&green).await?;
+
+        let switch_at = window_boundary(
+            Utc::now().timestamp() + FLOCK_CONFIG.group.grace_period,
+            window_size,
+        );
+        let route = Route::new(
+            &entry_name(&blue.query_code),
+            launcher.entry_function(group_size)?,
+            switch_at,
+        );
+        info!(
+            "Deployed {} alongside {}, switching at {}.",
+            green.query_code, blue.query_code, switch_at
+        );
+
+        Ok(BlueGreenUpgrade { blue, green, route })
+    }
+
+    /// Switches the data sources from blue to green at the window boundary of
+    /// the route.
+    pub async fn switch(&self) -> Result<()> {
+        self.route.save().await
+    }
+
+    /// Returns true if no fragment of blue's windows is expected anymore.
+    pub fn is_drained(&self, now: i64) -> bool {
+        now >= self.route.switch_at + FLOCK_CONFIG.group.drain_timeout
+    }
+
+    /// Migrates the keyed state and the incomplete windows of blue to green
+    /// after blue is drained. The windows whose fragments don't match the
+    /// inputs of the green stage are left to blue's teardown.
+    ///
+    /// # Arguments
+    /// * `launcher` - The launcher of the upgraded query.
+    ///
+    /// # Returns
+    /// The number of migrated windows.
+    pub async fn migrate_state(&self, launcher: &AwsLambdaLauncher) -> Result<usize> {
+        let mut stages = Stages::new();
+        let mut groups = launcher
+            .contexts()
+            .iter()
+            .map(|ctx| ctx.next.clone())
+            .collect::<Vec<_>>();
+        groups.push(self.route.to.clone());
+        for mut ctx in launcher.contexts() {
+            let group = groups
+                .iter()
+                .map(FunctionGroup::from_cloud_function)
+                .find(|g| g.name == ctx.name)
+                .unwrap_or_else(|| FunctionGroup::new(&ctx.name, 1));
+            stages.insert(ctx.name.clone(), (group, ctx.data_source_schemas().await?));
+        }
+
+        let keys = migrate_keyed_state(
+            launcher.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &self.blue.query_code,
+            &self.green.query_code,
+            &stages,
+        )
+        .await?;
+        let migrated = migrate_checkpoints(
+            launcher.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &self.blue.query_code,
+            &self.green.query_code,
+            &stages,
+        )
+        .await?;
+
+        // The green members resume the windows as if they were handed over
+        // near the deadline.
+        for (function_name, checkpoint) in &migrated {
+            if let Some(mut header) = checkpoint.header.clone() {
+                Continuation::default().to_metadata(&mut header.metadata)?;
+                lambda::invoke_function(
+                    function_name,
+                    &FLOCK_LAMBDA_ASYNC_CALL,
+                    Some(serde_json::to_vec(&header)?.into()),
+                )
+                .await?;
+            }
+        }
+        info!(
+            "Migrated {} windows and {} keyed states from {} to {}.",
+            migrated.len(),
+            keys,
+            self.blue.query_code,
+            self.green.query_code
+        );
+        Ok(migrated.len())
+    }
+
+    /// Tears blue down after it's drained. The route is kept, so that the data
+    /// sources that still name blue reach green.
+    pub async fn retire(&self, registry: &QueryRegistry) -> Result<()> {
+        if !self.is_drained(Utc::now().timestamp()) {
+            return Err(FlockError::Internal(format!(
+                "The query {} isn't drained before {}.",
+                self.blue.query_code,
+                self.route.switch_at + FLOCK_CONFIG.group.drain_timeout
+            )));
+        }
+        registry.teardown(&self.blue.query_code).await?;
+        info!(
+            "Retired {} in favor of {}.",
+            self.blue.query_code, self.green.query_code
+        );
+        Ok(())
+    }
+}
+
+/// Returns the name of the first function of the query.
+fn entry_name(query_code: &str) -> String {
+    format!("{}-{:02}", query_code, 0)
+}
+
+/// Returns the name of the green stage that corresponds to the blue function,
+/// i.e., the stage with the same index in the upgraded query.
+fn green_stage(blue_function: &str, blue_code: &str, green_code: &str) -> Option<String> {
+    group_of(blue_function)
+        .strip_prefix(&format!("{}-", blue_code))
+        .map(|stage| format!("{}-{}", green_code, stage))
+}
+
+/// Moves the state that blue's function groups share among their members, i.e.,
+/// the interval join states, the pane states and the heavy keys, under the
+/// group of the corresponding green stage. The keys within a group, such as
+/// the partitions and the pane owners, are kept, so the windows and the joins
+/// started on blue continue on green.
+///
+/// # Returns
+/// The number of migrated keys.
+async fn migrate_keyed_state(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    blue_code: &str,
+    green_code: &str,
+    stages: &Stages,
+) -> Result<usize> {
+    let mut migrated = 0;
+    for kind in [
+        JOIN_STATE_KEY_PREFIX,
+        PANE_STATE_KEY_PREFIX,
+        SKEW_KEY_PREFIX,
+    ] {
+        let prefix = format!("{}/{}-", kind, blue_code);
+        for key in state_backend.keys(bucket.to_owned(), prefix).await? {
+            let mut parts = key.splitn(3, '/').skip(1);
+            let (blue_group, rest) = match (parts.next(), parts.next()) {
+                (Some(group), Some(rest)) => (group, rest),
+                _ => continue,
+            };
+            let green_group = match green_stage(blue_group, blue_code, green_code)
+                .filter(|stage| stages.contains_key(stage))
+            {
+                Some(stage) => stage,
+                None => {
+                    warn!("No stage of {} matches the state {}.", green_code, key);
+                    continue;
+                }
+            };
+            let bytes = match state_backend.get(bucket.to_owned(), key.clone()).await? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            state_backend
+                .write(
+                    bucket.to_owned(),
+                    format!("{}/{}/{}", kind, green_group, rest),
+                    bytes,
+                )
+                .await?;
+            state_backend.delete(bucket.to_owned(), key).await?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+/// Moves the checkpoints of blue's windows to the green members that own
+/// them, if their data fragments match the inputs of the green stage.
+///
+/// # Returns
+/// The green members and the checkpoints of the migrated windows.
+async fn migrate_checkpoints(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    blue_code: &str,
+    green_code: &str,
+    stages: &Stages,
+) -> Result<Vec<(String, WindowCheckpoint)>> {
+    let prefix = format!("{}/{}-", CHECKPOINT_KEY_PREFIX, blue_code);
+    let mut migrated = vec![];
+    for key in state_backend.keys(bucket.to_owned(), prefix).await? {
+        let bytes = match state_backend.get(bucket.to_owned(), key.clone()).await? {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let checkpoint: WindowCheckpoint = serde_json::from_slice(&bytes)?;
+        let blue_function = key.split('/').nth(1).unwrap_or_default();
+        let (group, schemas) = match green_stage(blue_function, blue_code, green_code)
+            .and_then(|stage| stages.get(&stage))
+        {
+            Some(stage) => stage,
+            None => {
+                warn!(
+                    "No stage of {} matches {} for the window {:?}.",
+                    green_code, blue_function, checkpoint.window_id
+                );
+                continue;
+            }
+        };
+        if let Err(e) = checkpoint
+            .schemas()?
+            .iter()
+            .try_for_each(|schema| check_compatibility(schemas, schema))
+        {
+            warn!(
+                "The window {:?} can't be migrated: {}",
+                checkpoint.window_id, e
+            );
+            continue;
+        }
+
+        let member = group.route(&checkpoint.window_id.0)?;
+        state_backend
+            .write(
+                bucket.to_owned(),
+                checkpoint_key(&member, &checkpoint.window_id),
+                bytes,
+            )
+            .await?;
+        state_backend.delete(bucket.to_owned(), key).await?;
+        migrated.push((member, checkpoint));
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::arena::Arena;
+    use crate::runtime::join::join_state_prefix;
+    use crate::runtime::pane::{Pane, PaneStore, PANE_INPUT};
+    use crate::runtime::payload::{InputBatches, UuidBuilder};
+    use crate::state::HashMapStateBackend;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn green_stage_of_blue_function() {
+        let (blue, green) = ("SX72HzqFz1Qij4bP", "QzVj8yXGgEW1hcRw");
+        assert_eq!(
+            Some("QzVj8yXGgEW1hcRw-01".to_owned()),
+            green_stage("SX72HzqFz1Qij4bP-01-03", blue, green)
+        );
+        assert_eq!(
+            Some("QzVj8yXGgEW1hcRw-02".to_owned()),
+            green_stage("SX72HzqFz1Qij4bP-02", blue, green)
+        );
+        assert_eq!(None, green_stage("flock_datasource", blue, green));
+    }
+
+    #[tokio::test]
+    async fn migrate_compatible_windows() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let bucket = "flock-upgrade";
+        let (blue, green) = ("SX72HzqFz1Qij4bP", "QzVj8yXGgEW1hcRw");
+
+        let ints = Arc::new(Schema::new(vec![Field::new("v", DataType::Int64, false)]));
+        let strings = Arc::new(Schema::new(vec![Field::new("s", DataType::Utf8, false)]));
+        let ints_batch =
+            RecordBatch::try_new(ints.clone(), vec![Arc::new(Int64Array::from(vec![1, 2]))])?;
+        let strings_batch =
+            RecordBatch::try_new(strings, vec![Arc::new(StringArray::from(vec!["a"]))])?;
+
+        // Blue's aggregate stages collect a fragment of a window each.
+        let mut windows = vec![];
+        for (function_name, batch) in [
+            ("SX72HzqFz1Qij4bP-01-00", ints_batch),
+            ("SX72HzqFz1Qij4bP-02", strings_batch),
+        ] {
+            let uuids = UuidBuilder::new_with_ts(&group_of(function_name), 1024, 2);
+            let payload = to_payload(&[("input", &[batch])], uuids.get(1), false);
+            let window_id = payload.get_window_id();
+            let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX);
+            arena.collect(payload)?;
+            assert!(
+                arena
+                    .checkpoint_window(&state_backend, bucket, function_name, &window_id)
+                    .await?
+            );
+            windows.push(window_id);
+        }
+
+        // The first stage of green keeps the input of blue, but the second one
+        // doesn't.
+        let mut stages = Stages::new();
+        stages.insert(
+            "QzVj8yXGgEW1hcRw-01".to_owned(),
+            (FunctionGroup::new("QzVj8yXGgEW1hcRw-01", 4), vec![ints]),
+        );
+        let drifted = Arc::new(Schema::new(vec![Field::new("s", DataType::Int64, false)]));
+        stages.insert(
+            "QzVj8yXGgEW1hcRw-02".to_owned(),
+            (FunctionGroup::new("QzVj8yXGgEW1hcRw-02", 1), vec![drifted]),
+        );
+
+        let migrated = migrate_checkpoints(&state_backend, bucket, blue, green, &stages).await?;
+        assert_eq!(1, migrated.len());
+        let (member, checkpoint) = &migrated[0];
+        assert!(member.starts_with("QzVj8yXGgEW1hcRw-01-"));
+        assert_eq!(windows[0], checkpoint.window_id);
+        assert!(checkpoint.header.is_some());
+
+        // The migrated window is restored by the green member.
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX);
+        assert!(
+            arena
+                .restore_window(&state_backend, bucket, member, &windows[0])
+                .await?
+        );
+        assert_eq!(1, arena.get(&windows[0]).unwrap().received());
+
+        // The incompatible window is left to blue.
+        let keys = |prefix: String| state_backend.keys(bucket.to_owned(), prefix);
+        assert!(keys(format!("{}/{}-01-00/", CHECKPOINT_KEY_PREFIX, blue))
+            .await?
+            .is_empty());
+        assert_eq!(
+            1,
+            keys(format!("{}/{}-02/", CHECKPOINT_KEY_PREFIX, blue))
+                .await?
+                .len()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_heavy_keys() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let bucket = "flock-upgrade-keys";
+        let (blue, green) = ("SX72HzqFz1Qij4bP", "QzVj8yXGgEW1hcRw");
+        let key = |group: &str| format!("{}/{}/1", SKEW_KEY_PREFIX, group);
+
+        // The second stage of blue has no counterpart in green.
+        for group in ["SX72HzqFz1Qij4bP-01", "SX72HzqFz1Qij4bP-02"] {
+            state_backend
+                .write(bucket.to_owned(), key(group), vec![1])
+                .await?;
+        }
+        let mut stages = Stages::new();
+        stages.insert(
+            "QzVj8yXGgEW1hcRw-01".to_owned(),
+            (FunctionGroup::new("QzVj8yXGgEW1hcRw-01", 2), vec![]),
+        );
+        assert_eq!(
+            1,
+            migrate_keyed_state(&state_backend, bucket, blue, green, &stages).await?
+        );
+        assert_eq!(
+            Some(vec![1]),
+            state_backend
+                .get(bucket.to_owned(), key("QzVj8yXGgEW1hcRw-01"))
+                .await?
+        );
+        assert_eq!(
+            vec![key("SX72HzqFz1Qij4bP-02")],
+            state_backend
+                .keys(bucket.to_owned(), format!("{}/{}-", SKEW_KEY_PREFIX, blue))
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn complete_window_on_green() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let bucket = "flock-upgrade-panes";
+        let (blue, green) = ("SX72HzqFz1Qij4bP", "QzVj8yXGgEW1hcRw");
+
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int64, false)]));
+        let state = |v| -> Result<InputBatches> {
+            Ok(vec![(
+                PANE_INPUT.to_owned(),
+                vec![RecordBatch::try_new(
+                    schema.clone(),
+                    vec![Arc::new(Int64Array::from(vec![v]))],
+                )?],
+            )])
+        };
+
+        // Blue reduces the first pane of a window of two panes, and buffers the
+        // rows of an interval join.
+        let pane = |index| Pane::new(index, 2, 2).with_owner("stream".to_owned());
+        let store = |function_name: &str| {
+            PaneStore::new(&state_backend, bucket, function_name, "stream", 1)
+        };
+        let blue_store = store("SX72HzqFz1Qij4bP-01-00");
+        blue_store.write(&pane(0), &state(1)?).await?;
+        assert!(blue_store.complete_windows(&pane(0)).await?.is_empty());
+        let join_key = format!("{}/01/left", join_state_prefix("SX72HzqFz1Qij4bP-01-00"));
+        state_backend
+            .write(bucket.to_owned(), join_key, vec![1])
+            .await?;
+
+        let mut stages = Stages::new();
+        stages.insert(
+            "QzVj8yXGgEW1hcRw-01".to_owned(),
+            (
+                FunctionGroup::new("QzVj8yXGgEW1hcRw-01", 2),
+                vec![schema.clone()],
+            ),
+        );
+        assert_eq!(
+            2,
+            migrate_keyed_state(&state_backend, bucket, blue, green, &stages).await?
+        );
+        assert_eq!(
+            Some(vec![1]),
+            state_backend
+                .get(
+                    bucket.to_owned(),
+                    format!("{}/01/left", join_state_prefix("QzVj8yXGgEW1hcRw-01-01"))
+                )
+                .await?
+        );
+
+        // The second pane arrives at green and completes the window.
+        let green_store = store("QzVj8yXGgEW1hcRw-01-01");
+        green_store.write(&pane(1), &state(2)?).await?;
+        assert_eq!(vec![0], green_store.complete_windows(&pane(1)).await?);
+        let window = green_store.read_window(&pane(1), 0).await?;
+        assert_eq!(2, window[0].1.iter().map(|b| b.num_rows()).sum::<usize>());
+        assert!(state_backend
+            .keys(
+                bucket.to_owned(),
+                format!("{}/{}-", PANE_STATE_KEY_PREFIX, blue)
+            )
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+}
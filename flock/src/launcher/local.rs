@@ -13,23 +13,26 @@
 
 //! This crate responsibles for executing queries on the local machine.
 
+use crate::datasource::InputPartitions;
 use crate::error::{FlockError, Result};
 use crate::launcher::{ExecutionMode, Launcher};
 use crate::query::Query;
+use crate::runtime::context::{leaf_nodes, match_input};
+use crate::runtime::payload::InputId;
 use async_trait::async_trait;
-use datafusion::arrow::datatypes::{Schema, SchemaRef};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::collect;
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::ExecutionPlan;
-use std::collections::HashSet;
-use std::collections::VecDeque;
 use std::sync::Arc;
 
 /// LocalLauncher executes the query locally.
 pub struct LocalLauncher {
     /// The physical plan of the query.
     execution_plan: Arc<dyn ExecutionPlan>,
+    /// The source tables scanned by the leaf nodes of the plan, from left to
+    /// right.
+    inputs:         Vec<InputId>,
 }
 
 #[async_trait]
@@ -38,8 +41,14 @@ impl Launcher for LocalLauncher {
     where
         Self: Sized,
     {
+        let execution_plan = query.plan()?;
+        let mut inputs = query.source_tables()?;
+        if inputs.len() != leaf_nodes(&[execution_plan.clone()]).len() {
+            inputs.clear();
+        }
         Ok(LocalLauncher {
-            execution_plan: query.plan().unwrap(),
+            execution_plan,
+            inputs,
         })
     }
 
@@ -58,83 +67,35 @@ impl Launcher for LocalLauncher {
 }
 
 impl LocalLauncher {
-    /// Compare two execution plans' schemas.
-    /// Returns true if they are belong to the same plan node.
+    /// Feeds the query with data. Each leaf `MemoryExec` is fed the data
+    /// source of the table it scans, or the data source whose schema matches
+    /// if the source tables of the query are unknown.
     ///
     /// # Arguments
-    /// * `schema1` - The first schema.
-    /// * `schema2` - The second schema.
-    ///
-    /// # Returns
-    /// * `true` - If the schemas belong to the same plan node.
-    fn compare_schema(schema1: SchemaRef, schema2: SchemaRef) -> bool {
-        let (superset, subset) = if schema1.fields().len() >= schema2.fields().len() {
-            (schema1, schema2)
-        } else {
-            (schema2, schema1)
-        };
-
-        let fields = superset
-            .fields()
-            .iter()
-            .map(|f| f.name())
-            .collect::<HashSet<_>>();
-
-        subset.fields().iter().all(|f| fields.contains(&f.name()))
-    }
-
-    /// Feeds the query with data.
-    ///
-    /// # Arguments
-    /// * `sources` - A list of data sources.
-    pub fn feed_data_sources(&mut self, mut sources: Vec<Vec<Vec<RecordBatch>>>) {
-        // Breadth-first search
-        let mut queue = VecDeque::new();
-        queue.push_back(self.execution_plan.clone());
-
-        let mut found = false;
-        let mut index = 0xFFFFFFFF;
-        while !queue.is_empty() {
-            let mut plan = queue.pop_front().unwrap();
-            if plan.children().is_empty() {
-                for (i, partition) in sources.iter().enumerate() {
-                    let mut schema = Arc::new(Schema::new(vec![]));
-                    let mut flag = false;
-                    for p in partition.iter().filter(|p| !p.is_empty()) {
-                        if let Some(b) = p.iter().next() {
-                            schema = b.schema();
-                            flag = true;
-                            break;
-                        }
-                    }
-                    if !flag {
-                        continue;
-                    }
-
-                    if LocalLauncher::compare_schema(plan.schema(), schema) {
-                        index = i;
-                        found = true;
-                        break;
-                    }
-                }
-
-                if found {
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(sources.remove(index));
-                        index = 0xFFFFFFFF;
-                        found = false;
-                    }
-                }
+    /// * `sources` - A list of data sources keyed by their table names.
+    pub fn feed_data_sources(&mut self, mut sources: InputPartitions) {
+        let leaves = leaf_nodes(&[self.execution_plan.clone()]);
+        for (i, mut leaf) in leaves.into_iter().enumerate() {
+            if !leaf.as_any().is::<MemoryExec>() {
+                continue;
+            }
+            let id = self.inputs.get(i);
+            let matched = match_input(&leaf, id, &sources, |partitions| {
+                partitions.iter().flatten().next().map(|b| b.schema())
+            });
+            let partitions = match matched {
+                // The scans of a self-join are all fed the same data source.
+                Some(index) if id.is_some() => sources[index].1.clone(),
+                Some(index) => sources.remove(index).1,
+                None => continue,
+            };
+            unsafe {
+                Arc::get_mut_unchecked(&mut leaf)
+                    .as_mut_any()
+                    .downcast_mut::<MemoryExec>()
+                    .unwrap()
+                    .set_partitions(partitions);
             }
-
-            plan.children()
-                .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(plan.children()[i].clone()));
         }
     }
 
@@ -217,7 +178,7 @@ mod tests {
             ],
         )?;
 
-        launcher.feed_data_sources(vec![vec![vec![batch]]]);
+        launcher.feed_data_sources(vec![("test_table".to_owned(), vec![vec![batch]])]);
         let batches = launcher.collect().await?;
 
         let expected = vec![
@@ -232,4 +193,111 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn local_launcher_self_join() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int32, false),
+        ]));
+        let sql = "SELECT l.k AS k, r.k AS rk FROM t AS l JOIN t AS r ON l.v = r.k ORDER BY k";
+        let query = Query::new(
+            sql,
+            vec![Table::new("t", schema.clone())],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+        assert_eq!(vec!["t".to_owned(), "t".to_owned()], query.source_tables()?);
+
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3])),
+                Arc::new(Int32Array::from(vec![2, 3, 4])),
+            ],
+        )?;
+
+        // Both scans of the table are fed the same data source.
+        let mut launcher = LocalLauncher::new(&query).await?;
+        launcher.feed_data_sources(vec![("t".to_owned(), vec![vec![batch]])]);
+        let batches = launcher.collect().await?;
+
+        let expected = vec![
+            "+---+----+",
+            "| k | rk |",
+            "+---+----+",
+            "| 1 | 2  |",
+            "| 2 | 3  |",
+            "+---+----+",
+        ];
+
+        assert_batches_eq!(&expected, &batches);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_launcher_tables_sharing_column_names() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int32, false),
+        ]));
+        let sql = concat!(
+            "SELECT t1.k AS k, t1.v AS v1, t2.v AS v2 ",
+            "FROM t1 JOIN t2 ON t1.k = t2.k ",
+            "ORDER BY k"
+        );
+        let query = Query::new(
+            sql,
+            vec![
+                Table::new("t1", schema.clone()),
+                Table::new("t2", schema.clone()),
+            ],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+
+        let batch1 = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(Int32Array::from(vec![10, 20])),
+            ],
+        )?;
+        let batch2 = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2])),
+                Arc::new(Int32Array::from(vec![100, 200])),
+            ],
+        )?;
+
+        // The schemas of both tables match both scans, so the data sources are
+        // only fed to the right scans by their table names.
+        let mut launcher = LocalLauncher::new(&query).await?;
+        launcher.feed_data_sources(vec![
+            ("t2".to_owned(), vec![vec![batch2]]),
+            ("t1".to_owned(), vec![vec![batch1]]),
+        ]);
+        let batches = launcher.collect().await?;
+
+        let expected = vec![
+            "+---+----+-----+",
+            "| k | v1 | v2  |",
+            "+---+----+-----+",
+            "| 1 | 10 | 100 |",
+            "| 2 | 20 | 200 |",
+            "+---+----+-----+",
+        ];
+
+        assert_batches_eq!(&expected, &batches);
+
+        Ok(())
+    }
 }
@@ -18,6 +18,7 @@ pub mod aws;
 pub mod azure;
 pub mod gcp;
 pub mod local;
+pub use aws::upgrade::BlueGreenUpgrade;
 pub use aws::AwsLambdaLauncher;
 pub use local::LocalLauncher;
 
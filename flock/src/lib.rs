@@ -30,16 +30,22 @@ pub mod aws;
 pub mod configs;
 pub mod datasink;
 pub mod datasource;
+pub mod deadletter;
 pub mod distributed_plan;
 pub mod driver;
 pub mod encoding;
 pub mod error;
 pub mod launcher;
+pub mod metrics;
 pub mod prelude;
+pub mod quarantine;
 pub mod query;
+pub mod registry;
 pub mod runtime;
+pub mod sketch;
 pub mod state;
 pub mod stream;
 pub mod test_util;
 pub mod tests;
+pub mod trace;
 pub mod transmute;
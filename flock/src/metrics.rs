@@ -0,0 +1,460 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module provides the structured runtime metrics of the cloud functions.
+//!
+//! Each function invocation records its metrics, such as rows and bytes in/out,
+//! decode/execute/encode times, arena size, window completion latency, retries
+//! and invocation scheduling, tagged by query code, stage and window id. The
+//! metrics are emitted to stdout either in the CloudWatch Embedded Metric
+//! Format (EMF), which CloudWatch turns into metrics automatically, or in JSON
+//! lines. The same lines can be aggregated locally with [`MetricsAggregator`].
+
+use crate::configs::FLOCK_CONFIG;
+use crate::error::{FlockError, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The number of rows fed to the function.
+pub const ROWS_IN: &str = "RowsIn";
+/// The number of rows produced by the function.
+pub const ROWS_OUT: &str = "RowsOut";
+/// The number of data bytes in the incoming payload.
+pub const BYTES_IN: &str = "BytesIn";
+/// The number of bytes sent to the next functions or sinks.
+pub const BYTES_OUT: &str = "BytesOut";
+/// The time to decode the payload to record batches.
+pub const DECODE_TIME: &str = "DecodeTime";
+/// The time to execute the physical plan.
+pub const EXECUTE_TIME: &str = "ExecuteTime";
+/// The time to encode the output to payloads.
+pub const ENCODE_TIME: &str = "EncodeTime";
+/// The memory size of the windows in the arena.
+pub const ARENA_SIZE: &str = "ArenaSize";
+/// The time between the first and the last data fragment of a window.
+pub const WINDOW_LATENCY: &str = "WindowLatency";
+/// The number of invocation retries.
+pub const RETRIES: &str = "Retries";
+/// The number of invocation attempts sent by the invocation schedulers.
+pub const INVOCATIONS: &str = "Invocations";
+/// The number of invocation attempts throttled by AWS Lambda.
+pub const THROTTLES: &str = "Throttles";
+/// The total backoff delay after throttles.
+pub const THROTTLE_DELAY: &str = "ThrottleDelay";
+/// The total time the invocations waited for an in-flight slot or a token.
+pub const SCHEDULER_WAIT: &str = "SchedulerWait";
+/// The peak number of in-flight invocations.
+pub const PEAK_IN_FLIGHT: &str = "PeakInFlight";
+/// The number of incomplete windows evicted from the arena.
+pub const EVICTED_WINDOWS: &str = "EvictedWindows";
+/// The number of evicted windows whose partial results are emitted.
+pub const INCOMPLETE_WINDOWS: &str = "IncompleteWindows";
+/// The number of evicted windows that are dropped.
+pub const DROPPED_WINDOWS: &str = "DroppedWindows";
+/// The memory size of the window fragments spilled to disk.
+pub const SPILLED_BYTES: &str = "SpilledBytes";
+/// The number of incomplete windows checkpointed to the state backend.
+pub const CHECKPOINTED_WINDOWS: &str = "CheckpointedWindows";
+/// The number of computations handed over to a new invocation before the
+/// deadline.
+pub const CONTINUATIONS: &str = "Continuations";
+
+/// The number of quarantine entries that failed to be written.
+pub const QUARANTINE_FAILURES: &str = "QuarantineFailures";
+
+/// The dimension of the query code.
+pub const QUERY_CODE: &str = "QueryCode";
+/// The dimension of the query stage (plan index).
+pub const STAGE: &str = "Stage";
+/// The property of the window id.
+pub const WINDOW_ID: &str = "WindowId";
+
+/// The number of retries of the function invocations in the current container.
+static INVOKE_RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Records a retry of a function invocation.
+pub fn record_retry() {
+    INVOKE_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of retries since the last call, and resets the counter.
+pub fn take_retries() -> usize {
+    INVOKE_RETRIES.swap(0, Ordering::Relaxed)
+}
+
+/// The number of quarantine entries that failed to be written in the current
+/// container.
+static QUARANTINE_WRITE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the quarantine entries that failed to be written.
+pub fn record_quarantine_failures(num: usize) {
+    QUARANTINE_WRITE_FAILURES.fetch_add(num, Ordering::Relaxed);
+}
+
+/// Returns the number of quarantine entries that failed to be written since
+/// the last call, and resets the counter.
+pub fn take_quarantine_failures() -> usize {
+    QUARANTINE_WRITE_FAILURES.swap(0, Ordering::Relaxed)
+}
+
+/// The unit of a metric value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unit {
+    /// A counter.
+    Count,
+    /// A size in bytes.
+    Bytes,
+    /// A duration in milliseconds.
+    Milliseconds,
+}
+
+/// The output format of the metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsFormat {
+    /// CloudWatch Embedded Metric Format.
+    Emf,
+    /// JSON lines.
+    Json,
+    /// No output.
+    Off,
+}
+
+impl MetricsFormat {
+    /// Returns the format from the settings.
+    pub fn from_config() -> Self {
+        match FLOCK_CONFIG.metrics.format.as_str() {
+            "json" => MetricsFormat::Json,
+            "off" => MetricsFormat::Off,
+            _ => MetricsFormat::Emf,
+        }
+    }
+}
+
+/// A set of metrics with the same dimensions.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricSet {
+    /// The timestamp in milliseconds.
+    pub timestamp:  i64,
+    /// The dimensions of the metrics, e.g., query code and stage.
+    pub dimensions: BTreeMap<String, String>,
+    /// The properties that are searchable but not used as dimensions, e.g.,
+    /// window id.
+    pub properties: BTreeMap<String, String>,
+    /// The metric values and their units.
+    pub values:     BTreeMap<String, (f64, Unit)>,
+}
+
+impl MetricSet {
+    /// Returns the metric set in the CloudWatch Embedded Metric Format.
+    pub fn to_emf(&self, namespace: &str) -> Value {
+        let mut root = Map::new();
+        root.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": self.timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": namespace,
+                    "Dimensions": [self.dimensions.keys().collect::<Vec<_>>()],
+                    "Metrics": self.values.iter().map(|(name, (_, unit))| {
+                        json!({ "Name": name, "Unit": unit })
+                    }).collect::<Vec<_>>(),
+                }],
+            }),
+        );
+        for (k, v) in self.dimensions.iter().chain(self.properties.iter()) {
+            root.insert(k.clone(), json!(v));
+        }
+        for (name, (value, _)) in &self.values {
+            root.insert(name.clone(), json!(value));
+        }
+        Value::Object(root)
+    }
+
+    /// Returns the metric set as a JSON object.
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+    /// Parses a metric set from a line in EMF or JSON format.
+    pub fn from_line(line: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(line.trim())?;
+        let aws = match value.get("_aws") {
+            Some(aws) => aws,
+            None => return Ok(serde_json::from_value(value)?),
+        };
+
+        let invalid = || FlockError::Execution(format!("Invalid EMF metrics: {}", line));
+        let directive = aws["CloudWatchMetrics"].get(0).ok_or_else(invalid)?;
+        let mut set = MetricSet {
+            timestamp: aws["Timestamp"].as_i64().unwrap_or_default(),
+            ..Default::default()
+        };
+        let dimensions = directive["Dimensions"]
+            .get(0)
+            .and_then(|d| d.as_array())
+            .cloned()
+            .unwrap_or_default();
+        for name in dimensions.iter().filter_map(|d| d.as_str()) {
+            let value = value[name].as_str().ok_or_else(invalid)?;
+            set.dimensions.insert(name.to_string(), value.to_string());
+        }
+        for metric in directive["Metrics"].as_array().ok_or_else(invalid)? {
+            let name = metric["Name"].as_str().ok_or_else(invalid)?;
+            let unit = serde_json::from_value(metric["Unit"].clone())?;
+            let number = value[name].as_f64().ok_or_else(invalid)?;
+            set.values.insert(name.to_string(), (number, unit));
+        }
+        if let Some(object) = value.as_object() {
+            for (k, v) in object {
+                if let Some(v) = v.as_str() {
+                    if k != "_aws" && !set.dimensions.contains_key(k) {
+                        set.properties.insert(k.clone(), v.to_string());
+                    }
+                }
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// A thread-safe recorder of the metrics of a function invocation. The
+/// recorder can be cloned and shared with the spawned tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricSet>>,
+}
+
+impl Metrics {
+    /// Creates a metrics recorder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a metrics recorder for the cloud function. The function name
+    /// format is `<query code>-<plan index>-<group index>`.
+    pub fn for_function(function_name: &str) -> Self {
+        let metrics = Metrics::new();
+        let mut parts = function_name.split('-');
+        if let Some(query_code) = parts.next() {
+            metrics.set_dimension(QUERY_CODE, query_code);
+        }
+        if let Some(stage) = parts.next() {
+            metrics.set_dimension(STAGE, stage);
+        }
+        metrics
+    }
+
+    /// Sets a dimension.
+    pub fn set_dimension(&self, name: &str, value: &str) {
+        let mut set = self.inner.lock().unwrap();
+        set.dimensions.insert(name.to_string(), value.to_string());
+    }
+
+    /// Sets a property.
+    pub fn set_property(&self, name: &str, value: &str) {
+        let mut set = self.inner.lock().unwrap();
+        set.properties.insert(name.to_string(), value.to_string());
+    }
+
+    /// Sets the value of a metric.
+    pub fn put(&self, name: &str, value: f64, unit: Unit) {
+        let mut set = self.inner.lock().unwrap();
+        set.values.insert(name.to_string(), (value, unit));
+    }
+
+    /// Adds the value to a metric.
+    pub fn add(&self, name: &str, value: f64, unit: Unit) {
+        let mut set = self.inner.lock().unwrap();
+        set.values.entry(name.to_string()).or_insert((0.0, unit)).0 += value;
+    }
+
+    /// Adds the time elapsed since `start` to a metric in milliseconds.
+    pub fn add_elapsed(&self, name: &str, start: Instant) {
+        self.add(
+            name,
+            start.elapsed().as_secs_f64() * 1000.0,
+            Unit::Milliseconds,
+        );
+    }
+
+    /// Returns a snapshot of the recorded metrics.
+    pub fn snapshot(&self) -> MetricSet {
+        let mut set = self.inner.lock().unwrap().clone();
+        set.timestamp = Utc::now().timestamp_millis();
+        set
+    }
+
+    /// Emits the recorded metrics to stdout in the configured format.
+    pub fn emit(&self) {
+        self.emit_with(
+            MetricsFormat::from_config(),
+            &FLOCK_CONFIG.metrics.namespace,
+        );
+    }
+
+    /// Emits the recorded metrics to stdout in the given format.
+    pub fn emit_with(&self, format: MetricsFormat, namespace: &str) {
+        let set = self.snapshot();
+        match format {
+            MetricsFormat::Emf => println!("{}", set.to_emf(namespace)),
+            MetricsFormat::Json => println!("{}", set.to_json()),
+            MetricsFormat::Off => {}
+        }
+    }
+}
+
+/// The summary statistics of a metric.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// The number of samples.
+    pub count: usize,
+    /// The sum of the samples.
+    pub sum:   f64,
+    /// The minimum sample.
+    pub min:   f64,
+    /// The maximum sample.
+    pub max:   f64,
+}
+
+impl Summary {
+    /// Returns the mean of the samples.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+}
+
+/// Aggregates the metric sets by their dimensions.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsAggregator {
+    groups: BTreeMap<Vec<(String, String)>, BTreeMap<String, Summary>>,
+}
+
+impl MetricsAggregator {
+    /// Creates an empty aggregator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a metric set.
+    pub fn add(&mut self, set: &MetricSet) {
+        let key = set
+            .dimensions
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let group = self.groups.entry(key).or_default();
+        for (name, (value, _)) in &set.values {
+            let summary = group.entry(name.clone()).or_insert(Summary {
+                count: 0,
+                sum:   0.0,
+                min:   f64::MAX,
+                max:   f64::MIN,
+            });
+            summary.count += 1;
+            summary.sum += value;
+            summary.min = summary.min.min(*value);
+            summary.max = summary.max.max(*value);
+        }
+    }
+
+    /// Adds the metric sets in the log lines. The lines that are not metrics
+    /// are skipped.
+    ///
+    /// # Returns
+    /// The number of metric sets added.
+    pub fn add_lines(&mut self, lines: &str) -> usize {
+        lines
+            .lines()
+            .filter(|line| line.trim_start().starts_with('{'))
+            .filter_map(|line| MetricSet::from_line(line).ok())
+            .map(|set| self.add(&set))
+            .count()
+    }
+
+    /// Returns the summary of a metric under the given dimensions.
+    pub fn summary(&self, dimensions: &[(&str, &str)], name: &str) -> Option<Summary> {
+        let mut key = dimensions
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        key.sort();
+        self.groups.get(&key).and_then(|g| g.get(name)).copied()
+    }
+
+    /// Returns all summaries grouped by dimensions.
+    pub fn summaries(&self) -> &BTreeMap<Vec<(String, String)>, BTreeMap<String, Summary>> {
+        &self.groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_lines() -> Result<()> {
+        let metrics = Metrics::for_function("SX72HzqFz1Qij4bP-00-00");
+        metrics.set_property(WINDOW_ID, "window-0");
+        metrics.add(ROWS_IN, 10.0, Unit::Count);
+        metrics.add(ROWS_IN, 20.0, Unit::Count);
+        metrics.put(BYTES_OUT, 1024.0, Unit::Bytes);
+
+        let set = metrics.snapshot();
+        assert_eq!(Some(&(30.0, Unit::Count)), set.values.get(ROWS_IN));
+        assert_eq!("00", set.dimensions[STAGE]);
+
+        let emf = set.to_emf("Flock");
+        assert_eq!("Flock", emf["_aws"]["CloudWatchMetrics"][0]["Namespace"]);
+        assert_eq!("SX72HzqFz1Qij4bP", emf[QUERY_CODE]);
+        assert_eq!(30.0, emf[ROWS_IN]);
+
+        // Both formats can be parsed back.
+        assert_eq!(set, MetricSet::from_line(&emf.to_string())?);
+        assert_eq!(set, MetricSet::from_line(&set.to_json().to_string())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_metrics() {
+        let lines = (1..=4)
+            .map(|i| {
+                let metrics = Metrics::for_function(&format!("q-0{}-00", i % 2));
+                metrics.put(EXECUTE_TIME, i as f64, Unit::Milliseconds);
+                metrics.snapshot().to_emf("Flock").to_string()
+            })
+            .chain(std::iter::once("START RequestId: 1234".to_string()))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut aggregator = MetricsAggregator::new();
+        assert_eq!(4, aggregator.add_lines(&lines));
+
+        let summary = aggregator
+            .summary(&[(QUERY_CODE, "q"), (STAGE, "01")], EXECUTE_TIME)
+            .unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(1.0, summary.min);
+        assert_eq!(3.0, summary.max);
+        assert_eq!(2.0, summary.mean());
+    }
+}
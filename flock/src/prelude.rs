@@ -23,15 +23,22 @@
 
 pub use crate::configs::*;
 pub use crate::datasink::{DataSink, DataSinkFormat, DataSinkType};
-pub use crate::datasource::{nexmark, tpch, ysb, DataSource, DataStream, RelationPartitions};
+pub use crate::datasource::{
+    nexmark, tpch, ysb, DataSource, DataStream, InputPartitions, RelationPartitions,
+};
 pub use crate::encoding::Encoding;
 pub use crate::error::{FlockError, Result};
 pub use crate::launcher::aws::AwsLambdaLauncher;
 pub use crate::query::{Query, QueryType, StreamType, Table};
 pub use crate::runtime::arena::{Arena, HashAggregateStatus, WindowSession};
 pub use crate::runtime::context::{self, CloudFunction, CloudFunctionType, ExecutionContext};
-pub use crate::runtime::payload::{DataFrame, Payload, Uuid, UuidBuilder};
+pub use crate::runtime::join::{IntervalJoin, JoinSide};
+pub use crate::runtime::lookup::{AsOf, LookupJoin, LookupSource, TableFormat};
+pub use crate::runtime::payload::{
+    output_id, DataFrame, InputBatches, InputId, Payload, PayloadInput, Uuid, UuidBuilder,
+};
 pub use crate::runtime::plan::{physical_plan, CloudExecutionPlan};
+pub use crate::sketch::register_sketches;
 pub use crate::state::*;
 pub use crate::stream::{Schedule, Window};
 pub use crate::transmute::*;
@@ -0,0 +1,411 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The quarantine keeps the payloads and stream records that can't be decoded,
+//! together with the reason, so that a single corrupt frame doesn't crash the
+//! function and make the asynchronous retries loop on the same input.
+//!
+//! The `[quarantine]` section configures where the malformed data is written
+//! and the [`QuarantinePolicy`] for the rest of the window. A payload whose
+//! schema doesn't match the current stage always fails the invocation, since
+//! skipping it would silently drop every payload of the producer.
+
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::metrics;
+use crate::runtime::payload::{InputBatches, Payload, Uuid};
+use crate::runtime::schema::is_schema_error;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// What to do with the rest of the window when the data can't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantinePolicy {
+    /// Quarantine the malformed data and process the rest of the window.
+    Skip,
+    /// Quarantine the malformed data and fail the invocation.
+    Fail,
+}
+
+impl QuarantinePolicy {
+    /// Returns the policy in the settings.
+    pub fn from_config() -> Self {
+        match FLOCK_CONFIG.quarantine.policy.as_str() {
+            "fail" => QuarantinePolicy::Fail,
+            _ => QuarantinePolicy::Skip,
+        }
+    }
+}
+
+/// A stream record that can't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalformedRecord {
+    /// The raw bytes of the record.
+    pub data:   Vec<u8>,
+    /// The reason why the record is malformed.
+    pub reason: String,
+}
+
+/// A quarantined payload or stream record.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct QuarantineEntry {
+    /// The name of the function that received the data.
+    pub function_name: String,
+    /// The UUID of the payload, if the data is a Flock payload.
+    pub uuid:          Option<Uuid>,
+    /// The reason why the data is malformed.
+    pub reason:        String,
+    /// The time when the data is quarantined, in milliseconds.
+    pub timestamp:     i64,
+    /// The base64-encoded data.
+    pub data:          String,
+}
+
+impl QuarantineEntry {
+    /// Creates a quarantine entry.
+    pub fn new(function_name: &str, uuid: Option<Uuid>, reason: &str, data: &[u8]) -> Self {
+        Self {
+            function_name: function_name.to_owned(),
+            uuid,
+            reason: reason.to_owned(),
+            timestamp: Utc::now().timestamp_millis(),
+            data: base64::encode(data),
+        }
+    }
+
+    /// Returns the decoded data.
+    pub fn data(&self) -> Result<Vec<u8>> {
+        Ok(base64::decode(&self.data)?)
+    }
+}
+
+/// The location of the quarantined data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Quarantine {
+    /// A key prefix in an S3 bucket.
+    S3 {
+        /// The bucket name.
+        bucket: String,
+        /// The key prefix.
+        prefix: String,
+    },
+    /// A local JSON lines file.
+    File(PathBuf),
+    /// The malformed data is dropped.
+    Off,
+}
+
+impl Quarantine {
+    /// Returns the location in the settings.
+    pub fn from_config() -> Self {
+        let settings = &FLOCK_CONFIG.quarantine;
+        match settings.destination.as_str() {
+            "s3" => Quarantine::S3 {
+                bucket: FLOCK_S3_BUCKET.clone(),
+                prefix: settings.prefix.clone(),
+            },
+            "file" => Quarantine::File(PathBuf::from(&settings.path)),
+            _ => Quarantine::Off,
+        }
+    }
+
+    /// Writes the entries to the quarantine.
+    pub async fn write(&self, entries: &[QuarantineEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Quarantine::S3 { bucket, prefix } => {
+                let first = &entries[0];
+                let key = format!(
+                    "{}/{}/{}-{}",
+                    prefix,
+                    first.function_name,
+                    first.timestamp,
+                    rand::random::<u32>()
+                );
+                let mut body = vec![];
+                for entry in entries {
+                    serde_json::to_writer(&mut body, entry)?;
+                    body.push(b'\n');
+                }
+                s3::put_object(bucket, &key, body).await
+            }
+            Quarantine::File(path) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                for entry in entries {
+                    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+                }
+                Ok(())
+            }
+            Quarantine::Off => Ok(()),
+        }
+    }
+
+    /// Reads all entries in the local quarantine file.
+    pub fn read_file(path: &PathBuf) -> Result<Vec<QuarantineEntry>> {
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+}
+
+/// Writes the entries to the quarantine. The entries that fail to be written
+/// are counted in the metrics, and the error is returned, so that the data
+/// isn't dropped without a trace.
+async fn quarantine(location: &Quarantine, entries: Vec<QuarantineEntry>) -> Result<()> {
+    location.write(&entries).await.map_err(|e| {
+        metrics::record_quarantine_failures(entries.len());
+        FlockError::Execution(format!(
+            "Failed to quarantine {} entries in {:?}: {}",
+            entries.len(),
+            location,
+            e
+        ))
+    })
+}
+
+/// Decodes the payload into the record batches of its inputs. If the payload is
+/// malformed, it is quarantined with the reason and the policy decides whether
+/// the fragment is treated as empty, so the rest of the window can still be
+/// processed, or the invocation fails.
+///
+/// # Arguments
+/// * `function_name` - The name of the function that received the payload.
+/// * `payload` - The payload to decode.
+pub async fn decode_payload(function_name: &str, payload: &Payload) -> Result<InputBatches> {
+    decode_payload_with(
+        function_name,
+        payload,
+        QuarantinePolicy::from_config(),
+        &Quarantine::from_config(),
+    )
+    .await
+}
+
+/// Decodes the payload with the given policy and quarantine location. See
+/// [`decode_payload`].
+pub async fn decode_payload_with(
+    function_name: &str,
+    payload: &Payload,
+    policy: QuarantinePolicy,
+    location: &Quarantine,
+) -> Result<InputBatches> {
+    match payload.to_record_batch() {
+        Ok(batches) => Ok(batches),
+        Err(e) => {
+            reject_payload_with(function_name, payload, e, policy, location).await?;
+            Ok(vec![])
+        }
+    }
+}
+
+/// Quarantines the payload that can't be processed, for instance because it
+/// doesn't fit its window, with the error as the reason. Under the `skip`
+/// policy the payload is dropped, otherwise the error is returned. Schema
+/// errors are returned regardless of the policy.
+///
+/// # Arguments
+/// * `function_name` - The name of the function that received the payload.
+/// * `payload` - The rejected payload.
+/// * `error` - The reason why the payload is rejected.
+pub async fn reject_payload(
+    function_name: &str,
+    payload: &Payload,
+    error: FlockError,
+) -> Result<()> {
+    reject_payload_with(
+        function_name,
+        payload,
+        error,
+        QuarantinePolicy::from_config(),
+        &Quarantine::from_config(),
+    )
+    .await
+}
+
+/// Rejects the payload with the given policy and quarantine location. See
+/// [`reject_payload`].
+pub async fn reject_payload_with(
+    function_name: &str,
+    payload: &Payload,
+    error: FlockError,
+    policy: QuarantinePolicy,
+    location: &Quarantine,
+) -> Result<()> {
+    warn!(
+        "Quarantined the malformed payload {:?} of function {}: {}",
+        payload.uuid, function_name, error
+    );
+    let entry = QuarantineEntry::new(
+        function_name,
+        Some(payload.uuid.clone()),
+        &error.to_string(),
+        &serde_json::to_vec(payload)?,
+    );
+    quarantine(location, vec![entry]).await?;
+    match policy {
+        QuarantinePolicy::Skip if !is_schema_error(&error) => Ok(()),
+        _ => Err(error),
+    }
+}
+
+/// Quarantines the malformed stream records. Under the `fail` policy, an error
+/// is returned if any record is malformed.
+///
+/// # Arguments
+/// * `function_name` - The name of the function that received the records.
+/// * `records` - The malformed records.
+pub async fn quarantine_records(function_name: &str, records: Vec<MalformedRecord>) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let num = records.len();
+    warn!(
+        "Quarantined {} malformed records of function {}: {}",
+        num, function_name, records[0].reason
+    );
+    let reason = records[0].reason.clone();
+    quarantine(
+        &Quarantine::from_config(),
+        records
+            .into_iter()
+            .map(|r| QuarantineEntry::new(function_name, None, &r.reason, &r.data))
+            .collect(),
+    )
+    .await?;
+    match QuarantinePolicy::from_config() {
+        QuarantinePolicy::Skip => Ok(()),
+        QuarantinePolicy::Fail => Err(FlockError::Execution(format!(
+            "{} malformed records, the first one: {}",
+            num, reason
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::{DataFrame, PayloadInput};
+
+    #[tokio::test]
+    async fn quarantine_payloads() -> Result<()> {
+        let payload = Payload {
+            uuid: Uuid {
+                qid:     "q-00-1024".to_owned(),
+                seq_num: 1,
+                seq_len: 2,
+            },
+            inputs: vec![PayloadInput {
+                id: "bid".to_owned(),
+                data: vec![DataFrame {
+                    header: vec![0xde, 0xad],
+                    body:   vec![0xbe, 0xef],
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let path = std::env::temp_dir().join("flock_quarantine_payloads.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let location = Quarantine::File(path.clone());
+
+        // The malformed payload is treated as an empty fragment.
+        let inputs =
+            decode_payload_with("q-01-00", &payload, QuarantinePolicy::Skip, &location).await?;
+        assert!(inputs.is_empty());
+
+        // The malformed payload fails the invocation.
+        assert!(
+            decode_payload_with("q-01-00", &payload, QuarantinePolicy::Fail, &location)
+                .await
+                .is_err()
+        );
+
+        // Both attempts are quarantined with the original payload.
+        let entries = Quarantine::read_file(&path)?;
+        assert_eq!(2, entries.len());
+        assert_eq!(Some(payload.uuid.clone()), entries[0].uuid);
+        assert!(!entries[0].reason.is_empty());
+        let quarantined: Payload = serde_json::from_slice(&entries[0].data()?)?;
+        assert_eq!(payload, quarantined);
+
+        // The payload that doesn't fit its window is rejected under the same policy.
+        let error = || FlockError::Execution("sequence length mismatch".to_owned());
+        reject_payload_with(
+            "q-01-00",
+            &payload,
+            error(),
+            QuarantinePolicy::Skip,
+            &location,
+        )
+        .await?;
+        assert!(reject_payload_with(
+            "q-01-00",
+            &payload,
+            error(),
+            QuarantinePolicy::Fail,
+            &location
+        )
+        .await
+        .is_err());
+        let entries = Quarantine::read_file(&path)?;
+        assert_eq!(4, entries.len());
+        assert_eq!(
+            "Execution error: sequence length mismatch",
+            entries[3].reason
+        );
+
+        // The schema errors fail the invocation regardless of the policy.
+        let error = FlockError::Execution(format!(
+            "{} 0000000000000001",
+            crate::runtime::schema::UNKNOWN_SCHEMA_FINGERPRINT
+        ));
+        assert!(reject_payload_with(
+            "q-01-00",
+            &payload,
+            error,
+            QuarantinePolicy::Skip,
+            &location
+        )
+        .await
+        .is_err());
+
+        // The payload isn't dropped if it can't be quarantined.
+        let unwritable = Quarantine::File(path.join("unwritable.jsonl"));
+        assert!(
+            decode_payload_with("q-01-00", &payload, QuarantinePolicy::Skip, &unwritable)
+                .await
+                .is_err()
+        );
+        assert!(metrics::take_quarantine_failures() >= 1);
+
+        Ok(())
+    }
+}
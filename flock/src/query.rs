@@ -17,11 +17,15 @@
 use crate::datasink::DataSinkType;
 use crate::datasource::DataSource;
 use crate::error::{FlockError, Result};
+use crate::runtime::join::IntervalJoin;
+use crate::runtime::lookup::LookupJoin;
+use crate::sketch::register_sketches;
 use crate::state::*;
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
 use datafusion::execution::context::{ExecutionConfig, ExecutionContext};
+use datafusion::logical_plan::LogicalPlan;
 use datafusion::physical_plan::ExecutionPlan;
 use std::fmt::Debug;
 use std::sync::Arc;
@@ -100,6 +104,10 @@ pub struct Query {
     pub query_type:    QueryType,
     /// The state backend to use.
     pub state_backend: Arc<dyn StateBackend>,
+    /// The interval join that replaces the hash join of the query, if any.
+    pub interval_join: Option<IntervalJoin>,
+    /// The lookup join that replaces the hash join of the query, if any.
+    pub lookup_join:   Option<LookupJoin>,
 }
 
 impl Default for Query {
@@ -112,6 +120,8 @@ impl Default for Query {
             query_code:    None,
             query_type:    QueryType::default(),
             state_backend: Arc::new(HashMapStateBackend::new()),
+            interval_join: None,
+            lookup_join:   None,
         }
     }
 }
@@ -138,9 +148,26 @@ impl Query {
             query_code: query_code.map(|x| x.into()),
             query_type,
             state_backend,
+            interval_join: None,
+            lookup_join: None,
         }
     }
 
+    /// Joins the two streams of the query's hash join by an interval join,
+    /// which keeps the rows of both sides across invocations, instead of only
+    /// joining the rows of the same window.
+    pub fn with_interval_join(mut self, interval_join: IntervalJoin) -> Self {
+        self.interval_join = Some(interval_join);
+        self
+    }
+
+    /// Enriches the stream of the query's hash join with a cached reference
+    /// table by a lookup join, instead of reading the table as a second input.
+    pub fn with_lookup_join(mut self, lookup_join: LookupJoin) -> Self {
+        self.lookup_join = Some(lookup_join);
+        self
+    }
+
     /// Returns a SQL query.
     pub fn sql(&self) -> String {
         self.sql.to_owned()
@@ -167,9 +194,12 @@ impl Query {
         self.state_backend.clone()
     }
 
-    /// Returns the physical plan for a given query.
-    pub fn plan(&self) -> Result<Arc<dyn ExecutionPlan>> {
-        let mut ctx = ExecutionContext::new();
+    /// Returns a planning context with the query tables (empty) and the sketch
+    /// aggregates registered, so that every plan of the query resolves the
+    /// same functions.
+    fn context(&self, config: ExecutionConfig) -> Result<ExecutionContext> {
+        let mut ctx = ExecutionContext::with_config(config);
+        register_sketches(&mut ctx);
         for table in &self.tables {
             let mem_table = MemTable::try_new(
                 table.1.clone(),
@@ -177,6 +207,22 @@ impl Query {
             )?;
             ctx.register_table(table.0.as_ref(), Arc::new(mem_table))?;
         }
+        Ok(ctx)
+    }
+
+    /// Returns the interval join of the query.
+    pub fn interval_join(&self) -> Option<IntervalJoin> {
+        self.interval_join.clone()
+    }
+
+    /// Returns the lookup join of the query.
+    pub fn lookup_join(&self) -> Option<LookupJoin> {
+        self.lookup_join.clone()
+    }
+
+    /// Returns the physical plan for a given query.
+    pub fn plan(&self) -> Result<Arc<dyn ExecutionPlan>> {
+        let ctx = self.context(ExecutionConfig::new())?;
 
         let plan = ctx.create_logical_plan(self.sql.as_ref())?;
         let plan = ctx.optimize(&plan)?;
@@ -190,6 +236,24 @@ impl Query {
         self.query_code.to_owned()
     }
 
+    /// Returns the tables scanned by the leaf nodes of the query plan, from
+    /// left to right. A self-join scans the same table more than once. The
+    /// list is empty if a leaf node doesn't scan a table of the query, e.g.,
+    /// `SELECT 1`.
+    pub fn source_tables(&self) -> Result<Vec<TableName>> {
+        let ctx = self.context(ExecutionConfig::new())?;
+
+        let plan = ctx.create_logical_plan(self.sql.as_ref())?;
+        let plan = ctx.optimize(&plan)?;
+
+        let mut tables = vec![];
+        let known = |name: &TableName| self.tables.iter().any(|table| &table.0 == name);
+        if !scanned_tables(&plan, &mut tables) || !tables.iter().all(known) {
+            tables.clear();
+        }
+        Ok(tables)
+    }
+
     /// Returns the physical plan for a given query.
     ///
     /// # Arguments
@@ -200,14 +264,7 @@ impl Query {
         shuffle_partitions: usize,
     ) -> Result<Arc<dyn ExecutionPlan>> {
         let config = ExecutionConfig::new().with_target_partitions(shuffle_partitions);
-        let mut ctx = ExecutionContext::with_config(config);
-        for table in &self.tables {
-            let mem_table = MemTable::try_new(
-                table.1.clone(),
-                vec![vec![RecordBatch::new_empty(table.1.clone())]],
-            )?;
-            ctx.register_table(table.0.as_ref(), Arc::new(mem_table))?;
-        }
+        let ctx = self.context(config)?;
 
         let plan = ctx.create_logical_plan(self.sql.as_ref())?;
         let plan = ctx.optimize(&plan)?;
@@ -216,3 +273,21 @@ impl Query {
             .map_err(|e| FlockError::Internal(e.to_string()))
     }
 }
+
+/// Collects the tables scanned by the leaf nodes of the logical plan, from left
+/// to right. Returns false if a leaf node doesn't scan a table.
+fn scanned_tables(plan: &LogicalPlan, tables: &mut Vec<TableName>) -> bool {
+    match plan {
+        LogicalPlan::TableScan(scan) => {
+            tables.push(scan.table_name.clone());
+            true
+        }
+        _ => {
+            let inputs = plan.inputs();
+            !inputs.is_empty()
+                && inputs
+                    .into_iter()
+                    .all(|input| scanned_tables(input, tables))
+        }
+    }
+}
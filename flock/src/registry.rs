@@ -0,0 +1,381 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The query registry records each deployed query, so that its lifecycle can be
+//! managed as a whole: list, describe, pause, resume and tear down.
+//!
+//! A [`QueryRecord`] keeps the query's SQL, stages, functions, sink and
+//! creation time, and is stored in the Flock S3 bucket of the state backend
+//! configured in the `[registry]` section, under `registry/<query code>`.
+//!
+//! A paused query keeps its functions, but their reserved concurrency is set to
+//! 0, so they're throttled until the query is resumed. Tearing a query down
+//! deletes its functions, their event source mappings, the state buckets named
+//! after the query's windows, the checkpoints of its functions and its
+//! subscriptions to the shared data sources.
+
+use crate::aws::{lambda, s3};
+use crate::configs::*;
+use crate::datasink::DataSinkType;
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::CHECKPOINT_KEY_PREFIX;
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::subscription::Subscription;
+use crate::state::{self, StateBackend};
+use chrono::Utc;
+use datafusion::physical_plan::displayable;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// The key prefix of the query records in the state backend.
+pub const REGISTRY_KEY_PREFIX: &str = "registry";
+
+/// The status of a deployed query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueryStatus {
+    /// The functions of the query accept invocations.
+    Running,
+    /// The reserved concurrency of the query's functions is 0.
+    Paused,
+}
+
+impl fmt::Display for QueryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryStatus::Running => write!(f, "running"),
+            QueryStatus::Paused => write!(f, "paused"),
+        }
+    }
+}
+
+/// A stage of the query's DAG.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageRecord {
+    /// The name of the stage's function.
+    pub function_name: String,
+    /// The next function or the data sink of the stage.
+    pub next:          String,
+    /// The displayable physical plan of the stage.
+    pub plan:          String,
+}
+
+/// The record of a deployed query.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryRecord {
+    /// The query code, which is the first component of the function names.
+    pub query_code:         String,
+    /// The SQL statement of the query.
+    pub sql:                String,
+    /// The stages of the query's DAG.
+    pub stages:             Vec<StageRecord>,
+    /// The functions created for the query.
+    pub functions:          Vec<String>,
+    /// The data sink of the query.
+    pub sink:               DataSinkType,
+    /// The time when the query is deployed, in milliseconds.
+    pub created_at:         i64,
+    /// The status of the query.
+    pub status:             QueryStatus,
+    /// The reserved concurrency of each function before the query is paused.
+    /// None if the function uses the unreserved concurrency of the account.
+    pub paused_concurrency: BTreeMap<String, Option<i64>>,
+}
+
+impl QueryRecord {
+    /// Creates the record of a query deployed with the given contexts.
+    ///
+    /// # Arguments
+    /// * `query_code` - The query code.
+    /// * `sql` - The SQL statement of the query.
+    /// * `sink` - The data sink of the query.
+    /// * `contexts` - The execution contexts of the query's functions.
+    pub fn new(
+        query_code: &str,
+        sql: &str,
+        sink: DataSinkType,
+        contexts: &[ExecutionContext],
+    ) -> Self {
+        let stages = contexts
+            .iter()
+            .map(|ctx| StageRecord {
+                function_name: ctx.name.clone(),
+                next:          format!("{:?}", ctx.next),
+                plan:          ctx
+                    .plan
+                    .execution_plans
+                    .iter()
+                    .map(|p| format!("{}", displayable(p.as_ref()).indent()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            })
+            .collect::<Vec<_>>();
+        let functions = contexts
+            .iter()
+            .map(|ctx| ctx.name.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        QueryRecord {
+            query_code: query_code.to_owned(),
+            sql: sql.to_owned(),
+            stages,
+            functions,
+            sink,
+            created_at: Utc::now().timestamp_millis(),
+            status: QueryStatus::Running,
+            paused_concurrency: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the prefix of the names of the query's functions and state
+    /// buckets.
+    pub fn name_prefix(&self) -> String {
+        format!("{}-", self.query_code)
+    }
+}
+
+/// The registry of the deployed queries.
+#[derive(Debug, Clone)]
+pub struct QueryRegistry {
+    /// The state backend of the query records.
+    state_backend: Arc<dyn StateBackend>,
+    /// The bucket of the query records.
+    bucket:        String,
+}
+
+impl QueryRegistry {
+    /// Creates the registry in the given state backend.
+    pub fn new(state_backend: Arc<dyn StateBackend>) -> Self {
+        QueryRegistry {
+            state_backend,
+            bucket: FLOCK_S3_BUCKET.clone(),
+        }
+    }
+
+    /// Creates the registry in the state backend of the configuration
+    /// (`registry.state_backend`).
+    pub fn from_config() -> Result<Self> {
+        Ok(QueryRegistry::new(state::from_name(
+            &FLOCK_CONFIG.registry.state_backend,
+        )?))
+    }
+
+    /// Registers the query. A query deployed again replaces its former record.
+    pub async fn register(&self, record: &QueryRecord) -> Result<()> {
+        self.state_backend
+            .write(
+                self.bucket.clone(),
+                record_key(&record.query_code),
+                serde_json::to_vec(record)?,
+            )
+            .await
+    }
+
+    /// Removes the query's record without touching its resources.
+    pub async fn unregister(&self, query_code: &str) -> Result<()> {
+        self.state_backend
+            .delete(self.bucket.clone(), record_key(query_code))
+            .await
+    }
+
+    /// Returns the records of the deployed queries, in the order of their
+    /// creation.
+    pub async fn list(&self) -> Result<Vec<QueryRecord>> {
+        let prefix = format!("{}/", REGISTRY_KEY_PREFIX);
+        let mut records = vec![];
+        for key in self.state_backend.keys(self.bucket.clone(), prefix).await? {
+            if let Some(bytes) = self.state_backend.get(self.bucket.clone(), key).await? {
+                records.push(serde_json::from_slice::<QueryRecord>(&bytes)?);
+            }
+        }
+        records.sort_by_key(|r| r.created_at);
+        Ok(records)
+    }
+
+    /// Returns the record of the query.
+    pub async fn describe(&self, query_code: &str) -> Result<QueryRecord> {
+        match self
+            .state_backend
+            .get(self.bucket.clone(), record_key(query_code))
+            .await?
+        {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Err(FlockError::Internal(format!(
+                "Query {} is not registered",
+                query_code
+            ))),
+        }
+    }
+
+    /// Pauses the query by setting the reserved concurrency of its functions
+    /// to 0. Their former concurrency is kept in the record to resume the
+    /// query.
+    pub async fn pause(&self, query_code: &str) -> Result<QueryRecord> {
+        let mut record = self.describe(query_code).await?;
+        if record.status == QueryStatus::Paused {
+            return Ok(record);
+        }
+
+        for function_name in functions_of(&record).await? {
+            let concurrency = lambda::get_concurrency(&function_name).await?;
+            lambda::set_concurrency(&function_name, 0).await?;
+            info!("[OK] Paused function: {}", function_name);
+            record.paused_concurrency.insert(function_name, concurrency);
+        }
+        record.status = QueryStatus::Paused;
+        self.register(&record).await?;
+        Ok(record)
+    }
+
+    /// Resumes the paused query by restoring the reserved concurrency of its
+    /// functions.
+    pub async fn resume(&self, query_code: &str) -> Result<QueryRecord> {
+        let mut record = self.describe(query_code).await?;
+        if record.status == QueryStatus::Running {
+            return Ok(record);
+        }
+
+        for (function_name, concurrency) in record.paused_concurrency.iter() {
+            match concurrency {
+                Some(concurrency) => lambda::set_concurrency(function_name, *concurrency).await?,
+                None => lambda::delete_concurrency(function_name).await?,
+            }
+            info!("[OK] Resumed function: {}", function_name);
+        }
+        record.paused_concurrency.clear();
+        record.status = QueryStatus::Running;
+        self.register(&record).await?;
+        Ok(record)
+    }
+
+    /// Tears the query down: deletes its functions with their event source
+    /// mappings and checkpoints, its state buckets, its subscriptions to the
+    /// shared data sources and its record.
+    pub async fn teardown(&self, query_code: &str) -> Result<()> {
+        let record = self.describe(query_code).await?;
+
+        for function_name in functions_of(&record).await? {
+            lambda::delete_event_source_mappings(&function_name).await?;
+            lambda::delete_function(&function_name).await?;
+
+            let prefix = format!("{}/{}/", CHECKPOINT_KEY_PREFIX, function_name);
+            for key in self.state_backend.keys(self.bucket.clone(), prefix).await? {
+                self.state_backend.delete(self.bucket.clone(), key).await?;
+            }
+            info!("[OK] Deleted function: {}", function_name);
+        }
+
+        let prefix = record.name_prefix();
+        for bucket in s3::get_matched_buckets(&prefix).await? {
+            if bucket.starts_with(&prefix) {
+                s3::delete_bucket(&bucket).await?;
+                info!("[OK] Deleted state bucket: {}", bucket);
+            }
+        }
+
+        let unsubscribed = Subscription::unsubscribe_all(query_code).await?;
+        if unsubscribed > 0 {
+            info!("[OK] Deleted {} subscriptions", unsubscribed);
+        }
+
+        self.unregister(query_code).await
+    }
+}
+
+/// Returns the key of the query's record in the state backend.
+fn record_key(query_code: &str) -> String {
+    format!("{}/{}", REGISTRY_KEY_PREFIX, query_code)
+}
+
+/// Returns the functions of the query, including the members added to its
+/// function groups after the deployment.
+async fn functions_of(record: &QueryRecord) -> Result<Vec<String>> {
+    let mut functions = record.functions.iter().cloned().collect::<BTreeSet<_>>();
+    functions.extend(lambda::list_functions(&record.name_prefix()).await?);
+    Ok(functions.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::context::CloudFunction;
+    use crate::runtime::plan::CloudExecutionPlan;
+    use crate::state::HashMapStateBackend;
+
+    fn context(name: &str, next: CloudFunction) -> ExecutionContext {
+        ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
+            name: name.to_owned(),
+            next,
+            state_backend: Arc::new(HashMapStateBackend::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn register_and_describe_queries() -> Result<()> {
+        let registry = QueryRegistry::new(Arc::new(HashMapStateBackend::new()));
+
+        let mut q3 = QueryRecord::new(
+            "q3",
+            "SELECT * FROM bid",
+            DataSinkType::Blackhole,
+            &[
+                context("q3-01", CloudFunction::Group(("q3-00".to_owned(), 2))),
+                context("q3-00-00", CloudFunction::Sink(DataSinkType::Blackhole)),
+                context("q3-00-01", CloudFunction::Sink(DataSinkType::Blackhole)),
+            ],
+        );
+        q3.created_at = 2;
+        let mut q1 = QueryRecord::new(
+            "q1",
+            "SELECT auction FROM bid",
+            DataSinkType::S3,
+            &[context("q1-00", CloudFunction::Sink(DataSinkType::S3))],
+        );
+        q1.created_at = 1;
+        registry.register(&q3).await?;
+        registry.register(&q1).await?;
+
+        let records = registry.list().await?;
+        assert_eq!(
+            vec!["q1", "q3"],
+            records
+                .iter()
+                .map(|r| r.query_code.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let record = registry.describe("q3").await?;
+        assert_eq!(q3, record);
+        assert_eq!(vec!["q3-00-00", "q3-00-01", "q3-01"], record.functions);
+        assert_eq!(3, record.stages.len());
+        assert_eq!(QueryStatus::Running, record.status);
+        assert!(registry.describe("q2").await.is_err());
+
+        // A query deployed again replaces its former record.
+        q1.sql = "SELECT bidder FROM bid".to_owned();
+        registry.register(&q1).await?;
+        assert_eq!(q1.sql, registry.describe("q1").await?.sql);
+        assert_eq!(2, registry.list().await?.len());
+
+        registry.unregister("q1").await?;
+        registry.unregister("q3").await?;
+        assert!(registry.list().await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,532 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Checkpoints the incomplete windows of the arena to the state backend, and
+//! restores them when the function starts cold, so the fragments collected by
+//! a recycled container aren't lost.
+
+use super::spill::{read_batches, write_batches};
+use super::{Arena, Bitmap, SpillFile, WindowId, WindowInputs, WindowSession};
+use crate::error::Result;
+use crate::runtime::payload::{InputId, Payload};
+use crate::state::StateBackend;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The key prefix of the window checkpoints in the state backend.
+pub const CHECKPOINT_KEY_PREFIX: &str = "checkpoints";
+
+/// A data fragment of an input of a checkpointed window. The record batches are
+/// encoded in the Arrow IPC file format.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FragmentCheckpoint {
+    id:      InputId,
+    #[serde(with = "serde_bytes")]
+    batches: Vec<u8>,
+}
+
+/// The checkpoint of an incomplete window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowCheckpoint {
+    /// The window identifier.
+    pub window_id: WindowId,
+    /// The number of data fragments in the window.
+    pub size:      usize,
+    /// The sequence numbers of the data fragments that arrived.
+    pub seq_nums:  Vec<usize>,
+    /// The time elapsed since the first data fragment arrived, in
+    /// milliseconds.
+    pub age:       u64,
+    /// The payload of the first data fragment without its data.
+    pub header:    Option<Payload>,
+    /// The data fragments of each input that arrived.
+    fragments:     Vec<FragmentCheckpoint>,
+}
+
+impl WindowCheckpoint {
+    /// Creates the checkpoint of the window. The spilled fragments are read
+    /// from their files as they are.
+    ///
+    /// # Arguments
+    /// * `window_id` - The window identifier.
+    /// * `window` - The window to checkpoint.
+    /// * `age` - The time elapsed since the first data fragment arrived.
+    pub fn new(window_id: &WindowId, window: &WindowSession, age: Duration) -> Result<Self> {
+        let mut fragments = vec![];
+        for (id, input) in window.inputs.iter() {
+            for (i, batches) in input.records.iter().enumerate() {
+                fragments.push(FragmentCheckpoint {
+                    id:      id.clone(),
+                    batches: encode_fragment(batches, input.spilled.get(i))?,
+                });
+            }
+        }
+
+        Ok(WindowCheckpoint {
+            window_id: window_id.clone(),
+            size: window.size,
+            seq_nums: (1..=window.size)
+                .filter(|i| window.bitmap.is_set(*i))
+                .collect(),
+            age: age.as_millis() as u64,
+            header: window.header.clone(),
+            fragments,
+        })
+    }
+
+    /// Restores the window from the checkpoint.
+    ///
+    /// # Arguments
+    /// * `now` - The current time of the arena's clock.
+    pub fn into_window(self, now: Instant) -> Result<(WindowId, WindowSession)> {
+        let mut bitmap = Bitmap::new(self.size + 1);
+        self.seq_nums.iter().for_each(|i| bitmap.set(*i));
+
+        let mut inputs = WindowInputs::new();
+        for fragment in self.fragments {
+            let input = inputs.entry(fragment.id).or_default();
+            input.records.push(decode_fragment(fragment.batches)?);
+            input.spilled.push(None);
+        }
+
+        let window = WindowSession {
+            size: self.size,
+            inputs,
+            received: self.seq_nums.len(),
+            bitmap,
+            started: now
+                .checked_sub(Duration::from_millis(self.age))
+                .unwrap_or(now),
+            updated: now,
+            header: self.header,
+            checkpointed: Some(now),
+        };
+        Ok((self.window_id, window))
+    }
+
+    /// Returns the schemas of the data fragments, e.g., to check whether the
+    /// window can be migrated to the stage of an upgraded query.
+    pub fn schemas(&self) -> Result<Vec<SchemaRef>> {
+        let mut schemas: Vec<SchemaRef> = vec![];
+        for fragment in self.fragments.iter().filter(|f| !f.batches.is_empty()) {
+            let reader = FileReader::try_new(Cursor::new(&fragment.batches))?;
+            let schema = reader.schema();
+            if !schemas.contains(&schema) {
+                schemas.push(schema);
+            }
+        }
+        Ok(schemas)
+    }
+}
+
+/// Encodes the record batches of a data fragment, or reads its spilled file.
+fn encode_fragment(
+    batches: &[RecordBatch],
+    spilled: Option<&Option<SpillFile>>,
+) -> Result<Vec<u8>> {
+    if let Some(Some(file)) = spilled {
+        return Ok(std::fs::read(&file.path)?);
+    }
+    let mut bytes = vec![];
+    if let Some(batch) = batches.first() {
+        write_batches(&mut bytes, &batch.schema(), batches)?;
+    }
+    Ok(bytes)
+}
+
+/// Decodes the record batches of a data fragment.
+fn decode_fragment(bytes: Vec<u8>) -> Result<Vec<RecordBatch>> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    read_batches(Cursor::new(bytes))
+}
+
+/// Returns the key of the window's checkpoint in the state backend.
+pub fn checkpoint_key(function_name: &str, window_id: &WindowId) -> String {
+    format!(
+        "{}/{}/{}/{:02}",
+        CHECKPOINT_KEY_PREFIX, function_name, window_id.0, window_id.1
+    )
+}
+
+impl Arena {
+    /// Checkpoint the windows that are updated since their last checkpoint and
+    /// whose checkpoint interval has elapsed, and delete the checkpoints of the
+    /// windows removed from the arena.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the checkpoints.
+    /// * `bucket` - The bucket of the checkpoints.
+    /// * `function_name` - The name of the current function.
+    ///
+    /// # Returns
+    /// The number of checkpointed windows.
+    pub async fn checkpoint(
+        &mut self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+    ) -> Result<usize> {
+        let removed = self.removed_checkpoints.iter().cloned().collect::<Vec<_>>();
+        for window_id in removed {
+            state_backend
+                .delete(bucket.to_owned(), checkpoint_key(function_name, &window_id))
+                .await?;
+            self.removed_checkpoints.remove(&window_id);
+        }
+
+        if self.checkpoint_interval.is_zero() {
+            return Ok(0);
+        }
+        let now = self.clock.now();
+        let interval = self.checkpoint_interval;
+        let due = self
+            .windows
+            .iter()
+            .filter(|(_, w)| {
+                let since = w.checkpointed.unwrap_or(w.started);
+                w.checkpointed.map_or(true, |t| w.updated > t)
+                    && now.saturating_duration_since(since) >= interval
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for window_id in due.iter() {
+            self.checkpoint_window(state_backend, bucket, function_name, window_id)
+                .await?;
+        }
+        Ok(due.len())
+    }
+
+    /// Checkpoint the window regardless of the checkpoint interval, e.g., when
+    /// its processing is handed over to a new invocation near the deadline.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the checkpoints.
+    /// * `bucket` - The bucket of the checkpoints.
+    /// * `function_name` - The name of the current function.
+    /// * `window_id` - The window identifier.
+    ///
+    /// # Returns
+    /// False if the window isn't in the arena.
+    pub async fn checkpoint_window(
+        &mut self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+        window_id: &WindowId,
+    ) -> Result<bool> {
+        let now = self.clock.now();
+        let window = match self.windows.get(window_id) {
+            Some(window) => window,
+            None => return Ok(false),
+        };
+        let age = now.saturating_duration_since(window.started);
+        let checkpoint = WindowCheckpoint::new(window_id, window, age)?;
+        state_backend
+            .write(
+                bucket.to_owned(),
+                checkpoint_key(function_name, window_id),
+                serde_json::to_vec(&checkpoint)?,
+            )
+            .await?;
+        if let Some(window) = self.windows.get_mut(window_id) {
+            window.checkpointed = Some(now);
+        }
+        Ok(true)
+    }
+
+    /// Restore the checkpointed windows that aren't in the arena, e.g., after
+    /// the function's container is recycled.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the checkpoints.
+    /// * `bucket` - The bucket of the checkpoints.
+    /// * `function_name` - The name of the current function.
+    ///
+    /// # Returns
+    /// The number of restored windows.
+    pub async fn restore(
+        &mut self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+    ) -> Result<usize> {
+        let prefix = format!("{}/{}/", CHECKPOINT_KEY_PREFIX, function_name);
+        let now = self.clock.now();
+        let mut restored = 0;
+        for key in state_backend.keys(bucket.to_owned(), prefix).await? {
+            let bytes = match state_backend.get(bucket.to_owned(), key).await? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let checkpoint: WindowCheckpoint = serde_json::from_slice(&bytes)?;
+            if self.windows.contains_key(&checkpoint.window_id) {
+                continue;
+            }
+            let (window_id, window) = checkpoint.into_window(now)?;
+            self.windows.insert(window_id, window);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Restore the window from its checkpoint if it isn't in the arena, e.g.,
+    /// when a new invocation resumes the window handed over near the deadline.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the checkpoints.
+    /// * `bucket` - The bucket of the checkpoints.
+    /// * `function_name` - The name of the current function.
+    /// * `window_id` - The window identifier.
+    ///
+    /// # Returns
+    /// True if the window is in the arena.
+    pub async fn restore_window(
+        &mut self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+        window_id: &WindowId,
+    ) -> Result<bool> {
+        if self.windows.contains_key(window_id) {
+            return Ok(true);
+        }
+        let key = checkpoint_key(function_name, window_id);
+        let bytes = match state_backend.get(bucket.to_owned(), key).await? {
+            Some(bytes) => bytes,
+            None => return Ok(false),
+        };
+        let checkpoint: WindowCheckpoint = serde_json::from_slice(&bytes)?;
+        let (window_id, window) = checkpoint.into_window(self.clock.now())?;
+        self.windows.insert(window_id, window);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::arena::{HashAggregateStatus, ManualClock};
+    use crate::runtime::payload::UuidBuilder;
+    use crate::state::HashMapStateBackend;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    fn batch(values: Vec<i64>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int64, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(values))]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn restore_window_after_restart() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let (bucket, function_name) = ("flock", "SX72HzqFz1Qij4bP-01-00");
+        let dir = std::env::temp_dir().join(format!("flock-checkpoint-{}", uuid::Uuid::new_v4()));
+        let clock = ManualClock::new();
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 1024, 3);
+        let payload = |i: usize, values: Vec<i64>| {
+            to_payload(&[("v", &[batch(values)])], uuids.get(i), false)
+        };
+
+        // The function collects two fragments of the window, and the first one is
+        // spilled to disk.
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX)
+            .with_clock(Arc::new(clock.clone()))
+            .with_checkpointing(Duration::from_secs(5))
+            .with_spilling(1, &dir);
+        assert!(arena.collect(payload(1, vec![1, 2]))? == HashAggregateStatus::NotReady);
+        assert!(arena.spill()? > 0);
+        assert!(arena.collect(payload(2, vec![3]))? == HashAggregateStatus::NotReady);
+
+        // The window isn't checkpointed before the checkpoint interval elapses.
+        assert_eq!(
+            0,
+            arena
+                .checkpoint(&state_backend, bucket, function_name)
+                .await?
+        );
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            1,
+            arena
+                .checkpoint(&state_backend, bucket, function_name)
+                .await?
+        );
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            0,
+            arena
+                .checkpoint(&state_backend, bucket, function_name)
+                .await?
+        );
+
+        // The function is killed between fragments, and its container is recycled.
+        drop(arena);
+        std::fs::remove_dir_all(&dir)?;
+
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX)
+            .with_clock(Arc::new(clock.clone()))
+            .with_checkpointing(Duration::from_secs(5));
+        assert_eq!(
+            1,
+            arena.restore(&state_backend, bucket, function_name).await?
+        );
+        let window_id = payload(3, vec![]).get_window_id();
+        assert_eq!(2, arena.get(&window_id).unwrap().received());
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            arena.window_latency(&window_id)
+        );
+
+        // The duplicate fragment is ignored, and the last one completes the window.
+        assert!(arena.collect(payload(2, vec![3]))? == HashAggregateStatus::Processed);
+        assert!(arena.collect(payload(3, vec![4, 5]))? == HashAggregateStatus::Ready);
+        let (input, _) = arena.take_window(&window_id);
+        let rows = input["v"]
+            .records
+            .iter()
+            .flatten()
+            .map(|b| b.num_rows())
+            .sum::<usize>();
+        assert_eq!(5, rows);
+
+        // The checkpoint of the completed window is deleted.
+        assert_eq!(
+            0,
+            arena
+                .checkpoint(&state_backend, bucket, function_name)
+                .await?
+        );
+        let prefix = format!("{}/{}/", CHECKPOINT_KEY_PREFIX, function_name);
+        assert!(state_backend
+            .keys(bucket.to_owned(), prefix)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_checkpoint_of_reinserted_window() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let (bucket, function_name) = ("flock", "SX72HzqFz1Qij4bP-03-00");
+        let clock = ManualClock::new();
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 4096, 2);
+        let window_id =
+            to_payload(&[("v", &[batch(vec![1])])], uuids.get(1), false).get_window_id();
+        let keys = || {
+            state_backend.keys(
+                bucket.to_owned(),
+                format!("{}/{}/", CHECKPOINT_KEY_PREFIX, function_name),
+            )
+        };
+
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX)
+            .with_clock(Arc::new(clock.clone()))
+            .with_checkpointing(Duration::from_secs(5));
+        arena.collect(to_payload(&[("v", &[batch(vec![1])])], uuids.get(1), false))?;
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            1,
+            arena
+                .checkpoint(&state_backend, bucket, function_name)
+                .await?
+        );
+
+        // The evicted window is put back, e.g., to fetch its missing fragments, so
+        // its checkpoint is kept.
+        let window = arena.remove_window(&window_id).unwrap();
+        arena.reinsert(window_id.clone(), window);
+        arena
+            .checkpoint(&state_backend, bucket, function_name)
+            .await?;
+        assert_eq!(1, keys().await?.len());
+
+        // The checkpoint of the dropped window is deleted.
+        arena.remove_window(&window_id);
+        arena
+            .checkpoint(&state_backend, bucket, function_name)
+            .await?;
+        assert!(keys().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_window_handed_over() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let (bucket, function_name) = ("flock", "SX72HzqFz1Qij4bP-02-00");
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 2048, 2);
+        let payload = |i: usize, values: Vec<i64>| {
+            to_payload(&[("v", &[batch(values)])], uuids.get(i), false)
+        };
+        let window_id = payload(1, vec![]).get_window_id();
+
+        // The window is complete near the deadline, so it's checkpointed regardless of
+        // the checkpoint interval instead of being processed.
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX);
+        assert!(arena.collect(payload(1, vec![1]))? == HashAggregateStatus::NotReady);
+        assert!(arena.collect(payload(2, vec![2, 3]))? == HashAggregateStatus::Ready);
+        assert!(
+            arena
+                .checkpoint_window(&state_backend, bucket, function_name, &window_id)
+                .await?
+        );
+
+        // The next invocation runs in a new container, and resumes the window.
+        let mut arena = Arena::with_limits(Duration::from_secs(60), usize::MAX);
+        let other = payload(1, vec![]).get_window_id();
+        let other = (other.0, other.1 + 1);
+        assert!(
+            !arena
+                .restore_window(&state_backend, bucket, function_name, &other)
+                .await?
+        );
+        assert!(
+            arena
+                .restore_window(&state_backend, bucket, function_name, &window_id)
+                .await?
+        );
+        assert!(arena.is_complete(&window_id));
+        let (input, _) = arena.take_window(&window_id);
+        assert_eq!(
+            3,
+            input["v"]
+                .records
+                .iter()
+                .flatten()
+                .map(|b| b.num_rows())
+                .sum::<usize>()
+        );
+
+        // The checkpoint of the processed window is deleted.
+        arena
+            .checkpoint(&state_backend, bucket, function_name)
+            .await?;
+        let prefix = format!("{}/{}/", CHECKPOINT_KEY_PREFIX, function_name);
+        assert!(state_backend
+            .keys(bucket.to_owned(), prefix)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+}
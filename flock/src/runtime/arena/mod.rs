@@ -14,16 +14,41 @@
 //! The global data structure inside the lambda function is used to aggregate
 //! the data frames of the previous stage of dataflow to ensure the integrity of
 //! the window data for stream processing.
+//!
+//! A window whose fragments don't all arrive within its time-to-live, or that
+//! is the least recently updated one when the arena exceeds its memory limit,
+//! is evicted from the arena. The timeout policy decides what happens to an
+//! evicted window.
+//!
+//! Beyond the spill threshold, the fragments of the largest windows are spilled
+//! to disk, and streamed back to the query plan when their windows are
+//! complete. The incomplete windows are checkpointed to the state backend
+//! periodically, and restored when the function starts cold.
 
 mod bitmap;
+mod checkpoint;
+mod clock;
+mod spill;
 pub use bitmap::Bitmap;
+pub use checkpoint::{checkpoint_key, WindowCheckpoint, CHECKPOINT_KEY_PREFIX};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use spill::{read_batches, write_batches, SpillExec, SpillFile};
 
+use crate::configs::FLOCK_CONFIG;
 use crate::error::{FlockError, Result};
-use crate::runtime::payload::Payload;
+use crate::runtime::payload::{InputBatches, InputId, Payload, Uuid};
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
-use hashbrown::HashMap;
-use std::ops::{Deref, DerefMut};
+use hashbrown::{HashMap, HashSet};
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The metadata key that flags the output of an incomplete window.
+pub const INCOMPLETE_WINDOW_KEY: &str = "incomplete_window";
 
 type QueryId = String;
 type ShuffleId = usize;
@@ -31,6 +56,32 @@ type ShuffleId = usize;
 /// The window identifier to identify the window in the global arena.
 pub type WindowId = (QueryId, ShuffleId);
 
+/// The data fragments of each input of a window, keyed by the input id.
+pub type WindowInputs = BTreeMap<InputId, InputFragments>;
+
+/// The data fragments of an input of a window.
+#[derive(Debug, Default, Clone)]
+pub struct InputFragments {
+    /// The record batches of each fragment. A spilled fragment's record
+    /// batches are removed.
+    pub records: Vec<Vec<RecordBatch>>,
+    /// The spilled files of the fragments.
+    pub spilled: Vec<Option<SpillFile>>,
+}
+
+impl InputFragments {
+    /// Creates the fragments of an input that aren't spilled.
+    pub fn new(records: Vec<Vec<RecordBatch>>) -> Self {
+        let spilled = vec![None; records.len()];
+        InputFragments { records, spilled }
+    }
+
+    /// Returns true if some fragments are spilled.
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.iter().any(Option::is_some)
+    }
+}
+
 /// The aggregator function has three status to determine the next step.
 #[derive(PartialEq)]
 pub enum HashAggregateStatus {
@@ -42,6 +93,52 @@ pub enum HashAggregateStatus {
     NotReady,
 }
 
+/// What happens to an incomplete window when it's evicted from the arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPolicy {
+    /// Processes the fragments that arrived, and flags the output as
+    /// incomplete.
+    Emit,
+    /// Fetches the missing fragments from the state backend. The window is
+    /// dropped if some fragments are still missing.
+    Fetch,
+    /// Drops the window.
+    Drop,
+}
+
+impl FromStr for TimeoutPolicy {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "emit" => Ok(TimeoutPolicy::Emit),
+            "fetch" => Ok(TimeoutPolicy::Fetch),
+            "drop" => Ok(TimeoutPolicy::Drop),
+            _ => Err(FlockError::Config(format!("Unknown timeout policy: {}", s))),
+        }
+    }
+}
+
+/// Why a window is evicted from the arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The window is older than its time-to-live.
+    Timeout,
+    /// The arena exceeds its memory limit.
+    MemoryLimit,
+}
+
+/// An incomplete window evicted from the arena.
+#[derive(Debug)]
+pub struct EvictedWindow {
+    /// The window identifier.
+    pub window_id: WindowId,
+    /// The fragments of the window that arrived.
+    pub session:   WindowSession,
+    /// Why the window is evicted.
+    pub reason:    EvictionReason,
+}
+
 /// `Arena` is a global hash map inside the lambda function that is used to
 /// aggregate the data frames of the previous stage of dataflow to ensure the
 /// integrity of the window data for stream processing.
@@ -51,7 +148,28 @@ pub enum HashAggregateStatus {
 ///   query time.
 /// * The value is the data frames of the previous stage of dataflow for a given
 ///   query at a given time wrapped by `WindowSession`.
-pub struct Arena(HashMap<WindowId, WindowSession>);
+#[derive(Debug)]
+pub struct Arena {
+    /// The windows being collected.
+    windows:             HashMap<WindowId, WindowSession>,
+    /// The time-to-live of a window since its first fragment arrived.
+    ttl:                 Duration,
+    /// The maximum memory size of the windows, in bytes.
+    max_memory:          usize,
+    /// The clock to decide when the windows time out.
+    clock:               Arc<dyn Clock>,
+    /// The memory size of the windows beyond which their fragments are
+    /// spilled to disk, in bytes. 0 disables spilling.
+    spill_threshold:     usize,
+    /// The directory of the spilled fragments.
+    spill_dir:           PathBuf,
+    /// The interval between the checkpoints of a window. 0 disables
+    /// checkpointing.
+    checkpoint_interval: Duration,
+    /// The checkpointed windows that are removed from the arena, whose
+    /// checkpoints are deleted at the next checkpoint.
+    removed_checkpoints: HashSet<WindowId>,
+}
 
 /// `WindowSession` is an abstraction of a temporal window that is used to store
 /// the data frames of the previous stage of dataflow to ensure the integrity of
@@ -61,49 +179,293 @@ pub struct Arena(HashMap<WindowId, WindowSession>);
 pub struct WindowSession {
     /// The number of data fragments in the window.
     /// [`WindowSession::size`] equals to [`Uuid::seq_len`].
-    pub size:       usize,
-    /// Aggregate record batches for the first relation.
-    pub r1_records: Vec<Vec<RecordBatch>>,
-    /// Aggregate record batches for the second relation.
-    pub r2_records: Vec<Vec<RecordBatch>>,
+    pub size:         usize,
+    /// The data fragments of each input of the window. A fragment only adds
+    /// to the inputs it carries record batches for.
+    pub inputs:       WindowInputs,
+    /// The number of data fragments that arrived.
+    pub received:     usize,
     /// Bitmap indicating the data existence in the window.
-    pub bitmap:     Bitmap,
+    pub bitmap:       Bitmap,
+    /// The time when the first data fragment of the window arrived.
+    pub started:      Instant,
+    /// The time when the last data fragment of the window arrived.
+    pub updated:      Instant,
+    /// The payload of the first data fragment without its data. It's used to
+    /// forward the window if it's emitted incomplete, or handed over to a new
+    /// invocation near the deadline.
+    pub header:       Option<Payload>,
+    /// The time of the last checkpoint of the window, if any.
+    pub checkpointed: Option<Instant>,
 }
 
 impl WindowSession {
-    /// Return the schema of data fragments in the temporal window.
-    pub fn schema(&self) -> Result<(SchemaRef, Option<SchemaRef>)> {
-        if self.r1_records.is_empty() || self.r1_records[0].is_empty() {
-            return Err(FlockError::Internal(
-                "Record batches are empty.".to_string(),
-            ));
-        }
-        if !self.r2_records.is_empty() && !self.r2_records[0].is_empty() {
-            Ok((self.r1_records[0][0].schema(), None))
-        } else {
-            Ok((
-                self.r1_records[0][0].schema(),
-                Some(self.r2_records[0][0].schema()),
-            ))
+    /// Return the number of data fragments that arrived.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Return the memory size of the record batches in the window.
+    pub fn memory_size(&self) -> usize {
+        self.inputs
+            .values()
+            .flat_map(|input| input.records.iter())
+            .flatten()
+            .map(spill::batch_memory_size)
+            .sum()
+    }
+
+    /// Return the spilled files of the window.
+    pub fn spilled_files(&self) -> impl Iterator<Item = &SpillFile> {
+        self.inputs
+            .values()
+            .flat_map(|input| input.spilled.iter())
+            .flatten()
+    }
+
+    /// Spill the fragments in memory to disk.
+    ///
+    /// # Arguments
+    /// * `dir` - The directory of the spilled files.
+    /// * `window_id` - The window identifier, which names the spilled files.
+    ///
+    /// # Returns
+    /// The memory size of the spilled fragments, in bytes.
+    pub fn spill(&mut self, dir: &Path, window_id: &WindowId) -> Result<usize> {
+        let mut size = 0;
+        for (id, input) in self.inputs.iter_mut() {
+            for (index, batches) in input.records.iter_mut().enumerate() {
+                if batches.is_empty() {
+                    continue;
+                }
+                let path = dir.join(format!(
+                    "{}-{:02}-{}-{}.arrow",
+                    window_id.0, window_id.1, id, index
+                ));
+                let file = SpillFile::write(path, batches)?;
+                size += file.memory_size;
+                input.spilled[index] = Some(file);
+                batches.clear();
+            }
         }
+        Ok(size)
+    }
+
+    /// Remove the spilled files of the window from disk.
+    pub fn remove_spilled_files(&self) -> Result<()> {
+        self.spilled_files().try_for_each(|file| file.remove())
+    }
+
+    /// Return the schema of each input of the window.
+    pub fn schemas(&self) -> Vec<(InputId, SchemaRef)> {
+        self.inputs
+            .iter()
+            .filter_map(|(id, input)| {
+                input
+                    .records
+                    .iter()
+                    .flatten()
+                    .map(|batch| batch.schema())
+                    .chain(
+                        input
+                            .spilled
+                            .iter()
+                            .flatten()
+                            .map(|file| file.schema.clone()),
+                    )
+                    .next()
+                    .map(|schema| (id.clone(), schema))
+            })
+            .collect()
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Arena {
-    /// Create a new `Arena`.
+    /// Create a new `Arena` with the window time-to-live and the memory limit
+    /// in the Flock configuration.
     pub fn new() -> Arena {
-        Arena(HashMap::<WindowId, WindowSession>::new())
+        let settings = &FLOCK_CONFIG.arena;
+        Arena::with_limits(
+            Duration::from_secs(settings.window_ttl),
+            settings.max_memory * 1024 * 1024,
+        )
+        .with_spilling(settings.spill_threshold * 1024 * 1024, &settings.spill_dir)
+        .with_checkpointing(Duration::from_secs(settings.checkpoint_interval))
     }
 
-    /// Get the data fragments in the temporal window via the key.
-    pub fn take_batches(&mut self, window_id: &WindowId) -> Vec<Vec<Vec<RecordBatch>>> {
-        if let Some(window) = (*self).remove(window_id) {
-            vec![window.r1_records, window.r2_records]
-        } else {
-            vec![vec![], vec![]]
+    /// Create a new `Arena` with the given window time-to-live and memory
+    /// limit in bytes. A zero time-to-live or memory limit disables the
+    /// corresponding eviction.
+    pub fn with_limits(ttl: Duration, max_memory: usize) -> Arena {
+        Arena {
+            windows: HashMap::<WindowId, WindowSession>::new(),
+            ttl,
+            max_memory,
+            clock: Arc::new(SystemClock),
+            spill_threshold: 0,
+            spill_dir: PathBuf::from(&FLOCK_CONFIG.arena.spill_dir),
+            checkpoint_interval: Duration::ZERO,
+            removed_checkpoints: HashSet::new(),
         }
     }
 
+    /// Checkpoint the incomplete windows at the given interval. 0 disables
+    /// checkpointing.
+    pub fn with_checkpointing(mut self, interval: Duration) -> Arena {
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    /// Spill the fragments to the given directory beyond the given memory
+    /// size in bytes. 0 disables spilling.
+    pub fn with_spilling(mut self, threshold: usize, dir: impl Into<PathBuf>) -> Arena {
+        self.spill_threshold = threshold;
+        self.spill_dir = dir.into();
+        self
+    }
+
+    /// Replace the clock of the arena.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Arena {
+        self.clock = clock;
+        self
+    }
+
+    /// Get the data fragments of each input in the temporal window via the
+    /// key, along with the spilled files of the fragments that are no longer
+    /// in memory.
+    pub fn take_window(&mut self, window_id: &WindowId) -> WindowInputs {
+        self.remove_window(window_id)
+            .map(|window| window.inputs)
+            .unwrap_or_default()
+    }
+
+    /// Spill the fragments of the largest windows to disk until the memory
+    /// size of the arena is within the spill threshold.
+    ///
+    /// # Returns
+    /// The memory size of the spilled fragments, in bytes.
+    pub fn spill(&mut self) -> Result<usize> {
+        let mut memory_size = self.memory_size();
+        if self.spill_threshold == 0 || memory_size <= self.spill_threshold {
+            return Ok(0);
+        }
+
+        let mut windows = self
+            .windows
+            .iter()
+            .map(|(id, w)| (w.memory_size(), id.clone()))
+            .collect::<Vec<_>>();
+        windows.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut spilled = 0;
+        for (_, window_id) in windows {
+            if memory_size <= self.spill_threshold {
+                break;
+            }
+            if let Some(window) = self.windows.get_mut(&window_id) {
+                let size = window.spill(&self.spill_dir, &window_id)?;
+                memory_size -= size;
+                spilled += size;
+            }
+        }
+        Ok(spilled)
+    }
+
+    /// Return the total memory size of the windows in the arena.
+    pub fn memory_size(&self) -> usize {
+        self.values().map(|window| window.memory_size()).sum()
+    }
+
+    /// Return the time elapsed since the first data fragment of the window
+    /// arrived.
+    pub fn window_latency(&self, window_id: &WindowId) -> Option<Duration> {
+        let now = self.clock.now();
+        self.get(window_id)
+            .map(|window| now.saturating_duration_since(window.started))
+    }
+
+    /// Evict the windows that are older than the time-to-live, and then the
+    /// least recently updated windows until the arena fits in its memory
+    /// limit. Nothing is evicted without a time-to-live and a memory limit.
+    ///
+    /// # Returns
+    /// The evicted windows, which are all incomplete since the complete ones
+    /// are taken as soon as their last fragment arrives.
+    pub fn evict(&mut self) -> Vec<EvictedWindow> {
+        let now = self.clock.now();
+        let ttl = self.ttl;
+        let expired = self
+            .windows
+            .iter()
+            .filter(|(_, w)| !ttl.is_zero() && now.saturating_duration_since(w.started) >= ttl)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        let mut evicted = self.remove_windows(expired, EvictionReason::Timeout);
+
+        let max_memory = self.max_memory;
+        let mut memory_size = self.memory_size();
+        if max_memory > 0 && memory_size > max_memory {
+            let mut windows = self
+                .windows
+                .iter()
+                .map(|(id, w)| (w.updated, id.clone(), w.memory_size()))
+                .collect::<Vec<_>>();
+            windows.sort_by_key(|(updated, ..)| *updated);
+            let lru = windows
+                .into_iter()
+                .take_while(|(_, _, size)| {
+                    let over = memory_size > max_memory;
+                    memory_size -= size;
+                    over
+                })
+                .map(|(_, id, _)| id)
+                .collect();
+            evicted.extend(self.remove_windows(lru, EvictionReason::MemoryLimit));
+        }
+        evicted
+    }
+
+    fn remove_windows(
+        &mut self,
+        window_ids: Vec<WindowId>,
+        reason: EvictionReason,
+    ) -> Vec<EvictedWindow> {
+        window_ids
+            .into_iter()
+            .filter_map(|window_id| {
+                self.remove_window(&window_id).map(|session| EvictedWindow {
+                    window_id,
+                    session,
+                    reason,
+                })
+            })
+            .collect()
+    }
+
+    /// Remove the window from the arena. The checkpoint of the window, if any,
+    /// is deleted at the next checkpoint.
+    pub fn remove_window(&mut self, window_id: &WindowId) -> Option<WindowSession> {
+        let window = self.windows.remove(window_id)?;
+        if window.checkpointed.is_some() {
+            self.removed_checkpoints.insert(window_id.clone());
+        }
+        Some(window)
+    }
+
+    /// Put a removed window back into the arena, e.g., an evicted window whose
+    /// missing fragments are fetched from S3. Its checkpoint, if any, is no
+    /// longer deleted at the next checkpoint.
+    pub fn reinsert(&mut self, window_id: WindowId, window: WindowSession) {
+        self.removed_checkpoints.remove(&window_id);
+        self.windows.insert(window_id, window);
+    }
+
     /// Return the Bitmap reference of the temporal window.
     pub fn get_bitmap(&self, window_id: &WindowId) -> Option<&Bitmap> {
         self.get(window_id).map(|window| &window.bitmap)
@@ -112,10 +474,38 @@ impl Arena {
     /// Return true if the temporal window is empty.
     pub fn is_complete(&self, window_id: &WindowId) -> bool {
         self.get(window_id)
-            .map(|window| window.size == window.r1_records.len())
+            .map(|window| window.size == window.received)
+            .unwrap_or(false)
+    }
+
+    /// Return true if the data fragment has already been collected.
+    pub fn is_collected(&self, window_id: &WindowId, uuid: &Uuid) -> bool {
+        self.get(window_id)
+            .map(|window| uuid.seq_num <= window.size && window.bitmap.is_set(uuid.seq_num))
             .unwrap_or(false)
     }
 
+    /// Returns an error if the data fragment doesn't fit its temporal window:
+    /// its sequence number can't exceed the sequence length, and the sequence
+    /// length must match the size of the window.
+    pub fn check_fragment(&self, window_id: &WindowId, uuid: &Uuid) -> Result<()> {
+        if uuid.seq_num > uuid.seq_len {
+            return Err(FlockError::Execution(format!(
+                "The fragment of window {:?} has the sequence number {} beyond its sequence \
+                 length {}",
+                window_id, uuid.seq_num, uuid.seq_len
+            )));
+        }
+        match self.get(window_id) {
+            Some(window) if window.size != uuid.seq_len => Err(FlockError::Execution(format!(
+                "The fragment of window {:?} has the sequence length {}, but the window has {} \
+                 fragments",
+                window_id, uuid.seq_len, window.size
+            ))),
+            _ => Ok(()),
+        }
+    }
+
     /// Collect the data fragments for temporal windows.
     ///
     /// # Arguments
@@ -125,45 +515,90 @@ impl Arena {
     /// * Return true if the window data collection is complete, otherwise
     ///   return false. Uuid is also returned no matter whether the window data
     ///   collection is complete.
-    pub fn collect(&mut self, payload: Payload) -> HashAggregateStatus {
-        let uuid = payload.uuid.clone();
+    /// * Return an error if the payload can't be decoded, or if it doesn't fit
+    ///   its window.
+    pub fn collect(&mut self, payload: Payload) -> Result<HashAggregateStatus> {
         let window_id = payload.get_window_id();
-        match &mut (*self).get_mut(&window_id) {
-            Some(window) => {
-                assert!(uuid.seq_len == window.size);
-                if !window.bitmap.is_set(uuid.seq_num) {
-                    let (r1, r2) = payload.to_record_batch();
-                    window.r1_records.push(r1);
-                    window.r2_records.push(r2);
-                    assert!(window.r1_records.len() == window.r2_records.len());
-                    window.bitmap.set(uuid.seq_num);
-                    if window.size == window.r1_records.len() {
-                        HashAggregateStatus::Ready
-                    } else {
-                        HashAggregateStatus::NotReady
-                    }
-                } else {
-                    HashAggregateStatus::Processed
-                }
-            }
-            None => {
-                let (r1, r2) = payload.to_record_batch();
-                let mut window = WindowSession {
-                    size:       uuid.seq_len,
-                    r1_records: vec![r1],
-                    r2_records: vec![r2],
-                    bitmap:     Bitmap::new(uuid.seq_len + 1), // Starts from 1.
-                };
-                // SEQ_NUM is used to indicate the data existence in the window via bitmap.
-                window.bitmap.set(uuid.seq_num);
-                (*self).insert(window_id, window);
-                if uuid.seq_len == 1 {
-                    HashAggregateStatus::Ready
-                } else {
-                    HashAggregateStatus::NotReady
-                }
+        if self.is_collected(&window_id, &payload.uuid) {
+            return Ok(HashAggregateStatus::Processed);
+        }
+        let inputs = payload.to_record_batch()?;
+        self.add_fragment(&payload, inputs)
+    }
+
+    /// Add the decoded data fragment of the payload to its temporal window,
+    /// and keep the payload's header to forward the window if it's emitted
+    /// incomplete.
+    ///
+    /// # Arguments
+    /// * `payload` - The payload of the data fragment.
+    /// * `inputs` - The record batches of each input of the data fragment.
+    pub fn add_fragment(
+        &mut self,
+        payload: &Payload,
+        inputs: InputBatches,
+    ) -> Result<HashAggregateStatus> {
+        let window_id = payload.get_window_id();
+        let status = self.add(&payload.uuid, window_id.clone(), inputs)?;
+        if let Some(window) = self.windows.get_mut(&window_id) {
+            if window.header.is_none() {
+                window.header = Some(Payload {
+                    inputs: vec![],
+                    ..payload.clone()
+                });
             }
         }
+        Ok(status)
+    }
+
+    /// Add the decoded data fragment to its temporal window. A fragment
+    /// without record batches still counts towards the window, so a
+    /// quarantined payload doesn't stall the window. An error is returned if
+    /// the fragment doesn't fit the window, see [`Arena::check_fragment`].
+    ///
+    /// # Arguments
+    /// * `uuid` - The uuid of the data fragment.
+    /// * `window_id` - The window identifier of the data fragment.
+    /// * `inputs` - The record batches of each input of the data fragment.
+    pub fn add(
+        &mut self,
+        uuid: &Uuid,
+        window_id: WindowId,
+        inputs: InputBatches,
+    ) -> Result<HashAggregateStatus> {
+        let now = self.clock.now();
+        self.check_fragment(&window_id, uuid)?;
+        if self.is_collected(&window_id, uuid) {
+            return Ok(HashAggregateStatus::Processed);
+        }
+
+        let window = self
+            .windows
+            .entry(window_id)
+            .or_insert_with(|| WindowSession {
+                size:         uuid.seq_len,
+                inputs:       WindowInputs::new(),
+                received:     0,
+                bitmap:       Bitmap::new(uuid.seq_len + 1), // Starts from 1.
+                started:      now,
+                updated:      now,
+                header:       None,
+                checkpointed: None,
+            });
+        for (id, batches) in inputs.into_iter().filter(|(_, b)| !b.is_empty()) {
+            let input = window.inputs.entry(id).or_default();
+            input.records.push(batches);
+            input.spilled.push(None);
+        }
+        // SEQ_NUM is used to indicate the data existence in the window via bitmap.
+        window.bitmap.set(uuid.seq_num);
+        window.received += 1;
+        window.updated = now;
+        if window.size == window.received {
+            Ok(HashAggregateStatus::Ready)
+        } else {
+            Ok(HashAggregateStatus::NotReady)
+        }
     }
 }
 
@@ -171,13 +606,7 @@ impl Deref for Arena {
     type Target = HashMap<WindowId, WindowSession>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for Arena {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &self.windows
     }
 }
 
@@ -215,6 +644,10 @@ mod tests {
         batches
     }
 
+    fn cities(batches: Vec<RecordBatch>) -> InputBatches {
+        vec![("cities".to_owned(), batches)]
+    }
+
     #[tokio::test]
     async fn test_arena() -> Result<()> {
         let batches = init_batches();
@@ -227,15 +660,17 @@ mod tests {
         );
 
         let mut arena = Arena::new();
-        batches.into_iter().enumerate().for_each(|(i, batch)| {
-            let payload = to_payload(&[batch], &[], uuids.get(i + 1), false);
-            let status = arena.collect(payload);
+        for (i, batch) in batches.into_iter().enumerate() {
+            let payload = to_payload(&[("cities", &[batch])], uuids.get(i + 1), false);
+            let status = arena.collect(payload.clone())?;
             if i < 7 {
                 assert!(status == HashAggregateStatus::NotReady);
             } else {
                 assert!(status == HashAggregateStatus::Ready);
             }
-        });
+            // The duplicate payload is not collected again.
+            assert!(arena.collect(payload)? == HashAggregateStatus::Processed);
+        }
 
         let qid = uuids.get(1).qid;
         let window_id = (qid, 0);
@@ -243,12 +678,217 @@ mod tests {
 
         if let Some(window) = (*arena).get(&window_id) {
             assert_eq!(8, window.size);
-            assert_eq!(8, window.r1_records.len());
+            assert_eq!(8, window.received());
+            assert_eq!(8, window.inputs["cities"].records.len());
             (0..8).for_each(|i| assert!(window.bitmap.is_set(i + 1)));
         }
+        assert!(arena.memory_size() > 0);
+        assert!(arena.window_latency(&window_id).is_some());
+
+        assert_eq!(8, arena.take_window(&window_id)["cities"].records.len());
+        assert!(arena.take_window(&("no exists".to_owned(), 0)).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_fragment() -> Result<()> {
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00-2021-01-28T19:27:51", 1024, 2);
+        let window_id = (uuids.get(1).qid, 0);
+        let batches = init_batches();
+
+        // A quarantined fragment is added without record batches.
+        let mut arena = Arena::new();
+        let status = arena.add(&uuids.get(1), window_id.clone(), vec![])?;
+        assert!(status == HashAggregateStatus::NotReady);
+        let status = arena.add(&uuids.get(2), window_id.clone(), cities(batches))?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert!(arena.is_complete(&window_id));
+
+        // A fragment that doesn't fit the window is rejected.
+        let other = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00-2021-01-28T19:27:51", 1024, 3);
+        assert!(arena.add(&other.get(3), window_id.clone(), vec![]).is_err());
+        let mut uuid = uuids.get(2);
+        uuid.seq_num = 3;
+        assert!(arena.add(&uuid, window_id.clone(), vec![]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict_windows() -> Result<()> {
+        let batches = init_batches();
+        let clock = ManualClock::new();
+        let window = |i: usize| {
+            let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 1024 + i as i64, 2);
+            (uuids.get(1), (uuids.get(1).qid, 0))
+        };
+
+        // The windows time out after their time-to-live.
+        let mut arena = Arena::with_limits(Duration::from_secs(10), usize::MAX)
+            .with_clock(Arc::new(clock.clone()));
+        let (uuid_a, window_a) = window(0);
+        let (uuid_b, window_b) = window(1);
+        arena.add(&uuid_a, window_a.clone(), cities(vec![batches[0].clone()]))?;
+        clock.advance(Duration::from_secs(5));
+        arena.add(&uuid_b, window_b.clone(), cities(vec![batches[1].clone()]))?;
+        assert!(arena.evict().is_empty());
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            arena.window_latency(&window_a)
+        );
+
+        clock.advance(Duration::from_secs(5));
+        let evicted = arena.evict();
+        assert_eq!(1, evicted.len());
+        assert_eq!(window_a, evicted[0].window_id);
+        assert_eq!(EvictionReason::Timeout, evicted[0].reason);
+        assert_eq!(1, evicted[0].session.received());
+        assert!(arena.get(&window_b).is_some());
+
+        // Without a time-to-live and a memory limit, no window is evicted.
+        let mut unbounded =
+            Arena::with_limits(Duration::ZERO, 0).with_clock(Arc::new(clock.clone()));
+        unbounded.add(&uuid_b, window_b.clone(), cities(vec![batches[1].clone()]))?;
+        clock.advance(Duration::from_secs(3600));
+        assert!(unbounded.evict().is_empty());
+
+        // The least recently updated windows are evicted beyond the memory limit.
+        let size = |i: usize| spill::batch_memory_size(&batches[i]);
+        let mut arena = Arena::with_limits(Duration::from_secs(10), size(0) + size(2))
+            .with_clock(Arc::new(clock.clone()));
+        let (uuid_c, window_c) = window(2);
+        arena.add(&uuid_a, window_a.clone(), cities(vec![batches[0].clone()]))?;
+        clock.advance(Duration::from_secs(1));
+        arena.add(&uuid_b, window_b.clone(), cities(vec![batches[1].clone()]))?;
+        clock.advance(Duration::from_secs(1));
+        arena.add(&uuid_c, window_c.clone(), cities(vec![batches[2].clone()]))?;
+        clock.advance(Duration::from_secs(1));
+        // The 2nd fragment of window A makes it the most recently updated one.
+        arena.add(&next_fragment(&uuid_a), window_a.clone(), vec![])?;
+
+        let evicted = arena.evict();
+        assert_eq!(1, evicted.len());
+        assert_eq!(window_b, evicted[0].window_id);
+        assert_eq!(EvictionReason::MemoryLimit, evicted[0].reason);
+        assert!(arena.memory_size() <= size(0) + size(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spill_windows() -> Result<()> {
+        let batches = init_batches();
+        let dir = std::env::temp_dir().join(format!("flock-arena-{}", uuid::Uuid::new_v4()));
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 1024, 3);
+        let window_id = (uuids.get(1).qid, 0);
+
+        // Spilling the largest window brings the arena within the threshold.
+        let mut arena = Arena::with_limits(Duration::from_secs(10), usize::MAX)
+            .with_spilling(spill::batch_memory_size(&batches[0]), &dir);
+        arena.add(
+            &uuids.get(1),
+            window_id.clone(),
+            cities(batches[..4].to_vec()),
+        )?;
+        arena.add(
+            &uuids.get(2),
+            window_id.clone(),
+            cities(batches[4..].to_vec()),
+        )?;
+        assert!(arena.spill()? > 0);
+        assert_eq!(0, arena.memory_size());
+        assert_eq!(2, arena.get(&window_id).unwrap().spilled_files().count());
+        assert_eq!(0, arena.spill()?);
+
+        // The spilled fragments still count towards the window.
+        assert_eq!(2, arena.get(&window_id).unwrap().received());
+        let status = arena.add(&uuids.get(3), window_id.clone(), vec![])?;
+        assert!(status == HashAggregateStatus::Ready);
+
+        let inputs = arena.take_window(&window_id);
+        assert_eq!(2, inputs["cities"].records.len());
+        assert!(inputs["cities"].records.iter().all(|b| b.is_empty()));
+        let rows = inputs["cities"]
+            .spilled
+            .iter()
+            .flatten()
+            .map(|file| {
+                file.read()
+                    .map(|b| b.iter().map(|b| b.num_rows()).sum::<usize>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+            rows.iter().sum()
+        );
+        std::fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    fn next_fragment(uuid: &Uuid) -> Uuid {
+        Uuid {
+            seq_num: uuid.seq_num + 1,
+            ..uuid.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_header() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 1024, 2);
+        let mut payload = to_payload(&[("cities", &batches[..1])], uuids.get(1), false);
+        payload.query_number = Some(5);
+
+        let mut arena = Arena::new();
+        assert!(arena.collect(payload.clone())? == HashAggregateStatus::NotReady);
+        let window = arena.get(&payload.get_window_id()).unwrap();
+        let header = window.header.as_ref().unwrap();
+        assert!(header.inputs.is_empty());
+        assert_eq!(Some(5), header.query_number);
+        assert_eq!(payload.uuid, header.uuid);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_inputs() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00", 1024, 3);
+        let window_id = (uuids.get(1).qid, 0);
+
+        // The fragments carry the record batches of different inputs, e.g., the
+        // streams of a union or a multi-way join.
+        let mut arena = Arena::new();
+        for (i, ids) in [vec!["a", "b"], vec!["c"], vec!["a", "b", "c"]]
+            .iter()
+            .enumerate()
+        {
+            let inputs = ids
+                .iter()
+                .map(|id| (*id, &batches[i..i + 1]))
+                .collect::<Vec<_>>();
+            arena.collect(to_payload(&inputs, uuids.get(i + 1), false))?;
+        }
+        assert!(arena.is_complete(&window_id));
+
+        let window = arena.get(&window_id).unwrap();
+        assert_eq!(3, window.received());
+        assert_eq!(
+            vec!["a", "b", "c"],
+            window
+                .schemas()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        );
 
-        assert_eq!(8, arena.take_batches(&window_id)[0].len());
-        assert_eq!(0, arena.take_batches(&("no exists".to_owned(), 0))[0].len());
+        let inputs = arena.take_window(&window_id);
+        assert_eq!(2, inputs["a"].records.len());
+        assert_eq!(2, inputs["b"].records.len());
+        assert_eq!(2, inputs["c"].records.len());
+        assert_eq!(batches[1].num_rows(), inputs["c"].records[0][0].num_rows());
 
         Ok(())
     }
@@ -0,0 +1,312 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Spills the data fragments of large windows to local disk or the EFS mount
+//! as Arrow IPC files, and streams them back to the query plan as a data
+//! source, so the window size isn't capped by the function's memory size.
+
+use crate::error::{FlockError, Result};
+use async_trait::async_trait;
+use datafusion::arrow::array::Array;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::ipc::writer::FileWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result as DataFusionResult};
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream, SendableRecordBatchStream,
+    Statistics,
+};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A data fragment spilled to disk as an Arrow IPC file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpillFile {
+    /// The path of the Arrow IPC file.
+    pub path:        PathBuf,
+    /// The schema of the record batches in the file.
+    pub schema:      SchemaRef,
+    /// The number of rows in the file.
+    pub rows:        usize,
+    /// The memory size of the record batches before spilling, in bytes.
+    pub memory_size: usize,
+}
+
+impl SpillFile {
+    /// Writes the record batches of a data fragment to an Arrow IPC file.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file. Its parent directory is created if it
+    ///   doesn't exist.
+    /// * `batches` - The non-empty record batches of the data fragment.
+    pub fn write(path: impl AsRef<Path>, batches: &[RecordBatch]) -> Result<SpillFile> {
+        let path = path.as_ref();
+        let schema = batches
+            .first()
+            .map(|b| b.schema())
+            .ok_or_else(|| FlockError::Internal("Can't spill an empty fragment.".to_string()))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        write_batches(File::create(path)?, &schema, batches)?;
+        Ok(SpillFile {
+            path: path.to_path_buf(),
+            schema,
+            rows: batches.iter().map(|b| b.num_rows()).sum(),
+            memory_size: batches.iter().map(batch_memory_size).sum(),
+        })
+    }
+
+    /// Reads all the record batches in the file into memory.
+    pub fn read(&self) -> Result<Vec<RecordBatch>> {
+        read_batches(BufReader::new(File::open(&self.path)?))
+    }
+
+    /// Removes the file from disk.
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Writes the record batches in the Arrow IPC file format.
+pub fn write_batches<W: Write>(
+    writer: W,
+    schema: &SchemaRef,
+    batches: &[RecordBatch],
+) -> Result<()> {
+    let mut writer = FileWriter::try_new(writer, schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Reads the record batches in the Arrow IPC file format.
+pub fn read_batches<R: Read + Seek>(reader: R) -> Result<Vec<RecordBatch>> {
+    FileReader::try_new(reader)?
+        .map(|batch| batch.map_err(FlockError::Arrow))
+        .collect()
+}
+
+/// Returns the memory size of the record batch.
+pub fn batch_memory_size(batch: &RecordBatch) -> usize {
+    batch
+        .columns()
+        .iter()
+        .map(|c| c.get_array_memory_size())
+        .sum()
+}
+
+/// Execution plan for a data source whose fragments are partly spilled to
+/// disk. Each fragment is an output partition, as it is in the `MemoryExec`
+/// that the plan replaces. A spilled fragment is read from its file one record
+/// batch at a time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpillExec {
+    /// The schema of the data source.
+    schema:     SchemaRef,
+    /// The record batches of the fragments in memory.
+    #[serde(skip)]
+    partitions: Vec<Vec<RecordBatch>>,
+    /// The spilled files of the fragments.
+    files:      Vec<Option<SpillFile>>,
+}
+
+impl SpillExec {
+    /// Create a new `SpillExec`.
+    ///
+    /// # Arguments
+    /// * `schema` - The schema of the data source.
+    /// * `partitions` - The record batches of each fragment. A spilled
+    ///   fragment's batches are empty.
+    /// * `files` - The spilled file of each fragment, if any.
+    pub fn new(
+        schema: SchemaRef,
+        partitions: Vec<Vec<RecordBatch>>,
+        files: Vec<Option<SpillFile>>,
+    ) -> Self {
+        Self {
+            schema,
+            partitions,
+            files,
+        }
+    }
+
+    /// Returns the spilled files of the data source.
+    pub fn files(&self) -> impl Iterator<Item = &SpillFile> {
+        self.files.iter().flatten()
+    }
+}
+
+#[async_trait]
+#[typetag::serde(name = "spill_exec")]
+impl ExecutionPlan for SpillExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_mut_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(self.partitions.len().max(self.files.len()))
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        if children.is_empty() {
+            Ok(Arc::new(self.clone()))
+        } else {
+            Err(DataFusionError::Internal(format!(
+                "Children cannot be replaced in {:?}",
+                self
+            )))
+        }
+    }
+
+    async fn execute(&self, partition: usize) -> DataFusionResult<SendableRecordBatchStream> {
+        let batches: Box<dyn Iterator<Item = ArrowResult<RecordBatch>> + Send + Sync> =
+            match self.files.get(partition) {
+                Some(Some(file)) => Box::new(FileReader::try_new(BufReader::new(File::open(
+                    &file.path,
+                )?))?),
+                _ => Box::new(
+                    self.partitions
+                        .get(partition)
+                        .cloned()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(Ok),
+                ),
+            };
+        Ok(Box::pin(SpillStream {
+            schema: self.schema.clone(),
+            batches,
+        }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(
+                    f,
+                    "SpillExec: partitions={}, spilled={}",
+                    self.partitions.len().max(self.files.len()),
+                    self.files().count()
+                )
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+/// The stream of a fragment's record batches, read from memory or from its
+/// spilled file.
+struct SpillStream {
+    schema:  SchemaRef,
+    batches: Box<dyn Iterator<Item = ArrowResult<RecordBatch>> + Send + Sync>,
+}
+
+impl Stream for SpillStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.batches.next())
+    }
+}
+
+impl RecordBatchStream for SpillStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::physical_plan::collect;
+
+    fn fragment(schema: &SchemaRef, keys: Vec<&str>, values: Vec<i64>) -> Vec<RecordBatch> {
+        vec![RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(keys)),
+                Arc::new(Int64Array::from(values)),
+            ],
+        )
+        .unwrap()]
+    }
+
+    #[tokio::test]
+    async fn spill_and_stream_fragments() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("key", DataType::Utf8, false),
+            Field::new("value", DataType::Int64, false),
+        ]));
+        let dir = std::env::temp_dir().join(format!("flock-spill-{}", uuid::Uuid::new_v4()));
+
+        let spilled = fragment(&schema, vec!["a", "b", "c"], vec![1, 2, 3]);
+        let file = SpillFile::write(dir.join("fragment-1.arrow"), &spilled)?;
+        assert_eq!(3, file.rows);
+        assert_eq!(
+            pretty_format_batches(&spilled)?,
+            pretty_format_batches(&file.read()?)?
+        );
+
+        // The 1st fragment is in memory, and the 2nd one is spilled.
+        let exec = SpillExec::new(
+            schema.clone(),
+            vec![fragment(&schema, vec!["d"], vec![4]), vec![]],
+            vec![None, Some(file.clone())],
+        );
+        assert_eq!(1, exec.files().count());
+        assert_eq!(2, exec.output_partitioning().partition_count());
+        let output = collect(Arc::new(exec)).await?;
+        assert_eq!(4, output.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        file.remove()?;
+        assert!(!file.path.exists());
+        fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
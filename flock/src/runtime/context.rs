@@ -15,11 +15,15 @@
 //! corresponding execution context from the cloud environment variable.
 
 use crate::datasink::DataSinkType;
+use crate::datasource::InputPartitions;
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
+use crate::runtime::arena::{InputFragments, SpillExec, WindowInputs};
+use crate::runtime::payload::InputId;
 use crate::runtime::plan::CloudExecutionPlan;
+use crate::runtime::schema::{check_compatibility, register_schema, SchemaFingerprint};
 use crate::state::*;
-use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
 use datafusion::physical_plan::memory::MemoryExec;
@@ -212,6 +216,10 @@ impl ExecutionContext {
 
     /// Clean the data source in the given context.
     pub async fn clean_data_sources(&mut self) -> Result<()> {
+        if let Some(plans) = self.plan.unspilled_plans.take() {
+            self.plan.execution_plans = plans;
+        }
+
         // Breadth-first search
         let mut queue = VecDeque::new();
         self.plan().await?.into_iter().for_each(|plan| {
@@ -240,77 +248,149 @@ impl ExecutionContext {
         Ok(())
     }
 
-    /// Feeds all data sources to the execution plan.
-    pub async fn feed_data_sources(
-        &mut self,
-        mut sources: Vec<Vec<Vec<RecordBatch>>>,
-    ) -> Result<()> {
+    /// Returns the schemas of the data sources (the leaf nodes) of the plan.
+    pub async fn data_source_schemas(&mut self) -> Result<Vec<SchemaRef>> {
         // Breadth-first search
+        let mut schemas = vec![];
         let mut queue = VecDeque::new();
         self.plan().await?.into_iter().for_each(|plan| {
             queue.push_back(plan);
         });
 
-        let num_partitions = sources[0].len();
-        let mut found = false;
-        let mut index = 0xFFFFFFFF;
-        while !queue.is_empty() {
-            let mut plan = queue.pop_front().unwrap();
+        while let Some(plan) = queue.pop_front() {
             if plan.children().is_empty() {
-                for (i, partition) in sources.iter().enumerate() {
-                    let mut schema = Arc::new(Schema::new(vec![]));
-                    let mut flag = false;
-                    for p in partition.iter().filter(|p| !p.is_empty()) {
-                        if let Some(b) = p.iter().next() {
-                            schema = b.schema();
-                            flag = true;
-                            break;
-                        }
-                    }
-                    if !flag {
-                        continue;
-                    }
-
-                    if compare_schema(plan.schema(), schema) {
-                        index = i;
-                        found = true;
-                        break;
-                    }
+                let schema = plan.schema();
+                if !schemas.contains(&schema) {
+                    schemas.push(schema);
                 }
+            }
+            plan.children()
+                .into_iter()
+                .for_each(|child| queue.push_back(child));
+        }
 
-                if found {
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(sources.remove(index));
-                        index = 0xFFFFFFFF;
-                        found = false;
-                    }
-                } else {
-                    let batches = (0..num_partitions)
-                        .map(|_| RecordBatch::new_empty(plan.schema()))
-                        .collect::<Vec<RecordBatch>>();
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(vec![batches]);
-                    }
-                }
+        Ok(schemas)
+    }
+
+    /// Registers the schemas of the data sources in the container-wide schema
+    /// registry, so that the incoming payloads can only carry the schema
+    /// fingerprints. Returns the fingerprints of the registered schemas.
+    pub async fn register_schemas(&mut self) -> Result<Vec<SchemaFingerprint>> {
+        Ok(self
+            .data_source_schemas()
+            .await?
+            .into_iter()
+            .map(register_schema)
+            .collect())
+    }
+
+    /// Returns the leaf nodes of the plan from left to right, along with the
+    /// ids of the inputs fed to them.
+    async fn leaf_nodes(&mut self) -> Result<Vec<(Arc<dyn ExecutionPlan>, Option<InputId>)>> {
+        Ok(leaf_nodes(&self.plan().await?)
+            .into_iter()
+            .enumerate()
+            .map(|(i, leaf)| (leaf, self.plan.inputs.get(i).cloned()))
+            .collect())
+    }
+
+    /// Feeds all data sources to the execution plan. Each leaf `MemoryExec`
+    /// is fed the data source with its input id, or the data source whose
+    /// schema matches if the leaf has no input id. The leaves of a self-join
+    /// share the same input id, and are all fed the same data source.
+    ///
+    /// Returns an error if a non-empty data source doesn't match any leaf node
+    /// of the plan, i.e., the producer's schema has drifted from what the
+    /// stage plan expects.
+    pub async fn feed_data_sources(&mut self, mut sources: InputPartitions) -> Result<()> {
+        let num_partitions = sources
+            .first()
+            .map_or(1, |(_, partitions)| partitions.len());
+        let mut expected = vec![];
+        for (mut plan, id) in self.leaf_nodes().await? {
+            if !plan.as_any().is::<MemoryExec>() {
+                continue;
+            }
+            expected.push(plan.schema());
+
+            let matched = match_input(&plan, id.as_ref(), &sources, |partitions| {
+                partitions.iter().flatten().next().map(|b| b.schema())
+            });
+            let partitions = match matched {
+                Some(index) if id.is_some() => sources[index].1.clone(),
+                Some(index) => sources.remove(index).1,
+                None => vec![(0..num_partitions)
+                    .map(|_| RecordBatch::new_empty(plan.schema()))
+                    .collect()],
+            };
+            unsafe {
+                Arc::get_mut_unchecked(&mut plan)
+                    .as_mut_any()
+                    .downcast_mut::<MemoryExec>()
+                    .unwrap()
+                    .set_partitions(partitions);
             }
+        }
 
-            plan.children()
-                .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(plan.children()[i].clone()));
+        // The remaining data sources can't be fed to any leaf node.
+        for (id, partitions) in sources
+            .iter()
+            .filter(|(id, _)| !self.plan.inputs.contains(id))
+        {
+            if let Some(b) = partitions.iter().flatten().find(|b| b.num_rows() > 0) {
+                check_compatibility(&expected, &b.schema())?;
+                return Err(FlockError::Plan(format!(
+                    "The data source {} with schema {:?} doesn't match any input of the stage plan",
+                    id,
+                    b.schema()
+                )));
+            }
         }
 
         Ok(())
     }
 
+    /// Feeds the data sources whose fragments are partly spilled to disk. The
+    /// leaf `MemoryExec` of a spilled data source is replaced by a `SpillExec`
+    /// that streams the spilled fragments from disk. The other data sources
+    /// are fed as usual, and `clean_data_sources` restores the original plan.
+    ///
+    /// # Arguments
+    /// * `inputs` - The fragments of each data source, in memory or spilled.
+    pub async fn feed_spilled_sources(&mut self, inputs: WindowInputs) -> Result<()> {
+        let (mut spilled, remaining): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .partition(|(_, fragments)| fragments.is_spilled());
+        let remaining = remaining
+            .into_iter()
+            .map(|(id, fragments)| (id, fragments.records))
+            .collect::<InputPartitions>();
+        if spilled.is_empty() {
+            return self.feed_data_sources(remaining).await;
+        }
+
+        let plans = self.plan().await?;
+        let mut leaf = 0;
+        let execution_plans = plans
+            .iter()
+            .map(|plan| replace_spilled_sources(plan, &self.plan.inputs, &mut leaf, &mut spilled))
+            .collect::<Result<Vec<_>>>()?;
+        spilled.retain(|(id, _)| !self.plan.inputs.contains(id));
+        if let Some((id, _)) = spilled.first() {
+            return Err(FlockError::Plan(format!(
+                "The spilled data source {} doesn't match any input of the stage plan",
+                id
+            )));
+        }
+        self.plan.execution_plans = execution_plans;
+        self.plan.unspilled_plans = Some(plans);
+
+        if !remaining.is_empty() {
+            self.feed_data_sources(remaining).await?;
+        }
+        Ok(())
+    }
+
     /// Checks whether the execution plan needs to be shuffled.
     pub async fn is_shuffling(&self) -> Result<bool> {
         assert!(!self.plan.execution_plans.is_empty());
@@ -384,6 +464,98 @@ where
     })
 }
 
+/// Replaces the leaf nodes of the plan that match the spilled data sources with
+/// `SpillExec`s. The data sources matched by schema are removed from `spilled`,
+/// while the ones matched by input id are kept for the other leaves of a
+/// self-join.
+///
+/// # Arguments
+/// * `plan` - The execution plan.
+/// * `inputs` - The ids of the inputs fed to the leaf nodes of the plans.
+/// * `leaf` - The position of the next leaf node from left to right.
+/// * `spilled` - The fragments of the spilled data sources.
+fn replace_spilled_sources(
+    plan: &Arc<dyn ExecutionPlan>,
+    inputs: &[InputId],
+    leaf: &mut usize,
+    spilled: &mut Vec<(InputId, InputFragments)>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let children = plan.children();
+    if children.is_empty() {
+        let id = inputs.get(*leaf);
+        *leaf += 1;
+        let matched = match_input(plan, id, spilled, |fragments| {
+            fragments
+                .spilled
+                .iter()
+                .flatten()
+                .next()
+                .map(|file| file.schema.clone())
+        });
+        return Ok(match matched {
+            Some(i) => {
+                let fragments = match id {
+                    Some(_) => spilled[i].1.clone(),
+                    None => spilled.remove(i).1,
+                };
+                Arc::new(SpillExec::new(
+                    plan.schema(),
+                    fragments.records,
+                    fragments.spilled,
+                ))
+            }
+            None => plan.clone(),
+        });
+    }
+
+    let children = children
+        .iter()
+        .map(|child| replace_spilled_sources(child, inputs, leaf, spilled))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(plan.with_new_children(children)?)
+}
+
+/// Returns the leaf nodes of the plans from left to right, i.e., in the order
+/// the planner assigns the input ids to them.
+pub(crate) fn leaf_nodes(plans: &[Arc<dyn ExecutionPlan>]) -> Vec<Arc<dyn ExecutionPlan>> {
+    fn visit(plan: Arc<dyn ExecutionPlan>, leaves: &mut Vec<Arc<dyn ExecutionPlan>>) {
+        let children = plan.children();
+        if children.is_empty() {
+            leaves.push(plan);
+        }
+        children.into_iter().for_each(|child| visit(child, leaves));
+    }
+
+    let mut leaves = vec![];
+    plans
+        .iter()
+        .for_each(|plan| visit(plan.clone(), &mut leaves));
+    leaves
+}
+
+/// Returns the position of the input fed to the leaf node: the input with the
+/// leaf's input id, or the first input whose schema matches the leaf's if the
+/// leaf has no input id.
+///
+/// # Arguments
+/// * `leaf` - The leaf node of the plan.
+/// * `id` - The id of the input fed to the leaf node, if any.
+/// * `inputs` - The inputs keyed by their ids.
+/// * `schema` - Returns the schema of an input, if it has any record batch.
+pub(crate) fn match_input<T>(
+    leaf: &Arc<dyn ExecutionPlan>,
+    id: Option<&InputId>,
+    inputs: &[(InputId, T)],
+    schema: impl Fn(&T) -> Option<SchemaRef>,
+) -> Option<usize> {
+    match id {
+        Some(id) => inputs.iter().position(|(input, _)| input == id),
+        None => inputs.iter().position(|(_, input)| {
+            schema(input).map_or(false, |schema| compare_schema(leaf.schema(), schema))
+        }),
+    }
+}
+
 /// Compare two execution plans' schemas.
 /// Returns true if they are belong to the same plan node.
 fn compare_schema(schema1: SchemaRef, schema2: SchemaRef) -> bool {
@@ -473,7 +645,8 @@ mod tests {
             next: CloudFunction::Sink(DataSinkType::Blackhole),
             ..Default::default()
         };
-        ctx.feed_data_sources(vec![vec![vec![batch]]]).await?;
+        ctx.feed_data_sources(vec![("test".to_owned(), vec![vec![batch]])])
+            .await?;
 
         let batches = ctx.execute().await?;
 
@@ -549,7 +722,8 @@ mod tests {
 
         // Feed record batches back to the plan
         let mut ctx = ExecutionContext {
-            plan: CloudExecutionPlan::new(vec![plan], None),
+            plan: CloudExecutionPlan::new(vec![plan], None)
+                .with_inputs(vec!["t1".to_owned(), "t2".to_owned()]),
             name: "test".to_string(),
             next: CloudFunction::Sink(DataSinkType::Blackhole),
             ..Default::default()
@@ -558,8 +732,13 @@ mod tests {
         let de_json = unmarshal(&se_json)?;
         assert_eq!(ctx, de_json);
 
-        ctx.feed_data_sources(vec![vec![vec![batch1]], vec![vec![batch2]]])
-            .await?;
+        // The data sources are fed to the leaf nodes by their input ids, no matter
+        // in which order they arrive.
+        ctx.feed_data_sources(vec![
+            ("t2".to_owned(), vec![vec![batch2]]),
+            ("t1".to_owned(), vec![vec![batch1]]),
+        ])
+        .await?;
 
         let batches = ctx.execute().await?;
 
@@ -577,4 +756,56 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn feed_self_join() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3])),
+                Arc::new(Int32Array::from(vec![2, 3, 4])),
+            ],
+        )?;
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+        ctx.register_table("t", Arc::new(table))?;
+
+        let sql = "SELECT l.k AS k, r.k AS rk FROM t AS l JOIN t AS r ON l.v = r.k ORDER BY k";
+        let logical_plan = ctx.create_logical_plan(sql)?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan).await?;
+        let plan = serde_json::to_string(&physical_plan)?;
+        let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
+
+        let mut ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![plan], None)
+                .with_inputs(vec!["t".to_owned(), "t".to_owned()]),
+            name: "test".to_string(),
+            next: CloudFunction::Sink(DataSinkType::Blackhole),
+            ..Default::default()
+        };
+
+        // Both scans of the self-join are fed the same data source.
+        ctx.feed_data_sources(vec![("t".to_owned(), vec![vec![batch]])])
+            .await?;
+        let batches = ctx.execute().await?;
+
+        let expected = vec![
+            "+---+----+",
+            "| k | rk |",
+            "+---+----+",
+            "| 1 | 2  |",
+            "| 2 | 3  |",
+            "+---+----+",
+        ];
+
+        assert_batches_eq!(&expected, &batches[0]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,195 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The invocation deadline of a cloud function.
+//!
+//! A function is killed when its timeout expires, and the invocation is
+//! retried from scratch, so a long computation that never fits in a single
+//! invocation never finishes. A function tracks the time remaining before its
+//! deadline, and stops accepting new work when it's within the safety margin.
+//! The rest of the work is handed over to a new invocation of the function with
+//! a [`Continuation`] in the payload's metadata.
+
+use crate::configs::FLOCK_CONFIG;
+use crate::error::{FlockError, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The metadata key of the continuation in the payload.
+pub const CONTINUATION_KEY: &str = "continuation";
+
+/// The deadline of a function invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    /// The deadline in milliseconds since the Unix epoch. None if the
+    /// invocation has no deadline.
+    deadline: Option<i64>,
+    /// The time reserved before the deadline to hand over the rest of the work.
+    margin:   Duration,
+}
+
+impl Deadline {
+    /// Creates the deadline of the invocation with the safety margin of the
+    /// configuration (`deadline.margin`).
+    ///
+    /// # Arguments
+    /// * `deadline` - The deadline in milliseconds since the Unix epoch, i.e.,
+    ///   `LambdaEvent::context.deadline`.
+    pub fn new(deadline: u64) -> Self {
+        Deadline::with_margin(deadline, Duration::from_secs(FLOCK_CONFIG.deadline.margin))
+    }
+
+    /// Creates the deadline of the invocation with the given safety margin.
+    pub fn with_margin(deadline: u64, margin: Duration) -> Self {
+        Deadline {
+            deadline: Some(deadline as i64),
+            margin,
+        }
+    }
+
+    /// Returns a deadline that never comes, e.g., for local executions.
+    pub fn unlimited() -> Self {
+        Deadline {
+            deadline: None,
+            margin:   Duration::ZERO,
+        }
+    }
+
+    /// Returns the time remaining before the deadline, or None if the
+    /// invocation has no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            Duration::from_millis((deadline - Utc::now().timestamp_millis()).max(0) as u64)
+        })
+    }
+
+    /// Returns true if the remaining time is within the safety margin, so the
+    /// function must stop accepting new work.
+    pub fn is_near(&self) -> bool {
+        self.remaining().map_or(false, |r| r <= self.margin)
+    }
+}
+
+/// The progress of a long computation that is handed over to the next
+/// invocation of the same function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Continuation {
+    /// The number of times the computation has been handed over.
+    pub hops:  usize,
+    /// The epoch to resume from, for the data source generators.
+    pub epoch: usize,
+}
+
+impl Continuation {
+    /// Reads the continuation from the payload's metadata.
+    ///
+    /// # Returns
+    /// None if the payload doesn't continue a computation.
+    pub fn from_metadata(metadata: &Option<HashMap<String, String>>) -> Result<Option<Self>> {
+        match metadata.as_ref().and_then(|m| m.get(CONTINUATION_KEY)) {
+            Some(value) => serde_json::from_str(value).map(Some).map_err(|e| {
+                FlockError::Internal(format!("Invalid continuation `{}`: {}", value, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the continuation to the payload's metadata.
+    pub fn to_metadata(&self, metadata: &mut Option<HashMap<String, String>>) -> Result<()> {
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(CONTINUATION_KEY.to_owned(), serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Removes the continuation from the payload's metadata, so it isn't
+    /// forwarded to the next functions.
+    pub fn remove_from_metadata(metadata: &mut Option<HashMap<String, String>>) {
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.remove(CONTINUATION_KEY);
+        }
+    }
+
+    /// Returns the continuation of the next invocation, which resumes from the
+    /// given epoch.
+    ///
+    /// # Returns
+    /// None if the computation has been handed over too many times
+    /// (`deadline.max_continuations`), and must finish in the current
+    /// invocation.
+    pub fn next(current: Option<Self>, epoch: usize) -> Option<Self> {
+        let hops = current.map_or(0, |c| c.hops) + 1;
+        if hops > FLOCK_CONFIG.deadline.max_continuations {
+            return None;
+        }
+        Some(Continuation { hops, epoch })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_deadline() {
+        let now = Utc::now().timestamp_millis() as u64;
+        let margin = Duration::from_secs(10);
+
+        let deadline = Deadline::with_margin(now + 60_000, margin);
+        assert!(!deadline.is_near());
+        assert!(deadline.remaining().unwrap() > margin);
+
+        let deadline = Deadline::with_margin(now + 5_000, margin);
+        assert!(deadline.is_near());
+
+        // The deadline has passed.
+        let deadline = Deadline::with_margin(now - 5_000, margin);
+        assert!(deadline.is_near());
+        assert_eq!(Some(Duration::ZERO), deadline.remaining());
+
+        assert!(!Deadline::unlimited().is_near());
+        assert_eq!(None, Deadline::unlimited().remaining());
+    }
+
+    #[test]
+    fn continuation_metadata() -> Result<()> {
+        let mut metadata = None;
+        assert_eq!(None, Continuation::from_metadata(&metadata)?);
+
+        let continuation = Continuation::next(None, 3).unwrap();
+        assert_eq!(Continuation { hops: 1, epoch: 3 }, continuation);
+        continuation.to_metadata(&mut metadata)?;
+        assert_eq!(Some(continuation), Continuation::from_metadata(&metadata)?);
+
+        Continuation::remove_from_metadata(&mut metadata);
+        assert_eq!(None, Continuation::from_metadata(&metadata)?);
+
+        let next = Continuation::next(Some(continuation), 7).unwrap();
+        assert_eq!(Continuation { hops: 2, epoch: 7 }, next);
+
+        // A computation is handed over at most `max_continuations` times.
+        let last = Continuation {
+            hops:  FLOCK_CONFIG.deadline.max_continuations,
+            epoch: 9,
+        };
+        assert_eq!(None, Continuation::next(Some(last), 10));
+
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(CONTINUATION_KEY.to_owned(), "{".to_owned());
+        assert!(Continuation::from_metadata(&metadata).is_err());
+        Ok(())
+    }
+}
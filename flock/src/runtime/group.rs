@@ -0,0 +1,630 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! An elastic function group is a set of member functions that process the
+//! windows of the same query stage. Each member has a concurrency of *1*, and
+//! the upstream functions route all data fragments of a window to the same
+//! member with consistent hashing.
+//!
+//! The members are created and retired with the observed load. To route a
+//! window consistently while the membership changes, each member has a window
+//! timestamp from which it joins the hash ring, and another one from which it
+//! leaves the ring. A window is always routed with the ring of its own
+//! timestamp (from its query id), so that all fragments of the window, sent by
+//! any upstream function at any time, reach the same member. The membership
+//! changes only take effect after a grace period, which gives all upstream
+//! functions the time to reload the membership from S3 before the new ring is
+//! used. A retired member keeps receiving the fragments of the windows that
+//! started before its retirement until it's drained, and it's deleted
+//! afterwards. A retired member is drained once the drain timeout has passed
+//! and it has no checkpointed window left in the state backend.
+
+use crate::aws::{lambda, s3};
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::CHECKPOINT_KEY_PREFIX;
+use crate::runtime::context::CloudFunction;
+use crate::state::StateBackend;
+use chrono::Utc;
+use hashring::HashRing;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The key prefix of the function groups in the Flock S3 bucket.
+pub const GROUP_KEY_PREFIX: &str = "groups";
+
+/// The load of the scaled group must stay below this fraction of the target
+/// load, otherwise the group isn't scaled down.
+const SCALE_DOWN_HEADROOM: f64 = 0.8;
+
+/// A member function of the function group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupMember {
+    /// The function name of the member.
+    pub name:    String,
+    /// The window timestamp from which the member receives windows.
+    pub joined:  i64,
+    /// The window timestamp from which the member doesn't receive windows.
+    pub retired: Option<i64>,
+}
+
+impl GroupMember {
+    /// Returns true if the member receives the windows of the given timestamp.
+    pub fn is_active_at(&self, timestamp: i64) -> bool {
+        self.joined <= timestamp && self.retired.map_or(true, |retired| timestamp < retired)
+    }
+}
+
+/// The load reported by an upstream function of the group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoadSample {
+    /// The upstream function name.
+    pub upstream:  String,
+    /// The time when the load was observed.
+    pub timestamp: i64,
+    /// The payloads routed to the group per second.
+    pub rate:      f64,
+}
+
+/// An elastic function group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FunctionGroup {
+    /// The group name.
+    pub name:         String,
+    /// The version of the membership, which is bumped on every change.
+    pub version:      u64,
+    /// The members of the group, including the retired ones that are still
+    /// draining.
+    pub members:      Vec<GroupMember>,
+    /// The index of the next member function.
+    pub next_index:   usize,
+    /// The time of the last scaling decision.
+    pub scaled_at:    i64,
+    /// The upstream function that routes the windows to the group.
+    #[serde(skip)]
+    pub upstream:     String,
+    /// The time of the last membership refresh.
+    #[serde(skip)]
+    pub refreshed_at: i64,
+    /// The payloads routed to the group since the last refresh.
+    #[serde(skip)]
+    routed:           Arc<AtomicUsize>,
+}
+
+impl FunctionGroup {
+    /// Returns a new function group with the given number of members.
+    ///
+    /// The members follow the cloud function naming convention: a group of a
+    /// single function is named after the group, otherwise the members are
+    /// named `<group name>-<group index>`.
+    pub fn new(name: &str, size: usize) -> Self {
+        let members = match size {
+            0 => vec![],
+            1 => vec![GroupMember {
+                name:    name.to_owned(),
+                joined:  0,
+                retired: None,
+            }],
+            _ => (0..size)
+                .map(|i| GroupMember {
+                    name:    member_name(name, i),
+                    joined:  0,
+                    retired: None,
+                })
+                .collect(),
+        };
+        Self {
+            name: name.to_owned(),
+            members,
+            next_index: size,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the function group of the next cloud function.
+    pub fn from_cloud_function(next: &CloudFunction) -> Self {
+        match next {
+            CloudFunction::Lambda(name) => FunctionGroup::new(name, 1),
+            CloudFunction::Group((name, size)) => FunctionGroup::new(name, *size),
+            CloudFunction::Sink(..) => FunctionGroup::new("", 0),
+        }
+    }
+
+    /// Returns the number of members that receive new windows.
+    pub fn len(&self) -> usize {
+        self.members_at(Utc::now().timestamp()).len()
+    }
+
+    /// Returns true if no member receives new windows.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the names of the members that receive the windows of the given
+    /// timestamp.
+    pub fn members_at(&self, timestamp: i64) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|m| m.is_active_at(timestamp))
+            .map(|m| m.name.clone())
+            .collect()
+    }
+
+    /// Returns the member function of the given window.
+    ///
+    /// # Arguments
+    /// * `qid` - The query id of the window, which contains its timestamp.
+    pub fn route(&self, qid: &str) -> Result<String> {
+        self.route_key(&qid, window_timestamp(qid))
+    }
+
+    /// Returns the member function of the given key with the hash ring of the
+    /// given window timestamp.
+    pub fn route_key<K: Hash>(&self, key: &K, timestamp: i64) -> Result<String> {
+        self.routed.fetch_add(1, Ordering::Relaxed);
+
+        let mut ring: HashRing<String> = HashRing::new();
+        self.members_at(timestamp)
+            .into_iter()
+            .for_each(|name| ring.add(name));
+        ring.get(key).map(|name| name.to_owned()).ok_or_else(|| {
+            FlockError::Internal(format!(
+                "No member of function group {} at {}.",
+                self.name, timestamp
+            ))
+        })
+    }
+
+    /// Changes the number of members that receive new windows.
+    ///
+    /// The new members join and the retired members leave the hash ring from
+    /// the given window timestamp. The most recently joined members are
+    /// retired first.
+    ///
+    /// # Returns
+    /// The names of the added members and the retired members.
+    pub fn scale_to(&mut self, size: usize, effective: i64) -> (Vec<String>, Vec<String>) {
+        let mut active = self
+            .members
+            .iter_mut()
+            .filter(|m| m.retired.is_none())
+            .collect::<Vec<_>>();
+        let current = active.len();
+
+        let mut retired = vec![];
+        if size < current {
+            active.sort_by_key(|m| m.joined);
+            active.iter_mut().skip(size).for_each(|m| {
+                m.retired = Some(effective);
+                retired.push(m.name.clone());
+            });
+        }
+
+        let mut added = vec![];
+        for _ in current..size {
+            let name = member_name(&self.name, self.next_index);
+            self.next_index += 1;
+            self.members.push(GroupMember {
+                name:    name.clone(),
+                joined:  effective,
+                retired: None,
+            });
+            added.push(name);
+        }
+
+        if !added.is_empty() || !retired.is_empty() {
+            self.version += 1;
+        }
+        (added, retired)
+    }
+
+    /// Returns the retired members whose drain timeout has passed, which means
+    /// no fragment of their windows is expected anymore.
+    pub fn drained(&self, now: i64, drain_timeout: i64) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|m| {
+                m.retired
+                    .map_or(false, |retired| retired + drain_timeout <= now)
+            })
+            .map(|m| m.name.clone())
+            .collect()
+    }
+
+    /// Returns the retired members that can be deleted: their drain timeout
+    /// has passed, and they have no checkpointed window in the state backend.
+    /// A member with checkpoints still holds the partial state of its windows,
+    /// which would be lost with the function, so it's kept until its windows
+    /// are emitted.
+    pub async fn drained_members(
+        &self,
+        now: i64,
+        drain_timeout: i64,
+        state_backend: &Arc<dyn StateBackend>,
+    ) -> Result<Vec<String>> {
+        let mut drained = vec![];
+        for name in self.drained(now, drain_timeout) {
+            let prefix = format!("{}/{}/", CHECKPOINT_KEY_PREFIX, name);
+            let checkpoints = state_backend.keys(FLOCK_S3_BUCKET.clone(), prefix).await?;
+            if checkpoints.is_empty() {
+                drained.push(name);
+            } else {
+                info!(
+                    "The retired member {} still has {} checkpointed windows",
+                    name,
+                    checkpoints.len()
+                );
+            }
+        }
+        Ok(drained)
+    }
+
+    /// Removes the given members from the group.
+    pub fn remove(&mut self, names: &[String]) {
+        let len = self.members.len();
+        self.members.retain(|m| !names.contains(&m.name));
+        if self.members.len() != len {
+            self.version += 1;
+        }
+    }
+
+    /// Returns the desired number of members for the given load.
+    ///
+    /// # Arguments
+    /// * `load` - The payloads routed to the group per second.
+    /// * `settings` - The function group settings.
+    pub fn desired_size(&self, load: f64, settings: &GroupSettings) -> usize {
+        let current = self.members.iter().filter(|m| m.retired.is_none()).count();
+        let size = |target: f64| {
+            ((load / target).ceil() as usize)
+                .max(settings.min_size)
+                .min(settings.max_size)
+        };
+        let desired = size(settings.target_load);
+        if desired < current {
+            // Avoids flapping: the smaller group must be able to absorb the load
+            // with some headroom.
+            size(settings.target_load * SCALE_DOWN_HEADROOM).min(current)
+        } else {
+            desired
+        }
+    }
+
+    /// Reloads the membership, reports the observed load and, if autoscaling
+    /// is enabled, rebalances the group. It's a no-op if the last refresh is
+    /// more recent than the refresh interval.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend where the members checkpoint their
+    ///   windows.
+    pub async fn refresh(&mut self, state_backend: &Arc<dyn StateBackend>) -> Result<()> {
+        let settings = &FLOCK_CONFIG.group;
+        let now = Utc::now().timestamp();
+        if self.name.is_empty() || now - self.refreshed_at < settings.refresh_interval {
+            return Ok(());
+        }
+
+        if self.refreshed_at > 0 {
+            let routed = self.routed.swap(0, Ordering::Relaxed);
+            let sample = LoadSample {
+                upstream:  self.upstream.clone(),
+                timestamp: now,
+                rate:      routed as f64 / (now - self.refreshed_at) as f64,
+            };
+            s3::put_object(
+                &FLOCK_S3_BUCKET,
+                &load_key(&self.name, &self.upstream),
+                serde_json::to_vec(&sample)?,
+            )
+            .await?;
+        }
+        self.refreshed_at = now;
+
+        if let Some(group) = FunctionGroup::load(&self.name).await? {
+            if group.version > self.version {
+                info!("Function group {} version: {}", self.name, group.version);
+                self.version = group.version;
+                self.members = group.members;
+                self.next_index = group.next_index;
+                self.scaled_at = group.scaled_at;
+            }
+        }
+
+        if settings.autoscale {
+            let samples = FunctionGroup::load_samples(&self.name).await?;
+            let samples = samples
+                .into_iter()
+                .filter(|s| s.timestamp >= now - 2 * settings.refresh_interval)
+                .collect::<Vec<_>>();
+            // The upstream function with the smallest name makes the scaling
+            // decisions, so that the membership has a single writer.
+            if samples.iter().map(|s| &s.upstream).min() == Some(&self.upstream) {
+                let load = samples.iter().map(|s| s.rate).sum::<f64>();
+                let desired = self.desired_size(load, settings);
+                self.rebalance(desired, now, state_backend).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scales the group to the desired size, and deletes the drained members.
+    ///
+    /// The new member functions are cloned from an existing member, and the
+    /// membership change takes effect after the grace period. The membership
+    /// is saved to S3 if it's changed.
+    pub async fn rebalance(
+        &mut self,
+        desired: usize,
+        now: i64,
+        state_backend: &Arc<dyn StateBackend>,
+    ) -> Result<()> {
+        let settings = &FLOCK_CONFIG.group;
+        let version = self.version;
+
+        let active = self.members.iter().filter(|m| m.retired.is_none()).count();
+        if desired != active && now - self.scaled_at >= settings.cooldown {
+            let template = self
+                .members
+                .iter()
+                .find(|m| m.retired.is_none())
+                .map(|m| m.name.clone())
+                .ok_or_else(|| {
+                    FlockError::Internal(format!("No member in function group {}.", self.name))
+                })?;
+            let (added, retired) = self.scale_to(desired, now + settings.grace_period);
+            for name in &added {
+                lambda::clone_function(&template, name).await?;
+            }
+            info!(
+                "Scale function group {} from {} to {}: added {:?}, retired {:?}",
+                self.name, active, desired, added, retired
+            );
+            self.scaled_at = now;
+        }
+
+        let drained = self
+            .drained_members(now, settings.drain_timeout, state_backend)
+            .await?;
+        for name in &drained {
+            if let Err(e) = lambda::delete_function(name).await {
+                warn!("Failed to delete the drained member {}: {}", name, e);
+            }
+        }
+        self.remove(&drained);
+
+        if self.version != version {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Loads the membership of the group from S3.
+    pub async fn load(name: &str) -> Result<Option<FunctionGroup>> {
+        let key = membership_key(name);
+        if s3::get_matched_keys(&FLOCK_S3_BUCKET, &key)
+            .await?
+            .is_empty()
+        {
+            return Ok(None);
+        }
+        let group = serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?;
+        Ok(Some(group))
+    }
+
+    /// Saves the membership of the group to S3.
+    pub async fn save(&self) -> Result<()> {
+        s3::put_object(
+            &FLOCK_S3_BUCKET,
+            &membership_key(&self.name),
+            serde_json::to_vec(self)?,
+        )
+        .await
+    }
+
+    /// Loads the load samples reported by the upstream functions of the group.
+    pub async fn load_samples(name: &str) -> Result<Vec<LoadSample>> {
+        let keys = s3::get_matched_keys(&FLOCK_S3_BUCKET, &load_key(name, "")).await?;
+        let mut samples = vec![];
+        for key in keys {
+            samples.push(serde_json::from_slice(
+                &s3::get_object(&FLOCK_S3_BUCKET, &key).await?,
+            )?);
+        }
+        Ok(samples)
+    }
+}
+
+/// Returns the name of the member at the given index in the group.
+pub fn member_name(group_name: &str, index: usize) -> String {
+    format!("{}-{:02}", group_name, index)
+}
+
+/// Returns the name of the group that the function belongs to. The members
+/// of a group are named `<query code>-<plan index>-<member index>`, and a
+/// group of one function is named after the group itself.
+pub fn group_of(function_name: &str) -> String {
+    function_name
+        .splitn(3, '-')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Returns the timestamp of the window from its query id. If the query id
+/// has no timestamp, the current time is used.
+pub fn window_timestamp(qid: &str) -> i64 {
+    qid.split('-')
+        .nth(1)
+        .and_then(|ts| ts.parse::<i64>().ok())
+        .unwrap_or_else(|| Utc::now().timestamp())
+}
+
+fn membership_key(name: &str) -> String {
+    format!("{}/{}/membership", GROUP_KEY_PREFIX, name)
+}
+
+fn load_key(name: &str, upstream: &str) -> String {
+    format!("{}/{}/load/{}", GROUP_KEY_PREFIX, name, upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::HashMapStateBackend;
+
+    fn settings() -> GroupSettings {
+        GroupSettings {
+            autoscale:        true,
+            min_size:         1,
+            max_size:         8,
+            target_load:      10.0,
+            refresh_interval: 10,
+            grace_period:     30,
+            drain_timeout:    300,
+            cooldown:         60,
+        }
+    }
+
+    #[tokio::test]
+    async fn group_members() -> Result<()> {
+        let group = FunctionGroup::new("SX72HzqFz1Qij4bP-01", 1);
+        assert_eq!(vec!["SX72HzqFz1Qij4bP-01"], group.members_at(0));
+
+        let group = FunctionGroup::from_cloud_function(&CloudFunction::Group((
+            "SX72HzqFz1Qij4bP-01".to_string(),
+            3,
+        )));
+        assert_eq!(
+            vec![
+                "SX72HzqFz1Qij4bP-01-00",
+                "SX72HzqFz1Qij4bP-01-01",
+                "SX72HzqFz1Qij4bP-01-02"
+            ],
+            group.members_at(0)
+        );
+        assert_eq!(3, group.len());
+        for member in group.members_at(0) {
+            assert_eq!("SX72HzqFz1Qij4bP-01", group_of(&member));
+        }
+        assert_eq!("SX72HzqFz1Qij4bP-01", group_of("SX72HzqFz1Qij4bP-01"));
+
+        // The same window is routed to the same member by any upstream function.
+        let qid = "SX72HzqFz1Qij4bP-1638223521-294117488213459711";
+        assert_eq!(1638223521, window_timestamp(qid));
+        assert_eq!(group.route(qid)?, group.clone().route(qid)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scale_group() -> Result<()> {
+        let mut group = FunctionGroup::new("SX72HzqFz1Qij4bP-01", 2);
+        let qids = (0..64)
+            .map(|i| format!("SX72HzqFz1Qij4bP-{}-{}", 1000 + i, i))
+            .collect::<Vec<_>>();
+        let before = qids
+            .iter()
+            .map(|qid| group.route(qid))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Scale out from the window timestamp 1032.
+        let (added, retired) = group.scale_to(4, 1032);
+        assert_eq!(
+            vec!["SX72HzqFz1Qij4bP-01-02", "SX72HzqFz1Qij4bP-01-03"],
+            added
+        );
+        assert!(retired.is_empty());
+        assert_eq!(1, group.version);
+        for (i, qid) in qids.iter().enumerate() {
+            let member = group.route(qid)?;
+            if i < 32 {
+                // The earlier windows keep their members.
+                assert_eq!(before[i], member);
+            } else {
+                assert!(group.members_at(1032).contains(&member));
+            }
+        }
+
+        // Scale in from the window timestamp 2000: the newest members retire.
+        let (added, retired) = group.scale_to(2, 2000);
+        assert!(added.is_empty());
+        assert_eq!(
+            vec!["SX72HzqFz1Qij4bP-01-02", "SX72HzqFz1Qij4bP-01-03"],
+            retired
+        );
+        assert_eq!(4, group.members_at(1999).len());
+        assert_eq!(2, group.members_at(2000).len());
+
+        // The retired members drain their windows before they're removed.
+        assert!(group.drained(2000 + 299, 300).is_empty());
+        let drained = group.drained(2000 + 300, 300);
+        assert_eq!(retired, drained);
+        group.remove(&drained);
+        assert_eq!(2, group.members.len());
+        assert_eq!(3, group.version);
+
+        // The retired names aren't reused.
+        let (added, _) = group.scale_to(3, 3000);
+        assert_eq!(vec!["SX72HzqFz1Qij4bP-01-04"], added);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_checkpointed_members() -> Result<()> {
+        let mut group = FunctionGroup::new("kD3bMhKiQ5xW0aTp-01", 3);
+        let (_, retired) = group.scale_to(1, 2000);
+        assert_eq!(
+            vec!["kD3bMhKiQ5xW0aTp-01-01", "kD3bMhKiQ5xW0aTp-01-02"],
+            retired
+        );
+
+        // The first retired member still holds a partial window.
+        let state_backend: Arc<dyn StateBackend> = Arc::new(HashMapStateBackend::new());
+        let key = format!("{}/{}/2000-1", CHECKPOINT_KEY_PREFIX, retired[0]);
+        state_backend
+            .write(FLOCK_S3_BUCKET.clone(), key.clone(), vec![0])
+            .await?;
+
+        let drained = group.drained_members(2300, 300, &state_backend).await?;
+        assert_eq!(vec![retired[1].clone()], drained);
+
+        // The member is drained once its window is emitted.
+        state_backend.delete(FLOCK_S3_BUCKET.clone(), key).await?;
+        let drained = group.drained_members(2300, 300, &state_backend).await?;
+        assert_eq!(retired, drained);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn desired_group_size() -> Result<()> {
+        let settings = settings();
+        let group = FunctionGroup::new("SX72HzqFz1Qij4bP-01", 4);
+
+        assert_eq!(6, group.desired_size(55.0, &settings));
+        assert_eq!(8, group.desired_size(1000.0, &settings));
+        assert_eq!(4, group.desired_size(40.0, &settings));
+        assert_eq!(4, group.desired_size(35.0, &settings));
+        // 25 payloads/s would load 3 members at 83%.
+        assert_eq!(4, group.desired_size(25.0, &settings));
+        assert_eq!(3, group.desired_size(20.0, &settings));
+        assert_eq!(1, group.desired_size(0.0, &settings));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,833 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Stream-stream interval joins over unbounded streams, such as NEXMark Q3 and
+//! Q8. A left row joins the right rows with the same key whose event time is
+//! close to its own:
+//!
+//! ```sql
+//! l.key = r.key AND l.ts BETWEEN r.ts - lower AND r.ts + upper
+//! ```
+//!
+//! Unlike the window joins, the rows of both sides are buffered per join key in
+//! the state backend across invocations, and each match is emitted once, in
+//! the invocation where the later of its two rows arrives. The rows of each
+//! key are encoded as Arrow IPC files in a single state object per partition,
+//! so an invocation reads and writes the state once. The watermark of the join
+//! is the smallest of the latest event times of the two sides minus the allowed
+//! lateness. The buffered rows that can no longer match a row above the
+//! watermark are expired, and the new rows below the watermark are dropped as
+//! late.
+//!
+//! The distributed planner replaces the hash join of the query with the
+//! interval join: the former stage shuffles the two sides by the join key to
+//! the members of a function group, and the group joins them with the state of
+//! the keys and runs the rest of the stage over the matches.
+
+use super::arena::{read_batches, write_batches};
+use super::group::group_of;
+use super::payload::{output_id, InputBatches, InputId};
+use super::schema::FnvHasher;
+use crate::error::{FlockError, Result};
+use crate::state::StateBackend;
+use datafusion::arrow::array::{Array, BooleanArray, Int64Array, UInt32Array};
+use datafusion::arrow::compute::{cast, filter_record_batch, take};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::logical_plan::JoinType;
+use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::ExecutionPlan;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hasher;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// The key prefix of the interval join states in the state backend.
+pub const JOIN_STATE_KEY_PREFIX: &str = "joins";
+
+/// The id of the input that carries the matches of the interval join to the
+/// execution plans of the function.
+pub const INTERVAL_JOIN_INPUT: &str = "interval_join";
+
+/// The number of the latest windows of a partition whose matches are kept for
+/// the retries.
+const RECENT_WINDOWS: usize = 8;
+
+/// A side of the interval join.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JoinSide {
+    /// The id of the input stream.
+    pub input: InputId,
+    /// The name of the join key column.
+    pub key:   String,
+    /// The name of the event time column. It must be castable to `Int64`, e.g.
+    /// a timestamp column.
+    pub time:  String,
+}
+
+impl JoinSide {
+    /// Creates a new side of the interval join.
+    pub fn new(input: &str, key: &str, time: &str) -> Self {
+        JoinSide {
+            input: input.to_owned(),
+            key:   key.to_owned(),
+            time:  time.to_owned(),
+        }
+    }
+}
+
+/// The specification of a stream-stream interval join. The bounds and the
+/// lateness are in the units of the event time columns, e.g. milliseconds for
+/// the NEXMark timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntervalJoin {
+    /// The left side of the join.
+    pub left:             JoinSide,
+    /// The right side of the join.
+    pub right:            JoinSide,
+    /// How far the left row can precede the right row.
+    pub lower:            i64,
+    /// How far the left row can follow the right row.
+    pub upper:            i64,
+    /// How far the event time of a row can fall behind the latest event time
+    /// of its side before the row is dropped as late.
+    pub allowed_lateness: i64,
+}
+
+impl IntervalJoin {
+    /// Creates a new interval join of `left.time BETWEEN right.time - lower
+    /// AND right.time + upper` without allowed lateness.
+    pub fn new(left: JoinSide, right: JoinSide, lower: i64, upper: i64) -> Self {
+        IntervalJoin {
+            left,
+            right,
+            lower,
+            upper,
+            allowed_lateness: 0,
+        }
+    }
+
+    /// Sets the allowed lateness of the rows.
+    pub fn with_allowed_lateness(mut self, allowed_lateness: i64) -> Self {
+        self.allowed_lateness = allowed_lateness;
+        self
+    }
+
+    /// Binds the interval join to the hash join of the query plan, whose
+    /// inputs are the outputs of the former stage. The sides of the interval
+    /// join are swapped if the hash join has them the other way around, so
+    /// that the matches have the columns of the hash join's output.
+    pub fn bind(&self, hash_join: &HashJoinExec) -> Result<IntervalJoin> {
+        if *hash_join.join_type() != JoinType::Inner {
+            return Err(FlockError::Plan(format!(
+                "The interval join requires an inner join, not {:?}.",
+                hash_join.join_type()
+            )));
+        }
+        let (left_key, right_key) = match hash_join.on() {
+            [(left, right)] => (left.name(), right.name()),
+            on => {
+                return Err(FlockError::Plan(format!(
+                    "The interval join requires a single join key, not {:?}.",
+                    on
+                )))
+            }
+        };
+
+        let join = if self.left.key == left_key && self.right.key == right_key {
+            self.clone()
+        } else if self.left.key == right_key && self.right.key == left_key {
+            // `l.ts BETWEEN r.ts - lower AND r.ts + upper` is the same condition as
+            // `r.ts BETWEEN l.ts - upper AND l.ts + lower`.
+            IntervalJoin {
+                left:             self.right.clone(),
+                right:            self.left.clone(),
+                lower:            self.upper,
+                upper:            self.lower,
+                allowed_lateness: self.allowed_lateness,
+            }
+        } else {
+            return Err(FlockError::Plan(format!(
+                "The keys of the interval join {} and {} aren't the keys of the hash join {} and {}.",
+                self.left.key, self.right.key, left_key, right_key
+            )));
+        };
+
+        let children = hash_join.children();
+        for (side, child) in [&join.left, &join.right].iter().zip(children.iter()) {
+            child.schema().index_of(&side.time).map_err(|_| {
+                FlockError::Plan(format!(
+                    "The event time column {} isn't an input of the hash join.",
+                    side.time
+                ))
+            })?;
+        }
+
+        Ok(IntervalJoin {
+            left: JoinSide {
+                input: output_id(0),
+                ..join.left
+            },
+            right: JoinSide {
+                input: output_id(1),
+                ..join.right
+            },
+            ..join
+        })
+    }
+
+    /// Joins the new rows of the inputs with the join states of their keys, and
+    /// saves the updated states to the state backend. The states of a partition
+    /// are a single object, which is read and written once per invocation. The
+    /// former stage routes each partition to the same member of the function
+    /// group until the group is resized, and each member has a reserved
+    /// concurrency of 1, so a partition is joined by one invocation at a time.
+    ///
+    /// A window that is retried after its state is saved, e.g., because its
+    /// matches couldn't be sent, emits the same matches again instead of
+    /// joining its rows with the state a second time.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the join states.
+    /// * `bucket` - The bucket of the join states.
+    /// * `function_name` - The name of the current function.
+    /// * `partition` - The partition of the join key handled by the function.
+    /// * `window` - The id of the window of the new rows, i.e., its query id.
+    /// * `inputs` - The new rows of the inputs, keyed by the input ids.
+    ///
+    /// # Returns
+    /// The new matches of the join.
+    pub async fn process(
+        &self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+        partition: usize,
+        window: &str,
+        inputs: InputBatches,
+    ) -> Result<Vec<RecordBatch>> {
+        let state_key = partition_state_key(function_name, partition);
+        let mut state = match state_backend
+            .get(bucket.to_owned(), state_key.clone())
+            .await?
+        {
+            Some(bytes) => serde_json::from_slice::<PartitionState>(&bytes)?,
+            None => PartitionState::default(),
+        };
+        if let Some(matches) = state.windows.iter().find(|w| w.id == window) {
+            info!(
+                "The window {} is already joined in partition {}.",
+                window, partition
+            );
+            return decode_batches(matches.data.clone());
+        }
+
+        let (left, right) = self.sides(inputs)?;
+        let mut left = split_by_key(&left, &self.left.key)?;
+        let mut right = split_by_key(&right, &self.right.key)?;
+        let mut keys = left.keys().chain(right.keys()).cloned().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+
+        // All the keys drop the late rows by the watermark before the invocation.
+        let mut output = vec![];
+        let mut states = vec![];
+        let (mut left_time, mut right_time) = (state.left_time, state.right_time);
+        for key in keys {
+            let rows = state.keys.remove(&key).unwrap_or_default();
+            let mut key_state = IntervalJoinState {
+                left:       decode_batches(rows.left)?,
+                right:      decode_batches(rows.right)?,
+                left_time:  state.left_time,
+                right_time: state.right_time,
+            };
+            output.extend(key_state.join(
+                self,
+                left.remove(&key).unwrap_or_default(),
+                right.remove(&key).unwrap_or_default(),
+            )?);
+            left_time = left_time.max(key_state.left_time);
+            right_time = right_time.max(key_state.right_time);
+            states.push((key, key_state));
+        }
+        state.left_time = left_time;
+        state.right_time = right_time;
+
+        for (key, mut key_state) in states {
+            key_state.left_time = state.left_time;
+            key_state.right_time = state.right_time;
+            key_state.expire(self)?;
+            if let Some(horizon) = key_state.horizon(self)? {
+                let rows = KeyRows {
+                    horizon,
+                    left: encode_batches(&key_state.left)?,
+                    right: encode_batches(&key_state.right)?,
+                };
+                state.keys.insert(key, rows);
+            }
+        }
+
+        // The keys without new rows are expired as a whole once their latest rows
+        // fall behind the watermark.
+        if let Some(watermark) = watermark(self, state.left_time, state.right_time) {
+            state.keys.retain(|_, rows| rows.horizon >= watermark);
+        }
+
+        state.windows.push_back(EncodedMatches {
+            id:   window.to_owned(),
+            data: encode_batches(&output)?,
+        });
+        while state.windows.len() > RECENT_WINDOWS {
+            state.windows.pop_front();
+        }
+
+        state_backend
+            .write(bucket.to_owned(), state_key, serde_json::to_vec(&state)?)
+            .await?;
+        Ok(output)
+    }
+
+    /// Joins all the rows of a window at once, without the join states, e.g.,
+    /// when the panes of a hopping window are combined. Each match of the
+    /// window is emitted exactly once, and no row is late.
+    ///
+    /// # Arguments
+    /// * `inputs` - The rows of the window, keyed by the input ids.
+    pub fn join_window(&self, inputs: InputBatches) -> Result<Vec<RecordBatch>> {
+        let (left, right) = self.sides(inputs)?;
+        IntervalJoinState::default().join(self, left, right)
+    }
+
+    /// Separates the rows of the two sides of the join.
+    fn sides(&self, inputs: InputBatches) -> Result<(Vec<RecordBatch>, Vec<RecordBatch>)> {
+        let (mut left, mut right) = (vec![], vec![]);
+        for (id, batches) in inputs {
+            if id == self.left.input {
+                left.extend(batches);
+            } else if id == self.right.input {
+                right.extend(batches);
+            } else {
+                return Err(FlockError::Execution(format!(
+                    "The input {} isn't a side of the interval join.",
+                    id
+                )));
+            }
+        }
+        Ok((left, right))
+    }
+}
+
+/// Returns the key prefix of the interval join states of the function group in
+/// the state backend. The members of the group share the states, so a key can
+/// move to another member, e.g., after the group is resized.
+pub fn join_state_prefix(function_name: &str) -> String {
+    format!("{}/{}", JOIN_STATE_KEY_PREFIX, group_of(function_name))
+}
+
+/// Returns the key of the join states of a partition in the state backend.
+fn partition_state_key(function_name: &str, partition: usize) -> String {
+    format!("{}/{:02}", join_state_prefix(function_name), partition)
+}
+
+/// Returns the watermark of the join. There is no watermark until both sides
+/// have rows.
+fn watermark(join: &IntervalJoin, left_time: Option<i64>, right_time: Option<i64>) -> Option<i64> {
+    Some(left_time?.min(right_time?) - join.allowed_lateness)
+}
+
+/// The state of the interval join in a partition: the latest event times of
+/// the two sides, the buffered rows of each join key, and the matches of the
+/// latest windows, so that a retried window emits the same matches.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PartitionState {
+    left_time:  Option<i64>,
+    right_time: Option<i64>,
+    keys:       BTreeMap<String, KeyRows>,
+    windows:    VecDeque<EncodedMatches>,
+}
+
+/// The buffered rows of a join key encoded in the Arrow IPC file format, and
+/// the latest watermark they can still match a row above.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyRows {
+    horizon: i64,
+    #[serde(with = "serde_bytes")]
+    left:    Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    right:   Vec<u8>,
+}
+
+/// The matches of a window encoded in the Arrow IPC file format.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedMatches {
+    id:   String,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// Splits the rows by the hash of their join keys. The rows with a null key
+/// can't match any row, and are dropped. Different keys with the same hash
+/// share their state, which is harmless, since the rows are still joined by
+/// their keys.
+fn split_by_key(
+    batches: &[RecordBatch],
+    column: &str,
+) -> Result<HashMap<String, Vec<RecordBatch>>> {
+    let mut split: HashMap<String, Vec<RecordBatch>> = HashMap::new();
+    for batch in batches {
+        let keys = batch.column(batch.schema().index_of(column)?);
+        let mut rows: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for row in 0..batch.num_rows() {
+            if keys.is_valid(row) {
+                let mut hasher = FnvHasher::default();
+                hasher.write(array_value_to_string(keys, row)?.as_bytes());
+                rows.entry(format!("{:016x}", hasher.finish()))
+                    .or_default()
+                    .push(row as u32);
+            }
+        }
+        for (key, rows) in rows {
+            let rows = UInt32Array::from(rows);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|column| take(column.as_ref(), &rows, None))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            split
+                .entry(key)
+                .or_default()
+                .push(RecordBatch::try_new(batch.schema(), columns)?);
+        }
+    }
+    Ok(split)
+}
+
+/// The rows of the two sides buffered by the interval join of a join key.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalJoinState {
+    /// The buffered rows of the left side.
+    pub left:       Vec<RecordBatch>,
+    /// The buffered rows of the right side.
+    pub right:      Vec<RecordBatch>,
+    /// The latest event time of the left side.
+    pub left_time:  Option<i64>,
+    /// The latest event time of the right side.
+    pub right_time: Option<i64>,
+}
+
+impl IntervalJoinState {
+    /// Returns the watermark of the join. There is no watermark until both
+    /// sides have rows.
+    pub fn watermark(&self, join: &IntervalJoin) -> Option<i64> {
+        watermark(join, self.left_time, self.right_time)
+    }
+
+    /// Returns the latest watermark the buffered rows can still match a row
+    /// above, or `None` if there are no buffered rows.
+    fn horizon(&self, join: &IntervalJoin) -> Result<Option<i64>> {
+        let left = max_time(None, &self.left, &join.left.time)?.map(|t| t + join.lower);
+        let right = max_time(None, &self.right, &join.right.time)?.map(|t| t + join.upper);
+        Ok(left.max(right))
+    }
+
+    /// Returns the number of the buffered rows.
+    pub fn num_rows(&self) -> usize {
+        self.left
+            .iter()
+            .chain(self.right.iter())
+            .map(|b| b.num_rows())
+            .sum()
+    }
+
+    /// Joins the new rows of the two sides with the buffered rows and with
+    /// each other, buffers the new rows, and expires the buffered rows behind
+    /// the watermark.
+    ///
+    /// # Returns
+    /// The new matches, with the columns of the left side followed by the
+    /// columns of the right side.
+    pub fn join(
+        &mut self,
+        join: &IntervalJoin,
+        left: Vec<RecordBatch>,
+        right: Vec<RecordBatch>,
+    ) -> Result<Vec<RecordBatch>> {
+        let watermark = self.watermark(join);
+        let left = drop_late(left, &join.left.time, watermark)?;
+        let right = drop_late(right, &join.right.time, watermark)?;
+
+        // A match is emitted when the later of its two rows arrives, so the new
+        // left rows are joined with all the right rows, and the buffered left
+        // rows only with the new right rows.
+        let mut output = vec![];
+        for l in left.iter() {
+            for r in self.right.iter().chain(right.iter()) {
+                output.extend(join_batches(join, l, r)?);
+            }
+        }
+        for l in self.left.iter() {
+            for r in right.iter() {
+                output.extend(join_batches(join, l, r)?);
+            }
+        }
+
+        self.left_time = max_time(self.left_time, &left, &join.left.time)?;
+        self.right_time = max_time(self.right_time, &right, &join.right.time)?;
+        self.left.extend(left);
+        self.right.extend(right);
+        self.expire(join)?;
+        Ok(output)
+    }
+
+    /// Removes the buffered rows that can't match any row above the watermark.
+    fn expire(&mut self, join: &IntervalJoin) -> Result<()> {
+        if let Some(watermark) = self.watermark(join) {
+            // A left row matches the right rows up to `l.ts + lower`, and a right row
+            // matches the left rows up to `r.ts + upper`.
+            self.left = retain_rows(&self.left, &join.left.time, |t| t + join.lower >= watermark)?;
+            self.right = retain_rows(&self.right, &join.right.time, |t| {
+                t + join.upper >= watermark
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the values of the event time column as `Int64`.
+fn event_times(batch: &RecordBatch, column: &str) -> Result<Int64Array> {
+    let index = batch.schema().index_of(column)?;
+    let times = cast(batch.column(index), &DataType::Int64)?;
+    Ok(times
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .ok_or_else(|| FlockError::Internal(format!("Failed to cast {} to Int64.", column)))?
+        .clone())
+}
+
+/// Returns the rows whose event times satisfy the predicate, without the empty
+/// record batches.
+fn retain_rows(
+    batches: &[RecordBatch],
+    column: &str,
+    predicate: impl Fn(i64) -> bool,
+) -> Result<Vec<RecordBatch>> {
+    let mut retained = vec![];
+    for batch in batches {
+        let times = event_times(batch, column)?;
+        let mask = times
+            .iter()
+            .map(|t| Some(t.map_or(false, &predicate)))
+            .collect::<BooleanArray>();
+        let batch = filter_record_batch(batch, &mask)?;
+        if batch.num_rows() > 0 {
+            retained.push(batch);
+        }
+    }
+    Ok(retained)
+}
+
+/// Drops the rows below the watermark.
+fn drop_late(
+    batches: Vec<RecordBatch>,
+    column: &str,
+    watermark: Option<i64>,
+) -> Result<Vec<RecordBatch>> {
+    let watermark = match watermark {
+        Some(watermark) => watermark,
+        None => return Ok(batches.into_iter().filter(|b| b.num_rows() > 0).collect()),
+    };
+    let rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+    let batches = retain_rows(&batches, column, |t| t >= watermark)?;
+    let late = rows - batches.iter().map(|b| b.num_rows()).sum::<usize>();
+    if late > 0 {
+        info!(
+            "Dropped {} late rows below the watermark {}.",
+            late, watermark
+        );
+    }
+    Ok(batches)
+}
+
+/// Returns the latest event time of the rows and the former latest time.
+fn max_time(time: Option<i64>, batches: &[RecordBatch], column: &str) -> Result<Option<i64>> {
+    let mut time = time;
+    for batch in batches {
+        let times = event_times(batch, column)?;
+        time = times.iter().flatten().chain(time).max();
+    }
+    Ok(time)
+}
+
+/// Joins a record batch of the left side with a record batch of the right side.
+fn join_batches(
+    join: &IntervalJoin,
+    left: &RecordBatch,
+    right: &RecordBatch,
+) -> Result<Option<RecordBatch>> {
+    let left_keys = left.column(left.schema().index_of(&join.left.key)?);
+    let right_keys = right.column(right.schema().index_of(&join.right.key)?);
+    let left_times = event_times(left, &join.left.time)?;
+    let right_times = event_times(right, &join.right.time)?;
+
+    let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+    for row in 0..right.num_rows() {
+        if right_keys.is_valid(row) && right_times.is_valid(row) {
+            index
+                .entry(array_value_to_string(right_keys, row)?)
+                .or_default()
+                .push(row);
+        }
+    }
+
+    let (mut left_rows, mut right_rows) = (vec![], vec![]);
+    for row in 0..left.num_rows() {
+        if !left_keys.is_valid(row) || !left_times.is_valid(row) {
+            continue;
+        }
+        if let Some(rows) = index.get(&array_value_to_string(left_keys, row)?) {
+            let time = left_times.value(row);
+            for r in rows {
+                let other = right_times.value(*r);
+                if other - join.lower <= time && time <= other + join.upper {
+                    left_rows.push(row as u32);
+                    right_rows.push(*r as u32);
+                }
+            }
+        }
+    }
+    if left_rows.is_empty() {
+        return Ok(None);
+    }
+
+    let (left_rows, right_rows) = (UInt32Array::from(left_rows), UInt32Array::from(right_rows));
+    let mut columns = vec![];
+    for column in left.columns() {
+        columns.push(take(column.as_ref(), &left_rows, None)?);
+    }
+    for column in right.columns() {
+        columns.push(take(column.as_ref(), &right_rows, None)?);
+    }
+    let fields = left
+        .schema()
+        .fields()
+        .iter()
+        .chain(right.schema().fields().iter())
+        .cloned()
+        .collect();
+    Ok(Some(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?))
+}
+
+/// Encodes the record batches in the Arrow IPC file format.
+fn encode_batches(batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    if let Some(batch) = batches.first() {
+        write_batches(&mut bytes, &batch.schema(), batches)?;
+    }
+    Ok(bytes)
+}
+
+/// Decodes the record batches in the Arrow IPC file format.
+fn decode_batches(bytes: Vec<u8>) -> Result<Vec<RecordBatch>> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    read_batches(Cursor::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::{Int32Array, TimestampMillisecondArray};
+    use datafusion::arrow::datatypes::{Field, TimeUnit};
+
+    fn batch(prefix: &str, rows: Vec<(i32, i64)>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(&format!("{}_key", prefix), DataType::Int32, false),
+            Field::new(
+                &format!("{}_time", prefix),
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+        ]));
+        let (keys, times): (Vec<i32>, Vec<i64>) = rows.into_iter().unzip();
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(keys)),
+                Arc::new(TimestampMillisecondArray::from(times)),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn interval_join(lower: i64, upper: i64) -> IntervalJoin {
+        IntervalJoin::new(
+            JoinSide::new("l", "l_key", "l_time"),
+            JoinSide::new("r", "r_key", "r_time"),
+            lower,
+            upper,
+        )
+    }
+
+    fn matches(output: &[RecordBatch]) -> Vec<(i32, i64, i64)> {
+        let mut rows = vec![];
+        for batch in output {
+            let keys = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            let left = event_times(batch, "l_time").unwrap();
+            let right = event_times(batch, "r_time").unwrap();
+            for i in 0..batch.num_rows() {
+                rows.push((keys.value(i), left.value(i), right.value(i)));
+            }
+        }
+        rows.sort_unstable();
+        rows
+    }
+
+    #[test]
+    fn join_within_bounds() -> Result<()> {
+        // l.time BETWEEN r.time - 10 AND r.time + 5
+        let join = interval_join(10, 5);
+        let mut state = IntervalJoinState::default();
+        let output = state.join(
+            &join,
+            vec![batch("l", vec![(1, 90), (1, 100), (1, 106), (2, 100)])],
+            vec![batch("r", vec![(1, 101), (3, 100)])],
+        )?;
+        assert_eq!(vec![(1, 100, 101), (1, 106, 101)], matches(&output));
+        assert_eq!(4, output[0].num_columns());
+        Ok(())
+    }
+
+    #[test]
+    fn join_whole_window() -> Result<()> {
+        // The rows of a window match regardless of their arrival order.
+        let join = interval_join(10, 5);
+        let output = join.join_window(vec![
+            ("r".to_owned(), vec![batch("r", vec![(1, 101)])]),
+            ("l".to_owned(), vec![batch("l", vec![(1, 100), (2, 100)])]),
+            ("r".to_owned(), vec![batch("r", vec![(2, 300)])]),
+        ])?;
+        assert_eq!(vec![(1, 100, 101)], matches(&output));
+        assert!(join
+            .join_window(vec![("x".to_owned(), vec![batch("l", vec![(1, 100)])])])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expire_rows_behind_watermark() -> Result<()> {
+        let join = interval_join(10, 5).with_allowed_lateness(20);
+        let mut state = IntervalJoinState::default();
+        state.join(
+            &join,
+            vec![batch("l", vec![(1, 100)])],
+            vec![batch("r", vec![(1, 200)])],
+        )?;
+        assert_eq!(Some(80), state.watermark(&join));
+        assert_eq!(2, state.num_rows());
+
+        // The watermark moves to 280, so the left row at 100 and the right row at
+        // 200 can't match any more rows.
+        let output = state.join(
+            &join,
+            vec![batch("l", vec![(1, 300), (1, 195)])],
+            vec![batch("r", vec![(1, 305)])],
+        )?;
+        assert_eq!(vec![(1, 195, 200), (1, 300, 305)], matches(&output));
+        assert_eq!(Some(280), state.watermark(&join));
+        assert_eq!(2, state.num_rows());
+
+        // The late left row is dropped.
+        let output = state.join(&join, vec![batch("l", vec![(1, 200)])], vec![])?;
+        assert!(output.is_empty());
+        assert_eq!(2, state.num_rows());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn emit_matches_across_invocations() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let bucket = "flock";
+        let join = interval_join(10, 10);
+        let invoke = |member: usize, window: &str, l: Vec<(i32, i64)>, r: Vec<(i32, i64)>| {
+            let join = join.clone();
+            let state_backend = state_backend.clone();
+            let window = window.to_owned();
+            async move {
+                let inputs = vec![
+                    ("l".to_owned(), vec![batch("l", l)]),
+                    ("r".to_owned(), vec![batch("r", r)]),
+                ];
+                let function_name = format!("intervaljoin-01-{:02}", member);
+                join.process(&state_backend, bucket, &function_name, 1, &window, inputs)
+                    .await
+            }
+        };
+
+        let output = invoke(0, "w1", vec![(1, 100), (2, 100)], vec![]).await?;
+        assert!(output.is_empty());
+
+        // The right rows match the buffered left rows.
+        let output = invoke(0, "w2", vec![], vec![(1, 105), (2, 150)]).await?;
+        assert_eq!(vec![(1, 100, 105)], matches(&output));
+
+        // The left row matches the buffered right rows, and the former matches
+        // aren't emitted again.
+        let output = invoke(0, "w3", vec![(2, 145)], vec![(1, 108)]).await?;
+        assert_eq!(vec![(1, 100, 108), (2, 145, 150)], matches(&output));
+
+        // The retried window emits the same matches, and its rows aren't buffered
+        // twice.
+        let output = invoke(0, "w3", vec![(2, 145)], vec![(1, 108)]).await?;
+        assert_eq!(vec![(1, 100, 108), (2, 145, 150)], matches(&output));
+
+        // The watermark moves to 145, so the rows of the key 1 are expired, and
+        // only the rows of the key 2 at 145 and 150 are kept in the single state
+        // of the partition.
+        let prefix = join_state_prefix("intervaljoin-01-00");
+        let keys = state_backend.keys(bucket.to_owned(), prefix).await?;
+        assert_eq!(1, keys.len());
+        let bytes = state_backend
+            .get(bucket.to_owned(), keys[0].clone())
+            .await?;
+        let state: PartitionState = serde_json::from_slice(&bytes.unwrap())?;
+        assert_eq!(1, state.keys.len());
+        let mut rows = 0;
+        for key_rows in state.keys.into_values() {
+            for bytes in [key_rows.left, key_rows.right] {
+                rows += decode_batches(bytes)?
+                    .iter()
+                    .map(|b| b.num_rows())
+                    .sum::<usize>();
+            }
+        }
+        assert_eq!(2, rows);
+
+        // The state is kept per partition, so another member of the group finds
+        // the buffered rows of the key 2.
+        let output = invoke(1, "w4", vec![(2, 155)], vec![]).await?;
+        assert_eq!(vec![(2, 155, 150)], matches(&output));
+        Ok(())
+    }
+}
@@ -0,0 +1,843 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Lookup joins enrich the rows of a stream with a reference table, such as the
+//! side input of NEXMark Q13. The table is read from S3 (CSV, JSON or Parquet),
+//! DynamoDB or the state backend, and cached in the function's container, so
+//! the warm invocations don't read it again until its refresh interval
+//! elapses.
+//!
+//! If the table keeps several versions of a row, the "as of" join picks, for
+//! each stream row, the latest version of the key that isn't newer than the
+//! event time of the stream row.
+
+use super::arena::read_batches;
+use super::payload::{output_id, InputId};
+use crate::aws::{dynamodb, s3};
+use crate::error::{FlockError, Result};
+use crate::state::StateBackend;
+use datafusion::arrow::array::{Array, ArrayRef, Int64Array, UInt32Array};
+use datafusion::arrow::compute::{cast, concat, take};
+use datafusion::arrow::csv;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::json;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::logical_plan::JoinType;
+use datafusion::parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use datafusion::parquet::file::reader::SerializedFileReader;
+use datafusion::parquet::util::cursor::SliceableCursor;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::ExecutionPlan;
+use lazy_static::lazy_static;
+use log::info;
+use rusoto_dynamodb::AttributeValue;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The id of the input that carries the enriched rows of the lookup join to the
+/// execution plans of the function.
+pub const LOOKUP_JOIN_INPUT: &str = "lookup_join";
+
+/// The number of rows in each record batch read from the reference tables.
+const BATCH_SIZE: usize = 1024;
+
+lazy_static! {
+    /// The reference tables cached in the function's container, keyed by their
+    /// sources.
+    static ref LOOKUP_TABLES: Mutex<HashMap<String, Arc<CachedTable>>> = Mutex::new(HashMap::new());
+    /// The indexes of the cached reference tables, keyed by their sources and
+    /// key columns.
+    static ref LOOKUP_INDEXES: Mutex<HashMap<String, Arc<LookupIndex>>> = Mutex::new(HashMap::new());
+}
+
+/// The file format of a reference table in S3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TableFormat {
+    /// CSV with a header row.
+    Csv,
+    /// Line-delimited JSON.
+    Json,
+    /// Apache Parquet.
+    Parquet,
+}
+
+impl FromStr for TableFormat {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(TableFormat::Csv),
+            "json" => Ok(TableFormat::Json),
+            "parquet" => Ok(TableFormat::Parquet),
+            _ => Err(FlockError::Execution(format!(
+                "Unsupported reference table format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// The source of a reference table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LookupSource {
+    /// An object in S3.
+    S3 {
+        /// The bucket of the object.
+        bucket: String,
+        /// The key of the object.
+        key:    String,
+        /// The file format of the object.
+        format: TableFormat,
+    },
+    /// A DynamoDB table. All the items are read.
+    DynamoDB {
+        /// The name of the table.
+        table: String,
+    },
+    /// An object in the state backend of the function, encoded in the Arrow
+    /// IPC file format.
+    State {
+        /// The bucket of the object.
+        bucket: String,
+        /// The key of the object.
+        key:    String,
+    },
+}
+
+impl LookupSource {
+    /// Returns the key of the source in the container's cache.
+    fn cache_key(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    /// Reads the reference table from the source.
+    ///
+    /// # Arguments
+    /// * `schema` - The schema of the table. It's required by the sources that
+    ///   don't carry their schemas, i.e., CSV, JSON and DynamoDB.
+    /// * `state_backend` - The state backend of the function.
+    async fn read(
+        &self,
+        schema: Option<SchemaRef>,
+        state_backend: &dyn StateBackend,
+    ) -> Result<Vec<RecordBatch>> {
+        let table_schema = || {
+            schema.clone().ok_or_else(|| {
+                FlockError::Execution(format!(
+                    "The schema of the reference table {:?} is missing.",
+                    self
+                ))
+            })
+        };
+        match self {
+            LookupSource::S3 {
+                bucket,
+                key,
+                format,
+            } => decode_table(s3::get_object(bucket, key).await?, *format, table_schema),
+            LookupSource::DynamoDB { table } => {
+                let items = dynamodb::scan(table).await?;
+                let mut lines = vec![];
+                for item in items {
+                    let row = item
+                        .into_iter()
+                        .map(|(name, value)| (name, attribute_to_json(value)))
+                        .collect::<serde_json::Map<_, _>>();
+                    serde_json::to_writer(&mut lines, &row)?;
+                    lines.push(b'\n');
+                }
+                decode_table(lines, TableFormat::Json, table_schema)
+            }
+            LookupSource::State { bucket, key } => {
+                match state_backend.get(bucket.to_owned(), key.to_owned()).await? {
+                    Some(bytes) if !bytes.is_empty() => read_batches(Cursor::new(bytes)),
+                    _ => Ok(vec![]),
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a reference table from the bytes of a file.
+///
+/// # Arguments
+/// * `bytes` - The content of the file.
+/// * `format` - The file format.
+/// * `schema` - Returns the schema of the table, which the CSV and JSON readers
+///   require.
+pub fn decode_table(
+    bytes: Vec<u8>,
+    format: TableFormat,
+    schema: impl FnOnce() -> Result<SchemaRef>,
+) -> Result<Vec<RecordBatch>> {
+    let batches = match format {
+        TableFormat::Csv => csv::ReaderBuilder::new()
+            .with_schema(schema()?)
+            .has_header(true)
+            .with_batch_size(BATCH_SIZE)
+            .build(Cursor::new(bytes))?
+            .collect::<ArrowResult<Vec<_>>>()?,
+        TableFormat::Json => json::ReaderBuilder::new()
+            .with_schema(schema()?)
+            .with_batch_size(BATCH_SIZE)
+            .build(Cursor::new(bytes))?
+            .collect::<ArrowResult<Vec<_>>>()?,
+        TableFormat::Parquet => {
+            let reader = SerializedFileReader::new(SliceableCursor::new(bytes))?;
+            ParquetFileArrowReader::new(Arc::new(reader))
+                .get_record_reader(BATCH_SIZE)?
+                .collect::<ArrowResult<Vec<_>>>()?
+        }
+    };
+    Ok(batches)
+}
+
+/// Converts a DynamoDB attribute to a JSON value. The numbers are kept as
+/// integers if they can be.
+fn attribute_to_json(value: AttributeValue) -> Value {
+    if let Some(s) = value.s {
+        Value::String(s)
+    } else if let Some(n) = value.n {
+        n.parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| n.parse::<f64>().map(Value::from))
+            .unwrap_or(Value::String(n))
+    } else if let Some(b) = value.bool {
+        Value::Bool(b)
+    } else {
+        Value::Null
+    }
+}
+
+/// A reference table cached in the function's container.
+#[derive(Debug)]
+pub struct CachedTable {
+    /// The record batches of the table.
+    pub batches: Vec<RecordBatch>,
+    /// When the table was read from its source.
+    pub loaded:  Instant,
+}
+
+/// Returns the reference table from the container's cache, and reads it from
+/// its source if it isn't cached yet or its refresh interval has elapsed.
+///
+/// # Arguments
+/// * `source` - The source of the table.
+/// * `schema` - The schema of the table, if the source doesn't carry it.
+/// * `refresh_interval` - How long the cached table is used. `None` means the
+///   table is read once per container.
+/// * `state_backend` - The state backend of the function.
+pub async fn cached_table(
+    source: &LookupSource,
+    schema: Option<SchemaRef>,
+    refresh_interval: Option<Duration>,
+    state_backend: &dyn StateBackend,
+) -> Result<Arc<CachedTable>> {
+    let cache_key = source.cache_key();
+    if let Some(table) = LOOKUP_TABLES.lock().unwrap().get(&cache_key) {
+        if refresh_interval.map_or(true, |interval| table.loaded.elapsed() < interval) {
+            return Ok(table.clone());
+        }
+    }
+
+    info!("Reading the reference table from {:?}.", source);
+    let table = Arc::new(CachedTable {
+        batches: source.read(schema, state_backend).await?,
+        loaded:  Instant::now(),
+    });
+    LOOKUP_TABLES
+        .lock()
+        .unwrap()
+        .insert(cache_key, table.clone());
+    Ok(table)
+}
+
+/// The "as of" semantics of the lookup join.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AsOf {
+    /// The version column of the reference table, e.g., the time from which
+    /// the row is valid. It must be castable to `Int64`.
+    pub version: String,
+    /// The event time column of the stream. It must be castable to `Int64`.
+    pub time:    String,
+}
+
+/// The specification of a lookup join.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LookupJoin {
+    /// The source of the reference table.
+    pub source:           LookupSource,
+    /// The schema of the reference table, if the source doesn't carry it.
+    pub schema:           Option<SchemaRef>,
+    /// The id of the stream input.
+    pub stream:           InputId,
+    /// The join key column of the stream.
+    pub stream_key:       String,
+    /// The join key column of the reference table.
+    pub table_key:        String,
+    /// How long the cached table is used. `None` means the table is read once
+    /// per container.
+    pub refresh_interval: Option<Duration>,
+    /// The "as of" semantics. If not set, a stream row joins all the rows of
+    /// its key.
+    pub as_of:            Option<AsOf>,
+    /// Whether the stream rows without a match are kept, with nulls in the
+    /// columns of the reference table.
+    pub outer:            bool,
+    /// The columns of the reference table in the enriched rows. `None` means
+    /// all the columns.
+    #[serde(default)]
+    pub columns:          Option<Vec<String>>,
+    /// Whether the columns of the reference table precede the columns of the
+    /// stream in the enriched rows.
+    #[serde(default)]
+    pub table_first:      bool,
+}
+
+impl LookupJoin {
+    /// Creates a new inner lookup join.
+    pub fn new(source: LookupSource, stream: &str, stream_key: &str, table_key: &str) -> Self {
+        LookupJoin {
+            source,
+            schema: None,
+            stream: stream.to_owned(),
+            stream_key: stream_key.to_owned(),
+            table_key: table_key.to_owned(),
+            refresh_interval: None,
+            as_of: None,
+            outer: false,
+            columns: None,
+            table_first: false,
+        }
+    }
+
+    /// Sets the schema of the reference table.
+    pub fn with_schema(mut self, schema: SchemaRef) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Sets the refresh interval of the cached table.
+    pub fn with_refresh_interval(mut self, refresh_interval: Duration) -> Self {
+        self.refresh_interval = Some(refresh_interval);
+        self
+    }
+
+    /// Sets the "as of" semantics.
+    pub fn with_as_of(mut self, as_of: AsOf) -> Self {
+        self.as_of = Some(as_of);
+        self
+    }
+
+    /// Keeps the stream rows without a match.
+    pub fn with_outer(mut self, outer: bool) -> Self {
+        self.outer = outer;
+        self
+    }
+
+    /// Binds the lookup join to the hash join of the query plan, whose inputs
+    /// are the stream and the reference table. The stream is the only output
+    /// of the former stage, and the enriched rows have the columns of the hash
+    /// join's output.
+    pub fn bind(&self, hash_join: &HashJoinExec) -> Result<LookupJoin> {
+        if *hash_join.join_type() != JoinType::Inner
+            && !(self.outer && *hash_join.join_type() == JoinType::Left)
+        {
+            return Err(FlockError::Plan(format!(
+                "The lookup join doesn't support the {:?} join.",
+                hash_join.join_type()
+            )));
+        }
+        let table_first = match hash_join.on() {
+            [(left, right)] if left.name() == self.stream_key && right.name() == self.table_key => {
+                false
+            }
+            [(left, right)] if left.name() == self.table_key && right.name() == self.stream_key => {
+                true
+            }
+            on => {
+                return Err(FlockError::Plan(format!(
+                    "The keys of the lookup join {} and {} aren't the keys of the hash join {:?}.",
+                    self.stream_key, self.table_key, on
+                )))
+            }
+        };
+        if self.outer && table_first {
+            return Err(FlockError::Plan(
+                "The stream of the outer lookup join must be the left side of the join.".to_owned(),
+            ));
+        }
+
+        let table = hash_join.children()[if table_first { 0 } else { 1 }].clone();
+        if !scans_table(&table) {
+            return Err(FlockError::Plan(
+                "The lookup join can't filter or aggregate the reference table.".to_owned(),
+            ));
+        }
+        let columns = table
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+
+        Ok(LookupJoin {
+            stream: output_id(0),
+            columns: Some(columns),
+            table_first,
+            ..self.clone()
+        })
+    }
+
+    /// Joins the rows of the stream with the reference table.
+    ///
+    /// # Arguments
+    /// * `batches` - The rows of the stream.
+    /// * `state_backend` - The state backend of the function.
+    ///
+    /// # Returns
+    /// The enriched rows, with the columns of the stream followed by the
+    /// columns of the reference table.
+    pub async fn process(
+        &self,
+        batches: Vec<RecordBatch>,
+        state_backend: &dyn StateBackend,
+    ) -> Result<Vec<RecordBatch>> {
+        let table = cached_table(
+            &self.source,
+            self.schema.clone(),
+            self.refresh_interval,
+            state_backend,
+        )
+        .await?;
+        let index = self.index(&table)?;
+        batches
+            .iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .map(|batch| self.join(&index, batch))
+            .collect()
+    }
+
+    /// Returns the index of the cached table on the join key, and builds it if
+    /// the table is read again.
+    fn index(&self, table: &Arc<CachedTable>) -> Result<Arc<LookupIndex>> {
+        let cache_key = format!(
+            "{}/{}/{:?}",
+            self.source.cache_key(),
+            self.table_key,
+            self.as_of.as_ref().map(|a| &a.version)
+        );
+        if let Some(index) = LOOKUP_INDEXES.lock().unwrap().get(&cache_key) {
+            if index.loaded == table.loaded {
+                return Ok(index.clone());
+            }
+        }
+
+        let schema = match (table.batches.first(), self.schema.as_ref()) {
+            (Some(batch), _) => batch.schema(),
+            (None, Some(schema)) => schema.clone(),
+            (None, None) => {
+                return Err(FlockError::Execution(format!(
+                    "The schema of the empty reference table {:?} is missing.",
+                    self.source
+                )))
+            }
+        };
+        let index = Arc::new(LookupIndex::try_new(
+            schema,
+            &table.batches,
+            &self.table_key,
+            self.as_of.as_ref().map(|a| a.version.as_str()),
+            table.loaded,
+        )?);
+        LOOKUP_INDEXES
+            .lock()
+            .unwrap()
+            .insert(cache_key, index.clone());
+        Ok(index)
+    }
+
+    /// Joins a record batch of the stream with the indexed table.
+    fn join(&self, index: &LookupIndex, batch: &RecordBatch) -> Result<RecordBatch> {
+        let keys = batch.column(batch.schema().index_of(&self.stream_key)?);
+        let times = match &self.as_of {
+            Some(as_of) => Some(int64_column(batch, &as_of.time)?),
+            None => None,
+        };
+
+        let (mut stream_rows, mut table_rows) = (vec![], vec![]);
+        for row in 0..batch.num_rows() {
+            let matches = if keys.is_valid(row) {
+                index.lookup(
+                    &array_value_to_string(keys, row)?,
+                    times
+                        .as_ref()
+                        .map(|t| t.is_valid(row).then(|| t.value(row))),
+                )
+            } else {
+                vec![]
+            };
+            if matches.is_empty() && self.outer {
+                stream_rows.push(row as u32);
+                table_rows.push(None);
+            }
+            for r in matches {
+                stream_rows.push(row as u32);
+                table_rows.push(Some(r as u32));
+            }
+        }
+
+        let stream_rows = UInt32Array::from(stream_rows);
+        let table_rows = UInt32Array::from(table_rows);
+        let mut stream_columns = vec![];
+        for column in batch.columns() {
+            stream_columns.push(take(column.as_ref(), &stream_rows, None)?);
+        }
+        let stream_fields = batch.schema().fields().clone();
+
+        let table_schema = index.table.schema();
+        let indices = match &self.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|c| table_schema.index_of(c))
+                .collect::<ArrowResult<Vec<_>>>()?,
+            None => (0..table_schema.fields().len()).collect(),
+        };
+        let (mut table_columns, mut table_fields) = (vec![], vec![]);
+        for i in indices {
+            table_columns.push(take(index.table.column(i).as_ref(), &table_rows, None)?);
+            let f = table_schema.field(i);
+            table_fields.push(Field::new(
+                f.name(),
+                f.data_type().clone(),
+                f.is_nullable() || self.outer,
+            ));
+        }
+
+        let (columns, fields) = if self.table_first {
+            (
+                [table_columns, stream_columns].concat(),
+                [table_fields, stream_fields].concat(),
+            )
+        } else {
+            (
+                [stream_columns, table_columns].concat(),
+                [stream_fields, table_fields].concat(),
+            )
+        };
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new(fields)),
+            columns,
+        )?)
+    }
+}
+
+/// Returns true if the plan only scans a table, and maybe projects or
+/// repartitions its rows.
+fn scans_table(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    let any = plan.as_any();
+    if any.is::<MemoryExec>() {
+        true
+    } else if any.is::<ProjectionExec>()
+        || any.is::<CoalesceBatchesExec>()
+        || any.is::<RepartitionExec>()
+    {
+        plan.children().iter().all(scans_table)
+    } else {
+        false
+    }
+}
+
+/// A hash index of a reference table on its key column.
+#[derive(Debug)]
+pub struct LookupIndex {
+    /// The rows of the table in a single record batch.
+    table:  RecordBatch,
+    /// The rows of each key, with their versions in ascending order if the
+    /// table is versioned.
+    rows:   HashMap<String, Vec<(i64, usize)>>,
+    /// When the table was read from its source.
+    loaded: Instant,
+}
+
+impl LookupIndex {
+    /// Builds the index of the table.
+    ///
+    /// # Arguments
+    /// * `schema` - The schema of the table.
+    /// * `batches` - The record batches of the table.
+    /// * `key` - The key column.
+    /// * `version` - The version column, if the table is versioned.
+    /// * `loaded` - When the table was read from its source.
+    pub fn try_new(
+        schema: SchemaRef,
+        batches: &[RecordBatch],
+        key: &str,
+        version: Option<&str>,
+        loaded: Instant,
+    ) -> Result<Self> {
+        let table = if batches.is_empty() {
+            RecordBatch::new_empty(schema)
+        } else {
+            let columns = (0..schema.fields().len())
+                .map(|i| {
+                    let arrays = batches
+                        .iter()
+                        .map(|b| b.column(i).as_ref())
+                        .collect::<Vec<_>>();
+                    concat(&arrays)
+                })
+                .collect::<ArrowResult<Vec<ArrayRef>>>()?;
+            RecordBatch::try_new(schema, columns)?
+        };
+
+        let keys = table.column(table.schema().index_of(key)?);
+        let versions = match version {
+            Some(version) => Some(int64_column(&table, version)?),
+            None => None,
+        };
+        let mut rows: HashMap<String, Vec<(i64, usize)>> = HashMap::new();
+        for row in 0..table.num_rows() {
+            if !keys.is_valid(row) {
+                continue;
+            }
+            let version = match versions.as_ref() {
+                Some(v) if v.is_valid(row) => v.value(row),
+                Some(_) => continue,
+                None => 0,
+            };
+            rows.entry(array_value_to_string(keys, row)?)
+                .or_default()
+                .push((version, row));
+        }
+        if versions.is_some() {
+            rows.values_mut().for_each(|r| r.sort_by_key(|(v, _)| *v));
+        }
+
+        Ok(LookupIndex {
+            table,
+            rows,
+            loaded,
+        })
+    }
+
+    /// Returns the rows of the key. If the event time is given, only the
+    /// latest version that isn't newer than the time is returned.
+    pub fn lookup(&self, key: &str, time: Option<Option<i64>>) -> Vec<usize> {
+        let rows = match self.rows.get(key) {
+            Some(rows) => rows,
+            None => return vec![],
+        };
+        match time {
+            None => rows.iter().map(|(_, row)| *row).collect(),
+            Some(None) => vec![],
+            Some(Some(time)) => {
+                let n = rows.partition_point(|(version, _)| *version <= time);
+                rows[..n].last().map(|(_, row)| *row).into_iter().collect()
+            }
+        }
+    }
+}
+
+/// Returns the values of the column cast to `Int64`.
+fn int64_column(batch: &RecordBatch, column: &str) -> Result<Int64Array> {
+    let index = batch.schema().index_of(column)?;
+    let values = cast(batch.column(index), &DataType::Int64)?;
+    Ok(values
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .ok_or_else(|| FlockError::Internal(format!("Failed to cast {} to Int64.", column)))?
+        .clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::arena::write_batches;
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::{Int32Array, StringArray};
+
+    fn table(rows: Vec<(i32, i64, &str)>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("t_key", DataType::Int32, false),
+            Field::new("t_version", DataType::Int64, false),
+            Field::new("t_value", DataType::Utf8, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(
+                    rows.iter().map(|r| r.0).collect::<Vec<_>>(),
+                )),
+                Arc::new(Int64Array::from(
+                    rows.iter().map(|r| r.1).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    rows.iter().map(|r| r.2).collect::<Vec<_>>(),
+                )),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn stream(rows: Vec<(i32, i64)>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("s_key", DataType::Int32, false),
+            Field::new("s_time", DataType::Int64, false),
+        ]));
+        let (keys, times): (Vec<i32>, Vec<i64>) = rows.into_iter().unzip();
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(keys)),
+                Arc::new(Int64Array::from(times)),
+            ],
+        )
+        .unwrap()
+    }
+
+    async fn put_table(state_backend: &HashMapStateBackend, key: &str, batch: RecordBatch) {
+        let mut bytes = vec![];
+        write_batches(&mut bytes, &batch.schema(), &[batch]).unwrap();
+        state_backend
+            .write("flock".to_owned(), key.to_owned(), bytes)
+            .await
+            .unwrap();
+    }
+
+    fn values(output: &[RecordBatch]) -> Vec<(i32, Option<String>)> {
+        let mut rows = vec![];
+        for batch in output {
+            let keys = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            let values = batch
+                .column(4)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            for i in 0..batch.num_rows() {
+                let value = values.is_valid(i).then(|| values.value(i).to_owned());
+                rows.push((keys.value(i), value));
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn lookup_as_of() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let key = "lookup/as_of";
+        put_table(
+            &state_backend,
+            key,
+            table(vec![(1, 100, "a1"), (1, 200, "a2"), (2, 300, "b1")]),
+        )
+        .await;
+
+        let source = LookupSource::State {
+            bucket: "flock".to_owned(),
+            key:    key.to_owned(),
+        };
+        let join = LookupJoin::new(source, "s", "s_key", "t_key").with_as_of(AsOf {
+            version: "t_version".to_owned(),
+            time:    "s_time".to_owned(),
+        });
+        let input = stream(vec![(1, 50), (1, 150), (1, 250), (2, 250), (3, 400)]);
+
+        let output = join.process(vec![input.clone()], &state_backend).await?;
+        assert_eq!(
+            vec![(1, Some("a1".to_owned())), (1, Some("a2".to_owned()))],
+            values(&output)
+        );
+
+        // The rows without a match are kept with nulls.
+        let output = join
+            .with_outer(true)
+            .process(vec![input], &state_backend)
+            .await?;
+        assert_eq!(
+            vec![
+                (1, None),
+                (1, Some("a1".to_owned())),
+                (1, Some("a2".to_owned())),
+                (2, None),
+                (3, None)
+            ],
+            values(&output)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_cached_table() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let key = "lookup/refresh";
+        put_table(&state_backend, key, table(vec![(1, 0, "old")])).await;
+
+        let source = LookupSource::State {
+            bucket: "flock".to_owned(),
+            key:    key.to_owned(),
+        };
+        let join = LookupJoin::new(source, "s", "s_key", "t_key");
+        let output = join
+            .process(vec![stream(vec![(1, 0)])], &state_backend)
+            .await?;
+        assert_eq!(vec![(1, Some("old".to_owned()))], values(&output));
+
+        // The cached table is used until the refresh interval elapses.
+        put_table(&state_backend, key, table(vec![(1, 0, "new")])).await;
+        let output = join
+            .process(vec![stream(vec![(1, 0)])], &state_backend)
+            .await?;
+        assert_eq!(vec![(1, Some("old".to_owned()))], values(&output));
+
+        let join = join.with_refresh_interval(Duration::ZERO);
+        let output = join
+            .process(vec![stream(vec![(1, 0)])], &state_backend)
+            .await?;
+        assert_eq!(vec![(1, Some("new".to_owned()))], values(&output));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_csv_and_json() -> Result<()> {
+        let schema = table(vec![]).schema();
+        let csv = b"t_key,t_version,t_value\n1,100,a\n2,200,b\n".to_vec();
+        let batches = decode_table(csv, TableFormat::Csv, || Ok(schema.clone()))?;
+        assert_eq!(table(vec![(1, 100, "a"), (2, 200, "b")]), batches[0]);
+
+        let json = concat!(
+            "{\"t_key\": 1, \"t_version\": 100, \"t_value\": \"a\"}\n",
+            "{\"t_key\": 2, \"t_version\": 200, \"t_value\": \"b\"}\n"
+        );
+        let batches = decode_table(json.as_bytes().to_vec(), TableFormat::Json, || {
+            Ok(schema.clone())
+        })?;
+        assert_eq!(table(vec![(1, 100, "a"), (2, 200, "b")]), batches[0]);
+        Ok(())
+    }
+}
@@ -17,5 +17,14 @@
 
 pub mod arena;
 pub mod context;
+pub mod deadline;
+pub mod group;
+pub mod join;
+pub mod lookup;
+pub mod pane;
 pub mod payload;
 pub mod plan;
+pub mod route;
+pub mod schema;
+pub mod skew;
+pub mod subscription;
@@ -0,0 +1,608 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Pane-based evaluation of hopping windows. A hopping window of `size`
+//! seconds that advances every `hop` seconds is cut into panes of `hop`
+//! seconds, and each window is the union of `size / hop` consecutive panes.
+//! Instead of sending and aggregating every overlapping window from scratch,
+//! the data source sends each pane once, the aggregate function reduces it to
+//! a pane state in the state backend, and the panes of a window are combined
+//! when the last of them arrives.
+//!
+//! All panes of a stream are routed by the same key, their owner, so each
+//! partition of the panes is reduced by a single member of the function group,
+//! one pane at a time.
+//!
+//! If the execution plan of the function can be split at its final
+//! aggregation, the pane state is the output of the partial aggregation, and
+//! the panes are combined by the final aggregation. Otherwise, the pane state
+//! is the raw input of the pane, and the whole plan runs over the panes of the
+//! window.
+
+use super::arena::{read_batches, write_batches};
+use super::group::group_of;
+use super::payload::{InputBatches, InputId};
+use crate::error::{FlockError, Result};
+use crate::state::StateBackend;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::hash_aggregate::{AggregateMode, HashAggregateExec};
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::ExecutionPlan;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The metadata key of the pane in the payload.
+pub const PANE_KEY: &str = "pane";
+
+/// The metadata key of the pane owner in the payload of the data source, which
+/// is kept across the invocations that resume the stream.
+pub const PANE_OWNER_KEY: &str = "pane_owner";
+
+/// The key prefix of the pane states in the state backend.
+pub const PANE_STATE_KEY_PREFIX: &str = "panes";
+
+/// The id of the input that carries the partial states of the panes to the
+/// final aggregation.
+pub const PANE_INPUT: &str = "pane";
+
+/// A pane of a hopping window, i.e., the data of a hop.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pane {
+    /// The position of the pane in the stream, starting from 0.
+    pub index: usize,
+    /// The number of panes in a window, i.e., the window size divided by the
+    /// hop size.
+    pub panes: usize,
+    /// The total number of panes in the stream.
+    pub count: usize,
+    /// The key to route all panes of the stream to the same member of the
+    /// function group, instead of the query id of each pane.
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+impl Pane {
+    /// Creates a new pane.
+    pub fn new(index: usize, panes: usize, count: usize) -> Self {
+        assert!(panes > 0);
+        Pane {
+            index,
+            panes,
+            count,
+            owner: None,
+        }
+    }
+
+    /// Sets the key to route the panes of the stream.
+    pub fn with_owner(mut self, owner: String) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    /// Returns the pane of the same stream at the given position.
+    fn at(&self, index: usize) -> Pane {
+        Pane {
+            index,
+            ..self.clone()
+        }
+    }
+
+    /// Reads the pane from the payload's metadata.
+    ///
+    /// # Returns
+    /// None if the payload isn't a pane of a hopping window.
+    pub fn from_metadata(metadata: &Option<HashMap<String, String>>) -> Result<Option<Self>> {
+        match metadata.as_ref().and_then(|m| m.get(PANE_KEY)) {
+            Some(value) => serde_json::from_str(value)
+                .map(Some)
+                .map_err(|e| FlockError::Internal(format!("Invalid pane `{}`: {}", value, e))),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the pane to the payload's metadata.
+    pub fn to_metadata(&self, metadata: &mut Option<HashMap<String, String>>) -> Result<()> {
+        metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(PANE_KEY.to_owned(), serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Removes the pane from the payload's metadata, so the outputs of the
+    /// windows aren't taken as panes by the next functions.
+    pub fn remove_from_metadata(metadata: &mut Option<HashMap<String, String>>) {
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.remove(PANE_KEY);
+        }
+    }
+
+    /// Returns the windows that contain the pane. The window `w` is made of the
+    /// panes `w..w + panes`, and only the windows within the stream exist.
+    pub fn windows(&self) -> Range<usize> {
+        let start = (self.index + 1).saturating_sub(self.panes);
+        let end = (self.index + 1).min((self.count + 1).saturating_sub(self.panes));
+        start..end.max(start)
+    }
+
+    /// Returns the panes of the window.
+    pub fn panes_of(&self, window: usize) -> Range<usize> {
+        window..window + self.panes
+    }
+}
+
+/// The execution plan split at its final aggregation: the partial plan reduces
+/// a pane to its partial aggregation states, and the combine plan merges the
+/// states of the panes of a window.
+#[derive(Debug, Clone)]
+pub struct PanePlan {
+    /// The input of the final aggregation, which ends with the partial
+    /// aggregation.
+    pub partial: Arc<dyn ExecutionPlan>,
+    /// The execution plan whose final aggregation reads the pane states from
+    /// a `MemoryExec`.
+    pub combine: Arc<dyn ExecutionPlan>,
+}
+
+impl PanePlan {
+    /// Splits the execution plan at the topmost final aggregation whose input
+    /// can be evaluated pane by pane.
+    ///
+    /// # Returns
+    /// None if the plan has no such aggregation, e.g., the input of each
+    /// aggregation joins the streams, whose matches can span the panes.
+    pub fn try_new(plan: &Arc<dyn ExecutionPlan>) -> Result<Option<Self>> {
+        if let Some(aggregate) = plan.as_any().downcast_ref::<HashAggregateExec>() {
+            if matches!(
+                aggregate.mode(),
+                AggregateMode::Final | AggregateMode::FinalPartitioned
+            ) {
+                let input = plan.children()[0].clone();
+                if partial_aggregations(&input) == Some(1) {
+                    let leaf: Arc<dyn ExecutionPlan> =
+                        Arc::new(MemoryExec::try_new(&[], input.schema(), None)?);
+                    return Ok(Some(PanePlan {
+                        partial: input,
+                        combine: plan.with_new_children(vec![leaf])?,
+                    }));
+                }
+            }
+        }
+
+        match plan.children().as_slice() {
+            [child] => Ok(match PanePlan::try_new(child)? {
+                Some(split) => Some(PanePlan {
+                    partial: split.partial,
+                    combine: plan.with_new_children(vec![split.combine])?,
+                }),
+                None => None,
+            }),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Returns the number of partial aggregations in the plan, or None if the plan
+/// has an operator whose output over a window differs from the union of its
+/// outputs over the panes.
+fn partial_aggregations(plan: &Arc<dyn ExecutionPlan>) -> Option<usize> {
+    let any = plan.as_any();
+    let mut count = 0;
+    if let Some(aggregate) = any.downcast_ref::<HashAggregateExec>() {
+        if *aggregate.mode() != AggregateMode::Partial {
+            return None;
+        }
+        count += 1;
+    } else if !(any.is::<ProjectionExec>()
+        || any.is::<FilterExec>()
+        || any.is::<CoalesceBatchesExec>()
+        || any.is::<CoalescePartitionsExec>()
+        || any.is::<RepartitionExec>()
+        || any.is::<MemoryExec>())
+    {
+        return None;
+    }
+    for child in plan.children() {
+        count += partial_aggregations(&child)?;
+    }
+    Some(count)
+}
+
+/// An input of the pane state encoded in the Arrow IPC file format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncodedInput {
+    id:   InputId,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// The pane states of a stream in a function group's partition in the state
+/// backend. The state of a pane is written once, and removed after the last
+/// window that contains it is emitted.
+pub struct PaneStore<'a> {
+    state_backend: &'a dyn StateBackend,
+    bucket:        String,
+    prefix:        String,
+}
+
+impl<'a> PaneStore<'a> {
+    /// Creates the pane store of the stream in the function group's partition.
+    /// The members of the group share the store, so any of them can combine
+    /// the panes that are reduced by the others. Each run of the query is a
+    /// new stream, i.e., a new pane owner, so it never sees the panes and the
+    /// window claims left behind by a previous run.
+    ///
+    /// # Arguments
+    /// * `state_backend` - The state backend of the pane states.
+    /// * `bucket` - The bucket of the pane states.
+    /// * `function_name` - The name of the current function.
+    /// * `stream` - The stream of the panes, i.e., their owner.
+    /// * `partition` - The partition of the panes handled by the function.
+    pub fn new(
+        state_backend: &'a dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+        stream: &str,
+        partition: usize,
+    ) -> Self {
+        PaneStore {
+            state_backend,
+            bucket: bucket.to_owned(),
+            prefix: format!(
+                "{}/{}/{}/{:02}",
+                PANE_STATE_KEY_PREFIX,
+                group_of(function_name),
+                stream,
+                partition
+            ),
+        }
+    }
+
+    fn pane_key(&self, index: usize) -> String {
+        format!("{}/{:08}", self.prefix, index)
+    }
+
+    fn window_key(&self, window: usize) -> String {
+        format!("{}/windows/{:08}", self.prefix, window)
+    }
+
+    /// Writes the state of the pane.
+    pub async fn write(&self, pane: &Pane, state: &InputBatches) -> Result<()> {
+        let mut inputs = vec![];
+        for (id, batches) in state {
+            if let Some(batch) = batches.first() {
+                let mut data = vec![];
+                write_batches(&mut data, &batch.schema(), batches)?;
+                inputs.push(EncodedInput {
+                    id: id.clone(),
+                    data,
+                });
+            }
+        }
+        self.state_backend
+            .write(
+                self.bucket.clone(),
+                self.pane_key(pane.index),
+                serde_json::to_vec(&inputs)?,
+            )
+            .await
+    }
+
+    /// Returns the windows of the pane whose panes are all written, and claims
+    /// them, so they aren't emitted again when a pane is retried. The panes of
+    /// a partition are reduced one at a time by their owner, which has a
+    /// concurrency of 1, so a window is never completed by two invocations at
+    /// the same time.
+    pub async fn complete_windows(&self, pane: &Pane) -> Result<Vec<usize>> {
+        let keys = self
+            .state_backend
+            .keys(self.bucket.clone(), format!("{}/", self.prefix))
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut windows = vec![];
+        for window in pane.windows() {
+            let window_key = self.window_key(window);
+            if keys.contains(&window_key)
+                || !pane
+                    .panes_of(window)
+                    .all(|index| keys.contains(&self.pane_key(index)))
+            {
+                continue;
+            }
+            self.state_backend
+                .write(self.bucket.clone(), window_key, vec![])
+                .await?;
+            windows.push(window);
+        }
+        Ok(windows)
+    }
+
+    /// Reads the states of the panes of the window, keyed by the input ids.
+    pub async fn read_window(&self, pane: &Pane, window: usize) -> Result<InputBatches> {
+        let mut inputs: BTreeMap<InputId, Vec<RecordBatch>> = BTreeMap::new();
+        for index in pane.panes_of(window) {
+            let bytes = self
+                .state_backend
+                .get(self.bucket.clone(), self.pane_key(index))
+                .await?
+                .ok_or_else(|| {
+                    FlockError::Execution(format!(
+                        "The state of pane {} of window {} is missing.",
+                        index, window
+                    ))
+                })?;
+            for input in serde_json::from_slice::<Vec<EncodedInput>>(&bytes)? {
+                inputs
+                    .entry(input.id)
+                    .or_default()
+                    .extend(read_batches(Cursor::new(input.data))?);
+            }
+        }
+        Ok(inputs.into_iter().collect())
+    }
+
+    /// Removes the panes of the emitted window whose windows are all emitted,
+    /// and the claims of the windows whose panes are all removed. The windows
+    /// can be emitted out of order, e.g., if a pane arrives late, so a pane is
+    /// kept until the last window that contains it is emitted.
+    pub async fn expire(&self, pane: &Pane, window: usize) -> Result<()> {
+        let mut keys = self
+            .state_backend
+            .keys(self.bucket.clone(), format!("{}/", self.prefix))
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut windows = BTreeSet::new();
+        for index in pane.panes_of(window) {
+            let expired = pane.at(index);
+            if !expired
+                .windows()
+                .all(|w| keys.contains(&self.window_key(w)))
+            {
+                continue;
+            }
+            let key = self.pane_key(index);
+            self.state_backend
+                .delete(self.bucket.clone(), key.clone())
+                .await?;
+            keys.remove(&key);
+            windows.extend(expired.windows());
+        }
+
+        // An emitted window had all its panes, so its missing panes are removed
+        // and it can't be completed again.
+        for w in windows {
+            if !pane
+                .panes_of(w)
+                .any(|index| keys.contains(&self.pane_key(index)))
+            {
+                self.state_backend
+                    .delete(self.bucket.clone(), self.window_key(w))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_batches_sorted_eq;
+    use crate::datasink::DataSinkType;
+    use crate::runtime::context::{CloudFunction, ExecutionContext};
+    use crate::runtime::plan::CloudExecutionPlan;
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::datasource::MemTable;
+
+    fn pane_batch(schema: &SchemaRef, keys: Vec<&str>, values: Vec<i64>) -> Result<RecordBatch> {
+        Ok(RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(keys)),
+                Arc::new(Int64Array::from(values)),
+            ],
+        )?)
+    }
+
+    async fn execute(
+        plan: Arc<dyn ExecutionPlan>,
+        id: &str,
+        batches: Vec<RecordBatch>,
+    ) -> Result<Vec<RecordBatch>> {
+        let mut ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![plan], None),
+            name: "test".to_string(),
+            next: CloudFunction::Sink(DataSinkType::Blackhole),
+            ..Default::default()
+        };
+        ctx.feed_data_sources(vec![(id.to_owned(), vec![batches])])
+            .await?;
+        let output = ctx.execute().await?;
+        ctx.clean_data_sources().await?;
+        Ok(output.into_iter().flatten().collect())
+    }
+
+    #[test]
+    fn pane_windows() -> Result<()> {
+        assert_eq!(0..1, Pane::new(0, 3, 5).windows());
+        assert_eq!(0..3, Pane::new(2, 3, 5).windows());
+        assert_eq!(2..3, Pane::new(4, 3, 5).windows());
+        assert_eq!(0..0, Pane::new(1, 3, 2).windows());
+        assert_eq!(2..5, Pane::new(4, 3, 5).panes_of(2));
+
+        let mut metadata = None;
+        assert_eq!(None, Pane::from_metadata(&metadata)?);
+        let pane = Pane::new(4, 3, 5).with_owner("query-1024-42".to_owned());
+        pane.to_metadata(&mut metadata)?;
+        assert_eq!(Some(pane), Pane::from_metadata(&metadata)?);
+        Pane::remove_from_metadata(&mut metadata);
+        assert_eq!(None, Pane::from_metadata(&metadata)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn combine_pane_states() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Utf8, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let panes = vec![
+            pane_batch(&schema, vec!["a", "b", "a"], vec![1, 2, 3])?,
+            pane_batch(&schema, vec!["b", "c", "a"], vec![4, 5, 6])?,
+            pane_batch(&schema, vec!["c", "c", "d"], vec![7, 8, 9])?,
+        ];
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+        ctx.register_table("t", Arc::new(table))?;
+
+        for sql in [
+            "SELECT k, COUNT(*) AS n, MAX(v) AS m, AVG(v) AS a FROM t GROUP BY k",
+            "SELECT COUNT(DISTINCT k) AS n, SUM(v) AS s FROM t",
+        ] {
+            let logical_plan = ctx.create_logical_plan(sql)?;
+            let logical_plan = ctx.optimize(&logical_plan)?;
+            let plan = ctx.create_physical_plan(&logical_plan).await?;
+            let split = PanePlan::try_new(&plan)?.unwrap();
+
+            // Each pane is reduced once, and the windows of two panes merge the
+            // states of their panes.
+            let mut states = vec![];
+            for batch in panes.iter() {
+                states.push(execute(split.partial.clone(), "t", vec![batch.clone()]).await?);
+            }
+            for window in 0..2 {
+                let window_states = states[window..window + 2].concat();
+                let result = execute(split.combine.clone(), PANE_INPUT, window_states).await?;
+                let formatted = pretty_format_batches(&result).unwrap().to_string();
+                let expected: Vec<&str> = formatted.trim().lines().collect();
+
+                let batches =
+                    execute(plan.clone(), "t", panes[window..window + 2].to_vec()).await?;
+                assert_batches_sorted_eq!(expected, &batches);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pane_store() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Utf8, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let state_backend = HashMapStateBackend::new();
+        let store = PaneStore::new(&state_backend, "flock-pane-test", "query-00-00", "run-1", 1);
+        let state = |v| -> Result<InputBatches> {
+            Ok(vec![(
+                PANE_INPUT.to_owned(),
+                vec![pane_batch(&schema, vec!["a"], vec![v])?],
+            )])
+        };
+
+        // Windows of two panes over a stream of three panes.
+        let pane = |index| Pane::new(index, 2, 3);
+        store.write(&pane(0), &state(1)?).await?;
+        assert!(store.complete_windows(&pane(0)).await?.is_empty());
+
+        store.write(&pane(1), &state(2)?).await?;
+        assert_eq!(vec![0], store.complete_windows(&pane(1)).await?);
+        // The window is already claimed.
+        assert!(store.complete_windows(&pane(1)).await?.is_empty());
+
+        // Another run of the query neither sees the panes nor the claims.
+        let rerun = PaneStore::new(&state_backend, "flock-pane-test", "query-00-00", "run-2", 1);
+        let short = |index| Pane::new(index, 2, 2);
+        assert!(rerun.read_window(&short(1), 0).await.is_err());
+        rerun.write(&short(0), &state(1)?).await?;
+        rerun.write(&short(1), &state(2)?).await?;
+        assert_eq!(vec![0], rerun.complete_windows(&short(1)).await?);
+        rerun.expire(&short(1), 0).await?;
+
+        let window = store.read_window(&pane(1), 0).await?;
+        assert_eq!(1, window.len());
+        assert_eq!(2, window[0].1.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        store.expire(&pane(1), 0).await?;
+        assert!(store.read_window(&pane(1), 0).await.is_err());
+
+        store.write(&pane(2), &state(3)?).await?;
+        assert_eq!(vec![1], store.complete_windows(&pane(2)).await?);
+        store.expire(&pane(2), 1).await?;
+        assert!(state_backend
+            .keys("flock-pane-test".to_owned(), String::new())
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_pane() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Utf8, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let state_backend = HashMapStateBackend::new();
+        let bucket = "flock-late-pane-test";
+        let store = PaneStore::new(&state_backend, bucket, "query-00-00", "run-1", 1);
+        let state = |v| -> Result<InputBatches> {
+            Ok(vec![(
+                PANE_INPUT.to_owned(),
+                vec![pane_batch(&schema, vec!["a"], vec![v])?],
+            )])
+        };
+        let rows = |window: InputBatches| window[0].1.iter().map(|b| b.num_rows()).sum::<usize>();
+
+        // Windows of two panes over a stream of four panes, where the first pane
+        // arrives after the second window is emitted.
+        let pane = |index| Pane::new(index, 2, 4);
+        store.write(&pane(1), &state(2)?).await?;
+        assert!(store.complete_windows(&pane(1)).await?.is_empty());
+        store.write(&pane(2), &state(3)?).await?;
+        assert_eq!(vec![1], store.complete_windows(&pane(2)).await?);
+        assert_eq!(2, rows(store.read_window(&pane(2), 1).await?));
+        store.expire(&pane(2), 1).await?;
+
+        // The late pane still completes the first window.
+        store.write(&pane(0), &state(1)?).await?;
+        assert_eq!(vec![0], store.complete_windows(&pane(0)).await?);
+        assert_eq!(2, rows(store.read_window(&pane(0), 0).await?));
+        store.expire(&pane(0), 0).await?;
+
+        store.write(&pane(3), &state(4)?).await?;
+        assert_eq!(vec![2], store.complete_windows(&pane(3)).await?);
+        assert_eq!(2, rows(store.read_window(&pane(3), 2).await?));
+        store.expire(&pane(3), 2).await?;
+
+        assert!(state_backend
+            .keys(bucket.to_owned(), String::new())
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
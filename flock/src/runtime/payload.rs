@@ -18,15 +18,14 @@
 
 use crate::datasource::DataSource;
 use crate::encoding::Encoding;
+use crate::error::Result;
+use crate::runtime::schema::{resolve_schema, schema_bytes, SchemaFingerprint};
+use crate::runtime::skew::Salt;
+use crate::trace::TraceContext;
 use crate::transmute::*;
-use datafusion::arrow::datatypes::Schema;
 use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::arrow_flight::utils::flight_data_to_arrow_batch;
-use datafusion::arrow_flight::FlightData;
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
 use uuid::Uuid as RandomId;
 
 /// A helper struct for building uuids of payloads.
@@ -127,18 +126,53 @@ pub struct DataFrame {
     pub body:   Vec<u8>,
 }
 
+/// The identifier of an input of a query stage. The relations of a data
+/// source are identified by their table names, and the outputs of the former
+/// stage by [`output_id`].
+pub type InputId = String;
+
+/// The record batches of each input of a payload.
+pub type InputBatches = Vec<(InputId, Vec<RecordBatch>)>;
+
+/// Returns the input id of the output of the former stage's `index`-th
+/// execution plan.
+pub fn output_id(index: usize) -> InputId {
+    format!("output-{}", index)
+}
+
+/// `PayloadInput` is the record batches of an input of the next stage, which
+/// are encoded in the Arrow Flight Data format.
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PayloadInput {
+    /// The identifier of the input.
+    pub id:                 InputId,
+    /// The record batches are encoded in the Arrow Flight Data format.
+    pub data:               Vec<DataFrame>,
+    /// The schema of the record batches in binary format.
+    pub schema:             Vec<u8>,
+    /// The fingerprint of the schema. If it is set, the schema bytes can be
+    /// omitted, and the receiver resolves the schema from its schema registry.
+    pub schema_fingerprint: Option<SchemaFingerprint>,
+}
+
+impl PayloadInput {
+    /// Decodes the record batches of the input.
+    pub fn to_record_batch(&self, encoding: &Encoding) -> Result<Vec<RecordBatch>> {
+        if self.data.is_empty() {
+            return Ok(vec![]);
+        }
+        let schema = resolve_schema(self.schema_fingerprint, &self.schema)?;
+        dataframes_to_batches(&self.data, encoding, schema)
+    }
+}
+
 /// `Payload` is the wire format of the function's payload passed between
 /// cloud functions.
 #[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Payload {
-    /// The record batches are encoded in the Arrow Flight Data format.
-    pub data:         Vec<DataFrame>,
-    /// The schema of the record batches in binary format.
-    pub schema:       Vec<u8>,
-    /// The record batches for the 2nd relation.
-    pub data2:        Vec<DataFrame>,
-    /// The schema of the record batches for the 2nd relation.
-    pub schema2:      Vec<u8>,
+    /// The inputs of the next stage, such as the relations of a multi-way join
+    /// or a union. The inputs without record batches are omitted.
+    pub inputs:       Vec<PayloadInput>,
     /// The UUID of the payload.
     pub uuid:         Uuid,
     /// The encoding and compression method.
@@ -154,46 +188,54 @@ pub struct Payload {
     pub shuffle_id:   Option<usize>,
     /// The extra metadata for the payload.
     pub metadata:     Option<HashMap<String, String>>,
+    /// The trace context of the sender, which is used to correlate the spans
+    /// of the same window across function hops.
+    pub trace:        Option<TraceContext>,
+    /// The salted sub-partition of a heavy hash partition, if the partition
+    /// is split. The partial results of the sub-partitions are merged by the
+    /// combine stage.
+    pub salt:         Option<Salt>,
 }
 
 impl Payload {
     /// Convert incoming payload to record batch in Arrow.
-    pub fn to_record_batch(self) -> (Vec<RecordBatch>, Vec<RecordBatch>) {
-        let record_batch = |df: Vec<DataFrame>, schema: Arc<Schema>| -> Vec<RecordBatch> {
-            df.into_par_iter()
-                .map(|d| {
-                    flight_data_to_arrow_batch(
-                        &FlightData {
-                            data_body:         d.body,
-                            data_header:       d.header,
-                            app_metadata:      vec![],
-                            flight_descriptor: None,
-                        },
-                        schema.clone(),
-                        &[],
-                    )
-                    .unwrap()
-                })
-                .collect()
-        };
-
-        let mut res = (vec![], vec![]);
-        if !self.data.is_empty() {
-            let dataframe = unmarshal(self.data, self.encoding.clone());
-            let schema = schema_from_bytes(&self.schema).unwrap();
-            res.0 = record_batch(dataframe, schema);
-        }
-        if !self.data2.is_empty() {
-            let dataframe = unmarshal(self.data2, self.encoding.clone());
-            let schema = schema_from_bytes(&self.schema2).unwrap();
-            res.1 = record_batch(dataframe, schema);
+    ///
+    /// A corrupt frame or an unknown schema results in an error rather than a
+    /// panic, so that the caller can quarantine the payload.
+    pub fn to_record_batch(&self) -> Result<InputBatches> {
+        self.inputs
+            .iter()
+            .map(|input| Ok((input.id.clone(), input.to_record_batch(&self.encoding)?)))
+            .collect()
+    }
+
+    /// Attaches the IPC-encoded schemas to the inputs that only carry their
+    /// fingerprints, e.g., to resend the payload to a receiver that doesn't
+    /// know them.
+    pub fn attach_schemas(&mut self) {
+        for input in self
+            .inputs
+            .iter_mut()
+            .filter(|input| input.schema.is_empty())
+        {
+            if let Some(bytes) = input.schema_fingerprint.and_then(schema_bytes) {
+                input.schema = bytes;
+            }
         }
-        res
+    }
+
+    /// Return the number of encoded data bytes in the payload.
+    pub fn data_size(&self) -> usize {
+        self.inputs
+            .iter()
+            .flat_map(|input| input.data.iter())
+            .map(|d| d.header.len() + d.body.len())
+            .sum()
     }
 
     /// Return true if the records in the payload are empty.
     pub fn is_empty_data(&self) -> bool {
-        self.data.is_empty() && self.data2.is_empty()
+        self.inputs.iter().all(|input| input.data.is_empty())
     }
 
     /// Returns the window id of the payload.
@@ -241,12 +283,12 @@ impl Payload {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::error::Result;
     use datafusion::arrow::array::{Array, StructArray};
     use datafusion::arrow::csv;
     use datafusion::arrow::datatypes::{DataType, Field, Schema};
     use datafusion::arrow::json;
     use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
+    use rayon::prelude::*;
     use serde_json::Value;
     use std::sync::Arc;
     use std::time::Instant;
@@ -459,8 +501,12 @@ mod tests {
             .collect();
 
         serde_json::to_vec(&Payload {
-            data: data_frames,
-            schema: schema_to_bytes(batches[0].schema()),
+            inputs: vec![PayloadInput {
+                id: output_id(0),
+                data: data_frames,
+                schema: schema_to_bytes(batches[0].schema()),
+                ..Default::default()
+            }],
             uuid,
             encoding,
             ..Default::default()
@@ -483,7 +529,7 @@ mod tests {
 
         let payload1: Payload = serde_json::from_value(value.clone())?;
         let now = Instant::now();
-        let (de_batches, _) = json_value_to_batch(value);
+        let de_batches = json_value_to_batch(value)?.remove(0).1;
         println!(
             "serde value to batch (with decompression) - time: {} ms",
             now.elapsed().as_millis()
@@ -544,7 +590,7 @@ mod tests {
         let batches = init_batches();
         let bytes = to_bytes(&batches[0], uuid_builder.next_uuid(), Encoding::default());
         let value: Value = serde_json::from_slice(&bytes)?;
-        let (de_batches, _) = json_value_to_batch(value);
+        let de_batches = json_value_to_batch(value)?.remove(0).1;
 
         assert_eq!(batches[0].schema(), de_batches[0].schema());
         assert_eq!(batches[0].columns(), de_batches[0].columns());
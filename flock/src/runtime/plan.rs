@@ -17,6 +17,9 @@
 
 use crate::aws::s3;
 use crate::error::Result;
+use crate::runtime::join::IntervalJoin;
+use crate::runtime::lookup::LookupJoin;
+use crate::runtime::payload::InputId;
 use datafusion::execution::context::ExecutionContext;
 use datafusion::physical_plan::displayable;
 use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
@@ -39,6 +42,24 @@ pub struct CloudExecutionPlan {
     /// serialized and stored in the environment variable, the system will
     /// store the plan in S3.
     pub object_storage:  Option<(S3BUCKET, S3KEY)>,
+    /// The ids of the inputs fed to the leaf nodes of the execution plans, from
+    /// left to right. The leaf nodes without an input id are matched to the
+    /// inputs by their schemas.
+    #[serde(default)]
+    pub inputs:          Vec<InputId>,
+    /// The interval join of the input streams. If set, the function joins the
+    /// inputs with the join state in the state backend, and the execution
+    /// plans run over the new matches.
+    #[serde(default)]
+    pub interval_join:   Option<IntervalJoin>,
+    /// The lookup join of a stream input with a reference table. If set, the
+    /// execution plans run over the enriched rows of the stream.
+    #[serde(default)]
+    pub lookup_join:     Option<LookupJoin>,
+    /// The execution plans before their spilled data sources are replaced,
+    /// which are restored after the execution.
+    #[serde(skip)]
+    pub unspilled_plans: Option<Vec<Arc<dyn ExecutionPlan>>>,
 }
 
 impl std::fmt::Debug for CloudExecutionPlan {
@@ -51,8 +72,8 @@ impl std::fmt::Debug for CloudExecutionPlan {
             .join("\n");
         write!(
             f,
-            "CloudExecutionPlan {{ execution_plans: {}, object_storage: {:?} }}",
-            plan_str, self.object_storage
+            "CloudExecutionPlan {{ execution_plans: {}, object_storage: {:?}, inputs: {:?}, interval_join: {:?}, lookup_join: {:?} }}",
+            plan_str, self.object_storage, self.inputs, self.interval_join, self.lookup_join
         )
     }
 }
@@ -66,9 +87,32 @@ impl CloudExecutionPlan {
         CloudExecutionPlan {
             execution_plans,
             object_storage,
+            inputs: vec![],
+            interval_join: None,
+            lookup_join: None,
+            unspilled_plans: None,
         }
     }
 
+    /// Sets the ids of the inputs fed to the leaf nodes of the execution plans,
+    /// from left to right.
+    pub fn with_inputs(mut self, inputs: Vec<InputId>) -> Self {
+        self.inputs = inputs;
+        self
+    }
+
+    /// Sets the interval join of the input streams.
+    pub fn with_interval_join(mut self, interval_join: IntervalJoin) -> Self {
+        self.interval_join = Some(interval_join);
+        self
+    }
+
+    /// Sets the lookup join of a stream input with a reference table.
+    pub fn with_lookup_join(mut self, lookup_join: LookupJoin) -> Self {
+        self.lookup_join = Some(lookup_join);
+        self
+    }
+
     /// Create a new CloudExecutionPlan from
 
     /// Returns the execution plan.
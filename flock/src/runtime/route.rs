@@ -0,0 +1,196 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! A route redirects the windows sent to the first function of a query to the
+//! first function of its upgraded version (blue/green upgrade).
+//!
+//! The upgraded query (green) is deployed alongside the running query (blue)
+//! with a new query code, and the route from blue's first function to green's
+//! is stored in S3 under `routes/<blue function>`. The route takes effect from
+//! a window timestamp at least one grace period later, so that all data
+//! sources have reloaded it before the switch. The data sources check the
+//! route before generating each window, so the switch always happens at a
+//! window boundary: the windows that started before the switch are still
+//! processed by blue until it's drained, and all later windows by green.
+//!
+//! Routes are chained: after another upgrade, the route of green's first
+//! function points to the next version, so a data source that still names the
+//! original query reaches the latest one.
+
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::Result;
+use crate::runtime::context::CloudFunction;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The key prefix of the routes in S3.
+pub const ROUTE_KEY_PREFIX: &str = "routes";
+
+/// The maximum number of chained routes followed from a function.
+const MAX_ROUTE_HOPS: usize = 8;
+
+lazy_static! {
+    /// The routes loaded by the function, and the time when they're loaded.
+    static ref ROUTES: Mutex<HashMap<String, (i64, Option<Route>)>> = Mutex::new(HashMap::new());
+}
+
+/// The route from the first function of a query to the first function of its
+/// upgraded version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    /// The name of the function (or function group) being replaced.
+    pub from:      String,
+    /// The function (or function group) that replaces it.
+    pub to:        CloudFunction,
+    /// The window timestamp from which the windows are sent to the new
+    /// function.
+    pub switch_at: i64,
+}
+
+impl Route {
+    /// Creates a route that switches at the given window timestamp.
+    pub fn new(from: &str, to: CloudFunction, switch_at: i64) -> Self {
+        Route {
+            from: from.to_owned(),
+            to,
+            switch_at,
+        }
+    }
+
+    /// Returns the function that receives the window of the given timestamp,
+    /// or None if the window still goes to the function being replaced.
+    pub fn next_at(&self, timestamp: i64) -> Option<&CloudFunction> {
+        if timestamp >= self.switch_at {
+            Some(&self.to)
+        } else {
+            None
+        }
+    }
+
+    /// Loads the route of the given function from S3.
+    pub async fn load(from: &str) -> Result<Option<Route>> {
+        let key = route_key(from);
+        if s3::get_matched_keys(&FLOCK_S3_BUCKET, &key)
+            .await?
+            .is_empty()
+        {
+            return Ok(None);
+        }
+        let route = serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?;
+        Ok(Some(route))
+    }
+
+    /// Saves the route to S3.
+    pub async fn save(&self) -> Result<()> {
+        s3::put_object(
+            &FLOCK_S3_BUCKET,
+            &route_key(&self.from),
+            serde_json::to_vec(self)?,
+        )
+        .await
+    }
+}
+
+/// Returns the first window timestamp at or after the given time that is a
+/// multiple of the window size.
+pub fn window_boundary(after: i64, window_size: i64) -> i64 {
+    if window_size <= 0 {
+        return after;
+    }
+    (after + window_size - 1).div_euclid(window_size) * window_size
+}
+
+/// Follows the routes from the given function for the window of the given
+/// timestamp. The routes are reloaded from S3 at most once per refresh
+/// interval of the function groups (`group.refresh_interval`).
+///
+/// # Returns
+/// The function that replaces the given one, or None if it isn't replaced.
+pub async fn next_function(from: &str, timestamp: i64) -> Result<Option<CloudFunction>> {
+    let mut next = None;
+    let mut name = from.to_owned();
+    for _ in 0..MAX_ROUTE_HOPS {
+        match cached_route(&name).await? {
+            Some(route) => match route.next_at(timestamp) {
+                Some(to) => {
+                    name = function_name(to);
+                    next = Some(to.clone());
+                }
+                None => break,
+            },
+            None => break,
+        }
+    }
+    Ok(next)
+}
+
+/// Returns the route of the function, reloaded if it's older than the
+/// refresh interval.
+async fn cached_route(from: &str) -> Result<Option<Route>> {
+    let now = Utc::now().timestamp();
+    if let Some((loaded_at, route)) = ROUTES.lock().unwrap().get(from) {
+        if now - loaded_at < FLOCK_CONFIG.group.refresh_interval {
+            return Ok(route.clone());
+        }
+    }
+    let route = Route::load(from).await?;
+    ROUTES
+        .lock()
+        .unwrap()
+        .insert(from.to_owned(), (now, route.clone()));
+    Ok(route)
+}
+
+/// Returns the name of the function or function group.
+fn function_name(function: &CloudFunction) -> String {
+    match function {
+        CloudFunction::Lambda(name) => name.clone(),
+        CloudFunction::Group((name, _)) => name.clone(),
+        CloudFunction::Sink(..) => String::new(),
+    }
+}
+
+fn route_key(from: &str) -> String {
+    format!("{}/{}", ROUTE_KEY_PREFIX, from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_at_window_boundary() {
+        assert_eq!(120, window_boundary(101, 20));
+        assert_eq!(120, window_boundary(120, 20));
+        assert_eq!(101, window_boundary(101, 0));
+
+        let route = Route::new(
+            "SX72HzqFz1Qij4bP-00",
+            CloudFunction::Group(("QzVj8yXGgEW1hcRw-00".to_string(), 4)),
+            window_boundary(101, 20),
+        );
+        assert_eq!(None, route.next_at(119));
+        assert_eq!(
+            Some(&CloudFunction::Group((
+                "QzVj8yXGgEW1hcRw-00".to_string(),
+                4
+            ))),
+            route.next_at(120)
+        );
+        assert_eq!("QzVj8yXGgEW1hcRw-00", function_name(&route.to));
+    }
+}
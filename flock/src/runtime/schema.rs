@@ -0,0 +1,424 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The schema registry keeps the decoded Arrow schemas of a query inside the
+//! function's global memory. Instead of shipping the IPC-encoded schema with
+//! every [`Payload`](crate::runtime::payload::Payload), the sender only puts a
+//! [`SchemaFingerprint`] into the payload, and the receiver looks it up in the
+//! registry. The registry is populated once per container from the leaf nodes
+//! of the stage plan, so each schema is decoded at most once per container.
+//!
+//! A sender still ships the IPC-encoded schema the first time it sends it, and
+//! again when a receiver reports an unknown fingerprint, so a receiver whose
+//! plan doesn't register the schema can still decode the payload, or report
+//! the fields that drifted.
+
+use crate::error::{FlockError, Result};
+use crate::transmute::{schema_from_bytes, schema_to_bytes};
+use datafusion::arrow::datatypes::SchemaRef;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use std::hash::Hasher;
+use std::sync::RwLock;
+
+/// The fingerprint of an Arrow schema.
+///
+/// The fingerprint is the 64-bit FNV-1a hash of the IPC-encoded schema, so it
+/// is stable across processes, architectures and compiler versions.
+pub type SchemaFingerprint = u64;
+
+lazy_static! {
+    /// The schema registry shared by all invocations in the same container.
+    static ref SCHEMA_REGISTRY: RwLock<SchemaRegistry> = RwLock::new(SchemaRegistry::new());
+    /// The fingerprints whose schemas have been sent by the container.
+    static ref SENT_SCHEMAS: RwLock<HashSet<SchemaFingerprint>> = RwLock::new(HashSet::new());
+}
+
+/// The error message of a payload whose schema fingerprint isn't registered by
+/// the receiver. The sender resends the payload with the schema bytes.
+pub const UNKNOWN_SCHEMA_FINGERPRINT: &str = "Unknown schema fingerprint";
+
+/// The error message of a payload whose schema bytes don't match its
+/// fingerprint.
+pub const SCHEMA_FINGERPRINT_MISMATCH: &str = "Schema fingerprint mismatch";
+
+/// Returns true if the error means that the producer and the current stage
+/// disagree on the schema, rather than the payload being corrupt.
+pub fn is_schema_error(error: &FlockError) -> bool {
+    match error {
+        FlockError::Execution(msg) => {
+            msg.starts_with(UNKNOWN_SCHEMA_FINGERPRINT)
+                || msg.starts_with(SCHEMA_FINGERPRINT_MISMATCH)
+        }
+        FlockError::Plan(msg) => msg.starts_with("Schema drift"),
+        _ => false,
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Returns the fingerprint of the IPC-encoded schema.
+pub fn fingerprint_bytes(bytes: &[u8]) -> SchemaFingerprint {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A 64-bit FNV-1a hasher. Unlike the std `DefaultHasher`, its output is the
+/// same in every process and on every architecture: integers are hashed as
+/// little-endian bytes, and `usize` as a `u64`.
+#[derive(Debug, Clone, Copy)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        });
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// Returns the fingerprint of the given schema.
+pub fn fingerprint(schema: &SchemaRef) -> SchemaFingerprint {
+    fingerprint_bytes(&schema_to_bytes(schema.clone()))
+}
+
+/// A mapping from schema fingerprints to decoded schemas.
+#[derive(Debug, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<SchemaFingerprint, SchemaRef>,
+}
+
+impl SchemaRegistry {
+    /// Creates an empty schema registry.
+    pub fn new() -> Self {
+        Self {
+            schemas: HashMap::new(),
+        }
+    }
+
+    /// Registers a schema and returns its fingerprint.
+    pub fn register(&mut self, schema: SchemaRef) -> SchemaFingerprint {
+        let fp = fingerprint(&schema);
+        self.schemas.entry(fp).or_insert(schema);
+        fp
+    }
+
+    /// Returns the schema for the given fingerprint.
+    pub fn get(&self, fp: SchemaFingerprint) -> Option<SchemaRef> {
+        self.schemas.get(&fp).cloned()
+    }
+
+    /// Returns true if the fingerprint is registered.
+    pub fn contains(&self, fp: SchemaFingerprint) -> bool {
+        self.schemas.contains_key(&fp)
+    }
+
+    /// Returns the number of registered schemas.
+    pub fn len(&self) -> usize {
+        self.schemas.len()
+    }
+
+    /// Returns true if no schema is registered.
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// Resolves the schema of a relation in the payload.
+    ///
+    /// If the IPC bytes are present, they are decoded once and cached under
+    /// their fingerprint. If the payload only carries a fingerprint, the
+    /// schema must have been registered before, otherwise the producer and the
+    /// current stage disagree on the schema.
+    ///
+    /// # Arguments
+    /// * `fp` - The schema fingerprint in the payload.
+    /// * `bytes` - The IPC-encoded schema in the payload, possibly empty.
+    pub fn resolve(&mut self, fp: Option<SchemaFingerprint>, bytes: &[u8]) -> Result<SchemaRef> {
+        if !bytes.is_empty() {
+            let actual = fingerprint_bytes(bytes);
+            if let Some(expected) = fp {
+                if expected != actual {
+                    return Err(FlockError::Execution(format!(
+                        "{}: the payload claims {:016x}, but its schema bytes hash to {:016x}",
+                        SCHEMA_FINGERPRINT_MISMATCH, expected, actual
+                    )));
+                }
+            }
+            if let Some(schema) = self.schemas.get(&actual) {
+                return Ok(schema.clone());
+            }
+            let schema = schema_from_bytes(bytes)?;
+            self.schemas.insert(actual, schema.clone());
+            return Ok(schema);
+        }
+
+        match fp {
+            Some(fp) => self.get(fp).ok_or_else(|| {
+                FlockError::Execution(format!(
+                    "{} {:016x}: the producer's schema doesn't match any schema registered by \
+                     the current stage: {:?}",
+                    UNKNOWN_SCHEMA_FINGERPRINT,
+                    fp,
+                    self.schemas
+                        .values()
+                        .map(|s| s.fields().iter().map(|f| f.name().as_str()).collect())
+                        .collect::<Vec<Vec<_>>>()
+                ))
+            }),
+            None => Err(FlockError::Execution(
+                "The payload carries neither a schema nor a schema fingerprint".to_string(),
+            )),
+        }
+    }
+}
+
+/// Registers a schema in the container-wide registry.
+pub fn register_schema(schema: SchemaRef) -> SchemaFingerprint {
+    SCHEMA_REGISTRY.write().unwrap().register(schema)
+}
+
+/// Looks up a schema in the container-wide registry.
+pub fn lookup_schema(fp: SchemaFingerprint) -> Option<SchemaRef> {
+    SCHEMA_REGISTRY.read().unwrap().get(fp)
+}
+
+/// Returns true the first time the container sends the schema, in which case
+/// the payload carries the schema bytes along with the fingerprint.
+pub fn first_contact(fp: SchemaFingerprint) -> bool {
+    if SENT_SCHEMAS.read().unwrap().contains(&fp) {
+        return false;
+    }
+    SENT_SCHEMAS.write().unwrap().insert(fp)
+}
+
+/// Returns the IPC-encoded schema of the registered fingerprint.
+pub fn schema_bytes(fp: SchemaFingerprint) -> Option<Vec<u8>> {
+    lookup_schema(fp).map(schema_to_bytes)
+}
+
+/// Resolves a schema through the container-wide registry.
+/// See [`SchemaRegistry::resolve`].
+pub fn resolve_schema(fp: Option<SchemaFingerprint>, bytes: &[u8]) -> Result<SchemaRef> {
+    if bytes.is_empty() {
+        if let Some(schema) = fp.and_then(lookup_schema) {
+            return Ok(schema);
+        }
+    }
+    SCHEMA_REGISTRY.write().unwrap().resolve(fp, bytes)
+}
+
+/// Checks whether the schema of the incoming data matches one of the schemas
+/// the stage plan expects.
+///
+/// # Arguments
+/// * `expected` - The schemas of the leaf nodes in the stage plan.
+/// * `actual` - The schema of the incoming data.
+pub fn check_compatibility(expected: &[SchemaRef], actual: &SchemaRef) -> Result<()> {
+    if expected.iter().any(|schema| schema == actual) {
+        return Ok(());
+    }
+
+    // Reports the mismatched fields against the expected schema that shares the
+    // most field names with the producer's schema.
+    let shared = |schema: &SchemaRef| {
+        schema
+            .fields()
+            .iter()
+            .filter(|e| actual.field_with_name(e.name()).is_ok())
+            .count()
+    };
+    let closest = expected
+        .iter()
+        .filter(|schema| shared(schema) > 0)
+        .max_by_key(|schema| shared(schema));
+    if let Some(schema) = closest {
+        let mut mismatches = vec![];
+        for e in schema.fields() {
+            match actual.field_with_name(e.name()) {
+                Ok(a) if a.data_type() != e.data_type() => mismatches.push(format!(
+                    "`{}` expects {:?} but the producer sent {:?}",
+                    e.name(),
+                    e.data_type(),
+                    a.data_type()
+                )),
+                Ok(_) => {}
+                Err(_) => mismatches.push(format!("`{}` is missing", e.name())),
+            }
+        }
+        for a in actual.fields() {
+            if schema.field_with_name(a.name()).is_err() {
+                mismatches.push(format!("`{}` is unexpected", a.name()));
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(FlockError::Plan(format!(
+                "Schema drift: {}",
+                mismatches.join(", ")
+            )));
+        }
+    }
+
+    Err(FlockError::Plan(format!(
+        "The producer's schema {:?} doesn't match any input of the stage plan: {:?}",
+        actual
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>(),
+        expected
+            .iter()
+            .map(|s| s.fields().iter().map(|f| f.name().as_str()).collect())
+            .collect::<Vec<Vec<_>>>()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    fn schema(ty: DataType) -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", ty, false),
+        ]))
+    }
+
+    #[test]
+    fn stable_fingerprint() {
+        let s1 = schema(DataType::Int32);
+        let s2 = schema(DataType::Int32);
+        let s3 = schema(DataType::Int64);
+        assert_eq!(fingerprint(&s1), fingerprint(&s2));
+        assert_ne!(fingerprint(&s1), fingerprint(&s3));
+        assert_eq!(
+            fingerprint(&s1),
+            fingerprint_bytes(&schema_to_bytes(s1.clone()))
+        );
+    }
+
+    #[test]
+    fn stable_hasher() {
+        use std::hash::Hash;
+
+        // Integers are hashed as little-endian bytes on every architecture.
+        let mut hasher = FnvHasher::default();
+        42_i64.hash(&mut hasher);
+        assert_eq!(fingerprint_bytes(&42_u64.to_le_bytes()), hasher.finish());
+
+        let mut hasher = FnvHasher::default();
+        hasher.write(b"flock");
+        assert_eq!(0xa011_73a9_42b8_3cf6, hasher.finish());
+    }
+
+    #[test]
+    fn resolve_schemas() -> Result<()> {
+        let mut registry = SchemaRegistry::new();
+        let s1 = schema(DataType::Int32);
+        let bytes = schema_to_bytes(s1.clone());
+        let fp = fingerprint(&s1);
+
+        // Unknown fingerprint without schema bytes.
+        match registry.resolve(Some(fp), &[]) {
+            Err(FlockError::Execution(msg)) => assert!(msg.starts_with(UNKNOWN_SCHEMA_FINGERPRINT)),
+            _ => panic!("expected an unknown fingerprint error"),
+        }
+
+        // The first payload carries the schema bytes.
+        assert_eq!(s1, registry.resolve(Some(fp), &bytes)?);
+        assert_eq!(1, registry.len());
+
+        // The following payloads only carry the fingerprint.
+        assert_eq!(s1, registry.resolve(Some(fp), &[])?);
+
+        // The fingerprint doesn't match the schema bytes.
+        assert!(registry.resolve(Some(fp + 1), &bytes).is_err());
+        assert!(registry.resolve(None, &[]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn first_contact_schemas() {
+        let fp = register_schema(Arc::new(Schema::new(vec![Field::new(
+            "first_contact",
+            DataType::Utf8,
+            false,
+        )])));
+        assert!(first_contact(fp));
+        assert!(!first_contact(fp));
+        assert_eq!(Some(fp), schema_bytes(fp).map(|b| fingerprint_bytes(&b)));
+    }
+
+    #[test]
+    fn schema_drift() {
+        let expected = vec![schema(DataType::Int32)];
+        assert!(check_compatibility(&expected, &schema(DataType::Int32)).is_ok());
+
+        match check_compatibility(&expected, &schema(DataType::Int64)) {
+            Err(FlockError::Plan(msg)) => assert_eq!(
+                "Schema drift: `b` expects Int32 but the producer sent Int64",
+                msg
+            ),
+            _ => panic!("expected a schema drift error"),
+        }
+
+        // The missing and the unexpected fields are reported together.
+        let renamed = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("c", DataType::Int32, false),
+        ]));
+        match check_compatibility(&expected, &renamed) {
+            Err(FlockError::Plan(msg)) => {
+                assert_eq!("Schema drift: `b` is missing, `c` is unexpected", msg)
+            }
+            _ => panic!("expected a schema drift error"),
+        }
+
+        let other = Arc::new(Schema::new(vec![Field::new("c", DataType::Utf8, false)]));
+        assert!(check_compatibility(&expected, &other).is_err());
+    }
+}
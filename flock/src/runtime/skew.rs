@@ -0,0 +1,751 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Skew mitigation for the hash shuffles.
+//!
+//! A hash shuffle sends all rows of a key to the same function of the next
+//! group, so a heavy key (e.g., a hot seller in NEXMark) overloads a single
+//! function. The functions of a group aggregation sample the key frequencies of
+//! their partitions, and publish the heavy keys of each partition to S3. The
+//! upstream functions split the rows of the heavy keys into salted
+//! sub-partitions, which are aggregated by different functions of the group,
+//! and the combine stage inserted by the planner merges the partial results of
+//! the sub-partitions.
+//!
+//! All upstream functions must split the partitions of a window in the same
+//! way, otherwise the sub-partitions never complete. As the function group
+//! membership, the heavy keys are versioned, each version takes effect from a
+//! window timestamp after a grace period, and a window is always split with the
+//! version of its own timestamp.
+
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::schema::FnvHasher;
+use chrono::Utc;
+use datafusion::arrow::array::{ArrayRef, BooleanArray};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::physical_plan::expressions::{Column, Count, Max, Min, Sum};
+use datafusion::physical_plan::hash_aggregate::{AggregateMode, HashAggregateExec};
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::{AggregateExpr, ExecutionPlan, Partitioning, PhysicalExpr};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// The key prefix of the heavy keys in the Flock S3 bucket.
+pub const SKEW_KEY_PREFIX: &str = "skew";
+
+/// The number of versions of the heavy keys kept for the windows in flight.
+const MAX_VERSIONS: usize = 8;
+
+lazy_static! {
+    /// The heavy keys of the next function groups, read by the upstream functions.
+    static ref SKEW_PLANS: Mutex<HashMap<String, SkewPlanCache>> = Mutex::new(HashMap::new());
+    /// The heavy keys of the partitions aggregated by the current function.
+    static ref OWN_PLANS: Mutex<HashMap<(String, usize), (SkewPlans, i64)>> = Mutex::new(HashMap::new());
+}
+
+/// The salted sub-partition of a hash partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Salt {
+    /// The shuffle id of the hash partition.
+    pub partition: usize,
+    /// The index of the sub-partition, starting from 1.
+    pub index:     usize,
+    /// The number of sub-partitions.
+    pub count:     usize,
+}
+
+/// Returns the shuffle id of a salted sub-partition. The first sub-partition
+/// keeps the shuffle id of the partition, and the other ones are numbered
+/// after the partitions.
+///
+/// # Arguments
+/// * `partitions` - The number of hash partitions.
+/// * `salt` - The salted sub-partition.
+pub fn salted_shuffle_id(partitions: usize, salt: &Salt) -> usize {
+    if salt.index == 1 {
+        salt.partition
+    } else {
+        partitions + (salt.partition - 1) * (salt.count - 1) + (salt.index - 1)
+    }
+}
+
+/// Returns the hash of the key of each row. The key values are hashed by
+/// their string representations with FNV-1a, so that the functions with
+/// different schemas of the same key columns, and the heavy keys persisted
+/// across deployments, agree on the hashes.
+pub fn key_hashes(batch: &RecordBatch, key_columns: &[String]) -> Result<Vec<u64>> {
+    let columns = key_columns
+        .iter()
+        .map(|name| Ok(batch.column(batch.schema().index_of(name)?).clone()))
+        .collect::<Result<Vec<ArrayRef>>>()?;
+    (0..batch.num_rows())
+        .map(|row| key_hash(&columns, row))
+        .collect()
+}
+
+fn key_hash(columns: &[ArrayRef], row: usize) -> Result<u64> {
+    let mut hasher = FnvHasher::default();
+    for column in columns {
+        column.is_null(row).hash(&mut hasher);
+        array_value_to_string(column, row)?.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+/// Estimates the key frequencies of a partition from a systematic sample of
+/// its rows.
+#[derive(Debug, Clone, Default)]
+pub struct KeySampler {
+    /// One in `interval` rows is sampled.
+    pub interval: usize,
+    /// The total number of rows.
+    pub rows:     usize,
+    /// The sampled number of rows of each key hash.
+    pub counts:   HashMap<u64, usize>,
+}
+
+impl KeySampler {
+    /// Returns a new key sampler.
+    pub fn new(interval: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            ..Default::default()
+        }
+    }
+
+    /// Samples the keys of the record batch.
+    pub fn sample(&mut self, batch: &RecordBatch, key_columns: &[String]) -> Result<()> {
+        let columns = key_columns
+            .iter()
+            .map(|name| Ok(batch.column(batch.schema().index_of(name)?).clone()))
+            .collect::<Result<Vec<ArrayRef>>>()?;
+        // Continues the sampling positions across the batches.
+        let offset = (self.interval - self.rows % self.interval) % self.interval;
+        for row in (offset..batch.num_rows()).step_by(self.interval) {
+            *self.counts.entry(key_hash(&columns, row)?).or_insert(0) += 1;
+        }
+        self.rows += batch.num_rows();
+        Ok(())
+    }
+
+    /// Returns the estimated number of rows of the key.
+    pub fn estimate(&self, key: u64) -> usize {
+        self.counts
+            .get(&key)
+            .map_or(0, |count| count * self.interval)
+    }
+
+    /// Returns the heavy keys, which have more than `fraction` of the rows and
+    /// at least `min_rows` rows.
+    ///
+    /// # Arguments
+    /// * `fraction` - The fraction of the rows above which a key is heavy.
+    /// * `min_rows` - The minimum estimated number of rows of a heavy key.
+    /// * `split` - The currently heavy keys and their number of sub-partitions.
+    ///   The sampled partition only has a share of their rows.
+    pub fn heavy_keys(
+        &self,
+        fraction: f64,
+        min_rows: usize,
+        split: Option<(&HashSet<u64>, usize)>,
+    ) -> Vec<u64> {
+        let estimate = |key: u64| match split {
+            Some((keys, count)) if keys.contains(&key) => self.estimate(key) * count,
+            _ => self.estimate(key),
+        };
+        let rows = self.rows
+            + split.map_or(0, |(keys, count)| {
+                keys.iter().map(|k| self.estimate(*k) * (count - 1)).sum()
+            });
+        let threshold = ((rows as f64 * fraction).ceil() as usize).max(min_rows);
+        let mut keys = self
+            .counts
+            .keys()
+            .filter(|key| estimate(**key) >= threshold)
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+/// Splits the partition into salted sub-partitions. The rows of the heavy keys
+/// are spread over all sub-partitions in a round-robin fashion, and the other
+/// rows stay in the first sub-partition.
+///
+/// # Arguments
+/// * `batches` - The record batches of the partition.
+/// * `key_columns` - The key columns of the hash partitioning.
+/// * `heavy_keys` - The hashes of the heavy keys.
+/// * `count` - The number of sub-partitions.
+pub fn salt_partition(
+    batches: &[RecordBatch],
+    key_columns: &[String],
+    heavy_keys: &HashSet<u64>,
+    count: usize,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let mut subs = vec![vec![]; count];
+    let mut next = 0;
+    for batch in batches {
+        let targets = key_hashes(batch, key_columns)?
+            .into_iter()
+            .map(|hash| {
+                if heavy_keys.contains(&hash) {
+                    next += 1;
+                    (next - 1) % count
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        for (i, sub) in subs.iter_mut().enumerate() {
+            let mask = targets
+                .iter()
+                .map(|t| Some(*t == i))
+                .collect::<BooleanArray>();
+            let rows = filter_record_batch(batch, &mask)?;
+            if rows.num_rows() > 0 {
+                sub.push(rows);
+            }
+        }
+    }
+    Ok(subs)
+}
+
+/// A version of the heavy keys of a partition.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SkewPlan {
+    /// The window timestamp from which the version takes effect.
+    pub effective:  i64,
+    /// The hashes of the heavy keys.
+    pub heavy_keys: Vec<u64>,
+}
+
+/// The versions of the heavy keys of a partition.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SkewPlans {
+    /// The versions ordered by their effective timestamps.
+    pub versions: Vec<SkewPlan>,
+}
+
+impl SkewPlans {
+    /// Returns the heavy keys of the window timestamp.
+    pub fn heavy_keys_at(&self, timestamp: i64) -> HashSet<u64> {
+        self.versions
+            .iter()
+            .rev()
+            .find(|v| v.effective <= timestamp)
+            .map(|v| v.heavy_keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Adds a version of the heavy keys, which takes effect after the grace
+    /// period. The heavy keys don't change more often than the cooldown.
+    ///
+    /// # Returns
+    /// True if the version is added.
+    pub fn update(&mut self, mut heavy_keys: Vec<u64>, now: i64, settings: &SkewSettings) -> bool {
+        heavy_keys.sort_unstable();
+        let latest = self.versions.last();
+        if latest.map_or(heavy_keys.is_empty(), |v| v.heavy_keys == heavy_keys) {
+            return false;
+        }
+        if let Some(latest) = latest {
+            if now - (latest.effective - settings.grace_period) < settings.cooldown {
+                return false;
+            }
+        }
+        self.versions.push(SkewPlan {
+            effective: now + settings.grace_period,
+            heavy_keys,
+        });
+        if self.versions.len() > MAX_VERSIONS {
+            self.versions.remove(0);
+        }
+        true
+    }
+
+    /// Loads the heavy keys of the partition from S3.
+    pub async fn load(group_name: &str, shuffle_id: usize) -> Result<Option<SkewPlans>> {
+        let key = plans_key(group_name, shuffle_id);
+        if !s3::get_matched_keys(&FLOCK_S3_BUCKET, &key)
+            .await?
+            .contains(&key)
+        {
+            return Ok(None);
+        }
+        let plans = serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?;
+        Ok(Some(plans))
+    }
+
+    /// Saves the heavy keys of the partition to S3.
+    pub async fn save(&self, group_name: &str, shuffle_id: usize) -> Result<()> {
+        s3::put_object(
+            &FLOCK_S3_BUCKET,
+            &plans_key(group_name, shuffle_id),
+            serde_json::to_vec(self)?,
+        )
+        .await
+    }
+}
+
+/// The heavy keys of all partitions of a function group.
+#[derive(Debug, Clone, Default)]
+pub struct SkewPlanCache {
+    /// The heavy keys of each partition, keyed by shuffle id.
+    pub partitions:   HashMap<usize, SkewPlans>,
+    /// The time of the last refresh.
+    pub refreshed_at: i64,
+}
+
+impl SkewPlanCache {
+    /// Returns the heavy keys of the partition in the window timestamp.
+    pub fn heavy_keys_at(&self, shuffle_id: usize, timestamp: i64) -> HashSet<u64> {
+        self.partitions
+            .get(&shuffle_id)
+            .map(|plans| plans.heavy_keys_at(timestamp))
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the heavy keys of the partitions of the function group, which are
+/// reloaded from S3 at the refresh interval. The cached ones are returned if
+/// they can't be reloaded.
+pub async fn skew_plans(group_name: &str) -> SkewPlanCache {
+    let settings = &FLOCK_CONFIG.skew;
+    let now = Utc::now().timestamp();
+    let cache = SKEW_PLANS
+        .lock()
+        .unwrap()
+        .get(group_name)
+        .cloned()
+        .unwrap_or_default();
+    if now - cache.refreshed_at < settings.refresh_interval {
+        return cache;
+    }
+
+    let cache = match load_skew_plans(group_name).await {
+        Ok(partitions) => SkewPlanCache {
+            partitions,
+            refreshed_at: now,
+        },
+        Err(e) => {
+            log::warn!("Failed to reload the heavy keys of {}: {}", group_name, e);
+            SkewPlanCache {
+                refreshed_at: now,
+                ..cache
+            }
+        }
+    };
+    SKEW_PLANS
+        .lock()
+        .unwrap()
+        .insert(group_name.to_owned(), cache.clone());
+    cache
+}
+
+async fn load_skew_plans(group_name: &str) -> Result<HashMap<usize, SkewPlans>> {
+    let prefix = format!("{}/{}/", SKEW_KEY_PREFIX, group_name);
+    let mut partitions = HashMap::new();
+    for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await? {
+        let shuffle_id = key[prefix.len()..]
+            .parse::<usize>()
+            .map_err(|e| FlockError::Internal(format!("Invalid skew key {}: {}", key, e)))?;
+        let plans = serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?;
+        partitions.insert(shuffle_id, plans);
+    }
+    Ok(partitions)
+}
+
+/// Samples the key frequencies of the partition aggregated by the current
+/// function, and publishes its heavy keys if they change. Only the function
+/// of the first sub-partition publishes the heavy keys, so each partition has
+/// a single writer.
+///
+/// # Arguments
+/// * `group_name` - The function group of the current function.
+/// * `shuffle_id` - The shuffle id of the partition.
+/// * `salt` - The salted sub-partition of the input, if it's split.
+/// * `batches` - The input of the current function.
+/// * `key_columns` - The key columns of the aggregation.
+pub async fn publish_heavy_keys(
+    group_name: &str,
+    shuffle_id: usize,
+    salt: Option<Salt>,
+    batches: &[RecordBatch],
+    key_columns: &[String],
+) -> Result<()> {
+    if salt.map_or(false, |s| s.index != 1) {
+        return Ok(());
+    }
+    let settings = &FLOCK_CONFIG.skew;
+    let now = Utc::now().timestamp();
+
+    let mut sampler = KeySampler::new(settings.sample_interval);
+    for batch in batches {
+        sampler.sample(batch, key_columns)?;
+    }
+
+    // The windows of the partition can be aggregated by different functions of
+    // the group, so the heavy keys are reloaded at the refresh interval.
+    let id = (group_name.to_owned(), shuffle_id);
+    let cached = OWN_PLANS.lock().unwrap().get(&id).cloned();
+    let (mut plans, loaded_at) = match cached {
+        Some((plans, loaded_at)) if now - loaded_at < settings.refresh_interval => {
+            (plans, loaded_at)
+        }
+        _ => (
+            SkewPlans::load(group_name, shuffle_id)
+                .await?
+                .unwrap_or_default(),
+            now,
+        ),
+    };
+
+    let current = plans.heavy_keys_at(now);
+    let split = salt.map(|s| (&current, s.count));
+    let heavy_keys = sampler.heavy_keys(settings.heavy_fraction, settings.min_rows, split);
+    if plans.update(heavy_keys, now, settings) {
+        log::info!(
+            "Heavy keys of {} partition {}: {:?}",
+            group_name,
+            shuffle_id,
+            plans.versions.last()
+        );
+        plans.save(group_name, shuffle_id).await?;
+    }
+    OWN_PLANS.lock().unwrap().insert(id, (plans, loaded_at));
+    Ok(())
+}
+
+/// Returns the key columns of the hash shuffle at the end of the plan.
+pub fn shuffle_key_columns(plans: &[Arc<dyn ExecutionPlan>]) -> Vec<String> {
+    for plan in plans {
+        let mut curr = plan.clone();
+        loop {
+            if let Some(repartition) = curr.as_any().downcast_ref::<RepartitionExec>() {
+                if let Partitioning::Hash(exprs, _) = repartition.partitioning() {
+                    return exprs
+                        .iter()
+                        .filter_map(|e| e.as_any().downcast_ref::<Column>())
+                        .map(|c| c.name().to_owned())
+                        .collect();
+                }
+            }
+            match curr.children().first() {
+                Some(child) => curr = child.clone(),
+                None => break,
+            }
+        }
+    }
+    vec![]
+}
+
+/// Returns the group aggregation of a stage whose output is merged by a
+/// combine stage. The planner only produces a stage of a final aggregation
+/// over its input when it inserts a combine stage after it.
+pub fn salted_aggregate(plans: &[Arc<dyn ExecutionPlan>]) -> Option<&HashAggregateExec> {
+    if plans.len() != 1 {
+        return None;
+    }
+    let aggregate = plans[0].as_any().downcast_ref::<HashAggregateExec>()?;
+    let is_final = matches!(
+        aggregate.mode(),
+        AggregateMode::Final | AggregateMode::FinalPartitioned
+    );
+    let over_input = aggregate
+        .children()
+        .iter()
+        .all(|c| c.as_any().downcast_ref::<MemoryExec>().is_some());
+    if is_final && over_input && !aggregate.group_expr().is_empty() {
+        Some(aggregate)
+    } else {
+        None
+    }
+}
+
+/// Returns the key columns of the group aggregation.
+pub fn aggregate_key_columns(aggregate: &HashAggregateExec) -> Vec<String> {
+    aggregate
+        .group_expr()
+        .iter()
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// Returns the plan of the combine stage, which merges the partial results of
+/// the group aggregation computed over the salted sub-partitions. It returns
+/// `None` if some aggregate functions can't be merged.
+///
+/// # Arguments
+/// * `aggregate` - The final group aggregation.
+/// * `input` - The input of the combine stage, whose schema is the output
+///   schema of the aggregation.
+pub fn combine_plan(
+    aggregate: &HashAggregateExec,
+    input: Arc<dyn ExecutionPlan>,
+) -> Result<Option<Arc<dyn ExecutionPlan>>> {
+    let schema = aggregate.schema();
+    let groups = aggregate.group_expr().len();
+    if groups == 0 {
+        return Ok(None);
+    }
+
+    let group_expr = (0..groups)
+        .map(|i| {
+            let name = schema.field(i).name();
+            (
+                Arc::new(Column::new(name, i)) as Arc<dyn PhysicalExpr>,
+                name.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut aggr_expr: Vec<Arc<dyn AggregateExpr>> = vec![];
+    for (i, expr) in aggregate.aggr_expr().iter().enumerate() {
+        let field = schema.field(groups + i);
+        let column = Arc::new(Column::new(field.name(), groups + i));
+        let name = field.name().clone();
+        let data_type = field.data_type().clone();
+        let any = expr.as_any();
+        if any.is::<Max>() {
+            aggr_expr.push(Arc::new(Max::new(column, name, data_type)));
+        } else if any.is::<Min>() {
+            aggr_expr.push(Arc::new(Min::new(column, name, data_type)));
+        } else if any.is::<Sum>() || any.is::<Count>() {
+            aggr_expr.push(Arc::new(Sum::new(column, name, data_type)));
+        } else {
+            return Ok(None);
+        }
+    }
+
+    let partial = Arc::new(HashAggregateExec::try_new(
+        AggregateMode::Partial,
+        group_expr.clone(),
+        aggr_expr.clone(),
+        input,
+        schema.clone(),
+    )?);
+    let final_group_expr = (0..groups)
+        .map(|i| {
+            let name = partial.schema().field(i).name().clone();
+            (
+                Arc::new(Column::new(&name, i)) as Arc<dyn PhysicalExpr>,
+                name,
+            )
+        })
+        .collect();
+    Ok(Some(Arc::new(HashAggregateExec::try_new(
+        AggregateMode::Final,
+        final_group_expr,
+        aggr_expr,
+        partial,
+        schema,
+    )?)))
+}
+
+fn plans_key(group_name: &str, shuffle_id: usize) -> String {
+    format!("{}/{}/{}", SKEW_KEY_PREFIX, group_name, shuffle_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_batches_sorted_eq;
+    use datafusion::arrow::array::{Int64Array, UInt64Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::datasource::MemTable;
+    use datafusion::execution::context::ExecutionContext;
+    use datafusion::physical_plan::collect;
+
+    fn settings() -> SkewSettings {
+        SkewSettings {
+            enabled:          true,
+            sample_interval:  1,
+            heavy_fraction:   0.25,
+            min_rows:         10,
+            salts:            4,
+            refresh_interval: 10,
+            grace_period:     30,
+            cooldown:         60,
+        }
+    }
+
+    /// Returns a batch where the key 7 has half of the rows.
+    fn skewed_batch() -> Result<RecordBatch> {
+        let keys = (0..1000)
+            .map(|i| if i % 2 == 0 { 7 } else { i })
+            .collect::<Vec<u64>>();
+        let values = (0..1000).collect::<Vec<i64>>();
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("k", DataType::UInt64, false),
+                Field::new("v", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(UInt64Array::from(keys)),
+                Arc::new(Int64Array::from(values)),
+            ],
+        )?)
+    }
+
+    #[tokio::test]
+    async fn heavy_key_detection() -> Result<()> {
+        let batch = skewed_batch()?;
+        let keys = vec!["k".to_string()];
+        let hot = key_hashes(&batch, &keys)?[0];
+
+        let mut sampler = KeySampler::new(5);
+        sampler.sample(&batch.slice(0, 501), &keys)?;
+        sampler.sample(&batch.slice(501, 499), &keys)?;
+        assert_eq!(1000, sampler.rows);
+        assert_eq!(500, sampler.estimate(hot));
+        assert_eq!(vec![hot], sampler.heavy_keys(0.25, 10, None));
+        assert!(sampler.heavy_keys(0.25, 1000, None).is_empty());
+
+        // A quarter of the heavy key's rows in a salted sub-partition.
+        let mut sampler = KeySampler::new(1);
+        let subs = salt_partition(&[batch], &keys, &[hot].into_iter().collect(), 4)?;
+        subs[0].iter().try_for_each(|b| sampler.sample(b, &keys))?;
+        let current = [hot].into_iter().collect::<HashSet<_>>();
+        assert_eq!(vec![hot], sampler.heavy_keys(0.25, 10, Some((&current, 4))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn salted_sub_partitions() -> Result<()> {
+        let batch = skewed_batch()?;
+        let keys = vec!["k".to_string()];
+        let hot = key_hashes(&batch, &keys)?[0];
+
+        let subs = salt_partition(&[batch], &keys, &[hot].into_iter().collect(), 4)?;
+        let rows = subs
+            .iter()
+            .map(|sub| sub.iter().map(|b| b.num_rows()).sum::<usize>())
+            .collect::<Vec<_>>();
+        // The other 500 keys stay in the first sub-partition.
+        assert_eq!(vec![625, 125, 125, 125], rows);
+        for sub in &subs[1..] {
+            for batch in sub {
+                assert!(key_hashes(batch, &keys)?.iter().all(|h| *h == hot));
+            }
+        }
+
+        // 16 partitions with 4 sub-partitions each.
+        let ids = (1..=16)
+            .flat_map(|p| {
+                (1..=4).map(move |i| {
+                    salted_shuffle_id(
+                        16,
+                        &Salt {
+                            partition: p,
+                            index:     i,
+                            count:     4,
+                        },
+                    )
+                })
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(64, ids.len());
+        assert_eq!((1..=64).collect::<HashSet<_>>(), ids);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn versioned_heavy_keys() -> Result<()> {
+        let settings = settings();
+        let mut plans = SkewPlans::default();
+
+        assert!(!plans.update(vec![], 1000, &settings));
+        assert!(plans.update(vec![2, 1], 1000, &settings));
+        assert!(plans.heavy_keys_at(1029).is_empty());
+        assert_eq!(
+            [1, 2].into_iter().collect::<HashSet<_>>(),
+            plans.heavy_keys_at(1030)
+        );
+
+        // Within the cooldown.
+        assert!(!plans.update(vec![3], 1059, &settings));
+        assert!(!plans.update(vec![1, 2], 2000, &settings));
+        assert!(plans.update(vec![3], 2000, &settings));
+        assert_eq!(
+            [1, 2].into_iter().collect::<HashSet<_>>(),
+            plans.heavy_keys_at(2029)
+        );
+        assert_eq!(
+            [3].into_iter().collect::<HashSet<_>>(),
+            plans.heavy_keys_at(2030)
+        );
+
+        Ok(())
+    }
+
+    /// Returns the final aggregation of the physical plan.
+    fn final_aggregate(plan: Arc<dyn ExecutionPlan>) -> Arc<dyn ExecutionPlan> {
+        let mut curr = plan;
+        loop {
+            if let Some(aggregate) = curr.as_any().downcast_ref::<HashAggregateExec>() {
+                if *aggregate.mode() != AggregateMode::Partial {
+                    return curr;
+                }
+            }
+            curr = curr.children()[0].clone();
+        }
+    }
+
+    #[tokio::test]
+    async fn combine_salted_aggregates() -> Result<()> {
+        let batch = skewed_batch()?;
+        let sql = "SELECT k, MAX(v), MIN(v), SUM(v), COUNT(v) FROM t GROUP BY k";
+
+        let aggregate = |batches: Vec<RecordBatch>| async move {
+            let mut ctx = ExecutionContext::new();
+            let table = MemTable::try_new(batches[0].schema(), vec![batches])?;
+            ctx.register_table("t", Arc::new(table))?;
+            let plan = crate::runtime::plan::physical_plan(&ctx, sql).await?;
+            let plan = final_aggregate(plan);
+            let output = collect(plan.clone()).await?;
+            Ok::<_, FlockError>((plan, output))
+        };
+
+        let (plan, expected) = aggregate(vec![batch.clone()]).await?;
+
+        // Aggregates the salted sub-partitions separately.
+        let keys = vec!["k".to_string()];
+        let hot = key_hashes(&batch, &keys)?[0];
+        let mut partials = vec![];
+        for sub in salt_partition(&[batch], &keys, &[hot].into_iter().collect(), 4)? {
+            partials.extend(aggregate(sub).await?.1);
+        }
+
+        let aggregate = plan.as_any().downcast_ref::<HashAggregateExec>().unwrap();
+        let input = Arc::new(MemoryExec::try_new(&[partials], plan.schema(), None)?);
+        let output = collect(combine_plan(aggregate, input)?.unwrap()).await?;
+
+        let expected = datafusion::arrow::util::pretty::pretty_format_batches(&expected)?;
+        let expected = expected.trim().lines().collect::<Vec<_>>();
+        assert_batches_sorted_eq!(expected, &output);
+
+        Ok(())
+    }
+}
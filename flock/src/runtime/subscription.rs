@@ -0,0 +1,380 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Subscriptions let several queries share the same data source, so that the
+//! events are ingested and decoded once, and fanned out to the first function
+//! of each subscribing query.
+//!
+//! A [`Subscription`] is stored in S3 under `subscriptions/<source>/<query
+//! code>`, and the source reloads the subscriptions at most once per refresh
+//! interval of the function groups (`group.refresh_interval`). A subscriber is
+//! added or removed by saving or deleting its subscription, without
+//! redeploying the source or the other subscribers.
+//!
+//! Each subscription records the columns that the query reads from each
+//! relation of the source, keyed by the fingerprint of the relation's schema,
+//! and the source only sends these columns to the subscriber. The leaf nodes
+//! of the subscriber's first stage are replaced with the pruned schemas, see
+//! [`Subscription::prune_plan`]. A relation that the query doesn't read isn't
+//! sent at all, and a relation read by several leaf nodes (e.g., a self-join)
+//! is sent with all its columns.
+
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::CloudFunction;
+use crate::runtime::schema::{fingerprint, SchemaFingerprint};
+use chrono::Utc;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::ExecutionPlan;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// The key prefix of the subscriptions in S3.
+pub const SUBSCRIPTION_KEY_PREFIX: &str = "subscriptions";
+
+lazy_static! {
+    /// The subscriptions of each source loaded by the function, and the time
+    /// when they're loaded.
+    static ref SUBSCRIPTIONS: Mutex<HashMap<String, (i64, Vec<Subscription>)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The subscription of a query to a shared data source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subscription {
+    /// The name of the data source function.
+    pub source:        String,
+    /// The query code of the subscriber.
+    pub query_code:    String,
+    /// The first function (or function group) of the subscriber.
+    pub next:          CloudFunction,
+    /// The columns read by the subscriber from each relation of the source,
+    /// keyed by the fingerprint of the relation's schema. The relations that
+    /// aren't listed are pruned.
+    pub projections:   BTreeMap<SchemaFingerprint, Vec<String>>,
+    /// The time when the query subscribed, in milliseconds.
+    pub subscribed_at: i64,
+}
+
+impl Subscription {
+    /// Creates the subscription of a query to the source.
+    ///
+    /// # Arguments
+    /// * `source` - The name of the data source function.
+    /// * `query_code` - The query code of the subscriber.
+    /// * `next` - The first function (or function group) of the subscriber.
+    /// * `relations` - The schemas of the source's relations.
+    /// * `plan` - The plan of the subscriber's first stage.
+    pub fn new(
+        source: &str,
+        query_code: &str,
+        next: CloudFunction,
+        relations: &[SchemaRef],
+        plan: &Arc<dyn ExecutionPlan>,
+    ) -> Self {
+        let mut leaves = vec![];
+        collect_leaves(plan, &mut leaves);
+
+        let projections = relations
+            .iter()
+            .filter_map(|relation| {
+                let readers = leaves
+                    .iter()
+                    .filter(|leaf| reads(leaf, relation))
+                    .collect::<Vec<_>>();
+                let columns = match readers.as_slice() {
+                    [] => return None,
+                    [leaf] => leaf.fields().iter().map(|f| f.name().clone()).collect(),
+                    _ => relation.fields().iter().map(|f| f.name().clone()).collect(),
+                };
+                Some((fingerprint(relation), columns))
+            })
+            .collect();
+
+        Subscription {
+            source: source.to_owned(),
+            query_code: query_code.to_owned(),
+            next,
+            projections,
+            subscribed_at: Utc::now().timestamp_millis(),
+        }
+    }
+
+    /// Replaces the leaf nodes of the subscriber's first stage that read a
+    /// pruned relation with empty `MemoryExec`s of the pruned schema, so that
+    /// they accept the pruned record batches as they are.
+    pub fn prune_plan(&self, plan: &Arc<dyn ExecutionPlan>) -> Result<Arc<dyn ExecutionPlan>> {
+        let children = plan.children();
+        if children.is_empty() {
+            let schema = plan.schema();
+            let pruned = self
+                .projections
+                .values()
+                .any(|columns| same_columns(&schema, columns));
+            return Ok(if pruned && plan.as_any().is::<MemoryExec>() {
+                Arc::new(MemoryExec::try_new(&[], schema, None)?)
+            } else {
+                plan.clone()
+            });
+        }
+
+        let children = children
+            .iter()
+            .map(|child| self.prune_plan(child))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(plan.with_new_children(children)?)
+    }
+
+    /// Returns the columns of the relation read by the subscriber, or None if
+    /// the relation is pruned.
+    pub fn columns(&self, relation: &SchemaRef) -> Option<&Vec<String>> {
+        self.projections.get(&fingerprint(relation))
+    }
+
+    /// Projects the partitions of a relation to the columns read by the
+    /// subscriber.
+    ///
+    /// # Returns
+    /// None if the relation is pruned.
+    pub fn project(
+        &self,
+        partitions: &[Vec<RecordBatch>],
+    ) -> Result<Option<Vec<Vec<RecordBatch>>>> {
+        let relation = match partitions.iter().flatten().next() {
+            Some(batch) => batch.schema(),
+            None => return Ok(Some(partitions.to_vec())),
+        };
+        let columns = match self.columns(&relation) {
+            Some(columns) => columns,
+            None => return Ok(None),
+        };
+        if columns.len() == relation.fields().len() {
+            return Ok(Some(partitions.to_vec()));
+        }
+
+        let indices = columns
+            .iter()
+            .map(|name| relation.index_of(name))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let schema = Arc::new(Schema::new(
+            indices.iter().map(|i| relation.field(*i).clone()).collect(),
+        ));
+        partitions
+            .iter()
+            .map(|batches| {
+                batches
+                    .iter()
+                    .map(|batch| {
+                        RecordBatch::try_new(
+                            schema.clone(),
+                            indices.iter().map(|i| batch.column(*i).clone()).collect(),
+                        )
+                        .map_err(FlockError::Arrow)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Saves the subscription to S3. The source sends the windows to the
+    /// subscriber after its next refresh.
+    pub async fn subscribe(&self) -> Result<()> {
+        s3::put_object(
+            &FLOCK_S3_BUCKET,
+            &subscription_key(&self.source, &self.query_code),
+            serde_json::to_vec(self)?,
+        )
+        .await
+    }
+
+    /// Deletes the subscription of the query to the source.
+    pub async fn unsubscribe(source: &str, query_code: &str) -> Result<()> {
+        s3::delete_object(&FLOCK_S3_BUCKET, &subscription_key(source, query_code)).await
+    }
+
+    /// Deletes the subscriptions of the query to all sources.
+    ///
+    /// # Returns
+    /// The number of deleted subscriptions.
+    pub async fn unsubscribe_all(query_code: &str) -> Result<usize> {
+        let suffix = format!("/{}", query_code);
+        let keys = s3::get_matched_keys(&FLOCK_S3_BUCKET, SUBSCRIPTION_KEY_PREFIX)
+            .await?
+            .into_iter()
+            .filter(|key| key.ends_with(&suffix))
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            s3::delete_object(&FLOCK_S3_BUCKET, key).await?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Loads the subscriptions to the source from S3.
+    pub async fn list(source: &str) -> Result<Vec<Subscription>> {
+        let prefix = format!("{}/{}/", SUBSCRIPTION_KEY_PREFIX, source);
+        let mut subscriptions = vec![];
+        for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await? {
+            subscriptions.push(serde_json::from_slice(
+                &s3::get_object(&FLOCK_S3_BUCKET, &key).await?,
+            )?);
+        }
+        Ok(subscriptions)
+    }
+}
+
+/// Returns the subscriptions to the source, reloaded if they're older than
+/// the refresh interval of the function groups.
+pub async fn subscribers(source: &str) -> Result<Vec<Subscription>> {
+    let now = Utc::now().timestamp();
+    if let Some((loaded_at, subscriptions)) = SUBSCRIPTIONS.lock().unwrap().get(source) {
+        if now - loaded_at < FLOCK_CONFIG.group.refresh_interval {
+            return Ok(subscriptions.clone());
+        }
+    }
+    let subscriptions = Subscription::list(source).await?;
+    SUBSCRIPTIONS
+        .lock()
+        .unwrap()
+        .insert(source.to_owned(), (now, subscriptions.clone()));
+    Ok(subscriptions)
+}
+
+/// Collects the schemas of the leaf nodes of the plan.
+fn collect_leaves(plan: &Arc<dyn ExecutionPlan>, leaves: &mut Vec<SchemaRef>) {
+    let children = plan.children();
+    if children.is_empty() {
+        leaves.push(plan.schema());
+    }
+    children
+        .iter()
+        .for_each(|child| collect_leaves(child, leaves));
+}
+
+/// Returns true if all columns of the leaf node are in the relation.
+fn reads(leaf: &SchemaRef, relation: &SchemaRef) -> bool {
+    let columns = relation
+        .fields()
+        .iter()
+        .map(|f| f.name())
+        .collect::<HashSet<_>>();
+    !leaf.fields().is_empty() && leaf.fields().iter().all(|f| columns.contains(f.name()))
+}
+
+/// Returns true if the schema has exactly the given columns in order.
+fn same_columns(schema: &SchemaRef, columns: &[String]) -> bool {
+    schema.fields().len() == columns.len()
+        && schema
+            .fields()
+            .iter()
+            .zip(columns.iter())
+            .all(|(f, c)| f.name() == c)
+}
+
+fn subscription_key(source: &str, query_code: &str) -> String {
+    format!("{}/{}/{}", SUBSCRIPTION_KEY_PREFIX, source, query_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::context::ExecutionContext;
+    use crate::runtime::plan::CloudExecutionPlan;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use datafusion::physical_plan::expressions::Column;
+    use datafusion::physical_plan::projection::ProjectionExec;
+
+    #[tokio::test]
+    async fn prune_relations_per_subscriber() -> Result<()> {
+        let bid = Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int64, false),
+            Field::new("bidder", DataType::Int64, false),
+            Field::new("channel", DataType::Utf8, false),
+        ]));
+        let person = Arc::new(Schema::new(vec![
+            Field::new("p_id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let leaf = Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int64, false),
+            Field::new("channel", DataType::Utf8, false),
+        ]));
+
+        // The subscriber only reads two columns of the bids.
+        let scan = Arc::new(MemoryExec::try_new(&[], bid.clone(), Some(vec![0, 2]))?);
+        assert_eq!(leaf, scan.schema());
+        let plan: Arc<dyn ExecutionPlan> = Arc::new(ProjectionExec::try_new(
+            vec![(Arc::new(Column::new("auction", 0)), "auction".to_owned())],
+            scan,
+        )?);
+        let subscription = Subscription::new(
+            "flock_datasource",
+            "SX72HzqFz1Qij4bP",
+            CloudFunction::Lambda("SX72HzqFz1Qij4bP-00".to_owned()),
+            &[bid.clone(), person.clone()],
+            &plan,
+        );
+        assert_eq!(
+            Some(&vec!["auction".to_owned(), "channel".to_owned()]),
+            subscription.columns(&bid)
+        );
+        assert_eq!(None, subscription.columns(&person));
+
+        // The bids are pruned to the columns of the leaf node.
+        let batch = RecordBatch::try_new(
+            bid,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2])),
+                Arc::new(Int64Array::from(vec![3, 4])),
+                Arc::new(StringArray::from(vec!["a", "b"])),
+            ],
+        )?;
+        let pruned = subscription.project(&[vec![batch]])?.unwrap();
+        assert_eq!(leaf, pruned[0][0].schema());
+        assert_eq!(2, pruned[0][0].num_rows());
+
+        // The persons aren't sent at all.
+        let batch = RecordBatch::try_new(
+            person,
+            vec![
+                Arc::new(Int64Array::from(vec![1])),
+                Arc::new(StringArray::from(vec!["x"])),
+            ],
+        )?;
+        assert!(subscription.project(&[vec![batch]])?.is_none());
+
+        // The leaf node of the first stage accepts the pruned batches.
+        let plan = subscription.prune_plan(&plan)?;
+        let scan = plan.children()[0].clone();
+        assert_eq!(leaf, scan.schema());
+        let mut ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![plan], None),
+            ..Default::default()
+        };
+        ctx.feed_data_sources(vec![("bid".to_owned(), pruned)])
+            .await?;
+        let output = ctx.execute().await?;
+        assert_eq!(
+            2,
+            output.iter().flatten().map(|b| b.num_rows()).sum::<usize>()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! HyperLogLog estimates the number of distinct values with a fixed number of
+//! registers, and two sketches are merged by taking the maximum of each
+//! register.
+
+use super::{hash_of, ByteReader};
+use crate::error::{FlockError, Result};
+use std::hash::Hash;
+
+/// A HyperLogLog sketch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_PRECISION)
+    }
+}
+
+impl HyperLogLog {
+    /// The default precision, i.e., 4096 registers and a standard error of
+    /// about 1.6%.
+    pub const DEFAULT_PRECISION: u8 = 12;
+
+    /// Creates an empty sketch with `2^precision` registers. The precision is
+    /// between 4 and 16.
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(4, 16);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Adds a value to the sketch.
+    pub fn add<T: Hash + ?Sized>(&mut self, value: &T) {
+        self.add_hash(hash_of(value));
+    }
+
+    /// Adds the hash of a value to the sketch.
+    pub fn add_hash(&mut self, hash: u64) {
+        let p = self.precision as u32;
+        let index = (hash >> (64 - p)) as usize;
+        // The sentinel bit caps the rank when the remaining bits are all zeros.
+        let rank = ((hash << p) | (1 << (p - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Merges another sketch of the same precision into this one.
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<()> {
+        if self.precision != other.precision {
+            return Err(FlockError::Execution(format!(
+                "Can't merge HyperLogLog sketches of precisions {} and {}.",
+                self.precision, other.precision
+            )));
+        }
+        self.registers
+            .iter_mut()
+            .zip(other.registers.iter())
+            .for_each(|(a, b)| *a = (*a).max(*b));
+        Ok(())
+    }
+
+    /// Returns the estimated number of distinct values.
+    pub fn count(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-(*r as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+
+        // Small range correction with linear counting.
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// Encodes the sketch.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.registers.len() + 1);
+        bytes.push(self.precision);
+        bytes.extend_from_slice(&self.registers);
+        bytes
+    }
+
+    /// Decodes the sketch.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+        let precision = reader.u8()?;
+        if !(4..=16).contains(&precision) {
+            return Err(FlockError::Execution(format!(
+                "Invalid HyperLogLog precision {}.",
+                precision
+            )));
+        }
+        let registers = reader.bytes(1 << precision)?.to_vec();
+        Ok(HyperLogLog {
+            precision,
+            registers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: u64, actual: u64) {
+        let error = (actual as f64 - expected as f64).abs() / expected as f64;
+        assert!(error < 0.05, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn count_distinct() -> Result<()> {
+        let mut hll = HyperLogLog::default();
+        (0..100_000).for_each(|i| hll.add(&(i % 50_000)));
+        assert_close(50_000, hll.count());
+
+        let mut small = HyperLogLog::default();
+        (0..100).for_each(|i| small.add(&i));
+        assert_close(100, small.count());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_partial_sketches() -> Result<()> {
+        let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+        (0..60_000).for_each(|i| a.add(&i));
+        (40_000..100_000).for_each(|i| b.add(&i));
+
+        let mut merged = HyperLogLog::from_bytes(&a.to_bytes())?;
+        merged.merge(&HyperLogLog::from_bytes(&b.to_bytes())?)?;
+        assert_close(100_000, merged.count());
+
+        assert!(merged.merge(&HyperLogLog::new(10)).is_err());
+        Ok(())
+    }
+}
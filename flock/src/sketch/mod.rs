@@ -0,0 +1,410 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Mergeable approximate aggregates. `APPROX_COUNT_DISTINCT(x)` is backed by
+//! HyperLogLog, `APPROX_PERCENTILE(x, p)` by a t-digest, and `APPROX_TOP_K(x,
+//! k)` by a count-min sketch. Their partial states are encoded as a single
+//! binary column, so the partial aggregation of a stage is sent to the next
+//! stage in the payload like any other record batch, and merged there by the
+//! final aggregation.
+
+mod hll;
+mod tdigest;
+mod topk;
+
+pub use hll::HyperLogLog;
+pub use tdigest::TDigest;
+pub use topk::TopK;
+
+use crate::error::{FlockError, Result};
+use crate::runtime::schema::FnvHasher;
+use datafusion::arrow::array::{Array, ArrayRef, BinaryArray, Float64Array};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::DataType;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::error::{DataFusionError, Result as DataFusionResult};
+use datafusion::execution::context::ExecutionContext;
+use datafusion::physical_plan::aggregates::{AccumulatorFunctionImplementation, StateTypeFunction};
+use datafusion::physical_plan::functions::{ReturnTypeFunction, Signature, Volatility};
+use datafusion::physical_plan::udaf::AggregateUDF;
+use datafusion::physical_plan::Accumulator;
+use datafusion::scalar::ScalarValue;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// Returns the FNV-1a hash of the value. The hash doesn't depend on the
+/// process or the architecture, so the sketches built by different functions
+/// of a query hash the same value to the same hash and can be merged.
+pub(crate) fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = FnvHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads the little-endian fields of an encoded sketch.
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        ByteReader { bytes }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(FlockError::Execution(
+                "The encoded sketch is truncated.".to_string(),
+            ));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/// The partial state of an approximate aggregate.
+trait Sketch: Debug + Default + Send + Sync {
+    /// Adds the values of the arguments.
+    fn update(&mut self, values: &[ArrayRef]) -> Result<()>;
+    /// Merges an encoded partial state.
+    fn merge(&mut self, bytes: &[u8]) -> Result<()>;
+    /// Encodes the partial state.
+    fn to_bytes(&self) -> Vec<u8>;
+    /// Returns the result of the aggregate.
+    fn evaluate(&self) -> ScalarValue;
+}
+
+/// Returns the first non-null value of a constant argument as `Float64`, e.g.,
+/// the percentile of `APPROX_PERCENTILE`.
+fn constant_arg(values: &[ArrayRef], i: usize) -> Result<Option<f64>> {
+    let array = values.get(i).ok_or_else(|| {
+        FlockError::Execution(format!("The aggregate requires {} arguments.", i + 1))
+    })?;
+    let array = cast(array, &DataType::Float64)?;
+    let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+    Ok(array.iter().flatten().next())
+}
+
+/// The HyperLogLog state of `APPROX_COUNT_DISTINCT`.
+#[derive(Debug, Default)]
+struct CountDistinctSketch(HyperLogLog);
+
+impl Sketch for CountDistinctSketch {
+    fn update(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let array = &values[0];
+        for row in 0..array.len() {
+            if array.is_valid(row) {
+                self.0.add(&array_value_to_string(array, row)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, bytes: &[u8]) -> Result<()> {
+        self.0.merge(&HyperLogLog::from_bytes(bytes)?)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    fn evaluate(&self) -> ScalarValue {
+        ScalarValue::UInt64(Some(self.0.count()))
+    }
+}
+
+/// The t-digest state of `APPROX_PERCENTILE`, which carries the percentile to
+/// the final aggregation.
+#[derive(Debug, Default)]
+struct PercentileSketch {
+    digest:     TDigest,
+    percentile: Option<f64>,
+}
+
+impl Sketch for PercentileSketch {
+    fn update(&mut self, values: &[ArrayRef]) -> Result<()> {
+        if self.percentile.is_none() {
+            self.percentile = constant_arg(values, 1)?;
+            if let Some(p) = self.percentile {
+                if !(0.0..=1.0).contains(&p) {
+                    return Err(FlockError::Execution(format!(
+                        "The percentile {} isn't between 0 and 1.",
+                        p
+                    )));
+                }
+            }
+        }
+        let array = cast(&values[0], &DataType::Float64)?;
+        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+        array.iter().flatten().for_each(|v| self.digest.add(v));
+        Ok(())
+    }
+
+    fn merge(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.u8()? == 1 {
+            self.percentile = Some(reader.f64()?);
+        }
+        self.digest.merge(&TDigest::from_bytes(reader.bytes)?);
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = match self.percentile {
+            Some(p) => [vec![1], p.to_le_bytes().to_vec()].concat(),
+            None => vec![0],
+        };
+        bytes.extend(self.digest.to_bytes());
+        bytes
+    }
+
+    fn evaluate(&self) -> ScalarValue {
+        ScalarValue::Float64(self.percentile.and_then(|p| self.digest.quantile(p)))
+    }
+}
+
+/// The count-min state of `APPROX_TOP_K`. The sketch is created when the `k`
+/// argument is known.
+#[derive(Debug, Default)]
+struct TopKSketch(Option<TopK>);
+
+impl Sketch for TopKSketch {
+    fn update(&mut self, values: &[ArrayRef]) -> Result<()> {
+        if self.0.is_none() {
+            match constant_arg(values, 1)? {
+                Some(k) if k >= 1.0 => self.0 = Some(TopK::new(k as usize)),
+                Some(k) => {
+                    return Err(FlockError::Execution(format!(
+                        "The k {} of APPROX_TOP_K isn't positive.",
+                        k
+                    )))
+                }
+                None => return Ok(()),
+            }
+        }
+        let topk = self.0.as_mut().unwrap();
+        let array = &values[0];
+        for row in 0..array.len() {
+            if array.is_valid(row) {
+                topk.add(&array_value_to_string(array, row)?, 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, bytes: &[u8]) -> Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let other = TopK::from_bytes(bytes)?;
+        match self.0.as_mut() {
+            Some(topk) => topk.merge(&other),
+            None => {
+                self.0 = Some(other);
+                Ok(())
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .as_ref()
+            .map(|topk| topk.to_bytes())
+            .unwrap_or_default()
+    }
+
+    fn evaluate(&self) -> ScalarValue {
+        let top = self.0.as_ref().map(|topk| topk.top()).unwrap_or_default();
+        ScalarValue::Utf8(serde_json::to_string(&top).ok())
+    }
+}
+
+/// The accumulator of an approximate aggregate, whose state is the encoded
+/// sketch.
+#[derive(Debug, Default)]
+struct SketchAccumulator<S: Sketch> {
+    sketch: S,
+}
+
+fn execution_error(e: FlockError) -> DataFusionError {
+    DataFusionError::Execution(e.to_string())
+}
+
+impl<S: Sketch> Accumulator for SketchAccumulator<S> {
+    fn state(&self) -> DataFusionResult<Vec<ScalarValue>> {
+        Ok(vec![ScalarValue::Binary(Some(self.sketch.to_bytes()))])
+    }
+
+    fn update(&mut self, values: &[ScalarValue]) -> DataFusionResult<()> {
+        let values = values.iter().map(|v| v.to_array()).collect::<Vec<_>>();
+        self.update_batch(&values)
+    }
+
+    fn update_batch(&mut self, values: &[ArrayRef]) -> DataFusionResult<()> {
+        self.sketch.update(values).map_err(execution_error)
+    }
+
+    fn merge(&mut self, states: &[ScalarValue]) -> DataFusionResult<()> {
+        match &states[0] {
+            ScalarValue::Binary(Some(bytes)) => self.sketch.merge(bytes).map_err(execution_error),
+            ScalarValue::Binary(None) => Ok(()),
+            state => Err(DataFusionError::Internal(format!(
+                "Unexpected sketch state {:?}",
+                state
+            ))),
+        }
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> DataFusionResult<()> {
+        let states = states[0]
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .ok_or_else(|| DataFusionError::Internal("Sketch states must be binary".to_string()))?;
+        for bytes in states.iter().flatten() {
+            self.sketch.merge(bytes).map_err(execution_error)?;
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> DataFusionResult<ScalarValue> {
+        Ok(self.sketch.evaluate())
+    }
+}
+
+/// Creates an approximate aggregate of any argument types.
+fn sketch_udaf<S: Sketch + 'static>(
+    name: &str,
+    args: usize,
+    return_type: DataType,
+) -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(move |_| Ok(Arc::new(return_type.clone())));
+    let accumulator: AccumulatorFunctionImplementation =
+        Arc::new(|| Ok(Box::new(SketchAccumulator::<S>::default())));
+    let state_type: StateTypeFunction = Arc::new(|_| Ok(Arc::new(vec![DataType::Binary])));
+    AggregateUDF::new(
+        name,
+        &Signature::any(args, Volatility::Immutable),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// `APPROX_COUNT_DISTINCT(x)` returns the approximate number of distinct
+/// values of `x` as `UInt64`.
+pub fn approx_count_distinct() -> AggregateUDF {
+    sketch_udaf::<CountDistinctSketch>("approx_count_distinct", 1, DataType::UInt64)
+}
+
+/// `APPROX_PERCENTILE(x, p)` returns the approximate value of `x` at the
+/// percentile `p` in [0, 1] as `Float64`.
+pub fn approx_percentile() -> AggregateUDF {
+    sketch_udaf::<PercentileSketch>("approx_percentile", 2, DataType::Float64)
+}
+
+/// `APPROX_TOP_K(x, k)` returns the `k` most frequent values of `x` with their
+/// approximate frequencies, as a JSON array of `[value, count]` pairs.
+pub fn approx_top_k() -> AggregateUDF {
+    sketch_udaf::<TopKSketch>("approx_top_k", 2, DataType::Utf8)
+}
+
+/// Registers the approximate aggregates in the execution context.
+pub fn register_sketches(ctx: &mut ExecutionContext) {
+    ctx.register_udaf(approx_count_distinct());
+    ctx.register_udaf(approx_percentile());
+    ctx.register_udaf(approx_top_k());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Int64Array, StringArray, UInt64Array};
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::datasource::MemTable;
+    use datafusion::execution::context::ExecutionConfig;
+
+    #[tokio::test]
+    async fn aggregate_partitions() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Utf8, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        // Four partitions are aggregated partially, and then merged.
+        let partitions = (0..4)
+            .map(|p| {
+                let values = (p * 2500..(p + 1) * 2500).collect::<Vec<i64>>();
+                let keys = values
+                    .iter()
+                    .map(|v| if v % 2 == 0 { "hot" } else { "cold" })
+                    .collect::<Vec<_>>();
+                Ok(vec![RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(StringArray::from(keys)),
+                        Arc::new(Int64Array::from(values)),
+                    ],
+                )?])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut ctx =
+            ExecutionContext::with_config(ExecutionConfig::new().with_target_partitions(4));
+        register_sketches(&mut ctx);
+        ctx.register_table("t", Arc::new(MemTable::try_new(schema, partitions)?))?;
+        let sql = "SELECT k, approx_count_distinct(v), approx_percentile(v, 0.5), \
+                   approx_top_k(k, 1) FROM t GROUP BY k ORDER BY k";
+        let batches = ctx.sql(sql).await?.collect().await?;
+        let batch = &batches[0];
+
+        let distinct = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        let median = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        let top = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        for (row, key) in ["cold", "hot"].iter().enumerate() {
+            assert!((distinct.value(row) as i64 - 5000).abs() < 250);
+            assert!((median.value(row) - 5000.0).abs() < 100.0);
+            assert_eq!(format!("[[\"{}\",5000]]", key), top.value(row));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The t-digest estimates the quantiles of a distribution with a bounded number
+//! of centroids, which are small near the tails and large near the median.
+//! Two digests are merged by compressing their centroids together.
+
+use super::ByteReader;
+use crate::error::Result;
+
+/// A centroid of the t-digest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean:   f64,
+    weight: f64,
+}
+
+/// A t-digest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TDigest {
+    compression: f64,
+    centroids:   Vec<Centroid>,
+    buffer:      Vec<f64>,
+    min:         f64,
+    max:         f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_COMPRESSION)
+    }
+}
+
+impl TDigest {
+    /// The default compression, which keeps a few hundred centroids.
+    pub const DEFAULT_COMPRESSION: f64 = 100.0;
+
+    /// Creates an empty digest.
+    pub fn new(compression: f64) -> Self {
+        TDigest {
+            compression,
+            centroids: vec![],
+            buffer: vec![],
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Returns the number of values in the digest.
+    pub fn count(&self) -> f64 {
+        self.centroids.iter().map(|c| c.weight).sum::<f64>() + self.buffer.len() as f64
+    }
+
+    /// Adds a value to the digest. NaNs are ignored.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= 10 * self.compression as usize {
+            self.centroids = self.compressed();
+            self.buffer.clear();
+        }
+    }
+
+    /// Merges another digest into this one.
+    pub fn merge(&mut self, other: &TDigest) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.centroids.extend(other.compressed());
+        self.centroids = self.compressed();
+        self.buffer.clear();
+    }
+
+    /// Returns the centroids and the buffered values compressed together.
+    fn compressed(&self) -> Vec<Centroid> {
+        let mut items = self.centroids.clone();
+        items.extend(self.buffer.iter().map(|v| Centroid {
+            mean:   *v,
+            weight: 1.0,
+        }));
+        if items.len() <= 1 {
+            return items;
+        }
+        items.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let total = items.iter().map(|c| c.weight).sum::<f64>();
+        let mut merged = Vec::with_capacity(items.len());
+        let mut current = items[0];
+        let mut before = 0.0;
+        for next in items.into_iter().skip(1) {
+            let weight = current.weight + next.weight;
+            let q = (before + weight / 2.0) / total;
+            if weight <= 4.0 * total * q * (1.0 - q) / self.compression {
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                before += current.weight;
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        merged
+    }
+
+    /// Returns the estimated value at the quantile `q` in [0, 1], or `None` if
+    /// the digest is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let centroids = self.compressed();
+        if centroids.is_empty() {
+            return None;
+        }
+        if centroids.len() == 1 {
+            return Some(centroids[0].mean);
+        }
+
+        let total = centroids.iter().map(|c| c.weight).sum::<f64>();
+        let target = q.clamp(0.0, 1.0) * total;
+        // Each centroid is centered at the middle of its cumulative weight.
+        let first = &centroids[0];
+        if target <= first.weight / 2.0 {
+            return Some(self.min + (first.mean - self.min) * target / (first.weight / 2.0));
+        }
+        let mut before = 0.0;
+        for pair in centroids.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            let (center_a, center_b) =
+                (before + a.weight / 2.0, before + a.weight + b.weight / 2.0);
+            if target <= center_b {
+                let t = (target - center_a) / (center_b - center_a);
+                return Some(a.mean + (b.mean - a.mean) * t);
+            }
+            before += a.weight;
+        }
+        let last = centroids.last().unwrap();
+        let t = (target - (total - last.weight / 2.0)) / (last.weight / 2.0);
+        Some(last.mean + (self.max - last.mean) * t.min(1.0))
+    }
+
+    /// Encodes the digest.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let centroids = self.compressed();
+        let mut bytes = Vec::with_capacity(28 + centroids.len() * 16);
+        bytes.extend_from_slice(&self.compression.to_le_bytes());
+        bytes.extend_from_slice(&self.min.to_le_bytes());
+        bytes.extend_from_slice(&self.max.to_le_bytes());
+        bytes.extend_from_slice(&(centroids.len() as u32).to_le_bytes());
+        for c in centroids {
+            bytes.extend_from_slice(&c.mean.to_le_bytes());
+            bytes.extend_from_slice(&c.weight.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes the digest.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+        let mut digest = TDigest::new(reader.f64()?);
+        digest.min = reader.f64()?;
+        digest.max = reader.f64()?;
+        for _ in 0..reader.u32()? {
+            digest.centroids.push(Centroid {
+                mean:   reader.f64()?,
+                weight: reader.f64()?,
+            });
+        }
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: f64, actual: Option<f64>) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() <= 0.01 * 100_000.0,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn estimate_quantiles() {
+        let mut digest = TDigest::default();
+        // Shuffle the values deterministically.
+        (0..100_000).for_each(|i| digest.add(((i * 7919) % 100_000) as f64));
+        assert_eq!(100_000.0, digest.count());
+        assert_close(50_000.0, digest.quantile(0.5));
+        assert_close(99_000.0, digest.quantile(0.99));
+        assert_eq!(Some(0.0), digest.quantile(0.0));
+        assert_eq!(Some(99_999.0), digest.quantile(1.0));
+        assert!(digest.compressed().len() < 500);
+        assert_eq!(None, TDigest::default().quantile(0.5));
+    }
+
+    #[test]
+    fn merge_partial_digests() -> Result<()> {
+        let (mut a, mut b) = (TDigest::default(), TDigest::default());
+        (0..100_000).for_each(|i| {
+            if i % 3 == 0 {
+                a.add(i as f64)
+            } else {
+                b.add(i as f64)
+            }
+        });
+
+        let mut merged = TDigest::from_bytes(&a.to_bytes())?;
+        merged.merge(&TDigest::from_bytes(&b.to_bytes())?);
+        assert_eq!(100_000.0, merged.count());
+        assert_close(25_000.0, merged.quantile(0.25));
+        assert_close(90_000.0, merged.quantile(0.9));
+        Ok(())
+    }
+}
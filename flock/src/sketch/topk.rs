@@ -0,0 +1,241 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The count-min sketch estimates the frequencies of the values with a few rows
+//! of counters, and the top-K tracks the candidates with the largest estimated
+//! frequencies. Two sketches are merged by adding their counters and keeping
+//! the largest candidates of both.
+
+use super::{hash_of, ByteReader};
+use crate::error::{FlockError, Result};
+use std::collections::HashMap;
+
+/// The number of candidates tracked for each of the top-K values, so that the
+/// top-K of a merged sketch is still accurate.
+const CANDIDATE_FACTOR: usize = 4;
+
+/// A count-min sketch with the candidates of the top-K values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopK {
+    k:          usize,
+    width:      usize,
+    depth:      usize,
+    counters:   Vec<u64>,
+    candidates: HashMap<String, u64>,
+}
+
+impl TopK {
+    /// The default number of counters in each row.
+    pub const DEFAULT_WIDTH: usize = 2048;
+    /// The default number of rows.
+    pub const DEFAULT_DEPTH: usize = 4;
+
+    /// Creates an empty sketch of the top `k` values.
+    pub fn new(k: usize) -> Self {
+        Self::with_dimensions(k, Self::DEFAULT_WIDTH, Self::DEFAULT_DEPTH)
+    }
+
+    /// Creates an empty sketch of the top `k` values with `depth` rows of
+    /// `width` counters.
+    pub fn with_dimensions(k: usize, width: usize, depth: usize) -> Self {
+        TopK {
+            k,
+            width,
+            depth,
+            counters: vec![0; width * depth],
+            candidates: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of the top values.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    fn capacity(&self) -> usize {
+        self.k * CANDIDATE_FACTOR
+    }
+
+    fn cells(&self, value: &str) -> impl Iterator<Item = usize> + '_ {
+        let width = self.width;
+        (0..self.depth).map(move |row| row * width + (hash_of(&(row, value)) as usize) % width)
+    }
+
+    /// Returns the estimated frequency of the value, which is never lower than
+    /// the actual one.
+    pub fn estimate(&self, value: &str) -> u64 {
+        self.cells(value)
+            .map(|cell| self.counters[cell])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Adds the occurrences of a value to the sketch.
+    pub fn add(&mut self, value: &str, count: u64) {
+        let cells = self.cells(value).collect::<Vec<_>>();
+        cells
+            .into_iter()
+            .for_each(|cell| self.counters[cell] += count);
+
+        let estimate = self.estimate(value);
+        if let Some(c) = self.candidates.get_mut(value) {
+            *c = estimate;
+        } else if self.candidates.len() < self.capacity() {
+            self.candidates.insert(value.to_owned(), estimate);
+        } else if let Some((smallest, c)) = self.smallest_candidate() {
+            if estimate > c {
+                self.candidates.remove(&smallest);
+                self.candidates.insert(value.to_owned(), estimate);
+            }
+        }
+    }
+
+    fn smallest_candidate(&self) -> Option<(String, u64)> {
+        self.candidates
+            .iter()
+            .min_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(v, c)| (v.clone(), *c))
+    }
+
+    /// Merges another sketch of the same dimensions into this one.
+    pub fn merge(&mut self, other: &TopK) -> Result<()> {
+        if (self.width, self.depth) != (other.width, other.depth) {
+            return Err(FlockError::Execution(format!(
+                "Can't merge count-min sketches of {}x{} and {}x{} counters.",
+                self.depth, self.width, other.depth, other.width
+            )));
+        }
+        self.k = self.k.max(other.k);
+        self.counters
+            .iter_mut()
+            .zip(other.counters.iter())
+            .for_each(|(a, b)| *a += *b);
+
+        let mut candidates = self
+            .candidates
+            .keys()
+            .chain(other.candidates.keys())
+            .map(|v| (v.clone(), self.estimate(v)))
+            .collect::<HashMap<_, _>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        sort_by_count(&mut candidates);
+        candidates.truncate(self.capacity());
+        self.candidates = candidates.into_iter().collect();
+        Ok(())
+    }
+
+    /// Returns the top-K values with their estimated frequencies, in
+    /// descending order.
+    pub fn top(&self) -> Vec<(String, u64)> {
+        let mut top = self
+            .candidates
+            .iter()
+            .map(|(v, c)| (v.clone(), *c))
+            .collect::<Vec<_>>();
+        sort_by_count(&mut top);
+        top.truncate(self.k);
+        top
+    }
+
+    /// Encodes the sketch.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.counters.len() * 8);
+        for n in [self.k, self.width, self.depth] {
+            bytes.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        for c in self.counters.iter() {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.candidates.len() as u32).to_le_bytes());
+        for value in self.candidates.keys() {
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes the sketch. The frequencies of the candidates are estimated
+    /// again from the counters.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+        let (k, width, depth) = (
+            reader.u32()? as usize,
+            reader.u32()? as usize,
+            reader.u32()? as usize,
+        );
+        let mut topk = TopK::with_dimensions(k, width, depth);
+        for c in topk.counters.iter_mut() {
+            *c = reader.u64()?;
+        }
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let value = String::from_utf8(reader.bytes(len)?.to_vec())
+                .map_err(|e| FlockError::Execution(e.to_string()))?;
+            let estimate = topk.estimate(&value);
+            topk.candidates.insert(value, estimate);
+        }
+        Ok(topk)
+    }
+}
+
+/// Sorts the values by their frequencies in descending order, and then by the
+/// values.
+fn sort_by_count(values: &mut [(String, u64)]) {
+    values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds a skewed stream where the value `i` occurs `1000 / i` times.
+    fn add_skewed(topk: &mut TopK, values: impl Iterator<Item = u64>) {
+        for i in values {
+            topk.add(&i.to_string(), 1000 / i);
+        }
+    }
+
+    /// Checks the top values, whose estimated frequencies can be a bit higher
+    /// than the actual ones due to hash collisions.
+    fn assert_top(expected: Vec<(&str, u64)>, top: Vec<(String, u64)>) {
+        assert_eq!(expected.len(), top.len());
+        for ((value, count), (v, c)) in expected.into_iter().zip(top) {
+            assert_eq!(value, v);
+            assert!(c >= count && c <= count + 20, "{}: {} vs {}", v, c, count);
+        }
+    }
+
+    #[test]
+    fn track_heavy_hitters() {
+        let mut topk = TopK::new(3);
+        add_skewed(&mut topk, 1..=500);
+        assert_top(vec![("1", 1000), ("2", 500), ("3", 333)], topk.top());
+    }
+
+    #[test]
+    fn merge_partial_sketches() -> Result<()> {
+        let (mut a, mut b) = (TopK::new(3), TopK::new(3));
+        add_skewed(&mut a, (1..=500).filter(|i| i % 2 == 0));
+        add_skewed(&mut b, (1..=500).filter(|i| i % 2 == 1));
+        // The value 4 occurs in both halves.
+        b.add("4", 300);
+
+        let mut merged = TopK::from_bytes(&a.to_bytes())?;
+        merged.merge(&TopK::from_bytes(&b.to_bytes())?)?;
+        assert_top(vec![("1", 1000), ("4", 550), ("2", 500)], merged.top());
+
+        assert!(merged.merge(&TopK::with_dimensions(3, 64, 2)).is_err());
+        Ok(())
+    }
+}
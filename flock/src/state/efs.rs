@@ -14,14 +14,20 @@
 //! Use EFS state backend to manage the state of the execution engine.
 
 use super::StateBackend;
+use crate::configs::FLOCK_CONFIG;
 use crate::error::Result;
 use crate::runtime::payload::Payload;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 /// EfsStateBackend is a state backend that stores query states in Amazon
 /// Elastic File System (EFS).
+///
+/// An object is a file at `<mount path>/<bucket>/<key>`, where the mount path
+/// is `efs.mount_path` in the Flock configuration.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct EfsStateBackend {}
 
@@ -40,12 +46,50 @@ impl StateBackend for EfsStateBackend {
         self
     }
 
-    async fn write(&self, _: String, _: String, _: Vec<u8>) -> Result<()> {
-        unimplemented!();
+    async fn write(&self, bucket: String, key: String, payload_bytes: Vec<u8>) -> Result<()> {
+        let path = self.path(&bucket, &key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, payload_bytes)?;
+        Ok(())
     }
 
-    async fn read(&self, _: String, _: Vec<String>) -> Result<Vec<Payload>> {
-        unreachable!()
+    async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>> {
+        keys.into_iter()
+            .map(|key| {
+                Ok(serde_json::from_slice(&fs::read(
+                    self.path(&bucket, &key),
+                )?)?)
+            })
+            .collect()
+    }
+
+    async fn get(&self, bucket: String, key: String) -> Result<Option<Vec<u8>>> {
+        let path = self.path(&bucket, &key);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    async fn keys(&self, bucket: String, prefix: String) -> Result<Vec<String>> {
+        let root = self.path(&bucket, "");
+        let mut keys = vec![];
+        if root.is_dir() {
+            list_files(&root, &root, &mut keys)?;
+        }
+        keys.retain(|key| key.starts_with(&prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn delete(&self, bucket: String, key: String) -> Result<()> {
+        let path = self.path(&bucket, &key);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
     }
 }
 
@@ -54,4 +98,29 @@ impl EfsStateBackend {
     pub fn new() -> Self {
         Self {}
     }
+
+    /// Returns the path of the object on the EFS mount.
+    fn path(&self, bucket: &str, key: &str) -> PathBuf {
+        Path::new(&FLOCK_CONFIG.efs.mount_path)
+            .join(bucket)
+            .join(key)
+    }
+}
+
+/// Collects the paths of the files under the directory, relative to the root
+/// and separated by `/`.
+fn list_files(root: &Path, dir: &Path, keys: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(root, &path, keys)?;
+        } else if let Ok(key) = path.strip_prefix(root) {
+            let parts = key
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>();
+            keys.push(parts.join("/"));
+        }
+    }
+    Ok(())
 }
@@ -42,6 +42,10 @@
 //!
 //! If nothing else is configured, the system will use the HashMapStateBackend.
 //!
+//! Besides the payloads of the former stage, the state backends also hold the
+//! checkpoints of the incomplete windows in a function's arena, which are
+//! restored when the function starts cold.
+//!
 //! Note that S3StateBackend and EfsStateBackend allow keeping very large state,
 //! compared to the HashMapStateBackend that keeps state in memory. This also
 //! means, however, that the maximum throughput that can be achieved will be
@@ -65,12 +69,15 @@ pub use s3::S3StateBackend;
 mod efs;
 pub use efs::EfsStateBackend;
 
-use crate::error::Result;
+use crate::error::{FlockError, Result};
 use crate::runtime::payload::Payload;
 use async_trait::async_trait;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 
 /// The state backend trait defines the interface for state backends.
 #[async_trait]
@@ -88,6 +95,18 @@ pub trait StateBackend: Debug + Send + Sync {
     async fn write(&self, bucket: String, key: String, payload_bytes: Vec<u8>) -> Result<()>;
     /// Reads payloads from the state backend.
     async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>>;
+    /// Reads the bytes of an object from the state backend, if it exists.
+    async fn get(&self, bucket: String, key: String) -> Result<Option<Vec<u8>>>;
+    /// Returns the keys of the objects with the given prefix.
+    async fn keys(&self, bucket: String, prefix: String) -> Result<Vec<String>>;
+    /// Deletes an object from the state backend.
+    async fn delete(&self, bucket: String, key: String) -> Result<()>;
+}
+
+lazy_static! {
+    /// The objects of the `HashMapStateBackend`, keyed by bucket and key.
+    static ref HASHMAP_STATE: Mutex<BTreeMap<(String, String), Vec<u8>>> =
+        Mutex::new(BTreeMap::new());
 }
 
 /// The default state backend.
@@ -95,6 +114,9 @@ pub trait StateBackend: Debug + Send + Sync {
 /// Note: Currently, the functionalities of the HashMapStateBackend are
 /// implemented by the `Arena` module. `HashMapStateBackend` is an unified
 /// abstraction we will use to encapsulate the Arena module.
+///
+/// The objects written to the HashMapStateBackend are kept in the function's
+/// global memory, so they're lost when the function's container is recycled.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct HashMapStateBackend {}
 
@@ -113,12 +135,39 @@ impl StateBackend for HashMapStateBackend {
         self
     }
 
-    async fn write(&self, _: String, _: String, _: Vec<u8>) -> Result<()> {
-        unreachable!()
+    async fn write(&self, bucket: String, key: String, payload_bytes: Vec<u8>) -> Result<()> {
+        HASHMAP_STATE
+            .lock()
+            .unwrap()
+            .insert((bucket, key), payload_bytes);
+        Ok(())
     }
 
-    async fn read(&self, _: String, _: Vec<String>) -> Result<Vec<Payload>> {
-        unreachable!()
+    async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>> {
+        let state = HASHMAP_STATE.lock().unwrap();
+        keys.into_iter()
+            .filter_map(|key| state.get(&(bucket.clone(), key)))
+            .map(|bytes| Ok(serde_json::from_slice(bytes)?))
+            .collect()
+    }
+
+    async fn get(&self, bucket: String, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(HASHMAP_STATE.lock().unwrap().get(&(bucket, key)).cloned())
+    }
+
+    async fn keys(&self, bucket: String, prefix: String) -> Result<Vec<String>> {
+        Ok(HASHMAP_STATE
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(b, k)| *b == bucket && k.starts_with(&prefix))
+            .map(|(_, k)| k.clone())
+            .collect())
+    }
+
+    async fn delete(&self, bucket: String, key: String) -> Result<()> {
+        HASHMAP_STATE.lock().unwrap().remove(&(bucket, key));
+        Ok(())
     }
 }
 
@@ -128,3 +177,16 @@ impl HashMapStateBackend {
         Self {}
     }
 }
+
+/// Creates the state backend with the given name: `hashmap`, `s3` or `efs`.
+pub fn from_name(name: &str) -> Result<Arc<dyn StateBackend>> {
+    match name {
+        "hashmap" => Ok(Arc::new(HashMapStateBackend::new())),
+        "s3" => Ok(Arc::new(S3StateBackend::new())),
+        "efs" => Ok(Arc::new(EfsStateBackend::new())),
+        _ => Err(FlockError::Config(format!(
+            "Unknown state backend `{}`, expected `hashmap`, `s3` or `efs`",
+            name
+        ))),
+    }
+}
@@ -83,6 +83,21 @@ impl StateBackend for S3StateBackend {
             .map(|r| r.unwrap().unwrap())
             .collect())
     }
+
+    async fn get(&self, bucket: String, key: String) -> Result<Option<Vec<u8>>> {
+        if !s3::get_matched_keys(&bucket, &key).await?.contains(&key) {
+            return Ok(None);
+        }
+        Ok(Some(s3::get_object(&bucket, &key).await?))
+    }
+
+    async fn keys(&self, bucket: String, prefix: String) -> Result<Vec<String>> {
+        s3::get_matched_keys(&bucket, &prefix).await
+    }
+
+    async fn delete(&self, bucket: String, key: String) -> Result<()> {
+        s3::delete_object(&bucket, &key).await
+    }
 }
 
 impl S3StateBackend {
@@ -0,0 +1,269 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module provides the trace context propagated across function hops.
+//!
+//! A window's data flows from the data source through the query stages to the
+//! data sink. Each hop carries a [`TraceContext`] in its
+//! [`Payload`](crate::runtime::payload::Payload) (or
+//! [`DataSink`](crate::datasink::DataSink)), and each function records its
+//! [`Span`]s as children of the incoming context. The spans are exported as
+//! OpenTelemetry (OTLP) JSON lines, so the end-to-end critical path of any
+//! window can be reconstructed by its trace id.
+
+use crate::configs::FLOCK_CONFIG;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The trace context of a payload, compatible with the W3C Trace Context.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TraceContext {
+    /// The 16-byte trace id in lowercase hex.
+    pub trace_id: String,
+    /// The 8-byte span id of the sender in lowercase hex.
+    pub span_id:  String,
+}
+
+impl TraceContext {
+    /// Creates the context of a new trace.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: format!("{:032x}", rand::random::<u128>() | 1),
+            span_id:  new_span_id(),
+        }
+    }
+
+    /// Returns the context of a new span in the same trace.
+    pub fn new_child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id:  new_span_id(),
+        }
+    }
+
+    /// Returns the W3C `traceparent` header value.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+
+    /// Parses the W3C `traceparent` header value.
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let parts = traceparent.split('-').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [_, trace_id, span_id, _] if trace_id.len() == 32 && span_id.len() == 16 => {
+                Some(Self {
+                    trace_id: trace_id.to_string(),
+                    span_id:  span_id.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn new_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>() | 1)
+}
+
+fn now_nanos() -> u64 {
+    Utc::now().timestamp_nanos() as u64
+}
+
+/// A timed operation in a trace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    /// The trace context of the span.
+    pub context:    TraceContext,
+    /// The span id of the parent span.
+    pub parent_id:  Option<String>,
+    /// The name of the operation.
+    pub name:       String,
+    /// The start time in nanoseconds since the UNIX epoch.
+    pub start_time: u64,
+    /// The end time in nanoseconds since the UNIX epoch.
+    pub end_time:   u64,
+    /// The attributes of the span, e.g., function name and window id.
+    pub attributes: BTreeMap<String, String>,
+    /// The error message if the operation failed.
+    pub error:      Option<String>,
+}
+
+impl Span {
+    /// Starts a span. If the parent is `None`, the span starts a new trace.
+    pub fn start(name: &str, parent: Option<&TraceContext>) -> Self {
+        let (context, parent_id) = match parent {
+            Some(parent) => (parent.new_child(), Some(parent.span_id.clone())),
+            None => (TraceContext::new_root(), None),
+        };
+        Self {
+            context,
+            parent_id,
+            name: name.to_string(),
+            start_time: now_nanos(),
+            end_time: 0,
+            attributes: BTreeMap::new(),
+            error: None,
+        }
+    }
+
+    /// Starts a child span of the current span.
+    pub fn child(&self, name: &str) -> Self {
+        Span::start(name, Some(&self.context))
+    }
+
+    /// Sets an attribute of the span.
+    pub fn set_attribute(&mut self, key: &str, value: &str) {
+        self.attributes.insert(key.to_string(), value.to_string());
+    }
+
+    /// Ends the span.
+    pub fn end(mut self) -> Self {
+        self.end_time = now_nanos();
+        self
+    }
+
+    /// Ends the span with the result of the operation.
+    pub fn end_with<T, E: std::fmt::Display>(mut self, result: &Result<T, E>) -> Self {
+        if let Err(e) = result {
+            self.error = Some(e.to_string());
+        }
+        self.end()
+    }
+
+    /// Returns the span in the OTLP JSON format.
+    pub fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": self.context.trace_id,
+            "spanId": self.context.span_id,
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": self.start_time.to_string(),
+            "endTimeUnixNano": self.end_time.to_string(),
+            "attributes": to_otlp_attributes(&self.attributes),
+            "status": match &self.error {
+                // STATUS_CODE_ERROR
+                Some(e) => json!({ "code": 2, "message": e }),
+                // STATUS_CODE_OK
+                None => json!({ "code": 1 }),
+            },
+        });
+        if let Some(parent_id) = &self.parent_id {
+            span["parentSpanId"] = json!(parent_id);
+        }
+        span
+    }
+}
+
+fn to_otlp_attributes(attributes: &BTreeMap<String, String>) -> Value {
+    attributes
+        .iter()
+        .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+        .collect()
+}
+
+/// Collects the spans of a function invocation and exports them.
+#[derive(Debug, Clone, Default)]
+pub struct Tracer {
+    spans: Arc<Mutex<Vec<Span>>>,
+}
+
+impl Tracer {
+    /// Creates a tracer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a finished span.
+    pub fn record(&self, span: Span) {
+        self.spans.lock().unwrap().push(span);
+    }
+
+    /// Returns the recorded spans.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// Returns the recorded spans in the OTLP JSON format.
+    pub fn to_otlp(&self, service_name: &str) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": service_name },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "flock", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": self.spans().iter().map(|s| s.to_otlp()).collect::<Vec<_>>(),
+                }],
+            }],
+        })
+    }
+
+    /// Exports the recorded spans to stdout as an OTLP JSON line if tracing
+    /// is enabled, and clears them.
+    pub fn flush(&self, service_name: &str) {
+        if FLOCK_CONFIG.trace.enabled && !self.spans.lock().unwrap().is_empty() {
+            println!("{}", self.to_otlp(service_name));
+        }
+        self.spans.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_propagation() {
+        let tracer = Tracer::new();
+
+        // stage 0
+        let handler = Span::start("handler", None);
+        let invoke = handler.child("invoke_next_functions");
+        let outgoing = invoke.context.clone();
+        tracer.record(invoke.end());
+        tracer.record(handler.end());
+
+        // stage 1
+        let parsed = TraceContext::from_traceparent(&outgoing.traceparent()).unwrap();
+        assert_eq!(outgoing, parsed);
+        let mut handler = Span::start("handler", Some(&parsed));
+        handler.set_attribute("flock.function", "q-01-00");
+        let result: Result<(), String> = Err("boom".to_string());
+        tracer.record(handler.end_with(&result));
+
+        let spans = tracer.spans();
+        assert_eq!(3, spans.len());
+        assert!(spans
+            .iter()
+            .all(|s| s.context.trace_id == outgoing.trace_id));
+        assert_eq!(Some(outgoing.span_id.clone()), spans[2].parent_id);
+        assert_eq!(None, spans[1].parent_id);
+        assert!(spans.iter().all(|s| s.end_time >= s.start_time));
+
+        let otlp = tracer.to_otlp("flock");
+        let spans = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(3, spans.as_array().unwrap().len());
+        assert_eq!(json!(outgoing.span_id), spans[2]["parentSpanId"]);
+        assert_eq!(2, spans[2]["status"]["code"]);
+        assert_eq!("q-01-00", spans[2]["attributes"][0]["value"]["stringValue"]);
+
+        assert!(TraceContext::from_traceparent("00-abc-def-01").is_none());
+    }
+}
@@ -16,11 +16,13 @@
 use crate::datasource::DataSource;
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
-use crate::runtime::payload::{DataFrame, Payload, Uuid};
+use crate::quarantine::MalformedRecord;
+use crate::runtime::payload::{output_id, DataFrame, InputBatches, Payload, PayloadInput, Uuid};
+use crate::runtime::schema::{first_contact, register_schema};
 use datafusion::arrow::datatypes::{Schema, SchemaRef};
-use datafusion::arrow::json;
+use datafusion::arrow::json::{self, reader::infer_json_schema};
 use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
+use datafusion::arrow_flight::utils::{flight_data_from_arrow_batch, flight_data_to_arrow_batch};
 use datafusion::arrow_flight::FlightData;
 use datafusion::arrow_flight::SchemaAsIpc;
 use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
@@ -109,20 +111,50 @@ pub async fn repartition(
 }
 
 /// Deserialize `DataFrame` from cloud functions.
-pub fn unmarshal(data: Vec<DataFrame>, encoding: Encoding) -> Vec<DataFrame> {
+pub fn unmarshal(data: &[DataFrame], encoding: &Encoding) -> Result<Vec<DataFrame>> {
     match encoding {
         Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => data
             .par_iter()
-            .map(|d| DataFrame {
-                header: encoding.decompress(&d.header).unwrap(),
-                body:   encoding.decompress(&d.body).unwrap(),
+            .map(|d| {
+                Ok(DataFrame {
+                    header: encoding.decompress(&d.header)?,
+                    body:   encoding.decompress(&d.body)?,
+                })
             })
             .collect(),
-        Encoding::None => data,
-        _ => unimplemented!(),
+        Encoding::None => Ok(data.to_vec()),
+        _ => Err(FlockError::Execution(format!(
+            "Unsupported encoding: {:?}",
+            encoding
+        ))),
     }
 }
 
+/// Decode the `DataFrame`s from cloud functions to record batches in Arrow
+/// format. A corrupt frame results in an error rather than a panic.
+pub fn dataframes_to_batches(
+    data: &[DataFrame],
+    encoding: &Encoding,
+    schema: SchemaRef,
+) -> Result<Vec<RecordBatch>> {
+    unmarshal(data, encoding)?
+        .into_par_iter()
+        .map(|d| {
+            flight_data_to_arrow_batch(
+                &FlightData {
+                    data_body:         d.body,
+                    data_header:       d.header,
+                    app_metadata:      vec![],
+                    flight_descriptor: None,
+                },
+                schema.clone(),
+                &[],
+            )
+            .map_err(FlockError::Arrow)
+        })
+        .collect()
+}
+
 /// Serialize the schema
 pub fn schema_to_bytes(schema: SchemaRef) -> Vec<u8> {
     let options = datafusion::arrow::ipc::writer::IpcWriteOptions::default();
@@ -138,8 +170,8 @@ pub fn schema_from_bytes(bytes: &[u8]) -> Result<Arc<Schema>> {
 }
 
 /// Convert incoming payload to record batches in Arrow format.
-pub fn json_value_to_batch(event: Value) -> (Vec<RecordBatch>, Vec<RecordBatch>) {
-    let payload: Payload = serde_json::from_value(event).unwrap();
+pub fn json_value_to_batch(event: Value) -> Result<InputBatches> {
+    let payload: Payload = serde_json::from_value(event)?;
     payload.to_record_batch()
 }
 
@@ -165,8 +197,12 @@ pub fn batch_to_json_value(batches: &[RecordBatch], uuid: Uuid, encoding: Encodi
         .collect();
 
     serde_json::to_value(&Payload {
-        data: data_frames,
-        schema: schema_to_bytes(batches[0].schema()),
+        inputs: vec![PayloadInput {
+            id: output_id(0),
+            data: data_frames,
+            schema: schema_to_bytes(batches[0].schema()),
+            ..Default::default()
+        }],
         uuid,
         encoding,
         ..Default::default()
@@ -175,12 +211,13 @@ pub fn batch_to_json_value(batches: &[RecordBatch], uuid: Uuid, encoding: Encodi
 }
 
 /// Convert record batches to payload using the default encoding.
-pub fn to_payload(
-    batch1: &[RecordBatch],
-    batch2: &[RecordBatch],
-    uuid: Uuid,
-    sync: bool,
-) -> Payload {
+///
+/// # Arguments
+/// * `inputs` - The record batches of each input of the next stage, keyed by
+///   the input id. The inputs without record batches are omitted.
+/// * `uuid` - The uuid of the payload.
+/// * `sync` - The function invocation type.
+pub fn to_payload(inputs: &[(&str, &[RecordBatch])], uuid: Uuid, sync: bool) -> Payload {
     let options = datafusion::arrow::ipc::writer::IpcWriteOptions::default();
     let encoding = Encoding::default();
     let dataframe = |batches: &[RecordBatch]| -> Vec<DataFrame> {
@@ -203,21 +240,33 @@ pub fn to_payload(
             .collect()
     };
 
-    let mut payload = Payload {
+    // The schemas are registered by the next stage, so the payload only carries
+    // their fingerprints, except for the first payload of each schema.
+    let inputs = inputs
+        .iter()
+        .filter(|(_, batches)| !batches.is_empty())
+        .map(|(id, batches)| {
+            let fp = register_schema(batches[0].schema());
+            PayloadInput {
+                id:                 id.to_string(),
+                data:               dataframe(batches),
+                schema:             if first_contact(fp) {
+                    schema_to_bytes(batches[0].schema())
+                } else {
+                    vec![]
+                },
+                schema_fingerprint: Some(fp),
+            }
+        })
+        .collect();
+
+    Payload {
+        inputs,
         uuid,
         encoding: encoding.clone(),
         datasource: DataSource::Payload(sync),
         ..Default::default()
-    };
-    if !batch1.is_empty() {
-        payload.data = dataframe(batch1);
-        payload.schema = schema_to_bytes(batch1[0].schema());
-    }
-    if !batch2.is_empty() {
-        payload.data2 = dataframe(batch2);
-        payload.schema2 = schema_to_bytes(batch2[0].schema());
     }
-    payload
 }
 
 /// Convert record batch to bytes for network transmission.
@@ -241,8 +290,12 @@ pub fn to_bytes(batch: &RecordBatch, uuid: Uuid, encoding: Encoding) -> bytes::B
     };
 
     serde_json::to_vec(&Payload {
-        data: vec![data_frames],
-        schema,
+        inputs: vec![PayloadInput {
+            id: output_id(0),
+            data: vec![data_frames],
+            schema,
+            ..Default::default()
+        }],
         uuid,
         encoding,
         ..Default::default()
@@ -251,6 +304,73 @@ pub fn to_bytes(batch: &RecordBatch, uuid: Uuid, encoding: Encoding) -> bytes::B
     .into()
 }
 
+/// Converts the raw JSON records of a stream event to record batches in Arrow
+/// format. The schema is inferred from the first valid record.
+///
+/// # Arguments
+/// * `records` - The raw records, or the records that already failed to be
+///   decoded by the event source.
+///
+/// # Returns
+/// The record batches and the malformed records.
+pub fn json_records_to_batch(
+    records: Vec<std::result::Result<Vec<u8>, MalformedRecord>>,
+) -> Result<(Vec<RecordBatch>, Vec<MalformedRecord>)> {
+    let mut input = vec![];
+    let mut malformed = vec![];
+    let mut schema = None;
+
+    for record in records {
+        let data = match record {
+            Ok(data) => data,
+            Err(m) => {
+                malformed.push(m);
+                continue;
+            }
+        };
+        match serde_json::from_slice::<Value>(&data) {
+            Ok(value @ Value::Object(_)) => {
+                // Each record must be a single line for the JSON reader.
+                let line = serde_json::to_vec(&value)?;
+                if schema.is_none() {
+                    schema = Some(infer_json_schema(&mut BufReader::new(&line[..]), Some(1))?);
+                }
+                input.extend(line);
+                input.push(b'\n');
+            }
+            Ok(_) => malformed.push(MalformedRecord {
+                data,
+                reason: "The record is not a JSON object".to_owned(),
+            }),
+            Err(e) => malformed.push(MalformedRecord {
+                data,
+                reason: format!("Invalid JSON record: {}", e),
+            }),
+        }
+    }
+
+    let schema = match schema {
+        Some(schema) => Arc::new(schema),
+        None => return Ok((vec![], malformed)),
+    };
+
+    // The default batch size when using the
+    // [`ReaderBuilder`](json::Reader::ReaderBuilder) is 1024 records
+    let batch_size = 1024;
+    let mut reader = json::Reader::new(
+        BufReader::with_capacity(input.len(), &input[..]),
+        schema,
+        batch_size,
+        None,
+    );
+
+    let mut batches = vec![];
+    while let Some(batch) = reader.next()? {
+        batches.push(batch);
+    }
+    Ok((batches, malformed))
+}
+
 /// Converts events to record batches in Arrow format.
 pub fn event_bytes_to_batch(
     events: &[u8],
@@ -417,4 +537,46 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn malformed_json_records() -> Result<()> {
+        let records = vec![
+            Ok(br#"{"c0": 1, "c1": "a"}"#.to_vec()),
+            Ok(b"{\"c0\": 2,\n \"c1\": \"b\"}".to_vec()),
+            Ok(b"{\"c0\": 3, \"c1\"".to_vec()),
+            Ok(b"[1, 2, 3]".to_vec()),
+            Err(MalformedRecord {
+                data:   b"@@@".to_vec(),
+                reason: "Invalid base64 value".to_owned(),
+            }),
+        ];
+
+        let (batches, malformed) = json_records_to_batch(records)?;
+        assert_eq!(1, batches.len());
+        assert_eq!(2, batches[0].num_rows());
+        assert_eq!(2, batches[0].num_columns());
+
+        assert_eq!(3, malformed.len());
+        assert!(malformed[0].reason.starts_with("Invalid JSON record"));
+        assert_eq!(b"[1, 2, 3]".to_vec(), malformed[1].data);
+        assert_eq!("Invalid base64 value", malformed[2].reason);
+
+        // No valid record at all.
+        let (batches, malformed) = json_records_to_batch(vec![Ok(b"oops".to_vec())])?;
+        assert!(batches.is_empty());
+        assert_eq!(1, malformed.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_dataframes() {
+        let schema = test_schema();
+        let frames = vec![DataFrame {
+            header: vec![1, 2, 3],
+            body:   vec![4, 5, 6],
+        }];
+        assert!(dataframes_to_batches(&frames, &Encoding::None, schema.clone()).is_err());
+        assert!(dataframes_to_batches(&frames, &Encoding::Zstd, schema).is_err());
+    }
 }
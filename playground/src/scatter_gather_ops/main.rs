@@ -44,7 +44,7 @@ struct PlaygroundOpt {
 }
 
 async fn create_function(func_name: &str) -> Result<String> {
-    let s3_bucket = FLOCK_CONF["s3"]["bucket"].to_string();
+    let s3_bucket = FLOCK_CONFIG.s3.bucket.clone();
     if LAMBDA_CLIENT
         .get_function(GetFunctionRequest {
             function_name: String::from(func_name),